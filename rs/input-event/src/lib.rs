@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
-use strum::{EnumIter, FromRepr};
+use strum::{EnumIter, FromRepr, IntoStaticStr};
 
-#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, IntoStaticStr, Debug)]
+#[rustfmt::skip]
 pub enum InputEvent {
     MouseMove { dx: i16, dy: i16 },
 
@@ -13,27 +14,216 @@ pub enum InputEvent {
     KeyDown { key: KeyCode },
     KeyRepeat { key: KeyCode },
     KeyUp { key: KeyCode },
+
+    /// Key without a [KeyCode], identified by its HID usage.
+    RawKeyDown { usage: HidUsage },
+    RawKeyUp { usage: HidUsage },
+
+    /// High resolution scroll in 1/120 of a wheel click, positive delta
+    /// scrolls up or right.
+    MouseScrollHiRes { axis: ScrollAxis, delta: i16 },
+
+    /// Absolute pointer of tablets and touchscreens.
+    AbsolutePointer { pointer: AbsolutePointer },
+
+    /// Gamepads and joysticks.
+    Gamepad { event: GamepadEvent },
+
+    /// Touchpad gestures.
+    Gesture { gesture: Gesture },
 }
 
 #[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
 pub enum MouseScrollDirection {
     Up { clicks: u8 },
     Down { clicks: u8 },
+    Left { clicks: u8 },
+    Right { clicks: u8 },
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub enum ScrollAxis {
+    Vertical,
+    Horizontal,
+}
+
+/// State of an absolute pointer.
+///
+/// Coordinates are normalised, 0 and [u16::MAX] are the edges of the screen.
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize, Debug)]
+pub struct AbsolutePointer {
+    pub x: u16,
+    pub y: u16,
+    /// Whether the tool is close enough to the surface to be tracked.
+    pub in_range: bool,
+    /// Whether the pointer touches the surface.
+    pub touch: bool,
+    /// Normalised pressure, if the device reports it.
+    pub pressure: Option<u16>,
+    /// Tilt in degrees, if the device reports it.
+    pub tilt: Option<Tilt>,
+}
+
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize, Debug)]
+pub struct Tilt {
+    pub x: i8,
+    pub y: i8,
+}
+
+/// Touchpad gesture.
+///
+/// Every begin is followed by updates and an end of the same kind, the end is
+/// cancelled if fingers are added before the gesture finishes.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub enum Gesture {
+    SwipeBegin {
+        fingers: u8,
+    },
+    /// Movement since the last update, [u16::MAX] spans the touchpad.
+    SwipeUpdate {
+        dx: i16,
+        dy: i16,
+    },
+    SwipeEnd {
+        cancelled: bool,
+    },
+
+    PinchBegin {
+        fingers: u8,
+    },
+    /// Scale relative to the beginning, and clockwise rotation in degrees
+    /// since the last update.
+    PinchUpdate {
+        scale: f32,
+        rotation: f32,
+    },
+    PinchEnd {
+        cancelled: bool,
+    },
+}
+
+/// Event of a gamepad or joystick.
+///
+/// Axes are announced before [GamepadEvent::Connected], so the receiving end
+/// can create a gamepad with the same axis ranges.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+#[rustfmt::skip]
+pub enum GamepadEvent {
+    AxisRange { axis: GamepadAxis, range: AxisRange },
+    Connected,
+    Disconnected,
+
+    ButtonDown { button: GamepadButton },
+    ButtonUp { button: GamepadButton },
+
+    AxisMove { axis: GamepadAxis, value: i32 },
+    /// Ends a frame, the events since the previous one happened together.
+    Sync,
+}
+
+/// Range of a gamepad axis, in the units of the device.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, Debug)]
+pub struct AxisRange {
+    pub minimum: i32,
+    pub maximum: i32,
+    /// Noise filtered out by the device.
+    pub fuzz: i32,
+    /// Dead zone around the center.
+    pub flat: i32,
+}
+
+#[repr(u8)]
+#[derive(FromRepr, EnumIter, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub enum GamepadAxis {
+    X = 0,
+    Y,
+    Z,
+    Rx,
+    Ry,
+    Rz,
+    Throttle,
+    Rudder,
+    Wheel,
+    Gas,
+    Brake,
+    HatX,
+    HatY,
+}
+
+/// Gamepad and joystick buttons, face buttons are named after their position.
+#[repr(u8)]
+#[derive(FromRepr, EnumIter, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub enum GamepadButton {
+    South = 0,
+    East,
+    North,
+    West,
+    C,
+    Z,
+    LeftBumper,
+    RightBumper,
+    LeftTrigger,
+    RightTrigger,
+    Select,
+    Start,
+    Mode,
+    LeftThumb,
+    RightThumb,
+    DpadUp,
+    DpadDown,
+    DpadLeft,
+    DpadRight,
+
+    // joystick
+    Trigger,
+    Thumb,
+    Thumb2,
+    Top,
+    Top2,
+    Pinkie,
+    Base,
+    Base2,
+    Base3,
+    Base4,
+    Base5,
+    Base6,
 }
 
 #[repr(u8)]
-#[derive(FromRepr, EnumIter, Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+#[derive(FromRepr, EnumIter, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
 pub enum MouseButton {
     Left = 0,
     Right,
     Middle,
     Mouse4,
     Mouse5,
+    Forward,
+    Back,
+    Task,
+}
+
+/// USB HID usage, as defined in the HID Usage Tables.
+///
+/// Identifies keys that have no [KeyCode] in a platform neutral way. Keys
+/// without a known usage carry their platform's code in a vendor defined page,
+/// only hosts of the same platform can emulate them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub struct HidUsage {
+    pub page: u16,
+    pub id: u16,
+}
+
+impl HidUsage {
+    /// Vendor defined page of evdev key codes.
+    pub const EVDEV_PAGE: u16 = 0xFF01;
+    /// Vendor defined page of set 1 scan codes, extended ones have 0xE0 in
+    /// their high byte.
+    pub const SCAN_CODE_PAGE: u16 = 0xFF02;
 }
 
 /// Keyboard key.
 #[repr(u16)]
-#[derive(FromRepr, EnumIter, Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+#[derive(FromRepr, EnumIter, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
 pub enum KeyCode {
     Escape = 0,
 
@@ -144,11 +334,67 @@ pub enum KeyCode {
     Left,
     Down,
     Right,
+
+    // numpad keys
+    NumLock,
+    KpDivide,
+    KpMultiply,
+    KpMinus,
+    KpPlus,
+    KpEnter,
+    KpDot,
+    Kp0,
+    Kp1,
+    Kp2,
+    Kp3,
+    Kp4,
+    Kp5,
+    Kp6,
+    Kp7,
+    Kp8,
+    Kp9,
+
+    /// The context menu key, also known as the application key.
+    Menu,
+
+    // media keys
+    Mute,
+    VolumeDown,
+    VolumeUp,
+    PlayPause,
+    NextTrack,
+    PreviousTrack,
+    StopMedia,
+
+    // extended function keys
+    F13,
+    F14,
+    F15,
+    F16,
+    F17,
+    F18,
+    F19,
+    F20,
+    F21,
+    F22,
+    F23,
+    F24,
+
+    // international keys
+    /// The extra key next to left shift on ISO keyboards.
+    Iso102nd,
+    Yen,
+    Ro,
+    Henkan,
+    Muhenkan,
+    KatakanaHiragana,
+    ZenkakuHankaku,
 }
 
 /// Define a bidirectional injective conversion.
 ///
 /// Given a set A and a set B. This macro takes definition of `A -> B` and generates functions `A -> B` and `B -> Option A`.
+/// Definitions in the `A = B` form may leave some of A out, `A -> Option B` is generated for them instead.
 macro_rules! def_conversion {
     (
         $l_ty:ty,
@@ -188,17 +434,17 @@ macro_rules! def_conversion {
             $($l_var:ident = $r_var:expr,)*
         }
     ) => {
-        impl Into<$r_ty> for $l_ty {
-            fn into(self) -> $r_ty {
-                use $l_ty::*;
-                match self {
-                    $($l_var => $r_var,)*
-                }
-            }
-        }
-
         paste::paste! {
             impl $l_ty {
+                #[allow(unused)]
+                pub fn [<to_$r_ty:snake:lower>](self) -> Option<$r_ty> {
+                    use $l_ty::*;
+                    match self {
+                        $($l_var => Some($r_var),)*
+                        _ => None,
+                    }
+                }
+
                 #[allow(unused)]
                 pub fn [<from_$r_ty:snake:lower>](x: $r_ty) -> Option<Self> {
                     use $l_ty::*;
@@ -215,7 +461,7 @@ macro_rules! def_conversion {
 #[cfg(target_os = "linux")]
 pub mod linux {
     use super::*;
-    use evdev_rs::enums::EV_KEY;
+    use evdev_rs::enums::{int_to_ev_key, EV_ABS, EV_KEY};
 
     def_conversion!(KeyCode, EV_KEY, {
         Escape => KEY_ESC,
@@ -324,6 +570,55 @@ pub mod linux {
         Left => KEY_LEFT,
         Down => KEY_DOWN,
         Right => KEY_RIGHT,
+
+        NumLock => KEY_NUMLOCK,
+        KpDivide => KEY_KPSLASH,
+        KpMultiply => KEY_KPASTERISK,
+        KpMinus => KEY_KPMINUS,
+        KpPlus => KEY_KPPLUS,
+        KpEnter => KEY_KPENTER,
+        KpDot => KEY_KPDOT,
+        Kp0 => KEY_KP0,
+        Kp1 => KEY_KP1,
+        Kp2 => KEY_KP2,
+        Kp3 => KEY_KP3,
+        Kp4 => KEY_KP4,
+        Kp5 => KEY_KP5,
+        Kp6 => KEY_KP6,
+        Kp7 => KEY_KP7,
+        Kp8 => KEY_KP8,
+        Kp9 => KEY_KP9,
+
+        Menu => KEY_COMPOSE,
+
+        Mute => KEY_MUTE,
+        VolumeDown => KEY_VOLUMEDOWN,
+        VolumeUp => KEY_VOLUMEUP,
+        PlayPause => KEY_PLAYPAUSE,
+        NextTrack => KEY_NEXTSONG,
+        PreviousTrack => KEY_PREVIOUSSONG,
+        StopMedia => KEY_STOPCD,
+
+        F13 => KEY_F13,
+        F14 => KEY_F14,
+        F15 => KEY_F15,
+        F16 => KEY_F16,
+        F17 => KEY_F17,
+        F18 => KEY_F18,
+        F19 => KEY_F19,
+        F20 => KEY_F20,
+        F21 => KEY_F21,
+        F22 => KEY_F22,
+        F23 => KEY_F23,
+        F24 => KEY_F24,
+
+        Iso102nd => KEY_102ND,
+        Yen => KEY_YEN,
+        Ro => KEY_RO,
+        Henkan => KEY_HENKAN,
+        Muhenkan => KEY_MUHENKAN,
+        KatakanaHiragana => KEY_KATAKANAHIRAGANA,
+        ZenkakuHankaku => KEY_ZENKAKUHANKAKU,
     });
 
     def_conversion!(MouseButton, EV_KEY, {
@@ -332,7 +627,202 @@ pub mod linux {
         Middle => BTN_MIDDLE,
         Mouse4 => BTN_SIDE,
         Mouse5 => BTN_EXTRA,
+        Forward => BTN_FORWARD,
+        Back => BTN_BACK,
+        Task => BTN_TASK,
+    });
+
+    def_conversion!(GamepadButton, EV_KEY, {
+        South => BTN_SOUTH,
+        East => BTN_EAST,
+        North => BTN_NORTH,
+        West => BTN_WEST,
+        C => BTN_C,
+        Z => BTN_Z,
+        LeftBumper => BTN_TL,
+        RightBumper => BTN_TR,
+        LeftTrigger => BTN_TL2,
+        RightTrigger => BTN_TR2,
+        Select => BTN_SELECT,
+        Start => BTN_START,
+        Mode => BTN_MODE,
+        LeftThumb => BTN_THUMBL,
+        RightThumb => BTN_THUMBR,
+        DpadUp => BTN_DPAD_UP,
+        DpadDown => BTN_DPAD_DOWN,
+        DpadLeft => BTN_DPAD_LEFT,
+        DpadRight => BTN_DPAD_RIGHT,
+
+        Trigger => BTN_TRIGGER,
+        Thumb => BTN_THUMB,
+        Thumb2 => BTN_THUMB2,
+        Top => BTN_TOP,
+        Top2 => BTN_TOP2,
+        Pinkie => BTN_PINKIE,
+        Base => BTN_BASE,
+        Base2 => BTN_BASE2,
+        Base3 => BTN_BASE3,
+        Base4 => BTN_BASE4,
+        Base5 => BTN_BASE5,
+        Base6 => BTN_BASE6,
     });
+
+    def_conversion!(GamepadAxis, EV_ABS, {
+        X => ABS_X,
+        Y => ABS_Y,
+        Z => ABS_Z,
+        Rx => ABS_RX,
+        Ry => ABS_RY,
+        Rz => ABS_RZ,
+        Throttle => ABS_THROTTLE,
+        Rudder => ABS_RUDDER,
+        Wheel => ABS_WHEEL,
+        Gas => ABS_GAS,
+        Brake => ABS_BRAKE,
+        HatX => ABS_HAT0X,
+        HatY => ABS_HAT0Y,
+    });
+
+    /// HID usages of keys without a [KeyCode], following the kernel's hid-input mapping.
+    const RAW_KEYS: &[(u16, u16, EV_KEY)] = &[
+        // generic desktop
+        (0x01, 0x81, EV_KEY::KEY_POWER),
+        (0x01, 0x82, EV_KEY::KEY_SLEEP),
+        (0x01, 0x83, EV_KEY::KEY_WAKEUP),
+        // keyboard
+        (0x07, 0x67, EV_KEY::KEY_KPEQUAL),
+        (0x07, 0x75, EV_KEY::KEY_HELP),
+        (0x07, 0x79, EV_KEY::KEY_AGAIN),
+        (0x07, 0x7A, EV_KEY::KEY_UNDO),
+        (0x07, 0x7B, EV_KEY::KEY_CUT),
+        (0x07, 0x7C, EV_KEY::KEY_COPY),
+        (0x07, 0x7D, EV_KEY::KEY_PASTE),
+        (0x07, 0x7E, EV_KEY::KEY_FIND),
+        (0x07, 0x85, EV_KEY::KEY_KPCOMMA),
+        (0x07, 0x90, EV_KEY::KEY_HANGEUL),
+        (0x07, 0x91, EV_KEY::KEY_HANJA),
+        (0x07, 0xB6, EV_KEY::KEY_KPLEFTPAREN),
+        (0x07, 0xB7, EV_KEY::KEY_KPRIGHTPAREN),
+        // consumer
+        (0x0C, 0x6F, EV_KEY::KEY_BRIGHTNESSUP),
+        (0x0C, 0x70, EV_KEY::KEY_BRIGHTNESSDOWN),
+        (0x0C, 0xB3, EV_KEY::KEY_FASTFORWARD),
+        (0x0C, 0xB4, EV_KEY::KEY_REWIND),
+        (0x0C, 0xB8, EV_KEY::KEY_EJECTCD),
+        (0x0C, 0x183, EV_KEY::KEY_CONFIG),
+        (0x0C, 0x18A, EV_KEY::KEY_MAIL),
+        (0x0C, 0x192, EV_KEY::KEY_CALC),
+        (0x0C, 0x194, EV_KEY::KEY_FILE),
+        (0x0C, 0x221, EV_KEY::KEY_SEARCH),
+        (0x0C, 0x223, EV_KEY::KEY_HOMEPAGE),
+        (0x0C, 0x224, EV_KEY::KEY_BACK),
+        (0x0C, 0x225, EV_KEY::KEY_FORWARD),
+        (0x0C, 0x226, EV_KEY::KEY_STOP),
+        (0x0C, 0x227, EV_KEY::KEY_REFRESH),
+        (0x0C, 0x22A, EV_KEY::KEY_BOOKMARKS),
+    ];
+
+    /// Codes of keyboard keys, buttons of mice and gamepads sit in between, d-pad buttons split
+    /// the later keys and trigger happy buttons follow them.
+    const KEY_RANGES: [std::ops::Range<u32>; 3] = [1..0x100, 0x160..0x220, 0x224..0x2c0];
+
+    impl HidUsage {
+        pub fn from_ev_key(x: EV_KEY) -> Option<Self> {
+            let usage = RAW_KEYS
+                .iter()
+                .find(|(_, _, key)| *key == x)
+                .map(|&(page, id, _)| Self { page, id })
+                .unwrap_or(Self {
+                    page: Self::EVDEV_PAGE,
+                    id: x as _,
+                });
+            KEY_RANGES
+                .iter()
+                .any(|range| range.contains(&(x as u32)))
+                .then_some(usage)
+        }
+
+        pub fn to_ev_key(self) -> Option<EV_KEY> {
+            RAW_KEYS
+                .iter()
+                .find(|&&(page, id, _)| Self { page, id } == self)
+                .map(|&(_, _, key)| key)
+                .or_else(|| match self.page {
+                    Self::EVDEV_PAGE => int_to_ev_key(self.id as _),
+                    _ => None,
+                })
+        }
+
+        /// Returns keys raw key events can be emulated with.
+        pub fn ev_keys() -> impl Iterator<Item = EV_KEY> {
+            KEY_RANGES.into_iter().flatten().filter_map(int_to_ev_key)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use strum::IntoEnumIterator;
+
+        #[test]
+        fn test_key_code_round_trip() {
+            for key in KeyCode::iter() {
+                assert_eq!(KeyCode::from_ev_key(key.into()), Some(key));
+            }
+        }
+
+        #[test]
+        fn test_hid_usage_round_trip() {
+            for &(page, id, key) in RAW_KEYS {
+                let usage = HidUsage::from_ev_key(key).unwrap();
+                assert_eq!(usage, HidUsage { page, id });
+                assert_eq!(usage.to_ev_key(), Some(key));
+                assert_eq!(KeyCode::from_ev_key(key), None);
+            }
+            for key in HidUsage::ev_keys() {
+                let usage = HidUsage::from_ev_key(key).unwrap();
+                assert_eq!(usage.to_ev_key(), Some(key));
+            }
+        }
+
+        #[test]
+        fn test_raw_ev_key() {
+            let usage = HidUsage::from_ev_key(EV_KEY::KEY_PROG1).unwrap();
+            assert_eq!(usage.page, HidUsage::EVDEV_PAGE);
+            assert_eq!(usage.to_ev_key(), Some(EV_KEY::KEY_PROG1));
+            // keys past the d-pad buttons
+            let usage = HidUsage::from_ev_key(EV_KEY::KEY_MACRO1).unwrap();
+            assert_eq!(usage.page, HidUsage::EVDEV_PAGE);
+            assert_eq!(usage.to_ev_key(), Some(EV_KEY::KEY_MACRO1));
+            // buttons aren't keys
+            assert_eq!(HidUsage::from_ev_key(EV_KEY::BTN_SOUTH), None);
+            assert_eq!(HidUsage::from_ev_key(EV_KEY::BTN_DPAD_UP), None);
+            assert_eq!(HidUsage::from_ev_key(EV_KEY::BTN_TRIGGER_HAPPY2), None);
+            // scan codes are meaningless here
+            let usage = HidUsage {
+                page: HidUsage::SCAN_CODE_PAGE,
+                id: 0x5E,
+            };
+            assert_eq!(usage.to_ev_key(), None);
+        }
+
+        #[test]
+        fn test_mouse_button_round_trip() {
+            for button in MouseButton::iter() {
+                assert_eq!(MouseButton::from_ev_key(button.into()), Some(button));
+            }
+        }
+
+        #[test]
+        fn test_gamepad_round_trip() {
+            for button in GamepadButton::iter() {
+                assert_eq!(GamepadButton::from_ev_key(button.into()), Some(button));
+            }
+            for axis in GamepadAxis::iter() {
+                assert_eq!(GamepadAxis::from_ev_abs(axis.into()), Some(axis));
+            }
+        }
+    }
 }
 
 #[cfg(target_os = "windows")]
@@ -458,5 +948,184 @@ pub mod windows {
         Left = VK_LEFT.0.into(),
         Down = VK_DOWN.0.into(),
         Right = VK_RIGHT.0.into(),
+
+        NumLock = VK_NUMLOCK.0.into(),
+        KpDivide = VK_DIVIDE.0.into(),
+        KpMultiply = VK_MULTIPLY.0.into(),
+        KpMinus = VK_SUBTRACT.0.into(),
+        KpPlus = VK_ADD.0.into(),
+        // Windows tells the keypad enter apart with the extended key flag only.
+        KpDot = VK_DECIMAL.0.into(),
+        Kp0 = VK_NUMPAD0.0.into(),
+        Kp1 = VK_NUMPAD1.0.into(),
+        Kp2 = VK_NUMPAD2.0.into(),
+        Kp3 = VK_NUMPAD3.0.into(),
+        Kp4 = VK_NUMPAD4.0.into(),
+        Kp5 = VK_NUMPAD5.0.into(),
+        Kp6 = VK_NUMPAD6.0.into(),
+        Kp7 = VK_NUMPAD7.0.into(),
+        Kp8 = VK_NUMPAD8.0.into(),
+        Kp9 = VK_NUMPAD9.0.into(),
+
+        Menu = VK_APPS.0.into(),
+
+        Mute = VK_VOLUME_MUTE.0.into(),
+        VolumeDown = VK_VOLUME_DOWN.0.into(),
+        VolumeUp = VK_VOLUME_UP.0.into(),
+        PlayPause = VK_MEDIA_PLAY_PAUSE.0.into(),
+        NextTrack = VK_MEDIA_NEXT_TRACK.0.into(),
+        PreviousTrack = VK_MEDIA_PREV_TRACK.0.into(),
+        StopMedia = VK_MEDIA_STOP.0.into(),
+
+        F13 = VK_F13.0.into(),
+        F14 = VK_F14.0.into(),
+        F15 = VK_F15.0.into(),
+        F16 = VK_F16.0.into(),
+        F17 = VK_F17.0.into(),
+        F18 = VK_F18.0.into(),
+        F19 = VK_F19.0.into(),
+        F20 = VK_F20.0.into(),
+        F21 = VK_F21.0.into(),
+        F22 = VK_F22.0.into(),
+        F23 = VK_F23.0.into(),
+        F24 = VK_F24.0.into(),
+
+        Iso102nd = VK_OEM_102.0.into(),
+        // VK_ABNT_C1
+        Ro = 0xC1.into(),
+        Henkan = VK_CONVERT.0.into(),
+        Muhenkan = VK_NONCONVERT.0.into(),
+        // VK_DBE_HIRAGANA
+        KatakanaHiragana = 0xF2.into(),
+        ZenkakuHankaku = VK_OEM_AUTO.0.into(),
     });
+
+    /// Keys sharing a virtual key with another key and their set 1 scan codes,
+    /// they're told apart by scan code.
+    const SCAN_CODE_KEYS: [(KeyCode, u16); 2] = [
+        // extended scan code of enter
+        (KeyCode::KpEnter, 0xE01C),
+        // Japanese layouts put yen on the backslash virtual key
+        (KeyCode::Yen, 0x7D),
+    ];
+
+    impl KeyCode {
+        /// Returns the key of a keyboard event with the virtual key and set 1
+        /// scan code, extended ones have 0xE0 in their high byte.
+        pub fn from_key_event(vk: VirtualKey, scan_code: u16) -> Option<Self> {
+            SCAN_CODE_KEYS
+                .iter()
+                .find(|(_, code)| *code == scan_code)
+                .map(|&(key, _)| key)
+                .or_else(|| Self::from_virtual_key(vk))
+        }
+
+        /// Returns the scan code of keys emulated by scan code rather than by
+        /// virtual key.
+        pub fn to_scan_code(self) -> Option<u16> {
+            SCAN_CODE_KEYS
+                .iter()
+                .find(|(key, _)| *key == self)
+                .map(|&(_, code)| code)
+        }
+    }
+
+    /// HID usages of keys without a [KeyCode] and their set 1 scan codes, following
+    /// Microsoft's USB HID to PS/2 scan code translation table.
+    ///
+    /// Extended scan codes have 0xE0 in their high byte.
+    const RAW_KEYS: &[(u16, u16, u16)] = &[
+        // generic desktop
+        (0x01, 0x81, 0xE05E),
+        (0x01, 0x82, 0xE05F),
+        (0x01, 0x83, 0xE063),
+        // keyboard
+        (0x07, 0x67, 0x59),
+        (0x07, 0x85, 0x7E),
+        // consumer
+        (0x0C, 0x183, 0xE06D),
+        (0x0C, 0x18A, 0xE06C),
+        (0x0C, 0x192, 0xE021),
+        (0x0C, 0x194, 0xE06B),
+        (0x0C, 0x221, 0xE065),
+        (0x0C, 0x223, 0xE032),
+        (0x0C, 0x224, 0xE06A),
+        (0x0C, 0x225, 0xE069),
+        (0x0C, 0x226, 0xE068),
+        (0x0C, 0x227, 0xE067),
+        (0x0C, 0x22A, 0xE066),
+    ];
+
+    impl HidUsage {
+        pub fn from_scan_code(x: u16) -> Option<Self> {
+            if x & 0xFF == 0 {
+                return None;
+            }
+            let usage = RAW_KEYS
+                .iter()
+                .find(|(_, _, code)| *code == x)
+                .map(|&(page, id, _)| Self { page, id })
+                .unwrap_or(Self {
+                    page: Self::SCAN_CODE_PAGE,
+                    id: x,
+                });
+            Some(usage)
+        }
+
+        pub fn to_scan_code(self) -> Option<u16> {
+            RAW_KEYS
+                .iter()
+                .find(|&&(page, id, _)| Self { page, id } == self)
+                .map(|&(_, _, code)| code)
+                .or_else(|| match self.page {
+                    Self::SCAN_CODE_PAGE => Some(self.id),
+                    _ => None,
+                })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use strum::IntoEnumIterator;
+
+        #[test]
+        fn test_hid_usage_round_trip() {
+            for &(_, _, code) in RAW_KEYS {
+                let usage = HidUsage::from_scan_code(code).unwrap();
+                assert_eq!(usage.to_scan_code(), Some(code));
+            }
+        }
+
+        #[test]
+        fn test_raw_scan_code() {
+            let usage = HidUsage::from_scan_code(0xE010).unwrap();
+            assert_eq!(usage.page, HidUsage::SCAN_CODE_PAGE);
+            assert_eq!(usage.to_scan_code(), Some(0xE010));
+            assert_eq!(HidUsage::from_scan_code(0), None);
+            // evdev codes are meaningless here
+            let usage = HidUsage {
+                page: HidUsage::EVDEV_PAGE,
+                id: 0x94,
+            };
+            assert_eq!(usage.to_scan_code(), None);
+        }
+
+        #[test]
+        fn test_key_code_round_trip() {
+            for key in KeyCode::iter() {
+                let event = match (key.to_virtual_key(), key.to_scan_code()) {
+                    (Some(vk), None) => KeyCode::from_key_event(vk, 0),
+                    (None, Some(code)) => KeyCode::from_key_event(VK_RETURN.0.into(), code),
+                    _ => panic!("{:?} has no single way to emulate it", key),
+                };
+                assert_eq!(event, Some(key));
+            }
+            // the main enter key has no extended scan code
+            assert_eq!(
+                KeyCode::from_key_event(VK_RETURN.0.into(), 0x1C),
+                Some(KeyCode::Enter)
+            );
+        }
+    }
 }
@@ -2,4 +2,6 @@
 
 cargo build --release && ^
 copy ..\target\release\terong-server.exe . && ^
-copy ..\target\release\terong-client.exe .
+copy ..\target\release\terong-client.exe . && ^
+copy ..\target\release\terong-cert.exe . && ^
+copy ..\target\release\terong-replay.exe .
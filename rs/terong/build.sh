@@ -2,4 +2,6 @@
 
 cargo build --release && \
   cp ../target/release/terong-server . && \
-  cp ../target/release/terong-client .
+  cp ../target/release/terong-client . && \
+  cp ../target/release/terong-cert . && \
+  cp ../target/release/terong-replay .
@@ -0,0 +1,70 @@
+//! Self-signed certificate and Noise key helper.
+
+use crate::{
+    logging::init_tracing,
+    transport::{
+        noise::{generate_keypair, generate_psk},
+        tls::{generate_self_signed, renew_self_signed},
+    },
+};
+use anyhow::Error;
+use std::{env, path::Path, process};
+use tokio::fs;
+use tracing::info;
+
+const USAGE: &str = "\
+usage:
+  terong-cert new <cert path> <key path> <subject name>...
+  terong-cert renew <cert path> <key path>
+  terong-cert noise-keys
+  terong-cert noise-psk";
+
+/// Run the certificate helper application.
+pub async fn run() {
+    init_tracing();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["new", cert_path, key_path, names @ ..] if !names.is_empty() => {
+            let names = names.iter().map(|x| x.to_string()).collect();
+            new_cert(Path::new(cert_path), Path::new(key_path), names).await
+        }
+        ["renew", cert_path, key_path] => {
+            renew_self_signed(Path::new(cert_path), Path::new(key_path)).await
+        }
+        ["noise-keys"] => match generate_keypair() {
+            Ok((private_key, public_key)) => {
+                println!("private_key = \"{}\"", private_key);
+                println!("# give this to the peer as its peer_public_key");
+                println!("public_key = \"{}\"", public_key);
+                return;
+            }
+            Err(err) => Err(err),
+        },
+        ["noise-psk"] => {
+            println!("psk = \"{}\"", generate_psk());
+            return;
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("error: {:#}", err);
+        process::exit(1);
+    }
+
+    info!("certificate written, copy it to the peer machine as well");
+}
+
+/// Writes a new self-signed certificate for the subject names and its key.
+pub async fn new_cert(cert_path: &Path, key_path: &Path, names: Vec<String>) -> Result<(), Error> {
+    let (cert, key) = generate_self_signed(names, None)?;
+    fs::write(cert_path, &cert.0).await?;
+    fs::write(key_path, &key.0).await?;
+    Ok(())
+}
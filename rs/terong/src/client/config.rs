@@ -1,12 +1,118 @@
+use crate::{
+    config::{ClipboardConfig, LogConfig, MetricsConfig, NoiseConfig, SecureChannel, WireFormat},
+    transport::{
+        codec::Codec,
+        protocol::{KeyCode, MouseButton},
+    },
+};
+use anyhow::{ensure, Error};
 use serde::Deserialize;
-use std::{net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
 
 #[derive(Clone, Deserialize, Debug)]
 pub struct ClientConfig {
-    pub tls_cert_path: PathBuf,
-    pub tls_key_path: PathBuf,
+    #[serde(default)]
+    pub channel: SecureChannel,
+
+    /// Required if channel is TLS.
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
 
     pub server_addr: SocketAddr,
 
-    pub server_tls_cert_path: PathBuf,
+    pub server_tls_cert_path: Option<PathBuf>,
+
+    /// Required if channel is Noise.
+    pub noise: Option<NoiseConfig>,
+
+    /// Message codec to ask the server for.
+    #[serde(default)]
+    pub codec: Codec,
+
+    /// Either native or Go, the client can't detect the server's.
+    #[serde(default)]
+    pub wire_format: WireFormat,
+
+    #[serde(default)]
+    pub clipboard: ClipboardConfig,
+
+    /// Where files sent by the peer are saved, file transfer from the peer is
+    /// rejected if not set.
+    pub download_dir: Option<PathBuf>,
+
+    /// Log keys of input events, they're redacted otherwise.
+    #[serde(default)]
+    pub log_keystrokes: bool,
+
+    #[serde(default)]
+    pub pointer: PointerConfig,
+
+    #[serde(default)]
+    pub remap: RemapConfig,
+
+    /// Serves metrics if set.
+    pub metrics: Option<MetricsConfig>,
+
+    #[serde(default)]
+    pub log: LogConfig,
+}
+
+/// Scaling of pointer movements received from the server.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct PointerConfig {
+    /// Multiplier for every movement.
+    pub sensitivity: f64,
+    pub acceleration: Acceleration,
+}
+
+impl Default for PointerConfig {
+    fn default() -> Self {
+        Self {
+            sensitivity: 1.0,
+            acceleration: Default::default(),
+        }
+    }
+}
+
+/// Keys and mouse buttons sent as others, e.g. to follow this host's layout
+/// conventions.
+#[derive(Clone, Default, Deserialize, Debug)]
+#[serde(default)]
+pub struct RemapConfig {
+    pub keys: HashMap<KeyCode, KeyTarget>,
+    pub buttons: HashMap<MouseButton, MouseButton>,
+}
+
+impl RemapConfig {
+    /// Checks that every key is remapped to at least one key.
+    pub fn check(&self) -> Result<(), Error> {
+        for (key, target) in &self.keys {
+            if let KeyTarget::Chord(xs) = target {
+                ensure!(!xs.is_empty(), "{:?} is remapped to an empty chord", key);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum KeyTarget {
+    Key(KeyCode),
+    /// Keys pressed in order and released in reverse.
+    Chord(Vec<KeyCode>),
+}
+
+/// Extra gain for fast movements, speed is the distance of a single movement.
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Debug)]
+#[serde(tag = "profile", rename_all = "lowercase")]
+pub enum Acceleration {
+    /// No acceleration.
+    #[default]
+    Flat,
+    /// Movements faster than `threshold` are multiplied by `factor`.
+    Threshold { threshold: f64, factor: f64 },
+    /// Gain grows by `slope` per unit of speed, up to `max_gain`.
+    Linear { slope: f64, max_gain: f64 },
 }
@@ -0,0 +1,63 @@
+use super::{InputSink, SinkCommand};
+use crate::transport::protocol::LockState;
+use anyhow::Error;
+use tokio::{
+    sync::{mpsc, watch},
+    task::{self, JoinHandle},
+};
+
+/// Input sink that passes commands on through a channel, for running the
+/// client without emulating input.
+#[derive(Debug)]
+pub struct ChannelInputSink {
+    cmd_tx: mpsc::Sender<SinkCommand>,
+    lock_rx: watch::Receiver<LockState>,
+}
+
+/// Other end of a [ChannelInputSink].
+#[derive(Debug)]
+pub struct ChannelInputSinkHandle {
+    /// Commands the sink would carry out.
+    pub cmd_rx: mpsc::Receiver<SinkCommand>,
+    /// Lock state of the pretend host.
+    pub lock_tx: watch::Sender<LockState>,
+}
+
+impl ChannelInputSink {
+    pub fn new() -> (Self, ChannelInputSinkHandle) {
+        let (cmd_tx, cmd_rx) = mpsc::channel(16);
+        let (lock_tx, lock_rx) = watch::channel(LockState::default());
+        let sink = Self { cmd_tx, lock_rx };
+        let handle = ChannelInputSinkHandle { cmd_rx, lock_tx };
+        (sink, handle)
+    }
+}
+
+impl InputSink for ChannelInputSink {
+    fn start(
+        self,
+        mut cmd_rx: mpsc::Receiver<SinkCommand>,
+        lock_tx: watch::Sender<Option<LockState>>,
+    ) -> JoinHandle<Result<(), Error>> {
+        let Self {
+            cmd_tx,
+            mut lock_rx,
+        } = self;
+
+        task::spawn(async move {
+            lock_tx.send_replace(Some(*lock_rx.borrow_and_update()));
+            while lock_rx.changed().await.is_ok() {
+                lock_tx.send_replace(Some(*lock_rx.borrow_and_update()));
+            }
+        });
+
+        task::spawn(async move {
+            while let Some(cmd) = cmd_rx.recv().await {
+                if cmd_tx.send(cmd).await.is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -1,20 +1,49 @@
-use crate::transport::protocol::{InputEvent, KeyCode, MouseButton, MouseScrollDirection};
+use super::{InputSink, SinkCommand, UnsupportedRawKeys};
+use crate::{
+    lock_state,
+    transport::protocol::{
+        AbsolutePointer, AxisRange, GamepadAxis, GamepadButton, GamepadEvent, Gesture, HidUsage,
+        InputEvent, KeyCode, LockKey, LockState, MouseButton, MouseScrollDirection, ScrollAxis,
+    },
+};
 use anyhow::{anyhow, Error};
 use evdev_rs::{
-    enums::{BusType, EventCode, EventType, EV_REL, EV_SYN},
-    DeviceWrapper, InputEvent as LinuxInputEvent, UInputDevice, UninitDevice,
+    enums::{BusType, EventCode, EventType, InputProp, EV_ABS, EV_KEY, EV_REL, EV_SYN},
+    AbsInfo, DeviceWrapper, EnableCodeData, InputEvent as LinuxInputEvent, TimeVal, UInputDevice,
+    UninitDevice,
 };
 use std::{convert::TryInto, iter, time::SystemTime};
 use strum::IntoEnumIterator;
 use tokio::{
-    sync::mpsc,
+    sync::{mpsc, watch},
     task::{self, JoinHandle},
 };
+use tracing::{debug, warn};
+
+/// High resolution scroll units per wheel click.
+const WHEEL_DELTA: i32 = 120;
+
+/// Fingers the virtual touchpad can track.
+const TOUCHPAD_SLOTS: usize = 5;
+
+/// Distance between swiping fingers, [u16::MAX] spans the virtual touchpad.
+const SWIPE_SPACING: f64 = 6000.0;
+
+/// Distance of pinching fingers from their center before they're scaled.
+const PINCH_RADIUS: f64 = 8000.0;
 
-pub fn start(event_rx: mpsc::Receiver<InputEvent>) -> JoinHandle<()> {
-    task::spawn_blocking(|| {
-        run_input_sink(event_rx).unwrap();
-    })
+/// Emulates input with a uinput virtual device.
+#[derive(Default, Debug)]
+pub struct SystemInputSink;
+
+impl InputSink for SystemInputSink {
+    fn start(
+        self,
+        cmd_rx: mpsc::Receiver<SinkCommand>,
+        lock_tx: watch::Sender<Option<LockState>>,
+    ) -> JoinHandle<Result<(), Error>> {
+        task::spawn_blocking(|| run_input_sink(cmd_rx, lock_tx))
+    }
 }
 
 // relevant links:
@@ -49,40 +78,534 @@ fn create_virtual_device() -> Result<UninitDevice, Error> {
         let key = key.into();
         dev.enable_event_code(&EventCode::EV_KEY(key), None)?;
     }
+    for key in HidUsage::ev_keys() {
+        dev.enable_event_code(&EventCode::EV_KEY(key), None)?;
+    }
 
     dev.enable_event_type(&EventType::EV_REL)?;
     dev.enable_event_code(&EventCode::EV_REL(EV_REL::REL_X), None)?;
     dev.enable_event_code(&EventCode::EV_REL(EV_REL::REL_Y), None)?;
     dev.enable_event_code(&EventCode::EV_REL(EV_REL::REL_WHEEL), None)?;
+    dev.enable_event_code(&EventCode::EV_REL(EV_REL::REL_HWHEEL), None)?;
+    dev.enable_event_code(&EventCode::EV_REL(EV_REL::REL_WHEEL_HI_RES), None)?;
+    dev.enable_event_code(&EventCode::EV_REL(EV_REL::REL_HWHEEL_HI_RES), None)?;
+
+    Ok(dev)
+}
+
+/// Creates a pen device for absolute pointer events.
+///
+/// It's a direct input device, so the compositor maps it to the screen.
+fn create_virtual_pointer_device() -> Result<UninitDevice, Error> {
+    let dev = UninitDevice::new()
+        .ok_or_else(|| anyhow!("failed to create virtual evdev pointer device"))?;
+
+    dev.set_name("Terong Virtual Pointer Device");
+    dev.set_bustype(BusType::BUS_USB as _);
+
+    dev.enable_property(&InputProp::INPUT_PROP_DIRECT)?;
+
+    dev.enable_event_type(&EventType::EV_SYN)?;
+    dev.enable_event_code(&EventCode::EV_SYN(EV_SYN::SYN_REPORT), None)?;
+
+    dev.enable_event_type(&EventType::EV_KEY)?;
+    dev.enable_event_code(&EventCode::EV_KEY(EV_KEY::BTN_TOOL_PEN), None)?;
+    dev.enable_event_code(&EventCode::EV_KEY(EV_KEY::BTN_TOUCH), None)?;
+
+    dev.enable_event_type(&EventType::EV_ABS)?;
+    let axes = [
+        (EV_ABS::ABS_X, 0, u16::MAX as i32, 0),
+        (EV_ABS::ABS_Y, 0, u16::MAX as i32, 0),
+        (EV_ABS::ABS_PRESSURE, 0, u16::MAX as i32, 0),
+        // tilt resolution is in units per radian
+        (EV_ABS::ABS_TILT_X, -90, 90, 57),
+        (EV_ABS::ABS_TILT_Y, -90, 90, 57),
+    ];
+    for (axis, minimum, maximum, resolution) in axes {
+        let info = AbsInfo {
+            value: 0,
+            minimum,
+            maximum,
+            fuzz: 0,
+            flat: 0,
+            resolution,
+        };
+        dev.enable_event_code(
+            &EventCode::EV_ABS(axis),
+            Some(EnableCodeData::AbsInfo(info)),
+        )?;
+    }
+
+    Ok(dev)
+}
+
+/// Creates a multitouch touchpad for gestures.
+///
+/// It's about 100 by 65 millimetres, libinput needs the size to interpret
+/// gestures.
+fn create_virtual_touchpad_device() -> Result<UninitDevice, Error> {
+    let dev = UninitDevice::new()
+        .ok_or_else(|| anyhow!("failed to create virtual evdev touchpad device"))?;
+
+    dev.set_name("Terong Virtual Touchpad");
+    dev.set_bustype(BusType::BUS_USB as _);
+
+    dev.enable_property(&InputProp::INPUT_PROP_POINTER)?;
+
+    dev.enable_event_type(&EventType::EV_SYN)?;
+    dev.enable_event_code(&EventCode::EV_SYN(EV_SYN::SYN_REPORT), None)?;
+
+    dev.enable_event_type(&EventType::EV_KEY)?;
+    dev.enable_event_code(&EventCode::EV_KEY(EV_KEY::BTN_LEFT), None)?;
+    dev.enable_event_code(&EventCode::EV_KEY(EV_KEY::BTN_TOUCH), None)?;
+    for fingers in 1..=TOUCHPAD_SLOTS {
+        dev.enable_event_code(&EventCode::EV_KEY(tool_key(fingers)), None)?;
+    }
+
+    dev.enable_event_type(&EventType::EV_ABS)?;
+    let max = u16::MAX as i32;
+    let axes = [
+        (EV_ABS::ABS_X, max, 655),
+        (EV_ABS::ABS_Y, max, 1008),
+        (EV_ABS::ABS_MT_SLOT, TOUCHPAD_SLOTS as i32 - 1, 0),
+        (EV_ABS::ABS_MT_TRACKING_ID, max, 0),
+        (EV_ABS::ABS_MT_POSITION_X, max, 655),
+        (EV_ABS::ABS_MT_POSITION_Y, max, 1008),
+    ];
+    for (axis, maximum, resolution) in axes {
+        let info = AbsInfo {
+            value: 0,
+            minimum: 0,
+            maximum,
+            fuzz: 0,
+            flat: 0,
+            resolution,
+        };
+        dev.enable_event_code(
+            &EventCode::EV_ABS(axis),
+            Some(EnableCodeData::AbsInfo(info)),
+        )?;
+    }
+
+    Ok(dev)
+}
+
+/// Key telling how many fingers touch the touchpad.
+fn tool_key(fingers: usize) -> EV_KEY {
+    match fingers {
+        1 => EV_KEY::BTN_TOOL_FINGER,
+        2 => EV_KEY::BTN_TOOL_DOUBLETAP,
+        3 => EV_KEY::BTN_TOOL_TRIPLETAP,
+        4 => EV_KEY::BTN_TOOL_QUADTAP,
+        _ => EV_KEY::BTN_TOOL_QUINTTAP,
+    }
+}
+
+/// Replays gestures as fingers on the virtual touchpad.
+#[derive(Default, Debug)]
+struct GestureFingers {
+    /// Positions of the fingers that are down, by slot.
+    fingers: Vec<(f64, f64)>,
+    /// Angle of the first pinching finger, in degrees.
+    angle: f64,
+    tracking_id: i32,
+}
+
+impl GestureFingers {
+    fn events(&mut self, gesture: Gesture) -> Vec<(EventCode, i32)> {
+        let center = u16::MAX as f64 / 2.0;
+        match gesture {
+            Gesture::SwipeBegin { fingers } => {
+                let n = (fingers as usize).clamp(1, TOUCHPAD_SLOTS);
+                let offset = (n - 1) as f64 / 2.0;
+                let fingers = (0..n)
+                    .map(|i| (center + (i as f64 - offset) * SWIPE_SPACING, center))
+                    .collect();
+                self.put_down(fingers)
+            }
+            Gesture::SwipeUpdate { dx, dy } => {
+                for (x, y) in &mut self.fingers {
+                    *x += dx as f64;
+                    *y += dy as f64;
+                }
+                self.moved()
+            }
+            Gesture::PinchBegin { fingers } => {
+                let n = (fingers as usize).clamp(2, TOUCHPAD_SLOTS);
+                self.angle = 0.0;
+                self.put_down(pinch_fingers(n, self.angle, 1.0))
+            }
+            Gesture::PinchUpdate { scale, rotation } => {
+                self.angle += rotation as f64;
+                self.fingers = pinch_fingers(self.fingers.len(), self.angle, scale as f64);
+                self.moved()
+            }
+            Gesture::SwipeEnd { .. } | Gesture::PinchEnd { .. } => self.lift(),
+        }
+    }
+
+    fn put_down(&mut self, fingers: Vec<(f64, f64)>) -> Vec<(EventCode, i32)> {
+        // a gesture that didn't end is replaced
+        let mut es = self.lift();
+        self.fingers = fingers;
+        for slot in 0..self.fingers.len() {
+            self.tracking_id = (self.tracking_id + 1) % u16::MAX as i32;
+            es.push((EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), slot as i32));
+            es.push((
+                EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID),
+                self.tracking_id,
+            ));
+        }
+        es.extend(self.moved());
+        es.push((EventCode::EV_KEY(EV_KEY::BTN_TOUCH), 1));
+        es.push((EventCode::EV_KEY(tool_key(self.fingers.len())), 1));
+        es
+    }
+
+    fn moved(&self) -> Vec<(EventCode, i32)> {
+        let position = |x: f64| x.round().clamp(0.0, u16::MAX as f64) as i32;
+        let mut es = Vec::new();
+        for (slot, &(x, y)) in self.fingers.iter().enumerate() {
+            es.push((EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), slot as i32));
+            es.push((EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X), position(x)));
+            es.push((EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y), position(y)));
+        }
+        // single touch axes follow the first finger
+        if let Some(&(x, y)) = self.fingers.first() {
+            es.push((EventCode::EV_ABS(EV_ABS::ABS_X), position(x)));
+            es.push((EventCode::EV_ABS(EV_ABS::ABS_Y), position(y)));
+        }
+        es
+    }
+
+    fn lift(&mut self) -> Vec<(EventCode, i32)> {
+        if self.fingers.is_empty() {
+            return Vec::new();
+        }
+        let mut es = Vec::new();
+        for slot in 0..self.fingers.len() {
+            es.push((EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), slot as i32));
+            es.push((EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), -1));
+        }
+        es.push((EventCode::EV_KEY(EV_KEY::BTN_TOUCH), 0));
+        es.push((EventCode::EV_KEY(tool_key(self.fingers.len())), 0));
+        self.fingers.clear();
+        es
+    }
+}
+
+/// Places `n` pinching fingers evenly on a circle around the center.
+fn pinch_fingers(n: usize, angle: f64, scale: f64) -> Vec<(f64, f64)> {
+    let center = u16::MAX as f64 / 2.0;
+    let radius = (PINCH_RADIUS * scale).clamp(500.0, center);
+    (0..n)
+        .map(|i| {
+            let angle = (angle + i as f64 * 360.0 / n as f64).to_radians();
+            (center + radius * angle.cos(), center + radius * angle.sin())
+        })
+        .collect()
+}
+
+/// Creates a gamepad with the axes of the server's gamepad.
+fn create_virtual_gamepad_device(axes: &[(GamepadAxis, AxisRange)]) -> Result<UninitDevice, Error> {
+    let dev = UninitDevice::new()
+        .ok_or_else(|| anyhow!("failed to create virtual evdev gamepad device"))?;
+
+    dev.set_name("Terong Virtual Gamepad");
+    dev.set_bustype(BusType::BUS_USB as _);
+
+    dev.enable_event_type(&EventType::EV_SYN)?;
+    dev.enable_event_code(&EventCode::EV_SYN(EV_SYN::SYN_REPORT), None)?;
+
+    dev.enable_event_type(&EventType::EV_KEY)?;
+    for button in GamepadButton::iter() {
+        dev.enable_event_code(&EventCode::EV_KEY(button.into()), None)?;
+    }
+
+    dev.enable_event_type(&EventType::EV_ABS)?;
+    for &(axis, range) in axes {
+        let info = AbsInfo {
+            value: 0,
+            minimum: range.minimum,
+            maximum: range.maximum,
+            fuzz: range.fuzz,
+            flat: range.flat,
+            resolution: 0,
+        };
+        dev.enable_event_code(
+            &EventCode::EV_ABS(axis.into()),
+            Some(EnableCodeData::AbsInfo(info)),
+        )?;
+    }
 
     Ok(dev)
 }
 
-fn run_input_sink(mut event_rx: mpsc::Receiver<InputEvent>) -> Result<(), Error> {
+/// Virtual gamepad, it exists while the server relays its gamepad.
+#[derive(Default)]
+struct VirtualGamepad {
+    /// Axes announced for the next connection.
+    axes: Vec<(GamepadAxis, AxisRange)>,
+    device: Option<UInputDevice>,
+    /// Set after emulating the gamepad fails, its events are dropped until
+    /// it connects again.
+    failed: bool,
+}
+
+impl VirtualGamepad {
+    fn handle(&mut self, event: GamepadEvent) {
+        match event {
+            // a new connection gets another try
+            GamepadEvent::AxisRange { .. } | GamepadEvent::Connected => self.failed = false,
+            _ if self.failed => return,
+            _ => (),
+        }
+        if let Err(err) = self.try_handle(event) {
+            warn!(
+                ?err,
+                "failed to emulate gamepad, ignoring its events until it reconnects"
+            );
+            self.device = None;
+            self.failed = true;
+        }
+    }
+
+    fn try_handle(&mut self, event: GamepadEvent) -> Result<(), Error> {
+        let (event_code, value) = match event {
+            GamepadEvent::AxisRange { axis, range } => {
+                self.axes.push((axis, range));
+                return Ok(());
+            }
+            GamepadEvent::Connected => {
+                let axes = std::mem::take(&mut self.axes);
+                let dev = create_virtual_gamepad_device(&axes)?;
+                self.device = Some(UInputDevice::create_from_device(&dev)?);
+                return Ok(());
+            }
+            GamepadEvent::Disconnected => {
+                // destroying the device releases whatever it holds
+                self.device = None;
+                return Ok(());
+            }
+            GamepadEvent::ButtonDown { button } => (EventCode::EV_KEY(button.into()), 1),
+            GamepadEvent::ButtonUp { button } => (EventCode::EV_KEY(button.into()), 0),
+            GamepadEvent::AxisMove { axis, value } => (EventCode::EV_ABS(axis.into()), value),
+            GamepadEvent::Sync => (EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        };
+
+        let device = match &self.device {
+            Some(x) => x,
+            None => {
+                debug!(?event, "gamepad isn't connected, ignoring event");
+                return Ok(());
+            }
+        };
+        // the frame is reported by the sync that ends it
+        device.write_event(&LinuxInputEvent {
+            time: SystemTime::now().try_into()?,
+            event_code,
+            value,
+        })?;
+
+        Ok(())
+    }
+}
+
+fn run_input_sink(
+    mut cmd_rx: mpsc::Receiver<SinkCommand>,
+    lock_tx: watch::Sender<Option<LockState>>,
+) -> Result<(), Error> {
     let dev = create_virtual_device()?;
 
     let uidev = UInputDevice::create_from_device(&dev)?;
 
-    while let Some(event) = event_rx.blocking_recv() {
-        let events: Vec<LinuxInputEvent> = event.into_vec()?;
+    let pointer_dev = create_virtual_pointer_device()?;
+
+    let pointer_uidev = UInputDevice::create_from_device(&pointer_dev)?;
+
+    let touchpad_dev = create_virtual_touchpad_device()?;
+
+    let touchpad_uidev = UInputDevice::create_from_device(&touchpad_dev)?;
+
+    // lock state is read from the leds of the host's keyboard, the virtual
+    // device's don't follow it
+    let keyboard = match lock_state::find_keyboard() {
+        Ok(x) => {
+            let keyboard = x.clone();
+            lock_state::poll(lock_tx, move || lock_state::read(&keyboard));
+            Some(x)
+        }
+        Err(err) => {
+            warn!(?err, "failed to find keyboard, lock state won't be synced");
+            None
+        }
+    };
+
+    let mut unsupported = UnsupportedRawKeys::default();
+    let mut scroll = ScrollRemainder::default();
+    let mut gamepad = VirtualGamepad::default();
+    let mut gesture_fingers = GestureFingers::default();
 
-        for event in &events {
-            uidev.write_event(&event)?;
+    while let Some(cmd) = cmd_rx.blocking_recv() {
+        match cmd {
+            SinkCommand::Event(
+                InputEvent::RawKeyDown { usage } | InputEvent::RawKeyUp { usage },
+            ) if usage.to_ev_key().is_none() => unsupported.report(usage),
+
+            SinkCommand::Event(InputEvent::Gamepad { event }) => gamepad.handle(event),
+
+            SinkCommand::Event(InputEvent::Gesture { gesture }) => {
+                let es = gesture_fingers.events(gesture);
+                if es.is_empty() {
+                    continue;
+                }
+                let time = SystemTime::now().try_into()?;
+                for (event_code, value) in es {
+                    touchpad_uidev.write_event(&LinuxInputEvent {
+                        time,
+                        event_code,
+                        value,
+                    })?;
+                }
+                touchpad_uidev.write_event(&syn_event(time))?;
+            }
+
+            SinkCommand::Event(event) => {
+                let events: Vec<LinuxInputEvent> = event.into_vec(&mut scroll)?;
+
+                let uidev = match event {
+                    InputEvent::AbsolutePointer { .. } => &pointer_uidev,
+                    _ => &uidev,
+                };
+                for event in &events {
+                    uidev.write_event(&event)?;
+                }
+            }
+
+            SinkCommand::SetLockState(target) => {
+                let current = match keyboard.as_ref().map(|x| lock_state::read(x)) {
+                    Some(Ok(x)) => x,
+                    Some(Err(err)) => {
+                        warn!(?err, "failed to read lock state");
+                        continue;
+                    }
+                    None => continue,
+                };
+
+                let time = SystemTime::now().try_into()?;
+                for key in current.toggles_to(&target) {
+                    for value in [1, 0] {
+                        uidev.write_event(&key_event(time, lock_key(key), value))?;
+                        uidev.write_event(&syn_event(time))?;
+                    }
+                }
+            }
         }
     }
 
     Ok(())
 }
 
+fn lock_key(key: LockKey) -> EV_KEY {
+    match key {
+        LockKey::Caps => EV_KEY::KEY_CAPSLOCK,
+        LockKey::Num => EV_KEY::KEY_NUMLOCK,
+        LockKey::Scroll => EV_KEY::KEY_SCROLLLOCK,
+    }
+}
+
+fn raw_key(usage: HidUsage) -> Result<EV_KEY, Error> {
+    usage
+        .to_ev_key()
+        .ok_or_else(|| anyhow!("no key for {:?}", usage))
+}
+
+fn pointer_events(time: TimeVal, pointer: AbsolutePointer) -> Vec<LinuxInputEvent> {
+    let AbsolutePointer {
+        x,
+        y,
+        in_range,
+        touch,
+        pressure,
+        tilt,
+    } = pointer;
+    // devices without pressure press fully on touch
+    let pressure = pressure.unwrap_or(if touch { u16::MAX } else { 0 });
+    let mut es = vec![
+        (EventCode::EV_KEY(EV_KEY::BTN_TOOL_PEN), in_range as i32),
+        (EventCode::EV_ABS(EV_ABS::ABS_X), x as i32),
+        (EventCode::EV_ABS(EV_ABS::ABS_Y), y as i32),
+        (EventCode::EV_ABS(EV_ABS::ABS_PRESSURE), pressure as i32),
+        (EventCode::EV_KEY(EV_KEY::BTN_TOUCH), touch as i32),
+    ];
+    if let Some(tilt) = tilt {
+        es.push((EventCode::EV_ABS(EV_ABS::ABS_TILT_X), tilt.x as i32));
+        es.push((EventCode::EV_ABS(EV_ABS::ABS_TILT_Y), tilt.y as i32));
+    }
+    es.into_iter()
+        .map(|(event_code, value)| LinuxInputEvent {
+            time,
+            event_code,
+            value,
+        })
+        .chain(iter::once(syn_event(time)))
+        .collect()
+}
+
+fn key_event(time: TimeVal, key: EV_KEY, value: i32) -> LinuxInputEvent {
+    LinuxInputEvent {
+        time,
+        event_code: EventCode::EV_KEY(key),
+        value,
+    }
+}
+
+fn syn_event(time: TimeVal) -> LinuxInputEvent {
+    LinuxInputEvent {
+        time,
+        event_code: EventCode::EV_SYN(EV_SYN::SYN_REPORT),
+        value: 0,
+    }
+}
+
+/// High resolution scroll not yet reported in wheel clicks.
+///
+/// Readers that don't understand high resolution events only see the clicks.
+#[derive(Default, Debug)]
+struct ScrollRemainder {
+    vertical: i32,
+    horizontal: i32,
+}
+
+impl ScrollRemainder {
+    /// Adds high resolution delta, returns clicks it completes.
+    fn add(&mut self, axis: ScrollAxis, delta: i16) -> i32 {
+        let remainder = match axis {
+            ScrollAxis::Vertical => &mut self.vertical,
+            ScrollAxis::Horizontal => &mut self.horizontal,
+        };
+        *remainder += delta as i32;
+        let clicks = *remainder / WHEEL_DELTA;
+        *remainder -= clicks * WHEEL_DELTA;
+        clicks
+    }
+}
+
 trait IntoVec {
-    fn into_vec(self) -> Result<Vec<LinuxInputEvent>, Error>;
+    fn into_vec(self, scroll: &mut ScrollRemainder) -> Result<Vec<LinuxInputEvent>, Error>;
 }
 
 impl IntoVec for InputEvent {
-    fn into_vec(self) -> Result<Vec<LinuxInputEvent>, Error> {
+    fn into_vec(self, scroll: &mut ScrollRemainder) -> Result<Vec<LinuxInputEvent>, Error> {
         let time = SystemTime::now().try_into()?;
 
         let es = match self {
+            // absolute pointer
+            InputEvent::AbsolutePointer { pointer } => return Ok(pointer_events(time, pointer)),
+
+            // gamepads and gestures have their own devices
+            InputEvent::Gamepad { .. } | InputEvent::Gesture { .. } => return Ok(Vec::new()),
+
             // mouse move
             InputEvent::MouseMove { dx, dy } => vec![
                 (EventCode::EV_REL(EV_REL::REL_X), dx),
@@ -104,11 +627,33 @@ impl IntoVec for InputEvent {
             InputEvent::MouseScroll {
                 direction: MouseScrollDirection::Down { clicks },
             } => vec![(EventCode::EV_REL(EV_REL::REL_WHEEL), -(clicks as i16))],
+            InputEvent::MouseScroll {
+                direction: MouseScrollDirection::Right { clicks },
+            } => vec![(EventCode::EV_REL(EV_REL::REL_HWHEEL), clicks as i16)],
+            InputEvent::MouseScroll {
+                direction: MouseScrollDirection::Left { clicks },
+            } => vec![(EventCode::EV_REL(EV_REL::REL_HWHEEL), -(clicks as i16))],
+            InputEvent::MouseScrollHiRes { axis, delta } => {
+                let (hi_res, low_res) = match axis {
+                    ScrollAxis::Vertical => (EV_REL::REL_WHEEL_HI_RES, EV_REL::REL_WHEEL),
+                    ScrollAxis::Horizontal => (EV_REL::REL_HWHEEL_HI_RES, EV_REL::REL_HWHEEL),
+                };
+                let mut es = vec![(EventCode::EV_REL(hi_res), delta)];
+                let clicks = scroll.add(axis, delta);
+                if clicks != 0 {
+                    es.push((EventCode::EV_REL(low_res), clicks as i16));
+                }
+                es
+            }
 
             // keypress
             InputEvent::KeyDown { key } => vec![(EventCode::EV_KEY(key.into()), 1)],
             InputEvent::KeyRepeat { key } => vec![(EventCode::EV_KEY(key.into()), 2)],
             InputEvent::KeyUp { key } => vec![(EventCode::EV_KEY(key.into()), 0)],
+
+            // raw keypress
+            InputEvent::RawKeyDown { usage } => vec![(EventCode::EV_KEY(raw_key(usage)?), 1)],
+            InputEvent::RawKeyUp { usage } => vec![(EventCode::EV_KEY(raw_key(usage)?), 0)],
         };
 
         let es = es
@@ -118,13 +663,54 @@ impl IntoVec for InputEvent {
                 event_code,
                 value: value as _,
             })
-            .chain(iter::once(LinuxInputEvent {
-                time,
-                event_code: EventCode::EV_SYN(EV_SYN::SYN_REPORT),
-                value: 0,
-            }))
+            .chain(iter::once(syn_event(time)))
             .collect();
 
         Ok(es)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scroll_remainder() {
+        let mut scroll = ScrollRemainder::default();
+        assert_eq!(scroll.add(ScrollAxis::Vertical, 90), 0);
+        assert_eq!(scroll.add(ScrollAxis::Vertical, 60), 1);
+        assert_eq!(scroll.add(ScrollAxis::Horizontal, -240), -2);
+        // direction changes cancel what's left of the other direction
+        assert_eq!(scroll.add(ScrollAxis::Vertical, -30), 0);
+        assert_eq!(scroll.add(ScrollAxis::Vertical, -120), -1);
+    }
+
+    #[test]
+    fn test_gesture_fingers() {
+        let mut fingers = GestureFingers::default();
+        let count = |es: &[(EventCode, i32)], code: EV_ABS| {
+            es.iter()
+                .filter(|(c, _)| *c == EventCode::EV_ABS(code))
+                .count()
+        };
+
+        let es = fingers.events(Gesture::SwipeBegin { fingers: 3 });
+        assert_eq!(count(&es, EV_ABS::ABS_MT_TRACKING_ID), 3);
+        assert!(es.contains(&(EventCode::EV_KEY(EV_KEY::BTN_TOOL_TRIPLETAP), 1)));
+
+        let before = fingers.fingers.clone();
+        let es = fingers.events(Gesture::SwipeUpdate { dx: 100, dy: -50 });
+        assert_eq!(count(&es, EV_ABS::ABS_MT_POSITION_X), 3);
+        for ((x1, y1), (x2, y2)) in before.iter().zip(&fingers.fingers) {
+            assert_eq!((x2 - x1, y2 - y1), (100.0, -50.0));
+        }
+
+        let es = fingers.events(Gesture::SwipeEnd { cancelled: false });
+        assert!(es.contains(&(EventCode::EV_KEY(EV_KEY::BTN_TOUCH), 0)));
+        assert!(fingers.fingers.is_empty());
+        // nothing to lift
+        assert!(fingers
+            .events(Gesture::PinchEnd { cancelled: false })
+            .is_empty());
+    }
+}
@@ -1,15 +1,109 @@
+mod channel;
+mod pointer;
+mod remap;
+
+pub use self::channel::{ChannelInputSink, ChannelInputSinkHandle};
+
+use self::{pointer::PointerScaler, remap::Remapper};
+use crate::{
+    client::config::{PointerConfig, RemapConfig},
+    transport::protocol::{HidUsage, InputEvent, LockState},
+};
+use anyhow::Error;
 use cfg_if::cfg_if;
+use std::collections::HashSet;
+use tokio::{
+    sync::{mpsc, watch},
+    task::{self, JoinHandle},
+};
+use tracing::warn;
 
 cfg_if! {
     if #[cfg(target_os = "linux")] {
         mod linux;
-        pub use self::linux::start;
+        pub use self::linux::SystemInputSink;
     }
 }
 
 cfg_if! {
     if #[cfg(target_os = "windows")] {
         mod windows;
-        pub use self::windows::start;
+        pub use self::windows::SystemInputSink;
+    }
+}
+
+/// Emulates input on a host.
+pub trait InputSink: Send + 'static {
+    /// Starts emulating input of the commands until the channel is closed.
+    ///
+    /// Lock state of the host is sent through `lock_tx` when it changes. The
+    /// task fails if input can't be emulated.
+    fn start(
+        self,
+        cmd_rx: mpsc::Receiver<SinkCommand>,
+        lock_tx: watch::Sender<Option<LockState>>,
+    ) -> JoinHandle<Result<(), Error>>;
+}
+
+/// Starts the input sink.
+///
+/// Events are remapped and pointer movements are scaled here, before the sink
+/// emulates them.
+pub fn start(
+    sink: impl InputSink,
+    mut cmd_rx: mpsc::Receiver<SinkCommand>,
+    lock_tx: watch::Sender<Option<LockState>>,
+    pointer: PointerConfig,
+    remap: RemapConfig,
+) -> JoinHandle<Result<(), Error>> {
+    let (platform_tx, platform_rx) = mpsc::channel(1);
+    let platform = sink.start(platform_rx, lock_tx);
+    let mut scaler = PointerScaler::new(pointer);
+    let mut remapper = Remapper::new(remap);
+
+    task::spawn(async move {
+        'recv: while let Some(cmd) = cmd_rx.recv().await {
+            let cmds = match cmd {
+                SinkCommand::Event(event) => remapper
+                    .apply(event)
+                    .into_iter()
+                    .filter_map(|x| scaler.apply(x))
+                    .map(SinkCommand::Event)
+                    .collect(),
+                x => vec![x],
+            };
+            for cmd in cmds {
+                if platform_tx.send(cmd).await.is_err() {
+                    break 'recv;
+                }
+            }
+        }
+        // closing the channel stops the platform's sink
+        drop(platform_tx);
+        platform.await?
+    })
+}
+
+/// Instruction for the input sink.
+#[derive(Clone, Copy, Debug)]
+pub enum SinkCommand {
+    /// Emulate the input event.
+    Event(InputEvent),
+    /// Toggle lock keys that don't match the lock state.
+    SetLockState(LockState),
+}
+
+/// Raw keys the sink can't emulate, each is logged the first time it's seen.
+#[derive(Default, Debug)]
+struct UnsupportedRawKeys(HashSet<HidUsage>);
+
+impl UnsupportedRawKeys {
+    fn report(&mut self, usage: HidUsage) {
+        if self.0.insert(usage) {
+            warn!(
+                ?usage,
+                "raw key can't be emulated on this host, ignoring it"
+            );
+        }
     }
 }
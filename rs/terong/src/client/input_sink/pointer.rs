@@ -0,0 +1,149 @@
+use crate::{
+    client::config::{Acceleration, PointerConfig},
+    transport::protocol::InputEvent,
+};
+
+impl Acceleration {
+    fn gain(&self, speed: f64) -> f64 {
+        match *self {
+            Acceleration::Flat => 1.0,
+            Acceleration::Threshold { threshold, factor } => {
+                if speed > threshold {
+                    factor
+                } else {
+                    1.0
+                }
+            }
+            Acceleration::Linear { slope, max_gain } => (1.0 + slope * speed).min(max_gain),
+        }
+    }
+}
+
+/// Scales pointer movements.
+///
+/// Fractions of a pixel are carried to the next movement, so slow movements
+/// still move the pointer when they're scaled down.
+#[derive(Debug)]
+pub struct PointerScaler {
+    config: PointerConfig,
+    remainder: (f64, f64),
+}
+
+impl PointerScaler {
+    pub fn new(config: PointerConfig) -> Self {
+        Self {
+            config,
+            remainder: (0.0, 0.0),
+        }
+    }
+
+    /// Scales the event if it's a pointer movement, returns None if the
+    /// movement is too small to move the pointer yet.
+    pub fn apply(&mut self, event: InputEvent) -> Option<InputEvent> {
+        match event {
+            InputEvent::MouseMove { dx, dy } => {
+                let (dx, dy) = self.scale(dx, dy);
+                (dx != 0 || dy != 0).then_some(InputEvent::MouseMove { dx, dy })
+            }
+            x => Some(x),
+        }
+    }
+
+    fn scale(&mut self, dx: i16, dy: i16) -> (i16, i16) {
+        let (dx, dy) = (dx as f64, dy as f64);
+        let speed = dx.hypot(dy);
+        let gain = self.config.sensitivity * self.config.acceleration.gain(speed);
+
+        let x = dx * gain + self.remainder.0;
+        let y = dy * gain + self.remainder.1;
+        let (x_whole, y_whole) = (x.trunc(), y.trunc());
+        self.remainder = (x - x_whole, y - y_whole);
+
+        (saturate(x_whole), saturate(y_whole))
+    }
+}
+
+fn saturate(value: f64) -> i16 {
+    value.clamp(i16::MIN as _, i16::MAX as _) as _
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_scaler(sensitivity: f64, acceleration: Acceleration) -> PointerScaler {
+        PointerScaler::new(PointerConfig {
+            sensitivity,
+            acceleration,
+        })
+    }
+
+    #[test]
+    fn test_default_is_identity() {
+        let mut scaler = PointerScaler::new(Default::default());
+        assert_eq!(scaler.scale(3, -7), (3, -7));
+        assert_eq!(scaler.scale(i16::MAX, i16::MIN), (i16::MAX, i16::MIN));
+    }
+
+    #[test]
+    fn test_remainder_accumulates() {
+        let mut scaler = new_scaler(0.4, Acceleration::Flat);
+        let moves: Vec<_> = (0..5).map(|_| scaler.scale(1, -1)).collect();
+        assert_eq!(moves, vec![(0, 0), (0, 0), (1, -1), (0, 0), (1, -1)]);
+
+        let mut scaler = new_scaler(0.4, Acceleration::Flat);
+        assert_eq!(scaler.apply(InputEvent::MouseMove { dx: 1, dy: 0 }), None);
+        assert_eq!(
+            scaler.apply(InputEvent::MouseMove { dx: 2, dy: 0 }),
+            Some(InputEvent::MouseMove { dx: 1, dy: 0 })
+        );
+    }
+
+    #[test]
+    fn test_sensitivity_saturates() {
+        let mut scaler = new_scaler(2.5, Acceleration::Flat);
+        assert_eq!(scaler.scale(4, -2), (10, -5));
+        assert_eq!(scaler.scale(20000, -20000), (i16::MAX, i16::MIN));
+    }
+
+    #[test]
+    fn test_acceleration() {
+        let threshold = Acceleration::Threshold {
+            threshold: 5.0,
+            factor: 2.0,
+        };
+        assert_eq!(new_scaler(1.0, threshold).scale(3, 4), (3, 4));
+        assert_eq!(new_scaler(1.0, threshold).scale(6, 8), (12, 16));
+
+        let linear = Acceleration::Linear {
+            slope: 0.1,
+            max_gain: 3.0,
+        };
+        // speed 10 doubles
+        assert_eq!(new_scaler(1.0, linear).scale(6, 8), (12, 16));
+        // speed 100 is capped
+        assert_eq!(new_scaler(0.5, linear).scale(60, 80), (90, 120));
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: PointerConfig = toml::from_str(
+            r#"
+            sensitivity = 0.5
+            [acceleration]
+            profile = "linear"
+            slope = 0.05
+            max_gain = 3.0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.sensitivity, 0.5);
+        assert_eq!(
+            config.acceleration,
+            Acceleration::Linear {
+                slope: 0.05,
+                max_gain: 3.0
+            }
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use crate::{
+    client::config::{KeyTarget, RemapConfig},
+    transport::protocol::{InputEvent, KeyCode, MouseButton},
+};
+use std::{collections::HashMap, hash::Hash};
+
+/// Remaps keys and mouse buttons.
+#[derive(Debug)]
+pub struct Remapper {
+    keys: HashMap<KeyCode, Vec<KeyCode>>,
+    buttons: HashMap<MouseButton, MouseButton>,
+    key_presses: Presses<KeyCode>,
+    button_presses: Presses<MouseButton>,
+}
+
+impl Remapper {
+    pub fn new(config: RemapConfig) -> Self {
+        let keys = config
+            .keys
+            .into_iter()
+            .map(|(key, target)| match target {
+                KeyTarget::Key(x) => (key, vec![x]),
+                KeyTarget::Chord(xs) => (key, xs),
+            })
+            .collect();
+        Self {
+            keys,
+            buttons: config.buttons,
+            key_presses: Default::default(),
+            button_presses: Default::default(),
+        }
+    }
+
+    /// Returns events the event is remapped into.
+    pub fn apply(&mut self, event: InputEvent) -> Vec<InputEvent> {
+        match event {
+            InputEvent::KeyDown { key } => {
+                let targets = self.key_targets(key);
+                let (released, pressed) = self.key_presses.press(key, targets);
+                released
+                    .into_iter()
+                    .map(|key| InputEvent::KeyUp { key })
+                    .chain(pressed.into_iter().map(|key| InputEvent::KeyDown { key }))
+                    .collect()
+            }
+            InputEvent::KeyRepeat { key } => {
+                // chords repeat their last key
+                let target = match self.key_presses.held.get(&key) {
+                    Some(xs) => xs.last().copied(),
+                    None => self.key_targets(key).last().copied(),
+                };
+                target
+                    .map(|key| InputEvent::KeyRepeat { key })
+                    .into_iter()
+                    .collect()
+            }
+            InputEvent::KeyUp { key } => {
+                let targets = self.key_targets(key);
+                self.key_presses
+                    .release(key, targets)
+                    .into_iter()
+                    .map(|key| InputEvent::KeyUp { key })
+                    .collect()
+            }
+            InputEvent::MouseButtonDown { button } => {
+                let target = self.button_target(button);
+                let (released, pressed) = self.button_presses.press(button, vec![target]);
+                released
+                    .into_iter()
+                    .map(|button| InputEvent::MouseButtonUp { button })
+                    .chain(
+                        pressed
+                            .into_iter()
+                            .map(|button| InputEvent::MouseButtonDown { button }),
+                    )
+                    .collect()
+            }
+            InputEvent::MouseButtonUp { button } => {
+                let target = self.button_target(button);
+                self.button_presses
+                    .release(button, vec![target])
+                    .into_iter()
+                    .map(|button| InputEvent::MouseButtonUp { button })
+                    .collect()
+            }
+            x => vec![x],
+        }
+    }
+
+    fn key_targets(&self, key: KeyCode) -> Vec<KeyCode> {
+        self.keys.get(&key).cloned().unwrap_or_else(|| vec![key])
+    }
+
+    fn button_target(&self, button: MouseButton) -> MouseButton {
+        self.buttons.get(&button).copied().unwrap_or(button)
+    }
+}
+
+/// Keeps presses and releases of remapped targets paired.
+///
+/// A target held by several sources is pressed by the first of them and
+/// released by the last.
+#[derive(Debug)]
+struct Presses<T> {
+    /// Targets pressed by each held source.
+    held: HashMap<T, Vec<T>>,
+    /// Number of held sources pressing each target.
+    counts: HashMap<T, usize>,
+}
+
+impl<T> Default for Presses<T> {
+    fn default() -> Self {
+        Self {
+            held: Default::default(),
+            counts: Default::default(),
+        }
+    }
+}
+
+impl<T> Presses<T>
+where
+    T: Copy + Eq + Hash,
+{
+    /// Returns targets to release and targets to press.
+    ///
+    /// A source pressed while it's held lost its release, its targets are
+    /// released and pressed again so they aren't left stuck.
+    fn press(&mut self, source: T, targets: Vec<T>) -> (Vec<T>, Vec<T>) {
+        let released = if self.held.contains_key(&source) {
+            self.release(source, Vec::new())
+        } else {
+            Vec::new()
+        };
+        let pressed = targets
+            .iter()
+            .copied()
+            .filter(|x| {
+                let count = self.counts.entry(*x).or_default();
+                *count += 1;
+                *count == 1
+            })
+            .collect();
+        self.held.insert(source, targets);
+        (released, pressed)
+    }
+
+    /// Returns targets to release, in reverse order of their press.
+    ///
+    /// `targets` are released if the source isn't held, it may have been
+    /// pressed before the remapper started.
+    fn release(&mut self, source: T, targets: Vec<T>) -> Vec<T> {
+        let targets = self.held.remove(&source).unwrap_or(targets);
+        targets
+            .into_iter()
+            .rev()
+            .filter(|x| match self.counts.get_mut(x) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    false
+                }
+                _ => {
+                    self.counts.remove(x);
+                    true
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::protocol::{down, up};
+
+    fn remapper(config: &str) -> Remapper {
+        Remapper::new(toml::from_str(config).unwrap())
+    }
+
+    fn apply(remapper: &mut Remapper, events: &[InputEvent]) -> Vec<InputEvent> {
+        events.iter().flat_map(|x| remapper.apply(*x)).collect()
+    }
+
+    #[test]
+    fn test_swap_keys() {
+        let mut remapper = remapper(
+            r#"
+            keys = { LeftMeta = "LeftCtrl", LeftCtrl = "LeftMeta", CapsLock = "Escape" }
+            "#,
+        );
+        assert_eq!(
+            apply(
+                &mut remapper,
+                &[
+                    down(KeyCode::LeftMeta),
+                    down(KeyCode::A),
+                    up(KeyCode::A),
+                    up(KeyCode::LeftMeta),
+                    down(KeyCode::CapsLock),
+                    up(KeyCode::CapsLock),
+                ]
+            ),
+            vec![
+                down(KeyCode::LeftCtrl),
+                down(KeyCode::A),
+                up(KeyCode::A),
+                up(KeyCode::LeftCtrl),
+                down(KeyCode::Escape),
+                up(KeyCode::Escape),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chord() {
+        let mut remapper = remapper(
+            r#"
+            keys = { F13 = ["LeftCtrl", "C"] }
+            "#,
+        );
+        assert_eq!(
+            apply(
+                &mut remapper,
+                &[
+                    down(KeyCode::F13),
+                    InputEvent::KeyRepeat { key: KeyCode::F13 },
+                    up(KeyCode::F13),
+                ]
+            ),
+            vec![
+                down(KeyCode::LeftCtrl),
+                down(KeyCode::C),
+                InputEvent::KeyRepeat { key: KeyCode::C },
+                up(KeyCode::C),
+                up(KeyCode::LeftCtrl),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_chord() {
+        let config: RemapConfig = toml::from_str(
+            r#"
+            keys = { CapsLock = [] }
+            "#,
+        )
+        .unwrap();
+        assert!(config.check().is_err());
+
+        let config: RemapConfig = toml::from_str(
+            r#"
+            keys = { CapsLock = ["LeftCtrl", "Space"] }
+            "#,
+        )
+        .unwrap();
+        assert!(config.check().is_ok());
+    }
+
+    #[test]
+    fn test_shared_target_stays_pressed() {
+        let mut remapper = remapper(
+            r#"
+            keys = { CapsLock = "LeftCtrl" }
+            "#,
+        );
+        assert_eq!(
+            apply(
+                &mut remapper,
+                &[
+                    down(KeyCode::LeftCtrl),
+                    down(KeyCode::CapsLock),
+                    up(KeyCode::LeftCtrl),
+                    up(KeyCode::CapsLock),
+                ]
+            ),
+            vec![down(KeyCode::LeftCtrl), up(KeyCode::LeftCtrl)]
+        );
+    }
+
+    #[test]
+    fn test_press_of_held_key() {
+        let mut remapper = remapper(
+            r#"
+            keys = { F13 = ["LeftCtrl", "C"] }
+            "#,
+        );
+        // the release of the first press was lost
+        assert_eq!(
+            apply(
+                &mut remapper,
+                &[down(KeyCode::F13), down(KeyCode::F13), up(KeyCode::F13)]
+            ),
+            vec![
+                down(KeyCode::LeftCtrl),
+                down(KeyCode::C),
+                up(KeyCode::C),
+                up(KeyCode::LeftCtrl),
+                down(KeyCode::LeftCtrl),
+                down(KeyCode::C),
+                up(KeyCode::C),
+                up(KeyCode::LeftCtrl),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_buttons() {
+        let mut remapper = remapper(
+            r#"
+            buttons = { Mouse4 = "Middle" }
+            "#,
+        );
+        assert_eq!(
+            apply(
+                &mut remapper,
+                &[
+                    InputEvent::MouseButtonDown {
+                        button: MouseButton::Mouse4
+                    },
+                    InputEvent::MouseButtonUp {
+                        button: MouseButton::Mouse4
+                    },
+                    InputEvent::MouseMove { dx: 1, dy: 1 },
+                ]
+            ),
+            vec![
+                InputEvent::MouseButtonDown {
+                    button: MouseButton::Middle
+                },
+                InputEvent::MouseButtonUp {
+                    button: MouseButton::Middle
+                },
+                InputEvent::MouseMove { dx: 1, dy: 1 },
+            ]
+        );
+    }
+}
@@ -1,143 +1,363 @@
-use crate::transport::protocol::{
-    windows::VirtualKey, InputEvent, MouseButton, MouseScrollDirection,
+use super::{InputSink, SinkCommand, UnsupportedRawKeys};
+use crate::{
+    lock_state,
+    transport::protocol::{
+        AbsolutePointer, HidUsage, InputEvent, KeyCode, LockKey, LockState, MouseButton,
+        MouseScrollDirection, ScrollAxis,
+    },
 };
+use anyhow::Error;
 use std::mem::size_of;
 use tokio::{
-    sync::mpsc,
+    sync::{mpsc, watch},
     task::{self, JoinHandle},
 };
+use tracing::warn;
 use windows::Win32::UI::{
     Input::KeyboardAndMouse::{
-        SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, INPUT_MOUSE, KEYBDINPUT, KEYEVENTF_KEYUP,
-        MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP, MOUSEEVENTF_MIDDLEDOWN, MOUSEEVENTF_MIDDLEUP,
-        MOUSEEVENTF_MOVE, MOUSEEVENTF_RIGHTDOWN, MOUSEEVENTF_RIGHTUP, MOUSEEVENTF_WHEEL,
-        MOUSEEVENTF_XDOWN, MOUSEEVENTF_XUP, MOUSEINPUT, VIRTUAL_KEY,
+        SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, INPUT_MOUSE, KEYBDINPUT, KEYBD_EVENT_FLAGS,
+        KEYEVENTF_EXTENDEDKEY, KEYEVENTF_KEYUP, KEYEVENTF_SCANCODE, MOUSEEVENTF_ABSOLUTE,
+        MOUSEEVENTF_HWHEEL, MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP, MOUSEEVENTF_MIDDLEDOWN,
+        MOUSEEVENTF_MIDDLEUP, MOUSEEVENTF_MOVE, MOUSEEVENTF_RIGHTDOWN, MOUSEEVENTF_RIGHTUP,
+        MOUSEEVENTF_WHEEL, MOUSEEVENTF_XDOWN, MOUSEEVENTF_XUP, MOUSEINPUT, MOUSE_EVENT_FLAGS,
+        VIRTUAL_KEY, VK_CAPITAL, VK_NUMLOCK, VK_SCROLL,
     },
     WindowsAndMessaging::{WHEEL_DELTA, XBUTTON1, XBUTTON2},
 };
 
-pub fn start(mut event_rx: mpsc::Receiver<InputEvent>) -> JoinHandle<()> {
-    task::spawn_blocking(move || loop {
-        let event = match event_rx.blocking_recv() {
-            Some(x) => x,
-            None => break,
-        };
+/// Emulates input with SendInput.
+#[derive(Default, Debug)]
+pub struct SystemInputSink;
+
+impl InputSink for SystemInputSink {
+    fn start(
+        self,
+        cmd_rx: mpsc::Receiver<SinkCommand>,
+        lock_tx: watch::Sender<Option<LockState>>,
+    ) -> JoinHandle<Result<(), Error>> {
+        run_input_sink(cmd_rx, lock_tx)
+    }
+}
+
+fn run_input_sink(
+    mut cmd_rx: mpsc::Receiver<SinkCommand>,
+    lock_tx: watch::Sender<Option<LockState>>,
+) -> JoinHandle<Result<(), Error>> {
+    lock_state::poll(lock_tx, lock_state::read);
+
+    let mut unsupported = UnsupportedRawKeys::default();
+    let mut touching = false;
+    let mut gamepad_reported = false;
+    let mut gesture_reported = false;
 
-        let input = match event {
-            InputEvent::MouseMove { dx, dy } => INPUT {
-                r#type: INPUT_MOUSE,
-                Anonymous: INPUT_0 {
-                    mi: MOUSEINPUT {
-                        dx: dx as _,
-                        dy: dy as _,
-                        mouseData: 0,
-                        dwFlags: MOUSEEVENTF_MOVE,
-                        time: 0,
-                        dwExtraInfo: Default::default(),
+    task::spawn_blocking(move || {
+        loop {
+            let event = match cmd_rx.blocking_recv() {
+                // Windows has no task button
+                Some(SinkCommand::Event(
+                    InputEvent::MouseButtonDown {
+                        button: MouseButton::Task,
+                    }
+                    | InputEvent::MouseButtonUp {
+                        button: MouseButton::Task,
                     },
-                },
-            },
+                )) => continue,
+                Some(SinkCommand::Event(x)) => x,
+                Some(SinkCommand::SetLockState(target)) => {
+                    set_lock_state(target);
+                    continue;
+                }
+                None => break,
+            };
 
-            InputEvent::MouseButtonDown { button } => INPUT {
-                r#type: INPUT_MOUSE,
-                Anonymous: INPUT_0 {
-                    mi: MOUSEINPUT {
-                        dx: Default::default(),
-                        dy: Default::default(),
-                        mouseData: match button {
-                            MouseButton::Mouse4 => XBUTTON1.0 as _,
-                            MouseButton::Mouse5 => XBUTTON2.0 as _,
-                            _ => 0,
-                        },
-                        dwFlags: match button {
-                            MouseButton::Left => MOUSEEVENTF_LEFTDOWN,
-                            MouseButton::Right => MOUSEEVENTF_RIGHTDOWN,
-                            MouseButton::Middle => MOUSEEVENTF_MIDDLEDOWN,
-                            MouseButton::Mouse4 | MouseButton::Mouse5 => MOUSEEVENTF_XDOWN,
+            let input = match event {
+                InputEvent::MouseMove { dx, dy } => INPUT {
+                    r#type: INPUT_MOUSE,
+                    Anonymous: INPUT_0 {
+                        mi: MOUSEINPUT {
+                            dx: dx as _,
+                            dy: dy as _,
+                            mouseData: 0,
+                            dwFlags: MOUSEEVENTF_MOVE,
+                            time: 0,
+                            dwExtraInfo: Default::default(),
                         },
-                        time: 0,
-                        dwExtraInfo: Default::default(),
                     },
                 },
-            },
 
-            InputEvent::MouseButtonUp { button } => INPUT {
-                r#type: INPUT_MOUSE,
-                Anonymous: INPUT_0 {
-                    mi: MOUSEINPUT {
-                        dx: Default::default(),
-                        dy: Default::default(),
-                        mouseData: match button {
-                            MouseButton::Mouse4 => XBUTTON1.0 as _,
-                            MouseButton::Mouse5 => XBUTTON2.0 as _,
-                            _ => 0,
-                        },
-                        dwFlags: match button {
-                            MouseButton::Left => MOUSEEVENTF_LEFTUP,
-                            MouseButton::Right => MOUSEEVENTF_RIGHTUP,
-                            MouseButton::Middle => MOUSEEVENTF_MIDDLEUP,
-                            MouseButton::Mouse4 | MouseButton::Mouse5 => MOUSEEVENTF_XUP,
+                InputEvent::MouseButtonDown { button } => INPUT {
+                    r#type: INPUT_MOUSE,
+                    Anonymous: INPUT_0 {
+                        mi: MOUSEINPUT {
+                            dx: Default::default(),
+                            dy: Default::default(),
+                            mouseData: match button {
+                                MouseButton::Mouse4 | MouseButton::Back => XBUTTON1.0 as _,
+                                MouseButton::Mouse5 | MouseButton::Forward => XBUTTON2.0 as _,
+                                _ => 0,
+                            },
+                            dwFlags: match button {
+                                MouseButton::Left => MOUSEEVENTF_LEFTDOWN,
+                                MouseButton::Right => MOUSEEVENTF_RIGHTDOWN,
+                                MouseButton::Middle => MOUSEEVENTF_MIDDLEDOWN,
+                                MouseButton::Mouse4
+                                | MouseButton::Mouse5
+                                | MouseButton::Back
+                                | MouseButton::Forward
+                                | MouseButton::Task => MOUSEEVENTF_XDOWN,
+                            },
+                            time: 0,
+                            dwExtraInfo: Default::default(),
                         },
-                        time: 0,
-                        dwExtraInfo: Default::default(),
                     },
                 },
-            },
 
-            InputEvent::MouseScroll { direction } => INPUT {
-                r#type: INPUT_MOUSE,
-                Anonymous: INPUT_0 {
-                    mi: MOUSEINPUT {
-                        dx: Default::default(),
-                        dy: Default::default(),
-                        mouseData: match direction {
-                            MouseScrollDirection::Up { clicks } => {
-                                (WHEEL_DELTA * clicks as u32) as i32
-                            }
-                            MouseScrollDirection::Down { clicks } => {
-                                -((WHEEL_DELTA * clicks as u32) as i32)
-                            }
+                InputEvent::MouseButtonUp { button } => INPUT {
+                    r#type: INPUT_MOUSE,
+                    Anonymous: INPUT_0 {
+                        mi: MOUSEINPUT {
+                            dx: Default::default(),
+                            dy: Default::default(),
+                            mouseData: match button {
+                                MouseButton::Mouse4 | MouseButton::Back => XBUTTON1.0 as _,
+                                MouseButton::Mouse5 | MouseButton::Forward => XBUTTON2.0 as _,
+                                _ => 0,
+                            },
+                            dwFlags: match button {
+                                MouseButton::Left => MOUSEEVENTF_LEFTUP,
+                                MouseButton::Right => MOUSEEVENTF_RIGHTUP,
+                                MouseButton::Middle => MOUSEEVENTF_MIDDLEUP,
+                                MouseButton::Mouse4
+                                | MouseButton::Mouse5
+                                | MouseButton::Back
+                                | MouseButton::Forward
+                                | MouseButton::Task => MOUSEEVENTF_XUP,
+                            },
+                            time: 0,
+                            dwExtraInfo: Default::default(),
                         },
-                        dwFlags: MOUSEEVENTF_WHEEL,
-                        time: 0,
-                        dwExtraInfo: Default::default(),
                     },
                 },
-            },
 
-            InputEvent::KeyDown { key } | InputEvent::KeyRepeat { key } => INPUT {
-                r#type: INPUT_KEYBOARD,
-                Anonymous: INPUT_0 {
-                    ki: KEYBDINPUT {
-                        wVk: {
-                            let vk: VirtualKey = key.into();
-                            VIRTUAL_KEY(vk.0)
+                InputEvent::MouseScroll { direction } => INPUT {
+                    r#type: INPUT_MOUSE,
+                    Anonymous: INPUT_0 {
+                        mi: MOUSEINPUT {
+                            dx: Default::default(),
+                            dy: Default::default(),
+                            mouseData: match direction {
+                                MouseScrollDirection::Up { clicks }
+                                | MouseScrollDirection::Right { clicks } => {
+                                    (WHEEL_DELTA * clicks as u32) as i32
+                                }
+                                MouseScrollDirection::Down { clicks }
+                                | MouseScrollDirection::Left { clicks } => {
+                                    -((WHEEL_DELTA * clicks as u32) as i32)
+                                }
+                            },
+                            dwFlags: match direction {
+                                MouseScrollDirection::Up { .. }
+                                | MouseScrollDirection::Down { .. } => MOUSEEVENTF_WHEEL,
+                                MouseScrollDirection::Left { .. }
+                                | MouseScrollDirection::Right { .. } => MOUSEEVENTF_HWHEEL,
+                            },
+                            time: 0,
+                            dwExtraInfo: Default::default(),
                         },
-                        wScan: Default::default(),
-                        dwFlags: Default::default(),
-                        time: 0,
-                        dwExtraInfo: Default::default(),
                     },
                 },
-            },
 
-            InputEvent::KeyUp { key } => INPUT {
-                r#type: INPUT_KEYBOARD,
-                Anonymous: INPUT_0 {
-                    ki: KEYBDINPUT {
-                        wVk: {
-                            let vk: VirtualKey = key.into();
-                            VIRTUAL_KEY(vk.0)
+                InputEvent::MouseScrollHiRes { axis, delta } => INPUT {
+                    r#type: INPUT_MOUSE,
+                    Anonymous: INPUT_0 {
+                        mi: MOUSEINPUT {
+                            dx: Default::default(),
+                            dy: Default::default(),
+                            mouseData: delta as _,
+                            dwFlags: match axis {
+                                ScrollAxis::Vertical => MOUSEEVENTF_WHEEL,
+                                ScrollAxis::Horizontal => MOUSEEVENTF_HWHEEL,
+                            },
+                            time: 0,
+                            dwExtraInfo: Default::default(),
                         },
-                        wScan: Default::default(),
-                        dwFlags: KEYEVENTF_KEYUP,
-                        time: 0,
-                        dwExtraInfo: Default::default(),
                     },
                 },
-            },
-        };
 
-        unsafe { SendInput(&[input], size_of::<INPUT>() as _) };
+                InputEvent::AbsolutePointer { pointer } => {
+                    send_absolute_pointer(pointer, &mut touching);
+                    continue;
+                }
+
+                InputEvent::Gamepad { .. } => {
+                    if !gamepad_reported {
+                        gamepad_reported = true;
+                        warn!("gamepad can't be emulated on this host, ignoring it");
+                    }
+                    continue;
+                }
+
+                InputEvent::Gesture { .. } => {
+                    if !gesture_reported {
+                        gesture_reported = true;
+                        warn!("touchpad gestures can't be emulated on this host, ignoring them");
+                    }
+                    continue;
+                }
+
+                InputEvent::KeyDown { key }
+                | InputEvent::KeyRepeat { key }
+                | InputEvent::KeyUp { key } => {
+                    let flags = match event {
+                        InputEvent::KeyUp { .. } => KEYEVENTF_KEYUP,
+                        _ => Default::default(),
+                    };
+                    match key_code_input(key, flags) {
+                        Some(x) => x,
+                        None => {
+                            warn!(?key, "key can't be emulated on this host, ignoring it");
+                            continue;
+                        }
+                    }
+                }
+
+                InputEvent::RawKeyDown { usage } | InputEvent::RawKeyUp { usage } => {
+                    let flags = match event {
+                        InputEvent::RawKeyUp { .. } => KEYEVENTF_KEYUP,
+                        _ => Default::default(),
+                    };
+                    match raw_key_input(usage, flags) {
+                        Some(x) => x,
+                        None => {
+                            unsupported.report(usage);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            unsafe { SendInput(&[input], size_of::<INPUT>() as _) };
+        }
+        Ok(())
     })
 }
+
+/// Toggles lock keys that don't match the target state.
+fn set_lock_state(target: LockState) {
+    let current = match lock_state::read() {
+        Ok(x) => x,
+        Err(err) => {
+            warn!(?err, "failed to read lock state");
+            return;
+        }
+    };
+
+    let inputs: Vec<INPUT> = current
+        .toggles_to(&target)
+        .into_iter()
+        .flat_map(|key| {
+            let vk = match key {
+                LockKey::Caps => VK_CAPITAL,
+                LockKey::Num => VK_NUMLOCK,
+                LockKey::Scroll => VK_SCROLL,
+            };
+            [
+                key_input(vk, Default::default()),
+                key_input(vk, KEYEVENTF_KEYUP),
+            ]
+        })
+        .collect();
+
+    if !inputs.is_empty() {
+        unsafe { SendInput(&inputs, size_of::<INPUT>() as _) };
+    }
+}
+
+/// Moves the cursor to the pointer position, touching the surface presses the
+/// left button.
+fn send_absolute_pointer(pointer: AbsolutePointer, touching: &mut bool) {
+    let mut inputs = vec![mouse_input(
+        pointer.x as _,
+        pointer.y as _,
+        MOUSEEVENTF_MOVE | MOUSEEVENTF_ABSOLUTE,
+    )];
+    if pointer.touch != *touching {
+        *touching = pointer.touch;
+        let flags = if pointer.touch {
+            MOUSEEVENTF_LEFTDOWN
+        } else {
+            MOUSEEVENTF_LEFTUP
+        };
+        inputs.push(mouse_input(0, 0, flags));
+    }
+    unsafe { SendInput(&inputs, size_of::<INPUT>() as _) };
+}
+
+fn mouse_input(dx: i32, dy: i32, flags: MOUSE_EVENT_FLAGS) -> INPUT {
+    INPUT {
+        r#type: INPUT_MOUSE,
+        Anonymous: INPUT_0 {
+            mi: MOUSEINPUT {
+                dx,
+                dy,
+                mouseData: 0,
+                dwFlags: flags,
+                time: 0,
+                dwExtraInfo: Default::default(),
+            },
+        },
+    }
+}
+
+fn key_input(vk: VIRTUAL_KEY, flags: KEYBD_EVENT_FLAGS) -> INPUT {
+    INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 {
+            ki: KEYBDINPUT {
+                wVk: vk,
+                wScan: Default::default(),
+                dwFlags: flags,
+                time: 0,
+                dwExtraInfo: Default::default(),
+            },
+        },
+    }
+}
+
+/// Emulates keys sharing a virtual key with another key by their scan code.
+fn key_code_input(key: KeyCode, flags: KEYBD_EVENT_FLAGS) -> Option<INPUT> {
+    match key.to_scan_code() {
+        Some(code) => Some(scan_code_input(code, flags)),
+        None => key
+            .to_virtual_key()
+            .map(|vk| key_input(VIRTUAL_KEY(vk.0), flags)),
+    }
+}
+
+/// Returns None if the key has no scan code.
+fn raw_key_input(usage: HidUsage, flags: KEYBD_EVENT_FLAGS) -> Option<INPUT> {
+    usage
+        .to_scan_code()
+        .map(|code| scan_code_input(code, flags))
+}
+
+/// Extended scan codes have 0xE0 in their high byte.
+fn scan_code_input(code: u16, flags: KEYBD_EVENT_FLAGS) -> INPUT {
+    let flags = if code & 0xFF00 == 0xE000 {
+        flags | KEYEVENTF_SCANCODE | KEYEVENTF_EXTENDEDKEY
+    } else {
+        flags | KEYEVENTF_SCANCODE
+    };
+    INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 {
+            ki: KEYBDINPUT {
+                wVk: Default::default(),
+                wScan: code & 0xFF,
+                dwFlags: flags,
+                time: 0,
+                dwExtraInfo: Default::default(),
+            },
+        },
+    }
+}
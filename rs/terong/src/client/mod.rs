@@ -1,67 +1,327 @@
-mod input_sink;
 mod transport_client;
 
 pub mod config;
+pub mod input_sink;
 
 use crate::{
-    client::{config::ClientConfig, transport_client::TransportClient},
-    config::{read_certs, read_private_key, Config},
-    logging::init_tracing,
+    client::{
+        config::ClientConfig,
+        input_sink::{InputSink, SystemInputSink},
+        transport_client::TransportClient,
+    },
+    clipboard::{self, SystemClipboard},
+    config::{read_credentials, Config, SecureChannel, WireFormat},
+    file_transfer,
+    logging::init_tracing_with,
+    metrics::{self, Metrics},
+    transport::{
+        tls::{TlsFiles, TlsMaterial},
+        Credentials,
+    },
 };
-use anyhow::Error;
-use tokio::sync::mpsc;
-use tracing::info;
+use anyhow::{bail, Error};
+use std::{path::PathBuf, sync::Arc};
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    task::{self, JoinHandle},
+    try_join,
+};
+use tracing::{error, info};
 
-async fn start_app(cfg: ClientConfig) -> Result<(), Error> {
-    info!(?cfg, "starting client app");
+/// Starts the client app emulating input with `sink` and with paths of files to
+/// send to the server from `path_rx`, returns once it stops.
+pub async fn start_app(
+    cfg: ClientConfig,
+    sink: impl InputSink,
+    path_rx: mpsc::Receiver<PathBuf>,
+) -> Result<(), Error> {
+    let client = ClientBuilder::new(cfg, sink)
+        .file_paths(path_rx)
+        .start()
+        .await?;
+    client.wait().await
+}
 
-    let ClientConfig {
-        tls_cert_path,
-        tls_key_path,
-        server_addr,
-        server_tls_cert_path,
-    } = cfg;
+/// Builds a client for applications embedding it.
+///
+/// Nothing global like the tracing subscriber is set up, that's left to the
+/// application.
+pub struct ClientBuilder<S> {
+    cfg: ClientConfig,
+    sink: S,
+    tls: Option<TlsMaterial>,
+    path_rx: Option<mpsc::Receiver<PathBuf>>,
+}
 
-    // channel for input events from the transport client to the input sink
-    let (event_tx, event_rx) = mpsc::channel(1);
+impl<S: InputSink> ClientBuilder<S> {
+    pub fn new(cfg: ClientConfig, sink: S) -> Self {
+        Self {
+            cfg,
+            sink,
+            tls: None,
+            path_rx: None,
+        }
+    }
 
-    // transport client establishes connection with the server and propagate input
-    // events through the channel
-    let transport_client = {
-        let tls_certs = read_certs(&tls_cert_path).await?;
+    /// Secures the connection with TLS using these certificates and key
+    /// instead of the ones in the config.
+    pub fn tls(mut self, material: TlsMaterial) -> Self {
+        self.tls = Some(material);
+        self
+    }
 
-        let tls_key = read_private_key(&tls_key_path).await?;
+    /// Sends files whose paths are received from `path_rx` to the server.
+    pub fn file_paths(mut self, path_rx: mpsc::Receiver<PathBuf>) -> Self {
+        self.path_rx = Some(path_rx);
+        self
+    }
 
-        let server_tls_certs = read_certs(&server_tls_cert_path).await?;
+    /// Starts the client, it keeps connecting to the server until it gives up
+    /// or is shut down.
+    pub async fn start(self) -> Result<ClientHandle, Error> {
+        let Self {
+            cfg,
+            sink,
+            tls,
+            path_rx,
+        } = self;
 
-        let args = TransportClient {
+        info!(?cfg, "starting client app");
+
+        let ClientConfig {
+            channel,
+            tls_cert_path,
+            tls_key_path,
             server_addr,
-            tls_certs,
-            tls_key,
-            server_tls_certs,
+            server_tls_cert_path,
+            noise,
+            codec,
+            wire_format,
+            clipboard,
+            download_dir,
+            pointer,
+            remap,
+            log_keystrokes,
+            metrics: metrics_cfg,
+            // logging is set up by the application
+            log: _,
+        } = cfg;
+
+        if wire_format == WireFormat::Auto {
+            bail!("wire format can't be auto for client, set it to either native or go");
+        }
+        remap.check()?;
+
+        let credentials = match tls {
+            Some(material) => {
+                wire_format.check(SecureChannel::Tls)?;
+                Credentials::Tls(TlsFiles::from_material(material)?)
+            }
+            None => {
+                wire_format.check(channel)?;
+                read_credentials(
+                    channel,
+                    tls_cert_path,
+                    tls_key_path,
+                    server_tls_cert_path,
+                    noise.as_ref(),
+                )
+                .await?
+            }
         };
-        transport_client::start(args, event_tx)
-    };
 
-    // input sink receives input events and emulate the input events in its host
-    // machine
-    let input_sink = input_sink::start(event_rx);
+        // channel for input events and lock state from the transport client to
+        // the input sink
+        let metrics = Arc::new(Metrics::default());
+        let (sink_tx, sink_rx) = mpsc::channel(1);
+        metrics.watch_queue("sink", &sink_tx);
+
+        // lock state of this host, polled by the input sink
+        let (lock_tx, lock_rx) = watch::channel(None);
+
+        // clipboard is shared through the transport client, the client doesn't
+        // know when control switches so changes are only detected by polling
+        // helper tasks, they don't stop by themselves
+        let mut tasks = Vec::new();
 
-    // The input event channel will be closed when one of the workers, transport
-    // client or the input sink, is stopped,  In response to the channel closed
-    // the other worker will stop as well and this join will resume.
-    tokio::try_join!(transport_client, input_sink)?;
+        if let Some(cfg) = metrics_cfg {
+            tasks.push(metrics::serve(cfg.addr, metrics.clone()).await?);
+        }
+
+        let clipboard = if clipboard.enabled {
+            match SystemClipboard::new() {
+                Ok(backend) => {
+                    let (channel, task) = clipboard::start(backend, clipboard.max_size, None);
+                    tasks.push(task);
+                    Some(channel)
+                }
+                Err(err) => {
+                    error!(
+                        ?err,
+                        "failed to access clipboard, clipboard sharing is disabled"
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
 
-    info!("client app stopped");
+        let (connected_tx, connected_rx) = watch::channel(false);
+
+        // transport client establishes connection with the server and propagate
+        // input events through the channel
+        let transport_client = {
+            let args = TransportClient {
+                server_addr,
+                credentials,
+                codec,
+                wire_format,
+                download_dir,
+                log_keystrokes,
+                metrics: metrics.clone(),
+            };
+            // no files are sent without paths, a closed channel stands in for them
+            let path_rx = path_rx.unwrap_or_else(|| mpsc::channel(1).1);
+            transport_client::start(args, sink_tx, clipboard, path_rx, lock_rx, connected_tx)
+        };
+        let transport_client = match transport_client {
+            Ok(x) => x,
+            Err(err) => {
+                tasks.iter().for_each(JoinHandle::abort);
+                return Err(err);
+            }
+        };
+
+        // input sink receives input events and emulate the input events in its
+        // host machine
+        let input_sink = input_sink::start(sink, sink_rx, lock_tx, pointer, remap);
+
+        let (events_tx, _) = broadcast::channel(16);
+        publish_events(connected_rx.clone(), events_tx.clone());
+
+        Ok(ClientHandle {
+            connected_rx,
+            events_tx,
+            transport_client,
+            input_sink,
+            tasks,
+            metrics,
+        })
+    }
+}
+
+/// What a running client is doing.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ClientStatus {
+    /// If a session with the server is established.
+    pub connected: bool,
+}
 
-    Ok(())
+/// Changes of a client's status.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ClientEvent {
+    Connected,
+    Disconnected,
+}
+
+/// Controls a client started by [ClientBuilder].
+///
+/// Control is switched from the server, the client has no say in it.
+#[derive(Debug)]
+pub struct ClientHandle {
+    connected_rx: watch::Receiver<bool>,
+    events_tx: broadcast::Sender<ClientEvent>,
+    transport_client: JoinHandle<()>,
+    input_sink: JoinHandle<Result<(), Error>>,
+    /// Helper tasks, aborted when the app stops.
+    tasks: Vec<JoinHandle<()>>,
+    metrics: Arc<Metrics>,
+}
+
+impl ClientHandle {
+    pub fn status(&self) -> ClientStatus {
+        ClientStatus {
+            connected: *self.connected_rx.borrow(),
+        }
+    }
+
+    /// Subscribes to status changes from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events_tx.subscribe()
+    }
+
+    /// Renders the client's metrics in Prometheus text format.
+    pub fn metrics(&self) -> String {
+        self.metrics.render()
+    }
+
+    /// Waits for the client to stop by itself, which it does when it gives up
+    /// connecting to the server. Fails if the input sink failed.
+    pub async fn wait(mut self) -> Result<(), Error> {
+        // The input sink channel will be closed when one of the workers,
+        // transport client or the input sink, is stopped, In response to the
+        // channel closed the other worker will stop as well and this join will
+        // resume.
+        let result = try_join!(&mut self.transport_client, &mut self.input_sink);
+        self.stop_tasks();
+        let ((), sink) = result?;
+        sink?;
+
+        info!("client app stopped");
+
+        Ok(())
+    }
+
+    /// Stops the client, it disconnects from the server and the input sink
+    /// stops emulating input.
+    pub async fn shutdown(self) -> Result<(), Error> {
+        self.transport_client.abort();
+        self.stop_tasks();
+        match self.transport_client.await {
+            Err(err) if !err.is_cancelled() => return Err(err.into()),
+            _ => (),
+        }
+        // closing the sink's channel along with the transport client stops it
+        self.input_sink.await??;
+
+        info!("client app stopped");
+
+        Ok(())
+    }
+
+    fn stop_tasks(&self) {
+        self.tasks.iter().for_each(JoinHandle::abort);
+    }
+}
+
+/// Publishes changes of the connection as events, until it stops changing.
+fn publish_events(
+    mut connected_rx: watch::Receiver<bool>,
+    events_tx: broadcast::Sender<ClientEvent>,
+) -> JoinHandle<()> {
+    task::spawn(async move {
+        while connected_rx.changed().await.is_ok() {
+            let event = if *connected_rx.borrow_and_update() {
+                ClientEvent::Connected
+            } else {
+                ClientEvent::Disconnected
+            };
+            // no one may be subscribed
+            events_tx.send(event).ok();
+        }
+    })
 }
 
 /// Run the client application.
 pub async fn run() {
-    init_tracing();
-
     let cfg = Config::get().await.client();
 
-    start_app(cfg).await.unwrap();
+    init_tracing_with(&cfg.log).expect("failed to set up logging");
+
+    // paths of files to send to the server
+    let (path_tx, path_rx) = mpsc::channel(1);
+    file_transfer::read_commands(path_tx);
+
+    start_app(cfg, SystemInputSink, path_rx).await.unwrap();
 }
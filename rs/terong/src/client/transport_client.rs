@@ -1,22 +1,33 @@
+use super::input_sink::SinkCommand;
 use crate::{
+    clipboard::{self, ClipboardChannel},
+    config::WireFormat,
+    file_transfer::FileTransfers,
     log_error,
+    metrics::Metrics,
     transport::{
-        protocol::{ClientMessage, InputEvent, Ping, Pong, ServerMessage},
-        Certificate, PrivateKey, Transport,
+        codec::Codec,
+        frame::TransportError,
+        go_compat::{self, GoMessage, GoTransport},
+        noise::{self, NoiseAuth},
+        protocol::{ClientMessage, InputEvent, LockState, Ping, Pong, ServerMessage},
+        tls::{check_peer_certificate, ExpiryWatcher, TlsFiles, TlsMaterial},
+        AsyncStream, Credentials, Role, Transport,
     },
 };
-use anyhow::Error;
+use anyhow::{Context, Error};
+use futures::future;
 use macross::impl_from;
-use std::{fmt, net::SocketAddr, time::Duration};
+use std::{fmt, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 use tokio::{
     net::TcpStream,
     select,
-    sync::mpsc,
+    sync::{mpsc, watch},
     task::{self, JoinHandle},
     time::{interval_at, sleep, Instant, MissedTickBehavior},
 };
 use tokio_native_tls::native_tls;
-use tracing::{debug, error, info};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
 /// Time it takes before client giving up on connecting to the server.
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
@@ -26,38 +37,225 @@ type ClientTransport = Transport<ServerMessage, ClientMessage>;
 #[derive(Debug, Clone)]
 pub struct TransportClient {
     pub server_addr: SocketAddr,
-    pub tls_certs: Vec<Certificate>,
-    pub tls_key: PrivateKey,
-    pub server_tls_certs: Vec<Certificate>,
+    pub credentials: Credentials,
+    pub codec: Codec,
+    pub wire_format: WireFormat,
+    pub download_dir: Option<PathBuf>,
+    /// Log keys of input events, they're redacted otherwise.
+    pub log_keystrokes: bool,
+    pub metrics: Arc<Metrics>,
 }
 
-pub fn start(args: TransportClient, event_tx: mpsc::Sender<InputEvent>) -> JoinHandle<()> {
-    task::spawn(run_transport(args, event_tx))
+/// Starts connecting to the server, whether a session is established is sent
+/// to `connected_tx`.
+pub fn start(
+    args: TransportClient,
+    sink_tx: mpsc::Sender<SinkCommand>,
+    clipboard: Option<ClipboardChannel>,
+    path_rx: mpsc::Receiver<PathBuf>,
+    lock_rx: watch::Receiver<Option<LockState>>,
+    connected_tx: watch::Sender<bool>,
+) -> Result<JoinHandle<()>, Error> {
+    let TransportClient {
+        server_addr,
+        credentials,
+        codec,
+        wire_format,
+        download_dir,
+        log_keystrokes,
+        metrics,
+    } = args;
+
+    let connector = Connector::new(credentials)?;
+
+    let ctx = SessionContext {
+        sink_tx,
+        clipboard,
+        path_rx,
+        download_dir,
+        lock_rx,
+        connected_tx,
+        log_keystrokes,
+        metrics,
+    };
+
+    Ok(task::spawn(run_transport(
+        server_addr,
+        connector,
+        codec,
+        wire_format,
+        ctx,
+    )))
 }
 
-async fn run_transport(args: TransportClient, event_tx: mpsc::Sender<InputEvent>) {
-    let identity = native_tls::Identity::from_pkcs8(&args.tls_certs[0].0, &args.tls_key.0).unwrap();
-    let server_cert = native_tls::Certificate::from_pem(&args.server_tls_certs[0].0).unwrap();
+fn create_tls_connector(tls: &TlsMaterial) -> Result<tokio_native_tls::TlsConnector, Error> {
+    let identity = native_tls::Identity::from_pkcs8(&tls.certs[0].0, &tls.key.0)?;
+    let server_cert = native_tls::Certificate::from_pem(&tls.peer_certs[0].0)?;
     let tls_connector = native_tls::TlsConnector::builder()
         .identity(identity)
         .disable_built_in_roots(true)
         .add_root_certificate(server_cert)
-        .build()
-        .unwrap()
+        .build()?
         .into();
+    Ok(tls_connector)
+}
+
+/// Secures outgoing connections.
+enum Connector {
+    Tls {
+        tls: TlsFiles,
+        tls_connector: tokio_native_tls::TlsConnector,
+        _expiry: ExpiryWatcher,
+    },
+    Noise(NoiseAuth),
+}
+
+impl Connector {
+    fn new(credentials: Credentials) -> Result<Self, Error> {
+        let connector = match credentials {
+            Credentials::Tls(tls) => {
+                let tls_connector = create_tls_connector(tls.material())?;
+                let _expiry = ExpiryWatcher::start(tls.clone());
+                Connector::Tls {
+                    tls,
+                    tls_connector,
+                    _expiry,
+                }
+            }
+            Credentials::Noise(auth) => Connector::Noise(auth),
+        };
+        Ok(connector)
+    }
+
+    /// Picks up renewed certificates for the next connection.
+    async fn reload(&mut self) {
+        if let Connector::Tls {
+            tls, tls_connector, ..
+        } = self
+        {
+            match tls.reload().await {
+                Ok(true) => match create_tls_connector(tls.material()) {
+                    Ok(x) => {
+                        info!("tls files reloaded");
+                        *tls_connector = x;
+                    }
+                    Err(err) => log_error!(err),
+                },
+                Ok(false) => (),
+                Err(err) => log_error!(err),
+            }
+        }
+    }
+
+    /// Performs handshake with the server.
+    async fn connect(
+        &self,
+        stream: TcpStream,
+        wire_format: WireFormat,
+        codec: Codec,
+    ) -> Result<Connection, Error> {
+        match self {
+            Connector::Tls {
+                tls, tls_connector, ..
+            } => {
+                // the server is expected to present its pinned certificate
+                let server_cert = tls.material().peer_certs[0].info()?;
+                let stream = tls_connector.connect(server_cert.name(), stream).await;
+                let stream = stream.with_context(|| {
+                    format!(
+                        "tls handshake failed, check that certificates are valid and not expired, {}",
+                        tls.status()
+                    )
+                })?;
+                if let Some(cert) = check_peer_certificate(&stream) {
+                    Span::current().record("server_name", cert.name());
+                }
+                info!(status = %tls.status(), "tls handshake completed");
+
+                establish(stream, wire_format, codec).await
+            }
+
+            Connector::Noise(auth) => {
+                let stream = noise::initiate(stream, auth)
+                    .await
+                    .context("noise handshake failed")?;
+                establish(stream, wire_format, codec).await
+            }
+        }
+    }
+}
 
+/// Established connection with the server.
+#[derive(Debug)]
+enum Connection {
+    Native(ClientTransport),
+    Go(GoTransport),
+}
+
+impl Connection {
+    /// Sets if keys of input events are logged, they're redacted otherwise.
+    fn set_log_keystrokes(&mut self, value: bool) {
+        match self {
+            Connection::Native(x) => x.set_log_keystrokes(value),
+            Connection::Go(x) => x.set_log_keystrokes(value),
+        }
+    }
+
+    /// Sets the metrics to count sent bytes in.
+    fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        match self {
+            Connection::Native(x) => x.set_metrics(metrics),
+            Connection::Go(x) => x.set_metrics(metrics),
+        }
+    }
+}
+
+async fn establish(
+    stream: impl AsyncStream + Send + 'static,
+    wire_format: WireFormat,
+    codec: Codec,
+) -> Result<Connection, Error> {
+    if wire_format == WireFormat::Go {
+        return Ok(Connection::Go(GoTransport::new(stream, Default::default())));
+    }
+
+    let mut transport = Transport::new(stream);
+    let hello = transport
+        .hello(Role::Client { codec })
+        .await
+        .context("hello exchange failed")?;
+    if hello.codec != codec {
+        warn!(requested = %codec, negotiated = %hello.codec, "server doesn't support requested codec");
+    }
+    Ok(Connection::Native(transport))
+}
+
+async fn run_transport(
+    server_addr: SocketAddr,
+    mut connector: Connector,
+    codec: Codec,
+    wire_format: WireFormat,
+    mut ctx: SessionContext,
+) {
     let mut retry_count = 0;
 
     loop {
+        connector.reload().await;
+
+        // the server's name is known once it's authenticated
+        let span = info_span!("session", peer_addr = %server_addr, server_name = field::Empty);
         if let Err(err) = connect(
-            &args.server_addr,
-            &event_tx,
+            &server_addr,
+            &mut ctx,
             &mut retry_count,
-            &tls_connector,
+            &connector,
+            wire_format,
+            codec,
         )
+        .instrument(span.clone())
         .await
         {
-            log_error!(err);
+            span.in_scope(|| log_error!(err));
 
             if retry_count >= 5 {
                 info!("giving up after {} retries", retry_count);
@@ -65,6 +263,7 @@ async fn run_transport(args: TransportClient, event_tx: mpsc::Sender<InputEvent>
             }
 
             retry_count += 1;
+            ctx.metrics.reconnect_attempted();
             debug!("retry count incremented to {}", retry_count);
 
             let delay = Duration::from_secs(10);
@@ -104,9 +303,11 @@ impl std::error::Error for ConnectError {
 
 async fn connect(
     server_addr: &SocketAddr,
-    event_tx: &mpsc::Sender<InputEvent>,
+    ctx: &mut SessionContext,
     retry_count: &mut u8,
-    tls_connector: &tokio_native_tls::TlsConnector,
+    connector: &Connector,
+    wire_format: WireFormat,
+    codec: Codec,
 ) -> Result<(), ConnectError> {
     info!(?server_addr, "connecting to server");
 
@@ -126,15 +327,25 @@ async fn connect(
     *retry_count = 0;
     debug!("retry count reset to zero");
 
-    let stream = tls_connector.connect("", stream).await.unwrap();
-    let transport: ClientTransport = Transport::new(stream);
-
-    let session = Session {
-        event_tx,
-        transporter: transport,
-        state: Default::default(),
+    let mut connection = connector.connect(stream, wire_format, codec).await?;
+    connection.set_log_keystrokes(ctx.log_keystrokes);
+    connection.set_metrics(ctx.metrics.clone());
+
+    ctx.connected_tx.send_replace(true);
+    let session = ctx.metrics.session_started();
+    let result = match connection {
+        Connection::Native(transport) => {
+            let session = Session {
+                ctx,
+                transporter: transport,
+                state: Default::default(),
+            };
+            run_session(session).await
+        }
+        Connection::Go(transport) => run_go_session(ctx, transport).await,
     };
-    let result = run_session(session).await;
+    drop(session);
+    ctx.connected_tx.send_replace(false);
 
     info!(?server_addr, "disconnected from server");
 
@@ -143,9 +354,25 @@ async fn connect(
     Ok(())
 }
 
+/// What sessions exchange data with, outlives sessions.
+#[derive(Debug)]
+struct SessionContext {
+    sink_tx: mpsc::Sender<SinkCommand>,
+    clipboard: Option<ClipboardChannel>,
+    /// Paths of files to send to the server.
+    path_rx: mpsc::Receiver<PathBuf>,
+    download_dir: Option<PathBuf>,
+    /// Host's lock state, reported to the server when it changes.
+    lock_rx: watch::Receiver<Option<LockState>>,
+    /// Tells if a session is established.
+    connected_tx: watch::Sender<bool>,
+    log_keystrokes: bool,
+    metrics: Arc<Metrics>,
+}
+
 #[derive(Debug)]
 struct Session<'a> {
-    event_tx: &'a mpsc::Sender<InputEvent>,
+    ctx: &'a mut SessionContext,
     transporter: ClientTransport,
     state: SessionState,
 }
@@ -161,11 +388,31 @@ pub enum SessionState {
 
 async fn run_session(session: Session<'_>) -> Result<(), Error> {
     let Session {
-        event_tx,
+        ctx:
+            SessionContext {
+                sink_tx,
+                clipboard,
+                path_rx,
+                download_dir,
+                lock_rx,
+                metrics,
+                ..
+            },
         transporter: mut transport,
         mut state,
     } = session;
 
+    // report current lock state, later changes are reported as they happen
+    let lock_state = *lock_rx.borrow_and_update();
+    if let Some(lock_state) = lock_state {
+        transport
+            .send_msg(lock_state.into())
+            .await
+            .context("failed to send lock state")?;
+    }
+
+    let mut files = FileTransfers::new(download_dir.clone());
+
     let ping_ticker_interval = Duration::from_secs(15);
     let mut ping_ticker = {
         let mut ticker = interval_at(Instant::now() + ping_ticker_interval, ping_ticker_interval);
@@ -174,6 +421,8 @@ async fn run_session(session: Session<'_>) -> Result<(), Error> {
     };
 
     let mut local_ping_counter = 1;
+    // when the last ping was sent, for measuring round trip time
+    let mut ping_sent_at = None;
 
     loop {
         state = match state {
@@ -189,6 +438,7 @@ async fn run_session(session: Session<'_>) -> Result<(), Error> {
                             match transport.send_msg(msg).await {
                                 Ok(_) => {
                                     debug!("ping sent successfully, incrementing local counter");
+                                    ping_sent_at = Some(Instant::now());
                                     local_ping_counter += 1;
                                     SessionState::Idle
                                 },
@@ -205,14 +455,28 @@ async fn run_session(session: Session<'_>) -> Result<(), Error> {
                         }
                     }
 
-                    Ok(msg) = transport.recv_msg() => {
-                        debug!("received message, {:?}", msg);
-
+                    msg = transport.recv_msg() => {
+                        let msg = match msg {
+                            Ok(x) => x,
+                            Err(TransportError::Eof) => {
+                                info!("terminating session, server closed the connection");
+                                break;
+                            }
+                            Err(err @ TransportError::Decode(_)) => {
+                                // frames are length delimited, the next one can still be read
+                                warn!(?err, "ignoring undecodable message");
+                                continue;
+                            }
+                            Err(err) => return Err(err).context("failed to receive message"),
+                        };
                         let event = match msg {
                             ServerMessage::Event(event) => Some(event),
                             ServerMessage::Pong(Pong { counter })=> {
                                 if counter == local_ping_counter {
                                     debug!("received pong, incrementing local counter, resetting ticker");
+                                    if let Some(sent_at) = ping_sent_at.take() {
+                                        metrics.heartbeat_rtt(sent_at.elapsed());
+                                    }
                                     local_ping_counter += 1;
                                     ping_ticker.reset();
                                     None
@@ -222,6 +486,24 @@ async fn run_session(session: Session<'_>) -> Result<(), Error> {
                                     break;
                                 }
                             },
+                            ServerMessage::Clipboard(content) => {
+                                clipboard::forward_incoming(clipboard.as_ref().map(|x| &x.incoming_tx), content);
+                                None
+                            }
+                            ServerMessage::LockState(state) => {
+                                debug!(?state, "matching server's lock state");
+                                sink_tx.send(SinkCommand::SetLockState(state)).await?;
+                                None
+                            }
+                            ServerMessage::File(msg) => {
+                                if let Some(reply) = files.handle(msg).await {
+                                    transport
+                                        .send_msg(reply.into())
+                                        .await
+                                        .context("failed to send file transfer reply")?;
+                                }
+                                None
+                            }
                         };
 
                         match event {
@@ -229,12 +511,58 @@ async fn run_session(session: Session<'_>) -> Result<(), Error> {
                             None => SessionState::Idle
                         }
                     }
+
+                    content = clipboard::recv_outgoing(clipboard) => {
+                        match transport.send_msg(ClientMessage::Clipboard(content)).await {
+                            Ok(()) => (),
+                            Err(err @ TransportError::Oversized { .. }) => {
+                                warn!(?err, "clipboard content is too large to send");
+                            }
+                            Err(err) => return Err(err).context("failed to send clipboard"),
+                        }
+                        SessionState::Idle
+                    }
+
+                    Ok(()) = lock_rx.changed() => {
+                        let state = *lock_rx.borrow_and_update();
+                        if let Some(state) = state {
+                            debug!(?state, "reporting lock state");
+                            transport
+                                .send_msg(state.into())
+                                .await
+                                .context("failed to send lock state")?;
+                        }
+                        SessionState::Idle
+                    }
+
+                    Some(path) = path_rx.recv() => {
+                        match files.offer(&path).await {
+                            Ok(msg) => transport
+                                .send_msg(msg.into())
+                                .await
+                                .context("failed to send file offer")?,
+                            Err(err) => log_error!(err),
+                        }
+                        SessionState::Idle
+                    }
+
+                    // lowest priority so chunks don't delay relaying input events
+                    _ = future::ready(()), if files.is_sending() => {
+                        if let Some(msg) = files.next_chunk().await {
+                            transport
+                                .send_msg(msg.into())
+                                .await
+                                .context("failed to send file chunk")?;
+                        }
+                        SessionState::Idle
+                    }
                 }
             }
 
             SessionState::EventRelayed { event } => {
                 // propagate event to input sink
-                event_tx.send(event).await?;
+                sink_tx.send(SinkCommand::Event(event)).await?;
+                metrics.event_relayed(&event);
 
                 SessionState::Idle
             }
@@ -243,3 +571,56 @@ async fn run_session(session: Session<'_>) -> Result<(), Error> {
 
     Ok(())
 }
+
+/// The session loop with a Go server.
+async fn run_go_session(ctx: &SessionContext, mut transport: GoTransport) -> Result<(), Error> {
+    let mut ping_ticker = {
+        let mut ticker = interval_at(
+            Instant::now() + go_compat::PING_INTERVAL,
+            go_compat::PING_INTERVAL,
+        );
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker
+    };
+    let ping_deadline = sleep(go_compat::PING_TIMEOUT);
+    tokio::pin!(ping_deadline);
+
+    loop {
+        select! { biased;
+
+            _ = ping_ticker.tick() => {
+                transport
+                    .send_msg(GoMessage::Ping)
+                    .await
+                    .context("failed to send ping")?;
+            }
+
+            _ = &mut ping_deadline => {
+                info!("haven't heard ping from server for {} secs, terminating session", go_compat::PING_TIMEOUT.as_secs());
+                break;
+            }
+
+            msg = transport.recv_msg() => {
+                match msg {
+                    Ok(GoMessage::Ping) => {
+                        ping_deadline.as_mut().reset(Instant::now() + go_compat::PING_TIMEOUT);
+                    }
+                    Ok(GoMessage::Event(event)) => {
+                        ctx.sink_tx.send(SinkCommand::Event(event)).await?;
+                        ctx.metrics.event_relayed(&event);
+                    }
+                    Err(TransportError::Eof) => {
+                        info!("terminating session, server closed the connection");
+                        break;
+                    }
+                    Err(err @ TransportError::Decode(_)) => {
+                        warn!(?err, "ignoring undecodable message");
+                    }
+                    Err(err) => return Err(err).context("failed to receive message"),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
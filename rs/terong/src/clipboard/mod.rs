@@ -0,0 +1,346 @@
+//! Clipboard sharing.
+//!
+//! Local clipboard is checked for changes periodically and when control
+//! switches between machines. Changes are sent to the peer through the
+//! transport, and content received from the peer is written to the local
+//! clipboard.
+
+mod system;
+
+pub use self::system::SystemClipboard;
+
+use crate::transport::{frame::DEFAULT_MAX_FRAME_LEN, protocol::ClipboardContent};
+use anyhow::Error;
+use futures::future;
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::{mpsc, watch},
+    task::{self, JoinHandle},
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, error, warn};
+
+/// Interval the local clipboard is checked for changes at.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Larger content doesn't fit in a frame whatever the peer negotiates, content
+/// that fits this but not the negotiated frame length is skipped when sent.
+const MAX_SIZE: usize = DEFAULT_MAX_FRAME_LEN as _;
+
+/// Access to a clipboard.
+pub trait ClipboardBackend: Send {
+    /// Reads the clipboard, returns `None` if it's empty or holds content
+    /// we can't share.
+    fn get(&mut self) -> Result<Option<ClipboardContent>, Error>;
+
+    fn set(&mut self, content: &ClipboardContent) -> Result<(), Error>;
+}
+
+/// Clipboard that lives in memory, clones share the same content.
+#[cfg(test)]
+#[derive(Clone, Default, Debug)]
+pub struct MemoryClipboard {
+    content: Arc<Mutex<Option<ClipboardContent>>>,
+}
+
+#[cfg(test)]
+impl ClipboardBackend for MemoryClipboard {
+    fn get(&mut self) -> Result<Option<ClipboardContent>, Error> {
+        Ok(self.content.lock().unwrap().clone())
+    }
+
+    fn set(&mut self, content: &ClipboardContent) -> Result<(), Error> {
+        *self.content.lock().unwrap() = Some(content.clone());
+        Ok(())
+    }
+}
+
+/// Channels between the clipboard task and the transport.
+#[derive(Debug)]
+pub struct ClipboardChannel {
+    /// Local clipboard content to send to the peer.
+    pub outgoing_rx: mpsc::Receiver<ClipboardContent>,
+    /// Clipboard content received from the peer.
+    pub incoming_tx: mpsc::Sender<ClipboardContent>,
+}
+
+/// Starts the clipboard task.
+///
+/// `switch_rx` notifies when control switches between machines, if this host
+/// knows about it.
+pub fn start(
+    backend: impl ClipboardBackend + 'static,
+    max_size: usize,
+    switch_rx: Option<watch::Receiver<bool>>,
+) -> (ClipboardChannel, JoinHandle<()>) {
+    let (outgoing_tx, outgoing_rx) = mpsc::channel(1);
+    let (incoming_tx, incoming_rx) = mpsc::channel(1);
+
+    let sync = ClipboardSync::new(backend, max_size, POLL_INTERVAL);
+    let task = task::spawn(run(sync, outgoing_tx, incoming_rx, switch_rx));
+
+    let channel = ClipboardChannel {
+        outgoing_rx,
+        incoming_tx,
+    };
+    (channel, task)
+}
+
+/// Waits for local clipboard content to send to the peer, never completes if
+/// clipboard sharing is disabled or stopped.
+pub async fn recv_outgoing(channel: &mut Option<ClipboardChannel>) -> ClipboardContent {
+    match channel {
+        Some(x) => match x.outgoing_rx.recv().await {
+            Some(content) => content,
+            None => {
+                channel.take();
+                future::pending().await
+            }
+        },
+        None => future::pending().await,
+    }
+}
+
+/// Hands content received from the peer to the clipboard task.
+///
+/// Doesn't wait if the task is busy, it may be waiting on the transport.
+pub fn forward_incoming(tx: Option<&mpsc::Sender<ClipboardContent>>, content: ClipboardContent) {
+    match tx {
+        Some(tx) => {
+            if tx.try_send(content).is_err() {
+                warn!("clipboard is busy, dropping content from peer");
+            }
+        }
+        None => debug!("clipboard sharing is disabled, dropping content from peer"),
+    }
+}
+
+struct ClipboardSync {
+    backend: Arc<Mutex<dyn ClipboardBackend>>,
+    max_size: usize,
+    poll_interval: Duration,
+    /// Last content we've seen in or written to the local clipboard.
+    last: Option<ClipboardContent>,
+}
+
+impl ClipboardSync {
+    fn new(
+        backend: impl ClipboardBackend + 'static,
+        max_size: usize,
+        poll_interval: Duration,
+    ) -> Self {
+        if max_size > MAX_SIZE {
+            warn!(
+                max_size,
+                "clipboard max_size is larger than a frame, only content up to {} bytes is shared",
+                MAX_SIZE
+            );
+        }
+        Self {
+            backend: Arc::new(Mutex::new(backend)),
+            max_size: max_size.min(MAX_SIZE),
+            poll_interval,
+            last: None,
+        }
+    }
+
+    /// Reads the local clipboard without blocking the runtime.
+    async fn read(&self) -> Option<ClipboardContent> {
+        let backend = self.backend.clone();
+        match task::spawn_blocking(move || backend.lock().unwrap().get()).await {
+            Ok(Ok(x)) => x,
+            Ok(Err(err)) => {
+                debug!(?err, "failed to read clipboard");
+                None
+            }
+            Err(err) => {
+                error!(?err, "clipboard read task failed");
+                None
+            }
+        }
+    }
+
+    /// Returns local clipboard content to send to the peer if it has changed,
+    /// or regardless if `force` is true.
+    async fn poll(&mut self, force: bool) -> Option<ClipboardContent> {
+        let content = self.read().await?;
+
+        if !force && self.last.as_ref() == Some(&content) {
+            return None;
+        }
+        self.last = Some(content.clone());
+
+        if content.size() > self.max_size {
+            warn!(
+                size = content.size(),
+                max_size = self.max_size,
+                "clipboard content is too large to share"
+            );
+            return None;
+        }
+
+        Some(content)
+    }
+
+    /// Writes content received from the peer to the local clipboard.
+    async fn apply(&mut self, content: ClipboardContent) {
+        if content.size() > self.max_size {
+            warn!(
+                size = content.size(),
+                max_size = self.max_size,
+                "ignoring too large clipboard content from peer"
+            );
+            return;
+        }
+
+        let backend = self.backend.clone();
+        let content_ = content.clone();
+        match task::spawn_blocking(move || backend.lock().unwrap().set(&content_)).await {
+            Ok(Ok(())) => {
+                debug!(?content, "clipboard updated");
+                // so it's not sent back to the peer
+                self.last = Some(content);
+            }
+            Ok(Err(err)) => warn!(?err, "failed to write clipboard"),
+            Err(err) => error!(?err, "clipboard write task failed"),
+        }
+    }
+}
+
+async fn run(
+    mut sync: ClipboardSync,
+    outgoing_tx: mpsc::Sender<ClipboardContent>,
+    mut incoming_rx: mpsc::Receiver<ClipboardContent>,
+    mut switch_rx: Option<watch::Receiver<bool>>,
+) {
+    // content present at start was not copied in this session, don't share it
+    sync.last = sync.read().await;
+
+    let mut ticker = interval(sync.poll_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let switched = async {
+            match &mut switch_rx {
+                Some(x) => x.changed().await,
+                None => future::pending().await,
+            }
+        };
+
+        let content = select! {
+            _ = ticker.tick() => sync.poll(false).await,
+
+            Ok(()) = switched => {
+                debug!("control switched, sharing clipboard");
+                sync.poll(true).await
+            }
+
+            content = incoming_rx.recv() => {
+                match content {
+                    Some(content) => sync.apply(content).await,
+                    None => break,
+                }
+                None
+            }
+        };
+
+        if let Some(content) = content {
+            debug!(?content, "sharing clipboard");
+            if outgoing_tx.send(content).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(x: &str) -> ClipboardContent {
+        ClipboardContent::Text(x.to_owned())
+    }
+
+    fn start_memory(
+        max_size: usize,
+        switch_rx: Option<watch::Receiver<bool>>,
+    ) -> (MemoryClipboard, ClipboardChannel) {
+        let backend = MemoryClipboard::default();
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(1);
+        let (incoming_tx, incoming_rx) = mpsc::channel(1);
+        let sync = ClipboardSync::new(backend.clone(), max_size, Duration::from_millis(10));
+        task::spawn(run(sync, outgoing_tx, incoming_rx, switch_rx));
+        let channel = ClipboardChannel {
+            outgoing_rx,
+            incoming_tx,
+        };
+        (backend, channel)
+    }
+
+    async fn recv(channel: &mut ClipboardChannel) -> Option<ClipboardContent> {
+        tokio::time::timeout(Duration::from_millis(200), channel.outgoing_rx.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn test_sends_on_change() {
+        let (mut backend, mut channel) = start_memory(1024, None);
+        assert_eq!(recv(&mut channel).await, None);
+
+        backend.set(&text("hello")).unwrap();
+        assert_eq!(recv(&mut channel).await, Some(text("hello")));
+        assert_eq!(recv(&mut channel).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_incoming_is_not_sent_back() {
+        let (backend, mut channel) = start_memory(1024, None);
+        assert_eq!(recv(&mut channel).await, None);
+
+        channel.incoming_tx.send(text("from peer")).await.unwrap();
+        assert_eq!(recv(&mut channel).await, None);
+        assert_eq!(backend.clone().get().unwrap(), Some(text("from peer")));
+    }
+
+    #[tokio::test]
+    async fn test_size_limit() {
+        let (mut backend, mut channel) = start_memory(4, None);
+        assert_eq!(recv(&mut channel).await, None);
+
+        backend.set(&text("too large")).unwrap();
+        assert_eq!(recv(&mut channel).await, None);
+
+        channel.incoming_tx.send(text("too large")).await.unwrap();
+        backend.set(&text("ok")).unwrap();
+        assert_eq!(recv(&mut channel).await, Some(text("ok")));
+        assert_eq!(backend.get().unwrap(), Some(text("ok")));
+
+        // larger than a frame
+        let (mut backend, mut channel) = start_memory(usize::MAX, None);
+        assert_eq!(recv(&mut channel).await, None);
+        let large = text(&"x".repeat(MAX_SIZE + 1));
+        backend.set(&large).unwrap();
+        assert_eq!(recv(&mut channel).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_sends_on_switch() {
+        let (switch_tx, switch_rx) = watch::channel(false);
+        let (mut backend, mut channel) = start_memory(1024, Some(switch_rx));
+        assert_eq!(recv(&mut channel).await, None);
+
+        backend.set(&text("hello")).unwrap();
+        assert_eq!(recv(&mut channel).await, Some(text("hello")));
+
+        // unchanged, but sent again because control switched
+        switch_tx.send_replace(true);
+        assert_eq!(recv(&mut channel).await, Some(text("hello")));
+    }
+}
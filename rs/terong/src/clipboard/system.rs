@@ -0,0 +1,162 @@
+use super::ClipboardBackend;
+use crate::transport::protocol::ClipboardContent;
+use anyhow::{bail, Error};
+use arboard::{Clipboard, ImageData};
+use std::{
+    borrow::Cow,
+    collections::hash_map::DefaultHasher,
+    fmt,
+    hash::{Hash, Hasher},
+    io::Cursor,
+};
+
+/// The host's clipboard.
+pub struct SystemClipboard {
+    clipboard: Clipboard,
+    /// Hash of the pixels of the last image read and its PNG, so an unchanged
+    /// image isn't encoded on every poll.
+    last_image: Option<(u64, Vec<u8>)>,
+}
+
+impl SystemClipboard {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self {
+            clipboard: Clipboard::new()?,
+            last_image: None,
+        })
+    }
+}
+
+impl fmt::Debug for SystemClipboard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SystemClipboard").finish()
+    }
+}
+
+impl ClipboardBackend for SystemClipboard {
+    fn get(&mut self) -> Result<Option<ClipboardContent>, Error> {
+        match self.clipboard.get_text() {
+            Ok(x) => return Ok(Some(ClipboardContent::Text(x))),
+            Err(arboard::Error::ContentNotAvailable) => (),
+            Err(err) => return Err(err.into()),
+        }
+        match self.clipboard.get_image() {
+            Ok(x) => {
+                let hash = hash_image(&x);
+                let png = match &self.last_image {
+                    Some((last, png)) if *last == hash => png.clone(),
+                    _ => {
+                        let png = encode_png(&x)?;
+                        self.last_image = Some((hash, png.clone()));
+                        png
+                    }
+                };
+                Ok(Some(ClipboardContent::Image(png)))
+            }
+            Err(arboard::Error::ContentNotAvailable) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn set(&mut self, content: &ClipboardContent) -> Result<(), Error> {
+        match content {
+            ClipboardContent::Text(x) => self.clipboard.set_text(x.as_str())?,
+            ClipboardContent::Image(x) => self.clipboard.set_image(decode_png(x)?)?,
+        }
+        Ok(())
+    }
+}
+
+fn hash_image(image: &ImageData) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (image.width, image.height, &image.bytes[..]).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Encodes RGBA image as PNG.
+fn encode_png(image: &ImageData) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buf, image.width as _, image.height as _);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&image.bytes)?;
+    Ok(buf)
+}
+
+/// Decodes PNG into RGBA image.
+fn decode_png(bytes: &[u8]) -> Result<ImageData<'static>, Error> {
+    let mut decoder = png::Decoder::new(Cursor::new(bytes));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
+
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => buf,
+        png::ColorType::Rgb => buf
+            .chunks_exact(3)
+            .flat_map(|x| [x[0], x[1], x[2], 0xFF])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .flat_map(|x| [x[0], x[0], x[0], x[1]])
+            .collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|&x| [x, x, x, 0xFF]).collect(),
+        x => bail!("unsupported png color type {:?}", x),
+    };
+
+    Ok(ImageData {
+        width: info.width as _,
+        height: info.height as _,
+        bytes: Cow::Owned(rgba),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png_roundtrip() {
+        let image = ImageData {
+            width: 2,
+            height: 1,
+            bytes: Cow::Owned(vec![255, 0, 0, 255, 0, 0, 255, 128]),
+        };
+        let png = encode_png(&image).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+
+        let decoded = decode_png(&png).unwrap();
+        assert_eq!((decoded.width, decoded.height), (2, 1));
+        assert_eq!(decoded.bytes, image.bytes);
+    }
+
+    #[test]
+    fn test_hash_image() {
+        let image = |bytes: Vec<u8>| ImageData {
+            width: 1,
+            height: 1,
+            bytes: Cow::Owned(bytes),
+        };
+        let a = hash_image(&image(vec![1, 2, 3, 4]));
+        assert_eq!(a, hash_image(&image(vec![1, 2, 3, 4])));
+        assert_ne!(a, hash_image(&image(vec![1, 2, 3, 5])));
+    }
+
+    #[test]
+    fn test_decode_rgb_png() {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, 1, 1);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&[1, 2, 3])
+            .unwrap();
+
+        let decoded = decode_png(&png).unwrap();
+        assert_eq!(&decoded.bytes[..], &[1, 2, 3, 255]);
+    }
+}
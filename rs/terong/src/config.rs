@@ -3,17 +3,163 @@
 use crate::{
     client::config::ClientConfig,
     server::config::ServerConfig,
-    transport::{Certificate, PrivateKey},
+    transport::{noise::NoiseAuth, tls::TlsFiles, Certificate, Credentials, PrivateKey},
 };
-use anyhow::Error;
+use anyhow::{anyhow, Context, Error};
 use serde::Deserialize;
-use std::path::{Path, PathBuf};
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 use tokio::{
     fs::{self, File},
     io::AsyncReadExt,
 };
 use tracing::{debug, info};
 
+/// Secure channel between server and client.
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SecureChannel {
+    /// TLS with X.509 certificates.
+    #[default]
+    Tls,
+    /// Noise protocol with static keys or a pre-shared key.
+    Noise,
+}
+
+/// Wire format spoken with the peer.
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    /// Ours.
+    #[default]
+    Native,
+    /// The Go implementation's. Only supports TLS channel.
+    Go,
+    /// Detect from the client, server only.
+    Auto,
+}
+
+impl WireFormat {
+    /// Checks that the wire format can be used over the channel.
+    pub fn check(self, channel: SecureChannel) -> Result<(), Error> {
+        if self != WireFormat::Native && channel != SecureChannel::Tls {
+            return Err(anyhow!("{:?} wire format requires tls channel", self));
+        }
+        Ok(())
+    }
+}
+
+/// Noise credentials, base64 encoded.
+///
+/// Either `private_key` and `peer_public_key`, or `psk` is required.
+#[derive(Clone, Deserialize)]
+pub struct NoiseConfig {
+    pub private_key: Option<String>,
+    pub peer_public_key: Option<String>,
+    pub psk: Option<String>,
+}
+
+impl fmt::Debug for NoiseConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // keep secrets out of logs
+        f.debug_struct("NoiseConfig")
+            .field("peer_public_key", &self.peer_public_key)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Clipboard sharing settings.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct ClipboardConfig {
+    pub enabled: bool,
+    /// Content larger than this, in bytes, is not shared. Content larger than
+    /// a frame, 1 MiB, is never shared.
+    pub max_size: usize,
+}
+
+impl Default for ClipboardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_size: 512 * 1024,
+        }
+    }
+}
+
+/// Local HTTP endpoint serving metrics in Prometheus text format.
+#[derive(Clone, Deserialize, Debug)]
+pub struct MetricsConfig {
+    pub addr: SocketAddr,
+}
+
+/// Where and how logs are written.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Filter directives, e.g. `"info"` or `"info,terong=debug"`. `RUST_LOG`
+    /// takes precedence when it's set.
+    pub level: String,
+    pub format: LogFormat,
+    /// Logs are written to this file instead of stdout if set.
+    pub file: Option<PathBuf>,
+    pub rotation: RotationConfig,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_owned(),
+            format: Default::default(),
+            file: None,
+            rotation: Default::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// A JSON object per line.
+    Json,
+}
+
+/// When the log file is rotated.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct RotationConfig {
+    /// Rotate before the file grows past this many bytes.
+    pub max_size: Option<u64>,
+    pub period: RotationPeriod,
+    /// Rotated files kept, `<file>.1` is the most recent.
+    pub max_files: usize,
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            period: Default::default(),
+            max_files: 5,
+        }
+    }
+}
+
+/// Rotate when the hour or day, in UTC, changes.
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationPeriod {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
 /// Data structure representing config file scheme.
 #[derive(Clone, Deserialize, Debug)]
 pub struct Config {
@@ -51,6 +197,17 @@ impl Config {
         config
     }
 
+    /// Reads the server config from the config file at the path.
+    pub async fn read_server(path: &Path) -> Result<ServerConfig, Error> {
+        let mut file = File::open(path)
+            .await
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let config = Self::from_file(&mut file).await?;
+        config
+            .server
+            .ok_or_else(|| anyhow!("missing server config in {}", path.display()))
+    }
+
     async fn from_file(file: &mut File) -> Result<Self, Error> {
         let mut buf = String::new();
         file.read_to_string(&mut buf).await?;
@@ -88,6 +245,29 @@ pub async fn read_private_key(path: &Path) -> Result<PrivateKey, Error> {
     Ok(buf.into())
 }
 
+/// Reads credentials for the secure channel.
+pub async fn read_credentials(
+    channel: SecureChannel,
+    tls_cert_path: Option<PathBuf>,
+    tls_key_path: Option<PathBuf>,
+    peer_tls_cert_path: Option<PathBuf>,
+    noise: Option<&NoiseConfig>,
+) -> Result<Credentials, Error> {
+    let credentials = match channel {
+        SecureChannel::Tls => match (tls_cert_path, tls_key_path, peer_tls_cert_path) {
+            (Some(cert), Some(key), Some(peer_cert)) => {
+                Credentials::Tls(TlsFiles::load(cert, key, peer_cert).await?)
+            }
+            _ => return Err(anyhow!("missing tls certificate or key paths")),
+        },
+        SecureChannel::Noise => {
+            let noise = noise.ok_or_else(|| anyhow!("missing noise config"))?;
+            Credentials::Noise(NoiseAuth::from_config(noise)?)
+        }
+    };
+    Ok(credentials)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
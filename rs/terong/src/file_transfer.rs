@@ -0,0 +1,703 @@
+//! File transfer over a session.
+//!
+//! A transfer is started with `send <path>` typed into the terminal of either
+//! app. The file is offered to the peer, which accepts it if it has a download
+//! directory configured. Chunks are then sent when the session has nothing
+//! else to do, so they don't delay input events, followed by the file's
+//! checksum. The receiver writes chunks to a partial file and moves it into
+//! place once the checksum matches, on a task of its own so the disk doesn't
+//! hold the session up.
+//!
+//! Transfers live as long as the session, partial files are removed when the
+//! session ends.
+
+use crate::transport::protocol::{FileChunk, FileTransfer};
+use anyhow::{bail, Context, Error};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+use tracing::{debug, info, warn};
+
+/// Size of file chunks, kept small so a chunk doesn't hold input events back
+/// for long.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Offers of larger files are rejected.
+const MAX_FILE_SIZE: u64 = 4 << 30;
+
+/// Chunks queued for the writer, the session only waits for the disk once
+/// it falls this far behind.
+const WRITE_QUEUE_LEN: usize = 64;
+
+/// Device names Windows reserves, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Reads commands from stdin and sends paths of files to transfer to the
+/// channel.
+pub fn read_commands(path_tx: mpsc::Sender<PathBuf>) -> JoinHandle<()> {
+    task::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match parse_command(&line) {
+                Ok(Some(path)) => {
+                    if path_tx.send(path).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => (),
+                Err(err) => warn!("{}", err),
+            }
+        }
+        debug!("stopped reading commands");
+    })
+}
+
+fn parse_command(line: &str) -> Result<Option<PathBuf>, Error> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let (cmd, arg) = line.split_once(' ').unwrap_or((line, ""));
+    match cmd {
+        "send" if !arg.trim().is_empty() => Ok(Some(arg.trim().into())),
+        "send" => bail!("usage: send <path>"),
+        _ => bail!("unknown command `{}`, usage: send <path>", cmd),
+    }
+}
+
+/// File we're sending.
+#[derive(Debug)]
+struct Outgoing {
+    id: u32,
+    path: PathBuf,
+    file: File,
+    hasher: Sha256,
+}
+
+/// File we're receiving, the writer writes it.
+#[derive(Debug)]
+struct Incoming {
+    size: u64,
+    received: u64,
+}
+
+/// File the writer is writing.
+#[derive(Debug)]
+struct Writing {
+    /// Where the file is moved once complete.
+    path: PathBuf,
+    hasher: Sha256,
+    file: File,
+    // declared after file so the file is closed before it's removed
+    part: PartFile,
+}
+
+/// Disk work on received files, done by the writer.
+#[derive(Debug)]
+enum WriteCommand {
+    Start {
+        id: u32,
+        writing: Box<Writing>,
+    },
+    Chunk(FileChunk),
+    /// Verifies the file and moves it into place.
+    Complete {
+        id: u32,
+        sha256: [u8; 32],
+    },
+    /// Drops the file along with its partial file.
+    Remove {
+        id: u32,
+    },
+}
+
+/// Removes the partial file when dropped, unless it has been moved into place.
+#[derive(Debug)]
+struct PartFile(PathBuf);
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
+
+/// File transfers of a session.
+#[derive(Debug)]
+pub struct FileTransfers {
+    /// Where received files are saved, offers are rejected if `None`.
+    download_dir: Option<PathBuf>,
+    next_id: u32,
+    /// Offered to the peer, waiting for reply.
+    offered: HashMap<u32, Outgoing>,
+    /// Accepted by the peer, sent one at a time in order.
+    sending: VecDeque<Outgoing>,
+    receiving: HashMap<u32, Incoming>,
+    write_tx: mpsc::Sender<WriteCommand>,
+    /// Files the writer failed to write, by id.
+    failed_rx: mpsc::UnboundedReceiver<u32>,
+    failed: HashSet<u32>,
+}
+
+impl FileTransfers {
+    pub fn new(download_dir: Option<PathBuf>) -> Self {
+        let (write_tx, write_rx) = mpsc::channel(WRITE_QUEUE_LEN);
+        let (failed_tx, failed_rx) = mpsc::unbounded_channel();
+        task::spawn(write_files(write_rx, failed_tx));
+        Self {
+            download_dir,
+            next_id: 1,
+            offered: Default::default(),
+            sending: Default::default(),
+            receiving: Default::default(),
+            write_tx,
+            failed_rx,
+            failed: Default::default(),
+        }
+    }
+
+    /// Opens the file and returns an offer for it to send to the peer.
+    pub async fn offer(&mut self, path: &Path) -> Result<FileTransfer, Error> {
+        let file = File::open(path)
+            .await
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let metadata = file.metadata().await?;
+        if !metadata.is_file() {
+            bail!("{} is not a file", path.display());
+        }
+        let name = match path.file_name() {
+            Some(x) => x.to_string_lossy().into_owned(),
+            None => bail!("{} has no file name", path.display()),
+        };
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        info!(id, path = %path.display(), size = metadata.len(), "offering file");
+        self.offered.insert(
+            id,
+            Outgoing {
+                id,
+                path: path.to_owned(),
+                file,
+                hasher: Sha256::new(),
+            },
+        );
+
+        Ok(FileTransfer::Offer {
+            id,
+            name,
+            size: metadata.len(),
+        })
+    }
+
+    /// Returns true if there are chunks to send.
+    pub fn is_sending(&self) -> bool {
+        !self.sending.is_empty()
+    }
+
+    /// Returns the next message of the file being sent.
+    pub async fn next_chunk(&mut self) -> Option<FileTransfer> {
+        let outgoing = self.sending.front_mut()?;
+        let id = outgoing.id;
+
+        let mut data = vec![0; CHUNK_SIZE];
+        match outgoing.file.read(&mut data).await {
+            Ok(0) => {
+                let outgoing = self.sending.pop_front().unwrap();
+                info!(id, path = %outgoing.path.display(), "file sent");
+                Some(FileTransfer::Complete {
+                    id,
+                    sha256: outgoing.hasher.finalize().into(),
+                })
+            }
+            Ok(n) => {
+                data.truncate(n);
+                outgoing.hasher.update(&data);
+                Some(FileTransfer::Chunk(FileChunk { id, data }))
+            }
+            Err(err) => {
+                let outgoing = self.sending.pop_front().unwrap();
+                warn!(id, path = %outgoing.path.display(), ?err, "failed to read file");
+                Some(FileTransfer::Cancel {
+                    id,
+                    reason: "failed to read file".to_owned(),
+                })
+            }
+        }
+    }
+
+    /// Handles a message from the peer, returns the reply to send if any.
+    pub async fn handle(&mut self, msg: FileTransfer) -> Option<FileTransfer> {
+        match msg {
+            FileTransfer::Offer { id, name, size } => {
+                // replacing the transfer would remove its partial file
+                if self.receiving.contains_key(&id) {
+                    warn!(
+                        id,
+                        name, "ignoring offer reusing the id of a file being received"
+                    );
+                    return None;
+                }
+                Some(self.receive(id, &name, size).await)
+            }
+
+            FileTransfer::Accept { id } => {
+                match self.offered.remove(&id) {
+                    Some(outgoing) => {
+                        info!(id, "peer accepted file, sending");
+                        self.sending.push_back(outgoing);
+                    }
+                    None => debug!(id, "ignoring accept of unknown transfer"),
+                }
+                None
+            }
+
+            FileTransfer::Reject { id, reason } => {
+                let offered = self.offered.remove(&id);
+                let sending = self.sending.iter().position(|x| x.id == id);
+                if offered.is_some() || sending.is_some() {
+                    warn!(id, reason, "peer rejected file");
+                }
+                if let Some(i) = sending {
+                    self.sending.remove(i);
+                }
+                None
+            }
+
+            FileTransfer::Chunk(FileChunk { id, data }) => {
+                let incoming = match self.receiving.get_mut(&id) {
+                    Some(x) => x,
+                    None => {
+                        debug!(id, "ignoring chunk of unknown transfer");
+                        return None;
+                    }
+                };
+                incoming.received += data.len() as u64;
+                if incoming.received > incoming.size {
+                    return Some(self.abort(id, "file is larger than offered").await);
+                }
+                while let Ok(failed) = self.failed_rx.try_recv() {
+                    self.failed.insert(failed);
+                }
+                if self.failed.contains(&id) {
+                    return Some(self.abort(id, "failed to write file").await);
+                }
+                self.write(WriteCommand::Chunk(FileChunk { id, data }))
+                    .await;
+                None
+            }
+
+            FileTransfer::Complete { id, sha256 } => {
+                let incoming = match self.receiving.remove(&id) {
+                    Some(x) => x,
+                    None => {
+                        debug!(id, "ignoring completion of unknown transfer");
+                        return None;
+                    }
+                };
+                self.failed.remove(&id);
+                if incoming.received != incoming.size {
+                    warn!(
+                        id,
+                        "failed to receive file, received {} bytes, expecting {}",
+                        incoming.received,
+                        incoming.size
+                    );
+                    self.write(WriteCommand::Remove { id }).await;
+                    return None;
+                }
+                self.write(WriteCommand::Complete { id, sha256 }).await;
+                None
+            }
+
+            FileTransfer::Cancel { id, reason } => {
+                if self.receiving.remove(&id).is_some() {
+                    warn!(id, reason, "peer cancelled file transfer");
+                    self.failed.remove(&id);
+                    self.write(WriteCommand::Remove { id }).await;
+                }
+                None
+            }
+        }
+    }
+
+    /// Prepares to receive an offered file, returns the reply to the offer.
+    async fn receive(&mut self, id: u32, name: &str, size: u64) -> FileTransfer {
+        let reject = |reason: &str| FileTransfer::Reject {
+            id,
+            reason: reason.to_owned(),
+        };
+
+        let dir = match &self.download_dir {
+            Some(x) => x,
+            None => {
+                info!(
+                    id,
+                    name, "rejecting file, download directory is not configured"
+                );
+                return reject("file transfer is disabled");
+            }
+        };
+        if size > MAX_FILE_SIZE {
+            warn!(
+                id,
+                name, size, "rejecting file larger than {} bytes", MAX_FILE_SIZE
+            );
+            return reject("file is too large");
+        }
+        let name = match sanitize_name(name) {
+            Some(x) => x,
+            None => {
+                warn!(id, name, "rejecting file with invalid name");
+                return reject("invalid file name");
+            }
+        };
+
+        let part = PartFile(dir.join(format!(".{}.{}.part", name, id)));
+        let file = match File::create(&part.0).await {
+            Ok(x) => x,
+            Err(err) => {
+                warn!(id, ?err, "failed to create file");
+                return reject("failed to create file");
+            }
+        };
+
+        info!(id, name, size, "receiving file");
+        let writing = Box::new(Writing {
+            path: dir.join(name),
+            hasher: Sha256::new(),
+            file,
+            part,
+        });
+        self.write(WriteCommand::Start { id, writing }).await;
+        self.receiving.insert(id, Incoming { size, received: 0 });
+        FileTransfer::Accept { id }
+    }
+
+    /// Drops a file being received, returns the reply to tell the sender.
+    async fn abort(&mut self, id: u32, reason: &str) -> FileTransfer {
+        self.receiving.remove(&id);
+        self.failed.remove(&id);
+        self.write(WriteCommand::Remove { id }).await;
+        FileTransfer::Reject {
+            id,
+            reason: reason.to_owned(),
+        }
+    }
+
+    /// Queues disk work for the writer.
+    async fn write(&self, cmd: WriteCommand) {
+        // the writer only stops once the transfers are dropped
+        self.write_tx.send(cmd).await.ok();
+    }
+}
+
+/// Writes received files until the transfers are dropped, reporting the ids
+/// of files that fail to be written.
+async fn write_files(
+    mut write_rx: mpsc::Receiver<WriteCommand>,
+    failed_tx: mpsc::UnboundedSender<u32>,
+) {
+    let mut files: HashMap<u32, Writing> = HashMap::new();
+    while let Some(cmd) = write_rx.recv().await {
+        match cmd {
+            WriteCommand::Start { id, writing } => {
+                files.insert(id, *writing);
+            }
+            WriteCommand::Chunk(FileChunk { id, data }) => {
+                let writing = match files.get_mut(&id) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                writing.hasher.update(&data);
+                if let Err(err) = writing.file.write_all(&data).await {
+                    warn!(id, ?err, "failed to write file");
+                    files.remove(&id);
+                    failed_tx.send(id).ok();
+                }
+            }
+            WriteCommand::Complete { id, sha256 } => {
+                let writing = match files.remove(&id) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                match complete(writing, sha256).await {
+                    Ok(path) => info!(id, path = %path.display(), "file received"),
+                    Err(err) => warn!(id, ?err, "failed to receive file"),
+                }
+            }
+            WriteCommand::Remove { id } => {
+                files.remove(&id);
+            }
+        }
+    }
+    // partial files of unfinished transfers are removed as they're dropped
+}
+
+/// Verifies the received file and moves it into place.
+async fn complete(writing: Writing, sha256: [u8; 32]) -> Result<PathBuf, Error> {
+    let Writing {
+        path,
+        hasher,
+        mut file,
+        part,
+    } = writing;
+
+    if hasher.finalize()[..] != sha256 {
+        bail!("checksum mismatch");
+    }
+
+    file.flush().await?;
+    file.sync_all().await?;
+    drop(file);
+
+    let path = unique_path(path);
+    tokio::fs::rename(&part.0, &path).await?;
+    Ok(path)
+}
+
+/// Returns the name if it's a plain file name, and not a device on Windows.
+fn sanitize_name(name: &str) -> Option<&str> {
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    let reserved = RESERVED_NAMES.iter().any(|x| x.eq_ignore_ascii_case(stem));
+    let valid = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', ':', '\0'])
+        && !reserved;
+    valid.then_some(name)
+}
+
+/// Appends a number to the file name if the path already exists.
+fn unique_path(path: PathBuf) -> PathBuf {
+    if !path.exists() {
+        return path;
+    }
+    let stem = path
+        .file_stem()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|x| format!(".{}", x.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, ext)))
+        .find(|x| !x.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use tokio::time::sleep;
+
+    /// Waits for the writer to get the files into the expected state.
+    async fn wait_until(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "files weren't written in time");
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Runs a transfer between two sides until nothing is left to send.
+    async fn transfer(sender: &mut FileTransfers, receiver: &mut FileTransfers, path: &Path) {
+        let offer = sender.offer(path).await.unwrap();
+        let reply = receiver.handle(offer).await.unwrap();
+        assert!(sender.handle(reply).await.is_none());
+        while let Some(msg) = sender.next_chunk().await {
+            if let Some(reply) = receiver.handle(msg).await {
+                sender.handle(reply).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 7).map(|x| x as u8).collect();
+        let path = src.path().join("hello.bin");
+        fs::write(&path, &content).unwrap();
+
+        let mut sender = FileTransfers::new(None);
+        let mut receiver = FileTransfers::new(Some(dst.path().to_owned()));
+        transfer(&mut sender, &mut receiver, &path).await;
+        transfer(&mut sender, &mut receiver, &path).await;
+
+        // files are written in order
+        wait_until(|| dst.path().join("hello (1).bin").exists()).await;
+        assert_eq!(fs::read(dst.path().join("hello.bin")).unwrap(), content);
+        assert_eq!(fs::read(dst.path().join("hello (1).bin")).unwrap(), content);
+        assert_eq!(fs::read_dir(dst.path()).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_without_download_dir() {
+        let src = tempfile::tempdir().unwrap();
+        let path = src.path().join("hello.txt");
+        fs::write(&path, "hello").unwrap();
+
+        let mut sender = FileTransfers::new(None);
+        let mut receiver = FileTransfers::new(None);
+        let offer = sender.offer(&path).await.unwrap();
+        let reply = receiver.handle(offer).await.unwrap();
+        assert!(matches!(reply, FileTransfer::Reject { id: 1, .. }));
+        sender.handle(reply).await;
+        assert!(sender.offered.is_empty());
+        assert!(!sender.is_sending());
+    }
+
+    #[tokio::test]
+    async fn test_checksum_mismatch() {
+        let dst = tempfile::tempdir().unwrap();
+        let mut receiver = FileTransfers::new(Some(dst.path().to_owned()));
+
+        let offer = FileTransfer::Offer {
+            id: 1,
+            name: "hello.txt".to_owned(),
+            size: 5,
+        };
+        assert_eq!(
+            receiver.handle(offer).await,
+            Some(FileTransfer::Accept { id: 1 })
+        );
+        let chunk = FileTransfer::Chunk(FileChunk {
+            id: 1,
+            data: b"hello".to_vec(),
+        });
+        assert!(receiver.handle(chunk).await.is_none());
+        let complete = FileTransfer::Complete {
+            id: 1,
+            sha256: [0; 32],
+        };
+        assert!(receiver.handle(complete).await.is_none());
+
+        // neither the file nor the partial file is left
+        wait_until(|| fs::read_dir(dst.path()).unwrap().count() == 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_write_failure_rejects_file() {
+        let dst = tempfile::tempdir().unwrap();
+        let mut receiver = FileTransfers::new(Some(dst.path().to_owned()));
+
+        // files opened for reading fail to be written
+        let part = PartFile(dst.path().join(".hello.txt.1.part"));
+        fs::write(&part.0, "").unwrap();
+        let writing = Box::new(Writing {
+            path: dst.path().join("hello.txt"),
+            hasher: Sha256::new(),
+            file: File::open(&part.0).await.unwrap(),
+            part,
+        });
+        receiver.write(WriteCommand::Start { id: 1, writing }).await;
+        receiver.receiving.insert(
+            1,
+            Incoming {
+                size: 1 << 20,
+                received: 0,
+            },
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let reply = loop {
+            let chunk = FileTransfer::Chunk(FileChunk {
+                id: 1,
+                data: b"hello".to_vec(),
+            });
+            if let Some(reply) = receiver.handle(chunk).await {
+                break reply;
+            }
+            assert!(Instant::now() < deadline, "write failure wasn't reported");
+            sleep(Duration::from_millis(10)).await;
+        };
+        assert!(matches!(reply, FileTransfer::Reject { id: 1, .. }));
+        assert!(receiver.receiving.is_empty());
+        wait_until(|| fs::read_dir(dst.path()).unwrap().count() == 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_partial_file_removed_on_drop() {
+        let dst = tempfile::tempdir().unwrap();
+        let mut receiver = FileTransfers::new(Some(dst.path().to_owned()));
+        let offer = FileTransfer::Offer {
+            id: 1,
+            name: "hello.txt".to_owned(),
+            size: 5,
+        };
+        receiver.handle(offer).await;
+        assert_eq!(fs::read_dir(dst.path()).unwrap().count(), 1);
+
+        drop(receiver);
+        wait_until(|| fs::read_dir(dst.path()).unwrap().count() == 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_invalid_offers() {
+        let dst = tempfile::tempdir().unwrap();
+        let mut receiver = FileTransfers::new(Some(dst.path().to_owned()));
+        let offer = |size| FileTransfer::Offer {
+            id: 1,
+            name: "hello.txt".to_owned(),
+            size,
+        };
+
+        assert!(matches!(
+            receiver.handle(offer(MAX_FILE_SIZE + 1)).await,
+            Some(FileTransfer::Reject { id: 1, .. })
+        ));
+        assert_eq!(
+            receiver.handle(offer(5)).await,
+            Some(FileTransfer::Accept { id: 1 })
+        );
+
+        // the file being received keeps its partial file
+        assert!(receiver.handle(offer(5)).await.is_none());
+        assert_eq!(fs::read_dir(dst.path()).unwrap().count(), 1);
+        let chunk = FileTransfer::Chunk(FileChunk {
+            id: 1,
+            data: b"hello".to_vec(),
+        });
+        assert!(receiver.handle(chunk).await.is_none());
+        assert_eq!(receiver.receiving[&1].received, 5);
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(sanitize_name("hello.txt"), Some("hello.txt"));
+        assert_eq!(sanitize_name("../hello.txt"), None);
+        assert_eq!(sanitize_name("C:hello.txt"), None);
+        assert_eq!(sanitize_name(".."), None);
+        assert_eq!(sanitize_name(""), None);
+        assert_eq!(sanitize_name("nul"), None);
+        assert_eq!(sanitize_name("COM1.txt"), None);
+        assert_eq!(sanitize_name("con .tar.gz"), None);
+        assert_eq!(sanitize_name("console.txt"), Some("console.txt"));
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command("send /tmp/a b.txt").unwrap(),
+            Some(PathBuf::from("/tmp/a b.txt"))
+        );
+        assert_eq!(parse_command("  ").unwrap(), None);
+        assert!(parse_command("send").is_err());
+        assert!(parse_command("get x").is_err());
+    }
+}
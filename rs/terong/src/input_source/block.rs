@@ -0,0 +1,215 @@
+use crate::{
+    server::config::{BlockRule, BlockTarget},
+    transport::protocol::{InputEvent, KeyCode},
+};
+use std::collections::HashSet;
+
+/// Stops keys blocked for a target from getting to it.
+///
+/// Presses of keys that begin a blocked chord are held back until it's known
+/// if the chord is completed, so no part of a blocked chord gets through.
+#[derive(Debug)]
+pub struct KeyBlocker {
+    /// Chords, single keys are chords of one key.
+    chords: Vec<Vec<KeyCode>>,
+    /// Keys currently held, blocked or not.
+    held: HashSet<KeyCode>,
+    /// Held keys whose press was blocked, so their repeats and release are
+    /// blocked too.
+    blocked: HashSet<KeyCode>,
+    /// Held keys beginning a chord whose press is held back, in the order they
+    /// were pressed.
+    pending: Vec<KeyCode>,
+}
+
+impl KeyBlocker {
+    pub fn new(rules: &[BlockRule], target: BlockTarget) -> Self {
+        let chords = rules
+            .iter()
+            .filter(|x| x.targets.contains(&target))
+            .map(|x| x.keys.clone())
+            .collect();
+        Self {
+            chords,
+            held: Default::default(),
+            blocked: Default::default(),
+            pending: Default::default(),
+        }
+    }
+
+    /// Returns events that get through, held back presses come before the
+    /// event once their chord isn't completed.
+    pub fn filter(&mut self, event: InputEvent) -> Vec<InputEvent> {
+        match event {
+            InputEvent::KeyDown { key } => {
+                self.held.insert(key);
+                if self.blocked.contains(&key) || self.pending.contains(&key) {
+                    return Vec::new();
+                }
+                let chord = self.chords.iter().find(|chord| {
+                    chord.last() == Some(&key) && chord.iter().all(|x| self.held.contains(x))
+                });
+                if let Some(chord) = chord {
+                    // held back keys of the chord are blocked along with it
+                    for x in chord {
+                        if self.pending.contains(x) {
+                            self.blocked.insert(*x);
+                        }
+                    }
+                    self.pending.retain(|x| !chord.contains(x));
+                    self.blocked.insert(key);
+                    return self.release_pending(None);
+                }
+                if self.begins_chord(key) {
+                    self.pending.push(key);
+                    return Vec::new();
+                }
+                self.release_pending(Some(event))
+            }
+            InputEvent::KeyRepeat { key } => {
+                if self.blocked.contains(&key) || self.pending.contains(&key) {
+                    Vec::new()
+                } else {
+                    vec![event]
+                }
+            }
+            InputEvent::KeyUp { key } => {
+                self.held.remove(&key);
+                if self.blocked.remove(&key) {
+                    Vec::new()
+                } else if self.pending.contains(&key) {
+                    self.release_pending(Some(event))
+                } else {
+                    vec![event]
+                }
+            }
+            // moving the mouse doesn't use the held keys
+            InputEvent::MouseMove { .. } => vec![event],
+            _ => self.release_pending(Some(event)),
+        }
+    }
+
+    /// Forgets held keys, their releases may never be seen.
+    pub fn clear(&mut self) {
+        self.held.clear();
+        self.blocked.clear();
+        self.pending.clear();
+    }
+
+    fn begins_chord(&self, key: KeyCode) -> bool {
+        self.chords
+            .iter()
+            .any(|chord| chord.len() > 1 && chord[..chord.len() - 1].contains(&key))
+    }
+
+    /// Returns held back presses followed by the event.
+    fn release_pending(&mut self, event: Option<InputEvent>) -> Vec<InputEvent> {
+        self.pending
+            .drain(..)
+            .map(|key| InputEvent::KeyDown { key })
+            .chain(event)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        server::config::BlockConfig,
+        transport::protocol::{down, up},
+    };
+
+    fn new_blocker(config: &str, target: BlockTarget) -> KeyBlocker {
+        let config: BlockConfig = toml::from_str(config).unwrap();
+        KeyBlocker::new(&config.rules, target)
+    }
+
+    fn allowed(blocker: &mut KeyBlocker, events: &[InputEvent]) -> Vec<InputEvent> {
+        events.iter().flat_map(|x| blocker.filter(*x)).collect()
+    }
+
+    const RULES: &str = r#"
+        [[rules]]
+        keys = ["PrintScreen"]
+        [[rules]]
+        keys = ["LeftMeta", "L"]
+        targets = ["client"]
+        "#;
+
+    #[test]
+    fn test_block_key() {
+        let mut blocker = new_blocker(RULES, BlockTarget::Scripts);
+        assert_eq!(
+            allowed(
+                &mut blocker,
+                &[
+                    down(KeyCode::PrintScreen),
+                    InputEvent::KeyRepeat {
+                        key: KeyCode::PrintScreen
+                    },
+                    up(KeyCode::PrintScreen),
+                    down(KeyCode::A),
+                    up(KeyCode::A),
+                ]
+            ),
+            vec![down(KeyCode::A), up(KeyCode::A)]
+        );
+    }
+
+    #[test]
+    fn test_block_chord() {
+        let mut blocker = new_blocker(RULES, BlockTarget::Client);
+        assert_eq!(
+            allowed(
+                &mut blocker,
+                &[
+                    down(KeyCode::L),
+                    up(KeyCode::L),
+                    down(KeyCode::LeftMeta),
+                    down(KeyCode::L),
+                    // released after the modifier, still blocked
+                    up(KeyCode::LeftMeta),
+                    up(KeyCode::L),
+                ]
+            ),
+            vec![down(KeyCode::L), up(KeyCode::L)]
+        );
+
+        // the modifier gets through once it's used otherwise
+        let mouse_move = InputEvent::MouseMove { dx: 1, dy: 1 };
+        assert_eq!(
+            allowed(
+                &mut blocker,
+                &[
+                    down(KeyCode::LeftMeta),
+                    mouse_move,
+                    down(KeyCode::A),
+                    up(KeyCode::A),
+                    up(KeyCode::LeftMeta),
+                    down(KeyCode::LeftMeta),
+                    up(KeyCode::LeftMeta),
+                ]
+            ),
+            vec![
+                mouse_move,
+                down(KeyCode::LeftMeta),
+                down(KeyCode::A),
+                up(KeyCode::A),
+                up(KeyCode::LeftMeta),
+                down(KeyCode::LeftMeta),
+                up(KeyCode::LeftMeta),
+            ]
+        );
+
+        // the chord isn't blocked for scripts
+        let mut blocker = new_blocker(RULES, BlockTarget::Scripts);
+        let events = [
+            down(KeyCode::LeftMeta),
+            down(KeyCode::L),
+            up(KeyCode::L),
+            up(KeyCode::LeftMeta),
+        ];
+        assert_eq!(allowed(&mut blocker, &events), events.to_vec());
+    }
+}
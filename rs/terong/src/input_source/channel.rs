@@ -0,0 +1,74 @@
+use super::{controller::InputController, event::LocalInputEvent, InputSource, LockStateReader};
+use crate::transport::protocol::LockState;
+use anyhow::{anyhow, Error};
+use std::sync::{Arc, Mutex};
+use tokio::{
+    sync::{mpsc, watch},
+    task::{self, JoinHandle},
+};
+
+/// Input source fed through a channel, for running the server without input
+/// devices.
+#[derive(Debug)]
+pub struct ChannelInputSource {
+    event_rx: mpsc::Receiver<LocalInputEvent>,
+    lock_rx: watch::Receiver<LockState>,
+    capture_tx: watch::Sender<bool>,
+}
+
+/// Other end of a [ChannelInputSource].
+#[derive(Debug)]
+pub struct ChannelInputSourceHandle {
+    /// Local input to pass to the controller.
+    pub event_tx: mpsc::Sender<LocalInputEvent>,
+    /// Lock state of the pretend host.
+    pub lock_tx: watch::Sender<LockState>,
+    /// If input would be captured from the pretend host.
+    pub capture_rx: watch::Receiver<bool>,
+}
+
+impl ChannelInputSource {
+    pub fn new() -> (Self, ChannelInputSourceHandle) {
+        let (event_tx, event_rx) = mpsc::channel(16);
+        let (lock_tx, lock_rx) = watch::channel(LockState::default());
+        let (capture_tx, capture_rx) = watch::channel(false);
+        let source = Self {
+            event_rx,
+            lock_rx,
+            capture_tx,
+        };
+        let handle = ChannelInputSourceHandle {
+            event_tx,
+            lock_tx,
+            capture_rx,
+        };
+        (source, handle)
+    }
+}
+
+impl InputSource for ChannelInputSource {
+    fn start(mut self, controller: Arc<Mutex<InputController>>) -> JoinHandle<Result<(), Error>> {
+        task::spawn(async move {
+            while let Some(event) = self.event_rx.recv().await {
+                // the controller blocks on relaying events, blocking per event
+                // rather than for the whole loop lets paused time in tests
+                // auto-advance between events
+                let controller = controller.clone();
+                let capture = task::spawn_blocking(move || {
+                    controller
+                        .lock()
+                        .map_err(|_| anyhow!("input controller panicked"))?
+                        .on_input_event(event)
+                })
+                .await??;
+                self.capture_tx.send_replace(capture);
+            }
+            Ok(())
+        })
+    }
+
+    fn lock_state_reader(&self) -> LockStateReader {
+        let lock_rx = self.lock_rx.clone();
+        Arc::new(move || Ok(*lock_rx.borrow()))
+    }
+}
@@ -1,13 +1,35 @@
-use super::event::LocalInputEvent;
-use crate::transport::protocol::{InputEvent, KeyCode};
+use super::{
+    block::KeyBlocker,
+    event::LocalInputEvent,
+    script::{ControllerState, ScriptHook},
+};
+use crate::{
+    logging::Redacted,
+    metrics::Metrics,
+    recording::{RecordedEvent, Recorder},
+    server::config::{BlockConfig, BlockTarget},
+    transport::protocol::{AxisRange, GamepadAxis, GamepadEvent, InputEvent, KeyCode},
+};
 use anyhow::Error;
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
+    iter, mem,
     ops::Sub,
+    sync::{Arc, Mutex},
+    thread,
     time::{Duration, Instant},
 };
-use tokio::sync::mpsc;
-use tracing::debug;
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, warn};
+
+/// Double tapping it toggles the relay.
+const RELAY_HOTKEY: KeyCode = KeyCode::RightCtrl;
+
+/// Double tapping it toggles the gamepad relay.
+const GAMEPAD_HOTKEY: KeyCode = KeyCode::ScrollLock;
+
+/// Both presses of a double tap happen within it.
+const DOUBLE_TAP_WINDOW: Duration = Duration::from_millis(300);
 
 pub struct InputController {
     /// Buffer for local input events.
@@ -17,31 +39,138 @@ pub struct InputController {
     /// If this is true input source should be consumed from its host and
     /// propagated to the input sink.
     relay: bool,
-    /// Last time we detect inputs for toggling the relay flag.
+    /// Like the relay flag but for the gamepad, toggled independently.
+    gamepad_relay: bool,
+    /// Axes of the gamepad, announced to the input sink when gamepad relay is
+    /// switched on. None if there's no gamepad.
+    gamepad_axes: Option<Vec<(GamepadAxis, AxisRange)>>,
+    /// Last time we detect inputs for toggling either relay flag.
     relay_toggled_at: Option<Instant>,
+    /// Notified when the relay flag is toggled.
+    relay_tx: watch::Sender<bool>,
+    /// User scripts relayed events go through.
+    scripts: ScriptHook,
+    /// Blocks keys before the scripts see them.
+    script_blocker: KeyBlocker,
+    /// Blocks keys before they're relayed.
+    client_blocker: KeyBlocker,
+    /// If this is true hotkey presses aren't relayed.
+    swallow_hotkeys: bool,
+    /// Hotkey events held back until it's known if they toggle a relay, with
+    /// when they happened.
+    held_hotkeys: Vec<(InputEvent, Instant)>,
+    /// Records local and relayed events if set.
+    recorder: Option<Recorder>,
+    /// Log keys of input events, they're redacted otherwise.
+    log_keystrokes: bool,
+    /// Counts time spent relaying if set.
+    metrics: Option<Arc<Metrics>>,
+    /// If this is true input is left to its host, nothing is relayed anymore.
+    closed: bool,
 }
 
 impl InputController {
-    pub fn new(event_tx: mpsc::Sender<InputEvent>) -> Self {
+    pub fn new(
+        event_tx: mpsc::Sender<InputEvent>,
+        relay_tx: watch::Sender<bool>,
+        scripts: ScriptHook,
+        block: BlockConfig,
+    ) -> Self {
         Self {
             event_buf: Default::default(),
             event_tx,
             relay: false,
+            gamepad_relay: false,
+            gamepad_axes: None,
             relay_toggled_at: None,
+            relay_tx,
+            scripts,
+            script_blocker: KeyBlocker::new(&block.rules, BlockTarget::Scripts),
+            client_blocker: KeyBlocker::new(&block.rules, BlockTarget::Client),
+            swallow_hotkeys: block.swallow_hotkeys,
+            held_hotkeys: Vec::new(),
+            recorder: None,
+            log_keystrokes: false,
+            metrics: None,
+            closed: false,
+        }
+    }
+
+    /// Sets the gamepad whose inputs can be relayed.
+    pub fn set_gamepad(&mut self, axes: Vec<(GamepadAxis, AxisRange)>) {
+        self.gamepad_axes = Some(axes);
+    }
+
+    /// Records events the controller sees and relays.
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Sets if keys of input events are logged, they're redacted otherwise.
+    pub fn set_log_keystrokes(&mut self, value: bool) {
+        self.log_keystrokes = value;
+    }
+
+    /// Sets the metrics to count time spent relaying in.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Switches the relay on or off, like the hotkey does.
+    pub fn set_relay(&mut self, relay: bool) -> Result<(), Error> {
+        if !self.closed && relay != self.relay {
+            self.switch_relay(relay, Instant::now())?;
         }
+        Ok(())
     }
 
-    /// Returns boolean that denote if the next successive inputs should be
-    /// captured or not.
+    /// Stops relaying for good, later input is left to its host.
+    pub fn close(&mut self) {
+        self.closed = true;
+        self.relay = false;
+        self.gamepad_relay = false;
+        self.held_hotkeys.clear();
+        self.relay_tx.send_replace(false);
+        if let Some(metrics) = &self.metrics {
+            metrics.relay_switched(false);
+        }
+    }
+
+    /// Returns boolean that denote if the next successive inputs from the same
+    /// device should be captured or not.
     pub fn on_input_event(&mut self, event: LocalInputEvent) -> Result<bool, Error> {
-        debug!(?event, "received local input event");
+        self.on_input_event_at(event, Instant::now())
+    }
+
+    /// Like [Self::on_input_event] but for an event that happened at `now`,
+    /// for replaying events with their original timing.
+    pub fn on_input_event_at(
+        &mut self,
+        event: LocalInputEvent,
+        now: Instant,
+    ) -> Result<bool, Error> {
+        if self.closed {
+            return Ok(false);
+        }
+
+        debug!(event = ?Redacted(&event, self.log_keystrokes), "received local input event");
+        self.record(RecordedEvent::Local(event));
+        self.flush_expired_hotkeys(now)?;
+
+        if let LocalInputEvent::Gamepad { event } = event {
+            return self.on_gamepad_event(event);
+        }
 
-        self.event_buf.push_input_event(event, Instant::now());
+        self.event_buf.push_input_event(event, now);
 
         if self.relay {
             if let Some(event) = event.into_input_event() {
-                debug!(?event, "relaying input event");
-                self.event_tx.blocking_send(event)?;
+                if self.swallow_hotkeys && self.is_hotkey_event(&event) {
+                    self.held_hotkeys.push((event, now));
+                } else {
+                    self.flush_held_hotkeys()?;
+                    self.relay_event(event)?;
+                }
             }
         }
 
@@ -54,19 +183,184 @@ impl InputController {
             (first, second)
         };
 
-        // if the right ctrl key are pressed twice consecutively
-        if let (Some((KeyCode::RightCtrl, _)), Some((KeyCode::RightCtrl, _))) =
-            (most_recent, second_most)
-        {
-            let new_relay = !self.relay;
-            debug!(?new_relay, "relay toggled");
-            self.event_buf.clear();
-            self.relay = new_relay;
-            self.relay_toggled_at = Some(Instant::now());
+        match (most_recent, second_most) {
+            // if the right ctrl key are pressed twice consecutively
+            (Some((&RELAY_HOTKEY, _)), Some((&RELAY_HOTKEY, _))) => {
+                self.switch_relay(!self.relay, now)?;
+            }
+            // if the scroll lock key are pressed twice consecutively
+            (Some((&GAMEPAD_HOTKEY, _)), Some((&GAMEPAD_HOTKEY, _))) => {
+                self.event_buf.clear();
+                self.relay_toggled_at = Some(now);
+                self.toggle_gamepad_relay()?;
+            }
+            _ => (),
         }
 
         Ok(self.relay)
     }
+
+    fn switch_relay(&mut self, new_relay: bool, now: Instant) -> Result<(), Error> {
+        debug!(?new_relay, "relay toggled");
+        self.drop_held_hotkeys()?;
+        self.script_blocker.clear();
+        self.client_blocker.clear();
+        self.event_buf.clear();
+        self.relay = new_relay;
+        self.relay_toggled_at = Some(now);
+        self.relay_tx.send_replace(new_relay);
+        if let Some(metrics) = &self.metrics {
+            metrics.relay_switched(new_relay);
+        }
+        Ok(())
+    }
+
+    fn on_gamepad_event(&mut self, event: GamepadEvent) -> Result<bool, Error> {
+        if self.gamepad_relay {
+            self.relay_event(InputEvent::Gamepad { event })?;
+        }
+        Ok(self.gamepad_relay)
+    }
+
+    /// Relays held hotkey events that happened too long before `now` to
+    /// toggle a relay.
+    pub fn flush_expired_hotkeys(&mut self, now: Instant) -> Result<(), Error> {
+        let expired = self
+            .held_hotkeys
+            .iter()
+            .take_while(|(_, t)| now.saturating_duration_since(*t) > DOUBLE_TAP_WINDOW)
+            .count();
+        let events: Vec<_> = self.held_hotkeys.drain(..expired).collect();
+        for (event, _) in events {
+            self.relay_event(event)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the event through the scripts to the input sink.
+    fn relay_event(&mut self, event: InputEvent) -> Result<(), Error> {
+        let events = self.script_blocker.filter(event);
+        if events.is_empty() {
+            debug!(event = ?Redacted(&event, self.log_keystrokes), "blocked or held back input event");
+        }
+        let state = ControllerState {
+            relay: self.relay,
+            gamepad_relay: self.gamepad_relay,
+        };
+        for event in events {
+            for event in self.scripts.apply(event, state) {
+                let events = self.client_blocker.filter(event);
+                if events.is_empty() {
+                    debug!(event = ?Redacted(&event, self.log_keystrokes), "blocked or held back input event");
+                }
+                for event in events {
+                    self.send(event)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, event: InputEvent) -> Result<(), Error> {
+        debug!(event = ?Redacted(&event, self.log_keystrokes), "relaying input event");
+        self.record(RecordedEvent::Relayed(event));
+        self.event_tx.blocking_send(event)?;
+        Ok(())
+    }
+
+    fn record(&mut self, event: RecordedEvent) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.record(event) {
+                warn!(?err, "failed to record event, recording stopped");
+                self.recorder = None;
+            }
+        }
+    }
+
+    fn is_hotkey_event(&self, event: &InputEvent) -> bool {
+        let key = match *event {
+            InputEvent::KeyDown { key }
+            | InputEvent::KeyRepeat { key }
+            | InputEvent::KeyUp { key } => key,
+            _ => return false,
+        };
+        key == RELAY_HOTKEY || (key == GAMEPAD_HOTKEY && self.gamepad_axes.is_some())
+    }
+
+    /// Relays held hotkey events, they didn't toggle a relay.
+    fn flush_held_hotkeys(&mut self) -> Result<(), Error> {
+        for (event, _) in mem::take(&mut self.held_hotkeys) {
+            self.relay_event(event)?;
+        }
+        Ok(())
+    }
+
+    /// Drops held hotkey events after they toggled a relay, except releases
+    /// of keys pressed before them so they aren't left held.
+    fn drop_held_hotkeys(&mut self) -> Result<(), Error> {
+        let mut pressed = HashSet::new();
+        for (event, _) in mem::take(&mut self.held_hotkeys) {
+            match event {
+                InputEvent::KeyDown { key } => {
+                    pressed.insert(key);
+                }
+                InputEvent::KeyUp { key } if !pressed.remove(&key) => self.relay_event(event)?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Connects the gamepad to the input sink, or disconnects it.
+    fn toggle_gamepad_relay(&mut self) -> Result<(), Error> {
+        let axes = match &self.gamepad_axes {
+            Some(x) => x,
+            None => {
+                debug!("no gamepad to relay");
+                return Ok(());
+            }
+        };
+
+        let new_gamepad_relay = !self.gamepad_relay;
+        debug!(?new_gamepad_relay, "gamepad relay toggled");
+
+        let events: Vec<_> = if new_gamepad_relay {
+            axes.iter()
+                .map(|&(axis, range)| GamepadEvent::AxisRange { axis, range })
+                .chain(iter::once(GamepadEvent::Connected))
+                .collect()
+        } else {
+            vec![GamepadEvent::Disconnected]
+        };
+        self.drop_held_hotkeys()?;
+        for event in events {
+            self.send(InputEvent::Gamepad { event })?;
+        }
+
+        self.gamepad_relay = new_gamepad_relay;
+        Ok(())
+    }
+}
+
+/// Relays held hotkey events once they can't toggle a relay, even if no
+/// other input follows them. Stops once the controller is dropped.
+pub fn flush_hotkeys_on_timer(controller: &Arc<Mutex<InputController>>) {
+    let controller = Arc::downgrade(controller);
+    thread::spawn(move || loop {
+        thread::sleep(DOUBLE_TAP_WINDOW / 4);
+        let controller = match controller.upgrade() {
+            Some(x) => x,
+            None => return,
+        };
+        let mut controller = match controller.lock() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        if let Err(err) = controller.flush_expired_hotkeys(Instant::now()) {
+            debug!(?err, "failed to relay held hotkeys, stopping");
+            return;
+        }
+    });
 }
 
 #[derive(Debug)]
@@ -88,13 +382,13 @@ where
 {
     /// Add event to buffer and drop outdated events.
     ///
-    /// Outdated events are events older than 300 milliseconds from the newest
-    /// event.
+    /// Outdated events are events older than [DOUBLE_TAP_WINDOW] from the
+    /// newest event.
     fn push_input_event(&mut self, event: LocalInputEvent, time: OrderKey) {
         // drop outdated events
         let part = self.buf.partition_point(|(_, t)| {
             let d = time - *t;
-            d <= Duration::from_millis(300)
+            d <= DOUBLE_TAP_WINDOW
         });
         self.buf.truncate(part);
 
@@ -279,3 +573,186 @@ impl<'a, T> Iterator for RecentKeyPresses<'a, T> {
         Some((key_up.key, key_up.order_key))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{recording, transport::protocol::GamepadButton};
+
+    fn tap(controller: &mut InputController, key: KeyCode) {
+        for event in [
+            LocalInputEvent::KeyDown { key },
+            LocalInputEvent::KeyUp { key },
+        ] {
+            controller.on_input_event(event).unwrap();
+        }
+    }
+
+    fn new_controller() -> (InputController, mpsc::Receiver<InputEvent>) {
+        let (event_tx, event_rx) = mpsc::channel(16);
+        let (relay_tx, _) = watch::channel(false);
+        let scripts = ScriptHook::new(Default::default());
+        let controller = InputController::new(event_tx, relay_tx, scripts, Default::default());
+        (controller, event_rx)
+    }
+
+    /// Returns recent pressed keys after each local event of the fixture.
+    fn fixture_presses(name: &str) -> Vec<(u64, Vec<KeyCode>)> {
+        let mut buf = EventBuffer::default();
+        recording::fixture(name)
+            .into_iter()
+            .filter_map(|record| match record.event {
+                RecordedEvent::Local(event) => Some((record.time_ms, event)),
+                RecordedEvent::Relayed(_) => None,
+            })
+            .map(|(time_ms, event)| {
+                buf.push_input_event(event, Duration::from_millis(time_ms));
+                let keys = buf.recent_pressed_keys(None).map(|(x, _)| *x).collect();
+                (time_ms, keys)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_recent_key_presses_fixture() {
+        let presses = fixture_presses("hotkey_slow_taps.jsonl");
+        let at = |time_ms| {
+            presses
+                .iter()
+                .find(|(t, _)| *t == time_ms)
+                .map(|(_, keys)| keys.clone())
+                .unwrap()
+        };
+        // the first tap is too old to pair with the second
+        assert_eq!(at(430), vec![KeyCode::RightCtrl]);
+        assert_eq!(at(540), vec![KeyCode::RightCtrl, KeyCode::A]);
+        assert_eq!(at(1030), vec![KeyCode::RightCtrl, KeyCode::RightCtrl]);
+    }
+
+    #[test]
+    fn test_swallow_hotkey() {
+        let (mut controller, mut event_rx) = new_controller();
+        tap(&mut controller, KeyCode::RightCtrl);
+        tap(&mut controller, KeyCode::RightCtrl);
+        assert!(controller.relay);
+
+        // held right ctrl is relayed once it's used with other input
+        let key = KeyCode::RightCtrl;
+        controller
+            .on_input_event(LocalInputEvent::KeyDown { key })
+            .unwrap();
+        assert!(event_rx.try_recv().is_err());
+        let mouse_move = LocalInputEvent::MouseMove((1, 1).into());
+        controller.on_input_event(mouse_move).unwrap();
+        controller
+            .on_input_event(LocalInputEvent::KeyUp { key })
+            .unwrap();
+        assert_eq!(event_rx.try_recv().unwrap(), InputEvent::KeyDown { key });
+        assert_eq!(
+            event_rx.try_recv().unwrap(),
+            InputEvent::MouseMove { dx: 1, dy: 1 }
+        );
+
+        // the rest toggles relay off, only the release of the relayed press
+        // gets to the client
+        tap(&mut controller, KeyCode::RightCtrl);
+        assert!(!controller.relay);
+        assert_eq!(event_rx.try_recv().unwrap(), InputEvent::KeyUp { key });
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_held_hotkey_expires() {
+        let (mut controller, mut event_rx) = new_controller();
+        controller.set_relay(true).unwrap();
+
+        let key = KeyCode::RightCtrl;
+        let now = Instant::now();
+        controller
+            .on_input_event_at(LocalInputEvent::KeyDown { key }, now)
+            .unwrap();
+        controller.flush_expired_hotkeys(now).unwrap();
+        assert!(event_rx.try_recv().is_err());
+
+        // too late to be the first tap of a double tap
+        controller
+            .flush_expired_hotkeys(now + DOUBLE_TAP_WINDOW * 2)
+            .unwrap();
+        assert_eq!(event_rx.try_recv().unwrap(), InputEvent::KeyDown { key });
+    }
+
+    #[test]
+    fn test_set_relay_and_close() {
+        let (mut controller, mut event_rx) = new_controller();
+        controller.set_relay(true).unwrap();
+        let key = KeyCode::A;
+        assert!(controller
+            .on_input_event(LocalInputEvent::KeyDown { key })
+            .unwrap());
+        assert_eq!(event_rx.try_recv().unwrap(), InputEvent::KeyDown { key });
+
+        // closed controllers leave input to the host
+        controller.close();
+        assert!(!controller
+            .on_input_event(LocalInputEvent::KeyUp { key })
+            .unwrap());
+        controller.set_relay(true).unwrap();
+        assert!(!controller.relay);
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_gamepad_relay() {
+        let (mut controller, mut event_rx) = new_controller();
+        let range = AxisRange {
+            minimum: -32768,
+            maximum: 32767,
+            fuzz: 16,
+            flat: 128,
+        };
+        controller.set_gamepad(vec![(GamepadAxis::X, range)]);
+
+        let button_down = LocalInputEvent::Gamepad {
+            event: GamepadEvent::ButtonDown {
+                button: GamepadButton::South,
+            },
+        };
+        assert!(!controller.on_input_event(button_down).unwrap());
+        assert!(event_rx.try_recv().is_err());
+
+        tap(&mut controller, GAMEPAD_HOTKEY);
+        tap(&mut controller, GAMEPAD_HOTKEY);
+        let gamepad = |event| InputEvent::Gamepad { event };
+        assert_eq!(
+            event_rx.try_recv().unwrap(),
+            gamepad(GamepadEvent::AxisRange {
+                axis: GamepadAxis::X,
+                range
+            })
+        );
+        assert_eq!(
+            event_rx.try_recv().unwrap(),
+            gamepad(GamepadEvent::Connected)
+        );
+
+        // gamepad is relayed, mouse and keyboard aren't
+        assert!(controller.on_input_event(button_down).unwrap());
+        assert_eq!(
+            event_rx.try_recv().unwrap(),
+            gamepad(GamepadEvent::ButtonDown {
+                button: GamepadButton::South
+            })
+        );
+        let mouse_move = LocalInputEvent::MouseMove((1, 1).into());
+        assert!(!controller.on_input_event(mouse_move).unwrap());
+        assert!(event_rx.try_recv().is_err());
+
+        tap(&mut controller, GAMEPAD_HOTKEY);
+        tap(&mut controller, GAMEPAD_HOTKEY);
+        assert_eq!(
+            event_rx.try_recv().unwrap(),
+            gamepad(GamepadEvent::Disconnected)
+        );
+        assert!(!controller.on_input_event(button_down).unwrap());
+    }
+}
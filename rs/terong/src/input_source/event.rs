@@ -1,20 +1,54 @@
-use crate::transport::protocol::{InputEvent, KeyCode, MouseButton, MouseScrollDirection};
+use crate::{
+    logging::Redact,
+    transport::protocol::{
+        AbsolutePointer, GamepadEvent, Gesture, HidUsage, InputEvent, KeyCode, MouseButton,
+        MouseScrollDirection, ScrollAxis,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
 pub enum LocalInputEvent {
     MousePosition(MousePosition),
     MouseMove(MouseMovement),
+    AbsolutePointer { pointer: AbsolutePointer },
 
     MouseButtonDown { button: MouseButton },
     MouseButtonUp { button: MouseButton },
     MouseScroll { direction: MouseScrollDirection },
+    MouseScrollHiRes { axis: ScrollAxis, delta: i16 },
 
     KeyDown { key: KeyCode },
     KeyRepeat { key: KeyCode },
     KeyUp { key: KeyCode },
+
+    RawKeyDown { usage: HidUsage },
+    RawKeyUp { usage: HidUsage },
+
+    Gamepad { event: GamepadEvent },
+
+    Gesture { gesture: Gesture },
+}
+
+impl Redact for LocalInputEvent {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalInputEvent::KeyDown { .. } => f.debug_struct("KeyDown").finish_non_exhaustive(),
+            LocalInputEvent::KeyRepeat { .. } => {
+                f.debug_struct("KeyRepeat").finish_non_exhaustive()
+            }
+            LocalInputEvent::KeyUp { .. } => f.debug_struct("KeyUp").finish_non_exhaustive(),
+            LocalInputEvent::RawKeyDown { .. } => {
+                f.debug_struct("RawKeyDown").finish_non_exhaustive()
+            }
+            LocalInputEvent::RawKeyUp { .. } => f.debug_struct("RawKeyUp").finish_non_exhaustive(),
+            x => fmt::Debug::fmt(x, f),
+        }
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, Default, Debug)]
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize, Debug)]
 pub struct MouseMovement {
     pub dx: i16,
     pub dy: i16,
@@ -26,7 +60,7 @@ impl From<(i16, i16)> for MouseMovement {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Default, Debug)]
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize, Debug)]
 pub struct MousePosition {
     pub x: i16,
     pub y: i16,
@@ -52,9 +86,14 @@ impl LocalInputEvent {
     /// Converts local input event into protocol input event.
     pub fn into_input_event(self) -> Option<InputEvent> {
         match self {
+            // positions of the host's cursor aren't relayed
+            LocalInputEvent::MousePosition(_) => None,
             LocalInputEvent::MouseMove(MouseMovement { dx, dy }) => {
                 InputEvent::MouseMove { dx, dy }.into()
             }
+            LocalInputEvent::AbsolutePointer { pointer } => {
+                InputEvent::AbsolutePointer { pointer }.into()
+            }
             LocalInputEvent::MouseButtonDown { button } => {
                 InputEvent::MouseButtonDown { button }.into()
             }
@@ -64,10 +103,16 @@ impl LocalInputEvent {
             LocalInputEvent::MouseScroll { direction } => {
                 InputEvent::MouseScroll { direction }.into()
             }
+            LocalInputEvent::MouseScrollHiRes { axis, delta } => {
+                InputEvent::MouseScrollHiRes { axis, delta }.into()
+            }
             LocalInputEvent::KeyDown { key } => InputEvent::KeyDown { key }.into(),
             LocalInputEvent::KeyRepeat { key } => InputEvent::KeyRepeat { key }.into(),
             LocalInputEvent::KeyUp { key } => InputEvent::KeyUp { key }.into(),
-            _ => None,
+            LocalInputEvent::RawKeyDown { usage } => InputEvent::RawKeyDown { usage }.into(),
+            LocalInputEvent::RawKeyUp { usage } => InputEvent::RawKeyUp { usage }.into(),
+            LocalInputEvent::Gamepad { event } => InputEvent::Gamepad { event }.into(),
+            LocalInputEvent::Gesture { gesture } => InputEvent::Gesture { gesture }.into(),
         }
     }
 }
@@ -0,0 +1,215 @@
+use crate::transport::protocol::Gesture;
+
+/// Relative change of finger spread that starts a pinch.
+const PINCH_THRESHOLD: f64 = 0.15;
+
+/// Movement of the fingers that starts a swipe, [u16::MAX] spans the touchpad.
+const SWIPE_THRESHOLD: f64 = 1500.0;
+
+/// Position of a finger on the touchpad, [u16::MAX] spans the touchpad.
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct Contact {
+    pub x: f64,
+    pub y: f64,
+}
+
+/// Recognises swipes and pinches from frames of touchpad contacts.
+///
+/// Swipes need at least three fingers, two finger movement is left for
+/// scrolling.
+#[derive(Default, Debug)]
+pub struct GestureRecognizer {
+    fingers: usize,
+    state: State,
+}
+
+#[derive(Default, Debug)]
+enum State {
+    #[default]
+    Idle,
+    /// Fingers are down but their movement isn't a gesture yet.
+    Pending {
+        start: Frame,
+    },
+    Swipe {
+        last: Frame,
+    },
+    Pinch {
+        start: Frame,
+        last: Frame,
+    },
+}
+
+/// Summary of the contacts in a frame.
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    x: f64,
+    y: f64,
+    /// Mean distance of the fingers from their center.
+    spread: f64,
+    /// Angle of the line from the first to the second finger, in degrees.
+    angle: f64,
+}
+
+impl Frame {
+    fn new(contacts: &[Contact]) -> Self {
+        let n = contacts.len() as f64;
+        let x = contacts.iter().map(|c| c.x).sum::<f64>() / n;
+        let y = contacts.iter().map(|c| c.y).sum::<f64>() / n;
+        let spread = contacts
+            .iter()
+            .map(|c| (c.x - x).hypot(c.y - y))
+            .sum::<f64>()
+            / n;
+        let angle = match contacts {
+            [a, b, ..] => (b.y - a.y).atan2(b.x - a.x).to_degrees(),
+            _ => 0.0,
+        };
+        Self {
+            x,
+            y,
+            spread,
+            angle,
+        }
+    }
+
+    fn scale_from(&self, start: &Frame) -> f64 {
+        if start.spread > 0.0 {
+            self.spread / start.spread
+        } else {
+            1.0
+        }
+    }
+
+    fn rotation_from(&self, other: &Frame) -> f64 {
+        let d = self.angle - other.angle;
+        // shortest way around
+        (d + 180.0).rem_euclid(360.0) - 180.0
+    }
+}
+
+impl GestureRecognizer {
+    /// Takes contacts of a complete frame, returns the gesture event it makes.
+    pub fn frame(&mut self, contacts: &[Contact]) -> Option<Gesture> {
+        let fingers = contacts.len();
+
+        if fingers != self.fingers {
+            let cancelled = fingers > self.fingers;
+            self.fingers = fingers;
+            let state = if fingers >= 2 {
+                State::Pending {
+                    start: Frame::new(contacts),
+                }
+            } else {
+                State::Idle
+            };
+            return match std::mem::replace(&mut self.state, state) {
+                State::Swipe { .. } => Gesture::SwipeEnd { cancelled }.into(),
+                State::Pinch { .. } => Gesture::PinchEnd { cancelled }.into(),
+                _ => None,
+            };
+        }
+
+        let frame = Frame::new(contacts);
+        let fingers = fingers.min(u8::MAX as _) as u8;
+        match &mut self.state {
+            State::Idle => None,
+            State::Pending { start } => {
+                let start = *start;
+                let moved = (frame.x - start.x).hypot(frame.y - start.y);
+                if (frame.scale_from(&start) - 1.0).abs() > PINCH_THRESHOLD {
+                    self.state = State::Pinch { start, last: start };
+                    Gesture::PinchBegin { fingers }.into()
+                } else if fingers >= 3 && moved > SWIPE_THRESHOLD {
+                    // the movement so far is reported by the first update
+                    self.state = State::Swipe { last: start };
+                    Gesture::SwipeBegin { fingers }.into()
+                } else {
+                    None
+                }
+            }
+            State::Swipe { last } => {
+                let dx = (frame.x - last.x).round() as i16;
+                let dy = (frame.y - last.y).round() as i16;
+                if dx == 0 && dy == 0 {
+                    return None;
+                }
+                // keep the rounding error for the next update
+                last.x += dx as f64;
+                last.y += dy as f64;
+                Gesture::SwipeUpdate { dx, dy }.into()
+            }
+            State::Pinch { start, last } => {
+                let scale = frame.scale_from(start) as f32;
+                let rotation = frame.rotation_from(last) as f32;
+                *last = frame;
+                Gesture::PinchUpdate { scale, rotation }.into()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contacts(xs: &[(f64, f64)]) -> Vec<Contact> {
+        xs.iter().map(|&(x, y)| Contact { x, y }).collect()
+    }
+
+    #[test]
+    fn test_swipe() {
+        let mut recognizer = GestureRecognizer::default();
+        let frames = [
+            contacts(&[(10000., 10000.), (12000., 10000.), (14000., 10000.)]),
+            contacts(&[(11000., 10000.), (13000., 10000.), (15000., 10000.)]),
+            contacts(&[(12000., 10000.), (14000., 10000.), (16000., 10000.)]),
+            contacts(&[(12000., 10500.), (14000., 10500.), (16000., 10500.)]),
+            contacts(&[]),
+        ];
+        let gestures: Vec<_> = frames.iter().map(|x| recognizer.frame(x)).collect();
+        assert_eq!(
+            gestures,
+            vec![
+                None,
+                None,
+                Some(Gesture::SwipeBegin { fingers: 3 }),
+                Some(Gesture::SwipeUpdate { dx: 2000, dy: 500 }),
+                Some(Gesture::SwipeEnd { cancelled: false }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_two_finger_movement_is_not_a_swipe() {
+        let mut recognizer = GestureRecognizer::default();
+        recognizer.frame(&contacts(&[(10000., 10000.), (12000., 10000.)]));
+        assert_eq!(
+            recognizer.frame(&contacts(&[(10000., 20000.), (12000., 20000.)])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_pinch() {
+        let mut recognizer = GestureRecognizer::default();
+        recognizer.frame(&contacts(&[(10000., 10000.), (20000., 10000.)]));
+        assert_eq!(
+            recognizer.frame(&contacts(&[(5000., 10000.), (25000., 10000.)])),
+            Some(Gesture::PinchBegin { fingers: 2 })
+        );
+        // fingers rotated a quarter turn clockwise, y grows downwards
+        assert_eq!(
+            recognizer.frame(&contacts(&[(15000., 0.), (15000., 20000.)])),
+            Some(Gesture::PinchUpdate {
+                scale: 2.0,
+                rotation: 90.0
+            })
+        );
+        // finger added
+        assert_eq!(
+            recognizer.frame(&contacts(&[(15000., 0.), (15000., 20000.), (0., 0.)])),
+            Some(Gesture::PinchEnd { cancelled: true })
+        );
+    }
+}
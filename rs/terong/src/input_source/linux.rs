@@ -1,32 +1,59 @@
-use super::{controller::InputController, event::LocalInputEvent};
-use crate::transport::protocol::{InputEvent, KeyCode, MouseButton, MouseScrollDirection};
-use anyhow::Error;
+use super::{
+    controller::InputController,
+    event::LocalInputEvent,
+    gesture::{Contact, GestureRecognizer},
+    InputSource, LockStateReader,
+};
+use crate::{
+    lock_state,
+    server::config::LinuxConfig,
+    transport::protocol::{
+        AbsolutePointer, AxisRange, GamepadAxis, GamepadButton, GamepadEvent, HidUsage, KeyCode,
+        MouseButton, MouseScrollDirection, ScrollAxis, Tilt,
+    },
+};
+use anyhow::{anyhow, Context, Error};
 use evdev_rs::{
-    enums::{EventCode, EV_REL},
-    Device, GrabMode, InputEvent as LinuxInputEvent, ReadFlag,
+    enums::{EventCode, EV_ABS, EV_KEY, EV_REL, EV_SYN},
+    AbsInfo, Device, DeviceWrapper, GrabMode, InputEvent as LinuxInputEvent, ReadFlag,
 };
 use futures::future;
 use std::{
     cmp::Ordering,
+    collections::BTreeMap,
     fs::File,
     ops::{Deref, DerefMut},
     path::PathBuf,
     sync::{Arc, Mutex},
 };
-use tokio::{
-    sync::mpsc,
-    task::{self, JoinHandle},
-    try_join,
-};
-use tracing::warn;
+use strum::IntoEnumIterator;
+use tokio::task::{self, JoinHandle};
+use tracing::{error, warn};
+
+/// Reads input from the evdev devices.
+#[derive(Debug)]
+pub struct SystemInputSource {
+    devices: LinuxConfig,
+}
+
+impl SystemInputSource {
+    pub fn new(devices: LinuxConfig) -> Self {
+        Self { devices }
+    }
+}
+
+impl InputSource for SystemInputSource {
+    fn start(self, controller: Arc<Mutex<InputController>>) -> JoinHandle<Result<(), Error>> {
+        run(self.devices, controller)
+    }
 
-pub fn start(
-    keyboard_device: Option<PathBuf>,
-    mouse_device: Option<PathBuf>,
-    touchpad_device: Option<PathBuf>,
-    event_tx: mpsc::Sender<InputEvent>,
-) -> JoinHandle<()> {
-    run(keyboard_device, mouse_device, touchpad_device, event_tx).unwrap()
+    fn lock_state_reader(&self) -> LockStateReader {
+        let keyboard_device = self.devices.keyboard_device.clone();
+        Arc::new(move || match &keyboard_device {
+            Some(x) => lock_state::read(x),
+            None => Err(anyhow!("keyboard device is not configured")),
+        })
+    }
 }
 
 /// RAII ensuring the device's grab mode will be set to ungrab
@@ -36,9 +63,9 @@ struct Ungrabber(Device);
 
 impl Drop for Ungrabber {
     fn drop(&mut self) {
-        self.0
-            .grab(GrabMode::Ungrab)
-            .expect("failed to ungrab device");
+        if let Err(err) = self.0.grab(GrabMode::Ungrab) {
+            error!(?err, "failed to ungrab device");
+        }
     }
 }
 
@@ -65,6 +92,7 @@ impl DerefMut for Ungrabber {
 fn read_input_source<F>(
     device: &mut Device,
     controller: Arc<Mutex<InputController>>,
+    grab: Grab,
     mut map: F,
 ) -> Result<(), Error>
 where
@@ -74,13 +102,26 @@ where
         let (_, event) = device.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)?;
         let event = map(&event);
         if let Some(event) = event {
-            let mut controller = controller.lock().unwrap();
+            let mut controller = controller
+                .lock()
+                .map_err(|_| anyhow!("input controller panicked"))?;
             let consume_input = controller.on_input_event(event)?;
-            set_consume_input(device, consume_input)?;
+            if grab == Grab::WhileConsumed {
+                set_consume_input(device, consume_input)?;
+            }
         }
     }
 }
 
+/// Whether a device is kept from the host.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Grab {
+    /// Grabbed while its input is consumed.
+    WhileConsumed,
+    /// Never grabbed, for devices whose input is only partly mapped.
+    Never,
+}
+
 fn set_consume_input(device: &mut Device, flag: bool) -> Result<(), Error> {
     let mode = if flag {
         GrabMode::Grab
@@ -91,54 +132,99 @@ fn set_consume_input(device: &mut Device, flag: bool) -> Result<(), Error> {
 }
 
 fn run(
-    keyboard_device: Option<PathBuf>,
-    mouse_device: Option<PathBuf>,
-    touchpad_device: Option<PathBuf>,
-    event_tx: mpsc::Sender<InputEvent>,
-) -> Result<JoinHandle<()>, Error> {
-    let controller = Arc::new(Mutex::new(InputController::new(event_tx)));
-
-    let handle = task::spawn(async move {
+    devices: LinuxConfig,
+    controller: Arc<Mutex<InputController>>,
+) -> JoinHandle<Result<(), Error>> {
+    let LinuxConfig {
+        keyboard_device,
+        mouse_device,
+        touchpad_device,
+        tablet_device,
+        gamepad_device,
+    } = devices;
+
+    task::spawn(async move {
         let keyboard = keyboard_device
-            .map(|x| spawn_listener(x, controller.clone(), map_keyboard_event))
-            .transpose()
-            .unwrap()
-            .unwrap_or_else(|| task::spawn(future::ready(())));
+            .map(|x| {
+                spawn_listener(x, controller.clone(), Grab::WhileConsumed, |_| {
+                    map_keyboard_event
+                })
+            })
+            .transpose()?;
 
         let mouse = mouse_device
-            .map(|x| spawn_listener(x, controller.clone(), map_mouse_event))
-            .transpose()
-            .unwrap()
-            .unwrap_or_else(|| task::spawn(future::ready(())));
+            .map(|x| {
+                spawn_listener(x, controller.clone(), Grab::WhileConsumed, |_| {
+                    mouse_event_mapper()
+                })
+            })
+            .transpose()?;
 
         let touchpad = touchpad_device
-            .map(|x| spawn_listener(x, controller.clone(), |_| None))
-            .transpose()
-            .unwrap()
-            .unwrap_or_else(|| task::spawn(future::ready(())));
+            .map(|x| {
+                // only gestures are mapped, pointer motion and taps are left
+                // to the host
+                spawn_listener(x, controller.clone(), Grab::Never, |device| {
+                    let mut mapper = TouchpadMapper::new(device);
+                    move |x: &LinuxInputEvent| mapper.map(x)
+                })
+            })
+            .transpose()?;
 
-        try_join!(keyboard, mouse, touchpad).unwrap();
-    });
+        let tablet = tablet_device
+            .map(|x| {
+                spawn_listener(x, controller.clone(), Grab::WhileConsumed, |device| {
+                    let mut mapper = TabletMapper::new(device);
+                    move |x: &LinuxInputEvent| mapper.map(x)
+                })
+            })
+            .transpose()?;
 
-    Ok(handle)
+        let gamepad = gamepad_device
+            .map(|x| {
+                let gamepad_controller = controller.clone();
+                spawn_listener(x, controller.clone(), Grab::WhileConsumed, move |device| {
+                    if let Ok(mut controller) = gamepad_controller.lock() {
+                        controller.set_gamepad(gamepad_axes(device));
+                    }
+                    map_gamepad_event
+                })
+            })
+            .transpose()?;
+
+        // the first listener to fail fails the source
+        let listeners = [keyboard, mouse, touchpad, tablet, gamepad]
+            .into_iter()
+            .flatten()
+            .map(|x| async move { x.await? });
+        future::try_join_all(listeners).await?;
+
+        Ok(())
+    })
 }
 
+/// Spawns listener for the device, events are mapped with mapper `new_map`
+/// creates for the device.
 fn spawn_listener<F>(
-    device: PathBuf,
+    path: PathBuf,
     controller: Arc<Mutex<InputController>>,
-    map: F,
-) -> Result<JoinHandle<()>, Error>
+    grab: Grab,
+    new_map: impl FnOnce(&Device) -> F,
+) -> Result<JoinHandle<Result<(), Error>>, Error>
 where
     F: FnMut(&LinuxInputEvent) -> Option<LocalInputEvent> + Send + 'static,
 {
     let mut device = {
-        let file = File::open(device)?;
+        let file = File::open(&path)
+            .with_context(|| format!("failed to open input device at {:?}", path))?;
         let dev = Device::new_from_file(file)?;
         Ungrabber::from(dev)
     };
+    let map = new_map(&device);
 
     let handle = task::spawn_blocking(move || {
-        read_input_source(&mut device, controller, map).unwrap();
+        read_input_source(&mut device, controller, grab, map)
+            .with_context(|| format!("failed to read input device at {:?}", path))
     });
 
     Ok(handle)
@@ -152,15 +238,20 @@ fn map_keyboard_event(x: &LinuxInputEvent) -> Option<LocalInputEvent> {
         EventCode::EV_KEY(ev_key) => {
             let btn = MouseButton::from_ev_key(*ev_key);
             let key = KeyCode::from_ev_key(*ev_key);
-            match (value, btn, key) {
-                (1, Some(button), None) => LocalInputEvent::MouseButtonDown { button }.into(),
-                (0, Some(button), None) => LocalInputEvent::MouseButtonUp { button }.into(),
-                (1, None, Some(key)) => LocalInputEvent::KeyDown { key }.into(),
-                (2, None, Some(key)) => LocalInputEvent::KeyRepeat { key }.into(),
-                (0, None, Some(key)) => LocalInputEvent::KeyUp { key }.into(),
+            let usage = HidUsage::from_ev_key(*ev_key);
+            match (value, btn, key, usage) {
+                (1, Some(button), None, _) => LocalInputEvent::MouseButtonDown { button }.into(),
+                (0, Some(button), None, _) => LocalInputEvent::MouseButtonUp { button }.into(),
+                (1, None, Some(key), _) => LocalInputEvent::KeyDown { key }.into(),
+                (2, None, Some(key), _) => LocalInputEvent::KeyRepeat { key }.into(),
+                (0, None, Some(key), _) => LocalInputEvent::KeyUp { key }.into(),
+                (1, None, None, Some(usage)) => LocalInputEvent::RawKeyDown { usage }.into(),
+                (0, None, None, Some(usage)) => LocalInputEvent::RawKeyUp { usage }.into(),
+                // raw keys aren't repeated
+                (2, None, None, Some(_)) => None,
                 _ => {
                     warn!(
-                        "unexpected raw key {:?}, value {}, button {:?}, key {:?}",
+                        "ignoring unsupported key {:?}, value {}, button {:?}, key {:?}",
                         ev_key, value, btn, key
                     );
                     None
@@ -171,29 +262,504 @@ fn map_keyboard_event(x: &LinuxInputEvent) -> Option<LocalInputEvent> {
     }
 }
 
-fn map_mouse_event(x: &LinuxInputEvent) -> Option<LocalInputEvent> {
+/// Returns mapper for mouse events.
+///
+/// Devices with a high resolution wheel report scrolling in both resolutions,
+/// once a high resolution event is seen the low resolution ones are ignored.
+fn mouse_event_mapper() -> impl FnMut(&LinuxInputEvent) -> Option<LocalInputEvent> {
+    let mut hi_res = false;
+    move |x| {
+        if let EventCode::EV_REL(EV_REL::REL_WHEEL_HI_RES | EV_REL::REL_HWHEEL_HI_RES) =
+            x.event_code
+        {
+            hi_res = true;
+        }
+        map_mouse_event(x, hi_res)
+    }
+}
+
+fn map_mouse_event(x: &LinuxInputEvent, hi_res: bool) -> Option<LocalInputEvent> {
     let LinuxInputEvent {
         event_code, value, ..
     } = x;
     match event_code {
+        EventCode::EV_KEY(_) => map_keyboard_event(x),
         EventCode::EV_REL(ev_rel) => match ev_rel {
-            EV_REL::REL_WHEEL => match value.cmp(&0) {
-                Ordering::Less => LocalInputEvent::MouseScroll {
-                    direction: MouseScrollDirection::Down {
-                        clicks: *value as _,
-                    },
-                }
-                .into(),
-                Ordering::Equal => None,
-                Ordering::Greater => LocalInputEvent::MouseScroll {
-                    direction: MouseScrollDirection::Up {
-                        clicks: *value as _,
-                    },
-                }
-                .into(),
-            },
+            EV_REL::REL_WHEEL if !hi_res => map_scroll(
+                *value,
+                |clicks| MouseScrollDirection::Up { clicks },
+                |clicks| MouseScrollDirection::Down { clicks },
+            ),
+            EV_REL::REL_HWHEEL if !hi_res => map_scroll(
+                *value,
+                |clicks| MouseScrollDirection::Right { clicks },
+                |clicks| MouseScrollDirection::Left { clicks },
+            ),
+            EV_REL::REL_WHEEL_HI_RES => LocalInputEvent::MouseScrollHiRes {
+                axis: ScrollAxis::Vertical,
+                delta: saturate(*value),
+            }
+            .into(),
+            EV_REL::REL_HWHEEL_HI_RES => LocalInputEvent::MouseScrollHiRes {
+                axis: ScrollAxis::Horizontal,
+                delta: saturate(*value),
+            }
+            .into(),
             _ => None,
         },
         _ => None,
     }
 }
+
+/// Maps wheel value into scroll towards `positive` or `negative` direction.
+fn map_scroll(
+    value: i32,
+    positive: fn(u8) -> MouseScrollDirection,
+    negative: fn(u8) -> MouseScrollDirection,
+) -> Option<LocalInputEvent> {
+    let clicks = value.unsigned_abs().min(u8::MAX as _) as u8;
+    let direction = match value.cmp(&0) {
+        Ordering::Less => negative(clicks),
+        Ordering::Equal => return None,
+        Ordering::Greater => positive(clicks),
+    };
+    LocalInputEvent::MouseScroll { direction }.into()
+}
+
+fn saturate(value: i32) -> i16 {
+    value.clamp(i16::MIN as _, i16::MAX as _) as _
+}
+
+/// Maps events of tablets and touchscreens into absolute pointer events.
+///
+/// Axes are collected until the device reports a complete frame.
+#[derive(Debug)]
+struct TabletMapper {
+    x: Option<AbsInfo>,
+    y: Option<AbsInfo>,
+    pressure: Option<AbsInfo>,
+    tilt_x: Option<AbsInfo>,
+    tilt_y: Option<AbsInfo>,
+    pointer: AbsolutePointer,
+    changed: bool,
+}
+
+impl TabletMapper {
+    fn new(device: &impl DeviceWrapper) -> Self {
+        let info = |x| device.abs_info(&EventCode::EV_ABS(x));
+        Self::from_abs_info(
+            info(EV_ABS::ABS_X),
+            info(EV_ABS::ABS_Y),
+            info(EV_ABS::ABS_PRESSURE),
+            info(EV_ABS::ABS_TILT_X),
+            info(EV_ABS::ABS_TILT_Y),
+        )
+    }
+
+    fn from_abs_info(
+        x: Option<AbsInfo>,
+        y: Option<AbsInfo>,
+        pressure: Option<AbsInfo>,
+        tilt_x: Option<AbsInfo>,
+        tilt_y: Option<AbsInfo>,
+    ) -> Self {
+        let pointer = AbsolutePointer {
+            // devices without tool events are always in range
+            in_range: true,
+            pressure: pressure.as_ref().map(|_| 0),
+            tilt: (tilt_x.is_some() || tilt_y.is_some()).then(Tilt::default),
+            ..Default::default()
+        };
+        Self {
+            x,
+            y,
+            pressure,
+            tilt_x,
+            tilt_y,
+            pointer,
+            changed: false,
+        }
+    }
+
+    fn map(&mut self, x: &LinuxInputEvent) -> Option<LocalInputEvent> {
+        let LinuxInputEvent {
+            event_code, value, ..
+        } = x;
+        let value = *value;
+        let pointer = &mut self.pointer;
+        match event_code {
+            EventCode::EV_ABS(EV_ABS::ABS_X) => {
+                pointer.x = normalise(value, self.x.as_ref()?);
+            }
+            EventCode::EV_ABS(EV_ABS::ABS_Y) => {
+                pointer.y = normalise(value, self.y.as_ref()?);
+            }
+            EventCode::EV_ABS(EV_ABS::ABS_PRESSURE) => {
+                pointer.pressure = Some(normalise(value, self.pressure.as_ref()?));
+            }
+            EventCode::EV_ABS(EV_ABS::ABS_TILT_X) => {
+                let x = degrees(value, self.tilt_x.as_ref()?);
+                pointer.tilt.get_or_insert_with(Default::default).x = x;
+            }
+            EventCode::EV_ABS(EV_ABS::ABS_TILT_Y) => {
+                let y = degrees(value, self.tilt_y.as_ref()?);
+                pointer.tilt.get_or_insert_with(Default::default).y = y;
+            }
+            EventCode::EV_KEY(EV_KEY::BTN_TOUCH) => pointer.touch = value != 0,
+            EventCode::EV_KEY(
+                EV_KEY::BTN_TOOL_PEN
+                | EV_KEY::BTN_TOOL_RUBBER
+                | EV_KEY::BTN_TOOL_BRUSH
+                | EV_KEY::BTN_TOOL_PENCIL
+                | EV_KEY::BTN_TOOL_AIRBRUSH
+                | EV_KEY::BTN_TOOL_FINGER,
+            ) => pointer.in_range = value != 0,
+            EventCode::EV_SYN(EV_SYN::SYN_REPORT) if self.changed => {
+                self.changed = false;
+                return LocalInputEvent::AbsolutePointer { pointer: *pointer }.into();
+            }
+            _ => return None,
+        }
+        self.changed = true;
+        None
+    }
+}
+
+/// Returns ranges of the gamepad's axes.
+fn gamepad_axes(device: &impl DeviceWrapper) -> Vec<(GamepadAxis, AxisRange)> {
+    GamepadAxis::iter()
+        .filter_map(|axis| {
+            let info = device.abs_info(&EventCode::EV_ABS(axis.into()))?;
+            let range = AxisRange {
+                minimum: info.minimum,
+                maximum: info.maximum,
+                fuzz: info.fuzz,
+                flat: info.flat,
+            };
+            Some((axis, range))
+        })
+        .collect()
+}
+
+fn map_gamepad_event(x: &LinuxInputEvent) -> Option<LocalInputEvent> {
+    let LinuxInputEvent {
+        event_code, value, ..
+    } = x;
+    let event = match event_code {
+        EventCode::EV_KEY(ev_key) => {
+            let button = GamepadButton::from_ev_key(*ev_key)?;
+            match value {
+                1 => GamepadEvent::ButtonDown { button },
+                0 => GamepadEvent::ButtonUp { button },
+                _ => return None,
+            }
+        }
+        EventCode::EV_ABS(ev_abs) => GamepadEvent::AxisMove {
+            axis: GamepadAxis::from_ev_abs(*ev_abs)?,
+            value: *value,
+        },
+        EventCode::EV_SYN(EV_SYN::SYN_REPORT) => GamepadEvent::Sync,
+        _ => return None,
+    };
+    LocalInputEvent::Gamepad { event }.into()
+}
+
+/// Maps multitouch events of touchpads into gestures.
+///
+/// Contacts are tracked by their slot and handed to the recognizer on every
+/// complete frame.
+#[derive(Debug)]
+struct TouchpadMapper {
+    x: Option<AbsInfo>,
+    y: Option<AbsInfo>,
+    slot: i32,
+    contacts: BTreeMap<i32, Contact>,
+    recognizer: GestureRecognizer,
+}
+
+impl TouchpadMapper {
+    fn new(device: &impl DeviceWrapper) -> Self {
+        let info = |x| device.abs_info(&EventCode::EV_ABS(x));
+        let slot = info(EV_ABS::ABS_MT_SLOT)
+            .map(|x| x.value)
+            .unwrap_or_default();
+        Self {
+            slot,
+            ..Self::from_abs_info(
+                info(EV_ABS::ABS_MT_POSITION_X),
+                info(EV_ABS::ABS_MT_POSITION_Y),
+            )
+        }
+    }
+
+    fn from_abs_info(x: Option<AbsInfo>, y: Option<AbsInfo>) -> Self {
+        Self {
+            x,
+            y,
+            slot: 0,
+            contacts: Default::default(),
+            recognizer: Default::default(),
+        }
+    }
+
+    fn map(&mut self, x: &LinuxInputEvent) -> Option<LocalInputEvent> {
+        let LinuxInputEvent {
+            event_code, value, ..
+        } = x;
+        let value = *value;
+        match event_code {
+            EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT) => self.slot = value,
+            // lifted fingers have no tracking id
+            EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID) if value < 0 => {
+                self.contacts.remove(&self.slot);
+            }
+            EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID) => {
+                self.contacts.entry(self.slot).or_default();
+            }
+            EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X) => {
+                let x = normalise(value, self.x.as_ref()?) as f64;
+                self.contacts.entry(self.slot).or_default().x = x;
+            }
+            EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y) => {
+                let y = normalise(value, self.y.as_ref()?) as f64;
+                self.contacts.entry(self.slot).or_default().y = y;
+            }
+            EventCode::EV_SYN(EV_SYN::SYN_REPORT) => {
+                let contacts: Vec<_> = self.contacts.values().copied().collect();
+                let gesture = self.recognizer.frame(&contacts)?;
+                return LocalInputEvent::Gesture { gesture }.into();
+            }
+            _ => (),
+        }
+        None
+    }
+}
+
+/// Scales axis value into the full range of [u16].
+fn normalise(value: i32, info: &AbsInfo) -> u16 {
+    let range = (info.maximum as i64 - info.minimum as i64).max(1);
+    let value = value.clamp(info.minimum, info.maximum) as i64 - info.minimum as i64;
+    (value * u16::MAX as i64 / range) as _
+}
+
+/// Converts tilt axis value into degrees.
+fn degrees(value: i32, info: &AbsInfo) -> i8 {
+    // resolution is in units per radian, devices that don't set it report degrees
+    let degrees = if info.resolution > 0 {
+        (value as f64 / info.resolution as f64).to_degrees()
+    } else {
+        value as f64
+    };
+    degrees.round().clamp(-90.0, 90.0) as _
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::protocol::Gesture;
+    use evdev_rs::TimeVal;
+
+    fn rel(code: EV_REL, value: i32) -> LinuxInputEvent {
+        LinuxInputEvent::new(&TimeVal::new(0, 0), &EventCode::EV_REL(code), value)
+    }
+
+    fn abs(code: EV_ABS, value: i32) -> LinuxInputEvent {
+        LinuxInputEvent::new(&TimeVal::new(0, 0), &EventCode::EV_ABS(code), value)
+    }
+
+    fn syn() -> LinuxInputEvent {
+        LinuxInputEvent::new(
+            &TimeVal::new(0, 0),
+            &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
+            0,
+        )
+    }
+
+    fn abs_info(minimum: i32, maximum: i32, resolution: i32) -> Option<AbsInfo> {
+        Some(AbsInfo {
+            value: 0,
+            minimum,
+            maximum,
+            fuzz: 0,
+            flat: 0,
+            resolution,
+        })
+    }
+
+    fn key(code: EV_KEY, value: i32) -> LinuxInputEvent {
+        LinuxInputEvent::new(&TimeVal::new(0, 0), &EventCode::EV_KEY(code), value)
+    }
+
+    fn scroll(direction: MouseScrollDirection) -> Option<LocalInputEvent> {
+        LocalInputEvent::MouseScroll { direction }.into()
+    }
+
+    #[test]
+    fn test_map_scroll_sign() {
+        let mut map = mouse_event_mapper();
+        assert_eq!(
+            map(&rel(EV_REL::REL_WHEEL, 2)),
+            scroll(MouseScrollDirection::Up { clicks: 2 })
+        );
+        assert_eq!(
+            map(&rel(EV_REL::REL_WHEEL, -2)),
+            scroll(MouseScrollDirection::Down { clicks: 2 })
+        );
+        assert_eq!(
+            map(&rel(EV_REL::REL_HWHEEL, 1)),
+            scroll(MouseScrollDirection::Right { clicks: 1 })
+        );
+        assert_eq!(
+            map(&rel(EV_REL::REL_HWHEEL, -1)),
+            scroll(MouseScrollDirection::Left { clicks: 1 })
+        );
+        assert_eq!(
+            map(&rel(EV_REL::REL_WHEEL, -300)),
+            scroll(MouseScrollDirection::Down { clicks: 255 })
+        );
+    }
+
+    #[test]
+    fn test_map_hi_res_scroll() {
+        let mut map = mouse_event_mapper();
+        assert_eq!(
+            map(&rel(EV_REL::REL_WHEEL_HI_RES, -30)),
+            LocalInputEvent::MouseScrollHiRes {
+                axis: ScrollAxis::Vertical,
+                delta: -30,
+            }
+            .into()
+        );
+        // already reported in high resolution
+        assert_eq!(map(&rel(EV_REL::REL_WHEEL, -1)), None);
+    }
+
+    #[test]
+    fn test_tablet_mapper() {
+        let mut mapper = TabletMapper::from_abs_info(
+            abs_info(0, 1000, 0),
+            abs_info(-100, 100, 0),
+            abs_info(0, 255, 0),
+            abs_info(-64, 63, 57),
+            None,
+        );
+
+        // nothing until the frame completes
+        assert_eq!(mapper.map(&abs(EV_ABS::ABS_X, 500)), None);
+        assert_eq!(mapper.map(&abs(EV_ABS::ABS_Y, 100)), None);
+        assert_eq!(mapper.map(&abs(EV_ABS::ABS_PRESSURE, 255)), None);
+        assert_eq!(mapper.map(&abs(EV_ABS::ABS_TILT_X, -57)), None);
+        let pointer = AbsolutePointer {
+            x: u16::MAX / 2,
+            y: u16::MAX,
+            in_range: true,
+            touch: false,
+            pressure: Some(u16::MAX),
+            tilt: Some(Tilt { x: -57, y: 0 }),
+        };
+        assert_eq!(
+            mapper.map(&syn()),
+            LocalInputEvent::AbsolutePointer { pointer }.into()
+        );
+
+        // empty frames are dropped
+        assert_eq!(mapper.map(&syn()), None);
+
+        // the pen leaves
+        assert_eq!(mapper.map(&key(EV_KEY::BTN_TOOL_PEN, 0)), None);
+        assert_eq!(
+            mapper.map(&syn()),
+            LocalInputEvent::AbsolutePointer {
+                pointer: AbsolutePointer {
+                    in_range: false,
+                    ..pointer
+                }
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_touchpad_mapper() {
+        let mut mapper = TouchpadMapper::from_abs_info(abs_info(0, 1000, 0), abs_info(0, 1000, 0));
+        let mut frame = |fingers: &[(i32, i32, i32)]| {
+            let mut events = Vec::new();
+            for &(slot, x, y) in fingers {
+                events.push(abs(EV_ABS::ABS_MT_SLOT, slot));
+                events.push(abs(EV_ABS::ABS_MT_TRACKING_ID, slot));
+                events.push(abs(EV_ABS::ABS_MT_POSITION_X, x));
+                events.push(abs(EV_ABS::ABS_MT_POSITION_Y, y));
+            }
+            events.push(syn());
+            events
+                .iter()
+                .filter_map(|x| mapper.map(x))
+                .collect::<Vec<_>>()
+        };
+        let gesture = |gesture| vec![LocalInputEvent::Gesture { gesture }];
+
+        assert_eq!(
+            frame(&[(0, 100, 500), (1, 200, 500), (2, 300, 500)]),
+            vec![]
+        );
+        assert_eq!(
+            frame(&[(0, 100, 600), (1, 200, 600), (2, 300, 600)]),
+            gesture(Gesture::SwipeBegin { fingers: 3 })
+        );
+        assert_eq!(
+            frame(&[(0, 100, 700), (1, 200, 700), (2, 300, 700)]),
+            gesture(Gesture::SwipeUpdate { dx: 0, dy: 13107 })
+        );
+
+        // fingers lift
+        let events = [
+            abs(EV_ABS::ABS_MT_SLOT, 0),
+            abs(EV_ABS::ABS_MT_TRACKING_ID, -1),
+            abs(EV_ABS::ABS_MT_SLOT, 1),
+            abs(EV_ABS::ABS_MT_TRACKING_ID, -1),
+            abs(EV_ABS::ABS_MT_SLOT, 2),
+            abs(EV_ABS::ABS_MT_TRACKING_ID, -1),
+            syn(),
+        ];
+        let events: Vec<_> = events.iter().filter_map(|x| mapper.map(x)).collect();
+        assert_eq!(events, gesture(Gesture::SwipeEnd { cancelled: false }));
+    }
+
+    #[test]
+    fn test_map_gamepad_event() {
+        let gamepad = |event| Some(LocalInputEvent::Gamepad { event });
+        let events: Vec<_> = [
+            key(EV_KEY::BTN_SOUTH, 1),
+            abs(EV_ABS::ABS_X, -1200),
+            abs(EV_ABS::ABS_HAT0Y, 1),
+            syn(),
+            key(EV_KEY::BTN_SOUTH, 0),
+            // not a gamepad button
+            key(EV_KEY::KEY_A, 1),
+        ]
+        .iter()
+        .map(map_gamepad_event)
+        .collect();
+        assert_eq!(
+            events,
+            vec![
+                gamepad(GamepadEvent::ButtonDown {
+                    button: GamepadButton::South
+                }),
+                gamepad(GamepadEvent::AxisMove {
+                    axis: GamepadAxis::X,
+                    value: -1200
+                }),
+                gamepad(GamepadEvent::AxisMove {
+                    axis: GamepadAxis::HatY,
+                    value: 1
+                }),
+                gamepad(GamepadEvent::Sync),
+                gamepad(GamepadEvent::ButtonUp {
+                    button: GamepadButton::South
+                }),
+                None,
+            ]
+        );
+    }
+}
@@ -1,19 +1,49 @@
-mod controller;
+//! Input sources read local input and pass it to the input controller, which
+//! decides what's relayed to the client.
 
+mod block;
+mod channel;
+
+pub mod controller;
 pub mod event;
+pub mod script;
+
+pub use self::channel::{ChannelInputSource, ChannelInputSourceHandle};
 
+use self::controller::InputController;
+use crate::transport::protocol::LockState;
+use anyhow::Error;
 use cfg_if::cfg_if;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+/// Reads lock state of the host.
+pub type LockStateReader = Arc<dyn Fn() -> Result<LockState, Error> + Send + Sync>;
+
+/// Source of local input.
+pub trait InputSource: Send + 'static {
+    /// Starts reading local input.
+    ///
+    /// Each event is passed to the controller, input is captured from this
+    /// host while it says so. The task fails if input can't be read.
+    fn start(self, controller: Arc<Mutex<InputController>>) -> JoinHandle<Result<(), Error>>;
+
+    /// Returns reader of this host's lock state, called when control switches
+    /// to the client.
+    fn lock_state_reader(&self) -> LockStateReader;
+}
 
 cfg_if! {
     if #[cfg(target_os = "linux")] {
+        mod gesture;
         mod linux;
-        pub use self::linux::start;
+        pub use self::linux::SystemInputSource;
     }
 }
 
 cfg_if! {
     if #[cfg(target_os = "windows")] {
         mod windows;
-        pub use self::windows::start;
+        pub use self::windows::SystemInputSource;
     }
 }
@@ -0,0 +1,465 @@
+use crate::{server::config::ScriptConfig, transport::protocol::InputEvent};
+use anyhow::{anyhow, Error};
+use rhai::{serde, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{debug, info, warn};
+
+/// How often script files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Name of the function scripts define to see events.
+const HOOK_FN: &str = "on_event";
+
+/// Timeouts in a row after which a script is disabled until it changes.
+const MAX_TIMEOUTS: u32 = 3;
+
+/// Newly compiled versions of the scripts, by index.
+type Pending = Mutex<Vec<Option<AST>>>;
+
+/// State of the input controller shown to scripts.
+#[derive(Clone, Copy, Debug)]
+pub struct ControllerState {
+    pub relay: bool,
+    pub gamepad_relay: bool,
+}
+
+/// Runs events through user scripts before they're relayed.
+///
+/// Scripts define `fn on_event(event, state)`, returning nothing passes the
+/// event through, returning an array emits its events instead. A map bound
+/// to `this` is kept between calls. Scripts that fail or run out of time
+/// pass events through unchanged, ones that keep running out of time are
+/// disabled until they change. Changed scripts are compiled on a background
+/// thread.
+pub struct ScriptHook {
+    engine: Engine,
+    scripts: Vec<Script>,
+    budget: Duration,
+    /// Deadline of the running call, checked by the engine while it runs.
+    deadline: Arc<Mutex<Option<Instant>>>,
+    pending: Arc<Pending>,
+    started_at: Instant,
+}
+
+struct Script {
+    path: PathBuf,
+    /// Last successfully compiled version of the script, none while it's
+    /// disabled.
+    ast: Option<AST>,
+    memory: Dynamic,
+    /// Set after the script fails, so failures of a call per event don't
+    /// flood the log.
+    failing: bool,
+    timeouts: u32,
+}
+
+/// Compiles scripts that changed since they were last compiled.
+struct Watcher {
+    engine: Engine,
+    paths: Vec<PathBuf>,
+    modified: Vec<Option<SystemTime>>,
+    loaded: Vec<bool>,
+}
+
+impl ScriptHook {
+    pub fn new(config: ScriptConfig) -> Self {
+        let deadline: Arc<Mutex<Option<Instant>>> = Default::default();
+
+        let mut engine = Engine::new();
+        {
+            let deadline = deadline.clone();
+            engine.on_progress(move |_| match *deadline.lock().unwrap() {
+                Some(x) if Instant::now() > x => Some("time budget exceeded".into()),
+                _ => None,
+            });
+        }
+
+        let mut watcher = Watcher::new(config.paths.clone());
+        let scripts = config
+            .paths
+            .into_iter()
+            .zip(watcher.check())
+            .map(|(path, ast)| Script {
+                path,
+                ast,
+                memory: Map::new().into(),
+                failing: false,
+                timeouts: 0,
+            })
+            .collect::<Vec<_>>();
+
+        let pending = Arc::new(Mutex::new(vec![None; scripts.len()]));
+        if !scripts.is_empty() {
+            let pending = Arc::downgrade(&pending);
+            thread::spawn(move || watcher.run(pending));
+        }
+
+        Self {
+            engine,
+            scripts,
+            budget: Duration::from_millis(config.budget_ms),
+            deadline,
+            pending,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Returns events the scripts turn the event into.
+    pub fn apply(&mut self, event: InputEvent, state: ControllerState) -> Vec<InputEvent> {
+        if self.scripts.is_empty() {
+            return vec![event];
+        }
+
+        self.swap_pending();
+
+        let state = self.state_map(state);
+
+        let mut events = vec![event];
+        for script in &mut self.scripts {
+            // each script gets its own budget, a slow one doesn't eat into the next
+            *self.deadline.lock().unwrap() = Some(Instant::now() + self.budget);
+            events = events
+                .into_iter()
+                .flat_map(|event| script.call(&self.engine, event, state.clone()))
+                .collect();
+        }
+
+        *self.deadline.lock().unwrap() = None;
+        events
+    }
+
+    /// Switches to scripts the watcher compiled since the last event.
+    fn swap_pending(&mut self) {
+        let mut pending = self.pending.lock().unwrap();
+        for (script, ast) in self.scripts.iter_mut().zip(pending.iter_mut()) {
+            if let Some(ast) = ast.take() {
+                script.ast = Some(ast);
+                script.failing = false;
+                script.timeouts = 0;
+            }
+        }
+    }
+
+    fn state_map(&self, state: ControllerState) -> Dynamic {
+        let ControllerState {
+            relay,
+            gamepad_relay,
+        } = state;
+        let mut map = Map::new();
+        map.insert("relay".into(), relay.into());
+        map.insert("gamepad_relay".into(), gamepad_relay.into());
+        let time = self.started_at.elapsed().as_millis() as rhai::INT;
+        map.insert("time".into(), time.into());
+        map.into()
+    }
+}
+
+impl Script {
+    fn call(&mut self, engine: &Engine, event: InputEvent, state: Dynamic) -> Vec<InputEvent> {
+        let ast = match &self.ast {
+            Some(x) => x,
+            None => return vec![event],
+        };
+        match call_hook(engine, ast, &mut self.memory, event, state) {
+            Ok(events) => {
+                self.failing = false;
+                self.timeouts = 0;
+                events
+            }
+            Err(err) => {
+                if is_timeout(&err) {
+                    self.timeouts += 1;
+                } else {
+                    self.timeouts = 0;
+                }
+                if self.timeouts >= MAX_TIMEOUTS {
+                    warn!(path = ?self.path, "script keeps running out of time, disabling it until it changes");
+                    self.ast = None;
+                } else if !self.failing {
+                    warn!(path = ?self.path, ?err, "script failed, passing events through it unchanged");
+                } else {
+                    debug!(path = ?self.path, ?err, "script failed");
+                }
+                self.failing = true;
+                vec![event]
+            }
+        }
+    }
+}
+
+impl Watcher {
+    fn new(paths: Vec<PathBuf>) -> Self {
+        let n = paths.len();
+        Self {
+            engine: Engine::new(),
+            paths,
+            modified: vec![None; n],
+            loaded: vec![false; n],
+        }
+    }
+
+    /// Hands changed scripts over to the hook until it's dropped.
+    fn run(mut self, pending: Weak<Pending>) {
+        loop {
+            thread::sleep(RELOAD_INTERVAL);
+            let changed = self.check();
+            let pending = match pending.upgrade() {
+                Some(x) => x,
+                None => return,
+            };
+            let mut pending = pending.lock().unwrap();
+            for (slot, ast) in pending.iter_mut().zip(changed) {
+                if ast.is_some() {
+                    *slot = ast;
+                }
+            }
+        }
+    }
+
+    /// Returns new versions of scripts that changed since the last check.
+    fn check(&mut self) -> Vec<Option<AST>> {
+        let mut changed = Vec::new();
+        for (i, path) in self.paths.iter().enumerate() {
+            let modified = fs::metadata(path).and_then(|x| x.modified()).ok();
+            if modified.is_some() && modified == self.modified[i] {
+                changed.push(None);
+                continue;
+            }
+            self.modified[i] = modified;
+            match compile(&self.engine, path) {
+                Ok(ast) => {
+                    info!(?path, "loaded script");
+                    self.loaded[i] = true;
+                    changed.push(Some(ast));
+                }
+                Err(err) if self.loaded[i] => {
+                    warn!(
+                        ?path,
+                        ?err,
+                        "failed to reload script, keeping the previous version"
+                    );
+                    changed.push(None);
+                }
+                Err(err) => {
+                    warn!(?path, ?err, "failed to load script, events pass through it");
+                    changed.push(None);
+                }
+            }
+        }
+        changed
+    }
+}
+
+fn compile(engine: &Engine, path: &PathBuf) -> Result<AST, Error> {
+    let source = fs::read_to_string(path)?;
+    let ast = engine.compile(source)?;
+    if !ast.iter_functions().any(|x| x.name == HOOK_FN) {
+        return Err(anyhow!("script doesn't define {}", HOOK_FN));
+    }
+    Ok(ast)
+}
+
+fn is_timeout(err: &Error) -> bool {
+    matches!(
+        err.downcast_ref::<Box<EvalAltResult>>().map(|x| &**x),
+        Some(EvalAltResult::ErrorTerminated(..))
+    )
+}
+
+fn call_hook(
+    engine: &Engine,
+    ast: &AST,
+    memory: &mut Dynamic,
+    event: InputEvent,
+    state: Dynamic,
+) -> Result<Vec<InputEvent>, Error> {
+    let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(memory);
+    let event_dyn = serde::to_dynamic(event)?;
+    let result: Dynamic = engine.call_fn_with_options(
+        options,
+        &mut Scope::new(),
+        ast,
+        HOOK_FN,
+        (event_dyn, state),
+    )?;
+
+    if result.is_unit() {
+        return Ok(vec![event]);
+    }
+    let events = match result.try_cast::<rhai::Array>() {
+        Some(xs) => xs,
+        None => return Err(anyhow!("{} must return nothing or an array", HOOK_FN)),
+    };
+    events
+        .iter()
+        .map(|x| serde::from_dynamic(x).map_err(Into::into))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::protocol::{down, up, KeyCode};
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const STATE: ControllerState = ControllerState {
+        relay: true,
+        gamepad_relay: false,
+    };
+
+    fn script(source: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(source.as_bytes()).unwrap();
+        file
+    }
+
+    fn new_hook(file: &NamedTempFile, budget_ms: u64) -> ScriptHook {
+        ScriptHook::new(ScriptConfig {
+            paths: vec![file.path().to_owned()],
+            budget_ms,
+        })
+    }
+
+    #[test]
+    fn test_transform_and_memory() {
+        // hold caps lock and use h as left arrow
+        let file = script(
+            r#"
+            fn on_event(event, state) {
+                if "KeyDown" in event && event.KeyDown.key == "CapsLock" {
+                    this.caps = true;
+                    return [];
+                }
+                if "KeyUp" in event && event.KeyUp.key == "CapsLock" {
+                    this.caps = false;
+                    return [];
+                }
+                if this.caps == true && "KeyDown" in event && event.KeyDown.key == "H" {
+                    return [#{ KeyDown: #{ key: "Left" } }];
+                }
+            }
+            "#,
+        );
+        let mut hook = new_hook(&file, 50);
+        assert_eq!(hook.apply(down(KeyCode::H), STATE), vec![down(KeyCode::H)]);
+        assert_eq!(hook.apply(down(KeyCode::CapsLock), STATE), vec![]);
+        assert_eq!(
+            hook.apply(down(KeyCode::H), STATE),
+            vec![down(KeyCode::Left)]
+        );
+        assert_eq!(hook.apply(up(KeyCode::CapsLock), STATE), vec![]);
+        assert_eq!(hook.apply(down(KeyCode::H), STATE), vec![down(KeyCode::H)]);
+    }
+
+    #[test]
+    fn test_misbehaving_script_passes_events_through() {
+        let file = script("fn on_event(event, state) { loop {} }");
+        let mut hook = new_hook(&file, 10);
+        assert_eq!(hook.apply(down(KeyCode::A), STATE), vec![down(KeyCode::A)]);
+
+        let file = script(r#"fn on_event(event, state) { [#{ NotAnEvent: 1 }] }"#);
+        let mut hook = new_hook(&file, 10);
+        assert_eq!(hook.apply(down(KeyCode::A), STATE), vec![down(KeyCode::A)]);
+
+        let file = script("not a script {");
+        let mut hook = new_hook(&file, 10);
+        assert_eq!(hook.apply(down(KeyCode::A), STATE), vec![down(KeyCode::A)]);
+    }
+
+    #[test]
+    fn test_timeouts_disable_script() {
+        let file = script(
+            r#"
+            fn on_event(event, state) {
+                if "KeyDown" in event && event.KeyDown.key == "A" {
+                    loop {}
+                }
+                []
+            }
+            "#,
+        );
+        let mut hook = new_hook(&file, 10);
+        for _ in 0..MAX_TIMEOUTS {
+            assert_eq!(hook.apply(down(KeyCode::A), STATE), vec![down(KeyCode::A)]);
+        }
+        // disabled scripts pass everything through
+        assert_eq!(hook.apply(down(KeyCode::B), STATE), vec![down(KeyCode::B)]);
+    }
+
+    #[test]
+    fn test_budget_per_script() {
+        // each script takes most of the budget, both together run over it
+        let slow = script(
+            r#"
+            fn on_event(event, state) {
+                let start = timestamp();
+                while start.elapsed < 0.2 {}
+            }
+            "#,
+        );
+        let fast = script(
+            r#"
+            fn on_event(event, state) {
+                let start = timestamp();
+                while start.elapsed < 0.2 {}
+                [#{ KeyDown: #{ key: "B" } }]
+            }
+            "#,
+        );
+        let mut hook = ScriptHook::new(ScriptConfig {
+            paths: vec![slow.path().to_owned(), fast.path().to_owned()],
+            budget_ms: 300,
+        });
+        for _ in 0..MAX_TIMEOUTS {
+            assert_eq!(hook.apply(down(KeyCode::A), STATE), vec![down(KeyCode::B)]);
+        }
+    }
+
+    #[test]
+    fn test_reload() {
+        let file = script("fn on_event(event, state) { [] }");
+        let mut hook = new_hook(&file, 50);
+        assert_eq!(hook.apply(down(KeyCode::A), STATE), vec![]);
+
+        fs::write(file.path(), "fn on_event(event, state) { [event, event] }").unwrap();
+        // make sure the change is seen even on coarse file times
+        let later = SystemTime::now() + Duration::from_secs(10);
+        file.as_file().set_modified(later).unwrap();
+        let deadline = Instant::now() + RELOAD_INTERVAL * 5;
+        while hook.apply(down(KeyCode::A), STATE).is_empty() {
+            assert!(Instant::now() < deadline, "script wasn't reloaded");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            hook.apply(down(KeyCode::A), STATE),
+            vec![down(KeyCode::A), down(KeyCode::A)]
+        );
+    }
+
+    #[test]
+    fn test_watcher_keeps_previous_version() {
+        let file = script("fn on_event(event, state) { [] }");
+        let mut watcher = Watcher::new(vec![file.path().to_owned()]);
+        assert!(watcher.check()[0].is_some());
+        assert!(watcher.check()[0].is_none());
+
+        // broken versions aren't handed over
+        fs::write(file.path(), "fn on_event(").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        file.as_file().set_modified(later).unwrap();
+        assert!(watcher.check()[0].is_none());
+
+        fs::write(file.path(), "fn on_event(event, state) { }").unwrap();
+        file.as_file()
+            .set_modified(later + Duration::from_secs(10))
+            .unwrap();
+        assert!(watcher.check()[0].is_some());
+    }
+}
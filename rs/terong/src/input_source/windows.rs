@@ -1,12 +1,23 @@
-use super::event::{LocalInputEvent, MousePosition};
+use super::{
+    controller::InputController,
+    event::{LocalInputEvent, MousePosition},
+    InputSource, LockStateReader,
+};
 use crate::{
-    input_source::controller::InputController,
+    lock_state,
     transport::protocol::{
-        windows::VirtualKey, InputEvent, KeyCode, MouseButton, MouseScrollDirection,
+        windows::VirtualKey, HidUsage, KeyCode, MouseButton, MouseScrollDirection, ScrollAxis,
     },
 };
-use std::{cell::Cell, cmp, ffi::c_void, time::Duration};
-use tokio::{sync::mpsc, task};
+use anyhow::{anyhow, ensure, Context, Error};
+use std::{
+    cell::Cell,
+    cmp,
+    ffi::c_void,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::task;
 use tracing::{debug, error, warn};
 use windows::Win32::Foundation::POINT;
 use windows::Win32::System::Performance::QueryPerformanceCounter;
@@ -16,16 +27,26 @@ use windows::Win32::{
     UI::WindowsAndMessaging::{
         CallNextHookEx, DispatchMessageW, GetCursorPos, GetMessageW, PostMessageW, SetCursorPos,
         SetWindowsHookExW, SystemParametersInfoW, UnhookWindowsHookEx, HC_ACTION, HHOOK,
-        KBDLLHOOKSTRUCT, MOUSEHOOKSTRUCTEX_MOUSE_DATA, MSG, MSLLHOOKSTRUCT, SPI_GETWORKAREA,
-        SYSTEM_PARAMETERS_INFO_UPDATE_FLAGS, WHEEL_DELTA, WH_KEYBOARD_LL, WH_MOUSE_LL, WM_APP,
-        WM_KEYDOWN, WM_KEYUP, WM_LBUTTONDOWN, WM_LBUTTONUP, WM_MBUTTONDOWN, WM_MBUTTONUP,
-        WM_MOUSEMOVE, WM_MOUSEWHEEL, WM_RBUTTONDOWN, WM_RBUTTONUP, WM_SYSKEYDOWN, WM_SYSKEYUP,
-        WM_XBUTTONDOWN, WM_XBUTTONUP, XBUTTON1, XBUTTON2,
+        KBDLLHOOKSTRUCT, LLKHF_EXTENDED, MOUSEHOOKSTRUCTEX_MOUSE_DATA, MSG, MSLLHOOKSTRUCT,
+        SPI_GETWORKAREA, SYSTEM_PARAMETERS_INFO_UPDATE_FLAGS, WHEEL_DELTA, WH_KEYBOARD_LL,
+        WH_MOUSE_LL, WM_APP, WM_KEYDOWN, WM_KEYUP, WM_LBUTTONDOWN, WM_LBUTTONUP, WM_MBUTTONDOWN,
+        WM_MBUTTONUP, WM_MOUSEHWHEEL, WM_MOUSEMOVE, WM_MOUSEWHEEL, WM_RBUTTONDOWN, WM_RBUTTONUP,
+        WM_SYSKEYDOWN, WM_SYSKEYUP, WM_XBUTTONDOWN, WM_XBUTTONUP, XBUTTON1, XBUTTON2,
     },
 };
 
-pub fn start(event_tx: mpsc::Sender<InputEvent>) -> task::JoinHandle<()> {
-    task::spawn_blocking(|| run_input_source(event_tx))
+/// Reads input from low level keyboard and mouse hooks.
+#[derive(Default, Debug)]
+pub struct SystemInputSource;
+
+impl InputSource for SystemInputSource {
+    fn start(self, controller: Arc<Mutex<InputController>>) -> task::JoinHandle<Result<(), Error>> {
+        task::spawn_blocking(|| run_input_source(controller))
+    }
+
+    fn lock_state_reader(&self) -> LockStateReader {
+        Arc::new(lock_state::read)
+    }
 }
 
 /// Application defined message code.
@@ -37,23 +58,22 @@ enum MessageCode {
     InputEvent = WM_APP,
 }
 
-fn run_input_source(event_tx: mpsc::Sender<InputEvent>) {
-    let mut controller = InputController::new(event_tx);
-
+fn run_input_source(controller: Arc<Mutex<InputController>>) -> Result<(), Error> {
     // get module handle for this application
-    let module = unsafe { GetModuleHandleW(None) }.expect("failed to get current module handle");
-    assert!(!module.is_invalid());
+    let module =
+        unsafe { GetModuleHandleW(None) }.context("failed to get current module handle")?;
+    ensure!(!module.is_invalid(), "current module handle is invalid");
 
     // set low level mouse hook
     let _mouse_hook = Unhooker(
         unsafe { SetWindowsHookExW(WH_MOUSE_LL, Some(mouse_hook_proc), module, 0) }
-            .expect("failed to set mouse hook"),
+            .context("failed to set mouse hook")?,
     );
 
     // set low level keyboard hook
     let _keyboard_hook = Unhooker(
         unsafe { SetWindowsHookExW(WH_KEYBOARD_LL, Some(keyboard_hook_proc), module, 0) }
-            .expect("failed to set keyboard hook"),
+            .context("failed to set keyboard hook")?,
     );
 
     let mut msg = MSG::default();
@@ -83,11 +103,10 @@ fn run_input_source(event_tx: mpsc::Sender<InputEvent>) {
         // wait for message
         let ok = unsafe { GetMessageW(&mut msg, None, 0, 0) };
         match ok.0 {
-            -1 => unsafe {
-                let err = GetLastError();
-                error!(?err);
-                break;
-            },
+            -1 => {
+                let err = unsafe { GetLastError() };
+                return Err(anyhow!("failed to get message, {:?}", err));
+            }
             0 => {
                 debug!("received quit message");
                 break;
@@ -107,7 +126,10 @@ fn run_input_source(event_tx: mpsc::Sender<InputEvent>) {
                         };
 
                         // propagate input event to the controller
-                        let should_consume_input = controller.on_input_event(event).unwrap();
+                        let should_consume_input = controller
+                            .lock()
+                            .map_err(|_| anyhow!("input controller panicked"))?
+                            .on_input_event(event)?;
 
                         if should_consume_input != consume_input() {
                             // consuming input is turned off, restore old cursor position
@@ -125,6 +147,8 @@ fn run_input_source(event_tx: mpsc::Sender<InputEvent>) {
             }
         }
     }
+
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
@@ -260,26 +284,8 @@ extern "system" fn mouse_hook_proc(ncode: i32, wparam: WPARAM, lparam: LPARAM) -
         WM_XBUTTONUP => get_mouse_button(hook_event.mouseData)
             .map(|button| LocalInputEvent::MouseButtonUp { button }),
 
-        WM_MOUSEWHEEL => {
-            let delta = {
-                let mut bytes = [0; 2];
-                bytes.copy_from_slice(&hook_event.mouseData.0.to_be_bytes()[..2]);
-                i16::from_be_bytes(bytes)
-            };
-            let clicks = delta / WHEEL_DELTA as i16;
-            let direction = match clicks.cmp(&0) {
-                cmp::Ordering::Less => MouseScrollDirection::Down {
-                    clicks: clicks.abs() as _,
-                }
-                .into(),
-                cmp::Ordering::Equal => None,
-                cmp::Ordering::Greater => MouseScrollDirection::Up {
-                    clicks: clicks.abs() as _,
-                }
-                .into(),
-            };
-            direction.map(|direction| LocalInputEvent::MouseScroll { direction })
-        }
+        WM_MOUSEWHEEL => get_scroll(hook_event.mouseData, ScrollAxis::Vertical),
+        WM_MOUSEHWHEEL => get_scroll(hook_event.mouseData, ScrollAxis::Horizontal),
 
         action => {
             warn!(?action, "unhandled mouse event");
@@ -317,11 +323,15 @@ extern "system" fn keyboard_hook_proc(ncode: i32, wparam: WPARAM, lparam: LPARAM
 
     // map hook event to input event
     let event = match wparam.0 as u32 {
-        WM_KEYDOWN | WM_SYSKEYDOWN => KeyCode::from_virtual_key(VirtualKey(hook_event.vkCode as _))
-            .map(|key| LocalInputEvent::KeyDown { key }),
+        WM_KEYDOWN | WM_SYSKEYDOWN => get_key(&hook_event)
+            .map(|key| LocalInputEvent::KeyDown { key })
+            .or_else(|| {
+                get_raw_key(&hook_event).map(|usage| LocalInputEvent::RawKeyDown { usage })
+            }),
 
-        WM_KEYUP | WM_SYSKEYUP => KeyCode::from_virtual_key(VirtualKey(hook_event.vkCode as _))
-            .map(|key| LocalInputEvent::KeyUp { key }),
+        WM_KEYUP | WM_SYSKEYUP => get_key(&hook_event)
+            .map(|key| LocalInputEvent::KeyUp { key })
+            .or_else(|| get_raw_key(&hook_event).map(|usage| LocalInputEvent::RawKeyUp { usage })),
 
         action => {
             warn!(?action, "unhandled keyboard event");
@@ -365,6 +375,30 @@ fn post_input_event(event: LocalInputEvent, time: Duration) {
     }
 }
 
+/// Maps wheel delta into scroll, deltas that aren't whole clicks are kept in
+/// high resolution.
+fn get_scroll(data: MOUSEHOOKSTRUCTEX_MOUSE_DATA, axis: ScrollAxis) -> Option<LocalInputEvent> {
+    let delta = {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(&data.0.to_be_bytes()[..2]);
+        i16::from_be_bytes(bytes)
+    };
+    if delta % WHEEL_DELTA as i16 != 0 {
+        return LocalInputEvent::MouseScrollHiRes { axis, delta }.into();
+    }
+    let clicks = (delta / WHEEL_DELTA as i16)
+        .unsigned_abs()
+        .min(u8::MAX as _) as u8;
+    let direction = match (axis, delta.cmp(&0)) {
+        (_, cmp::Ordering::Equal) => return None,
+        (ScrollAxis::Vertical, cmp::Ordering::Greater) => MouseScrollDirection::Up { clicks },
+        (ScrollAxis::Vertical, cmp::Ordering::Less) => MouseScrollDirection::Down { clicks },
+        (ScrollAxis::Horizontal, cmp::Ordering::Greater) => MouseScrollDirection::Right { clicks },
+        (ScrollAxis::Horizontal, cmp::Ordering::Less) => MouseScrollDirection::Left { clicks },
+    };
+    LocalInputEvent::MouseScroll { direction }.into()
+}
+
 fn get_mouse_button(data: MOUSEHOOKSTRUCTEX_MOUSE_DATA) -> Option<MouseButton> {
     let mut bytes = [0; 2];
     bytes.copy_from_slice(&data.0.to_be_bytes()[..2]);
@@ -376,6 +410,25 @@ fn get_mouse_button(data: MOUSEHOOKSTRUCTEX_MOUSE_DATA) -> Option<MouseButton> {
     }
 }
 
+fn get_key(event: &KBDLLHOOKSTRUCT) -> Option<KeyCode> {
+    KeyCode::from_key_event(VirtualKey(event.vkCode as _), get_scan_code(event))
+}
+
+/// Returns HID usage of a key that has no [KeyCode].
+fn get_raw_key(event: &KBDLLHOOKSTRUCT) -> Option<HidUsage> {
+    HidUsage::from_scan_code(get_scan_code(event))
+}
+
+/// Returns the set 1 scan code, extended ones have 0xE0 in their high byte.
+fn get_scan_code(event: &KBDLLHOOKSTRUCT) -> u16 {
+    let prefix = if event.flags.0 & LLKHF_EXTENDED.0 != 0 {
+        0xE000
+    } else {
+        0
+    };
+    prefix | event.scanCode as u16
+}
+
 /// RAII for unhooking hook.
 ///
 /// Calls [UnhookWindowsHookEx] on drop.
@@ -1,7 +1,23 @@
+mod clipboard;
 mod config;
-mod input_source;
+mod file_transfer;
+mod lock_state;
 mod logging;
+mod metrics;
+mod recording;
 mod transport;
 
+pub mod cert;
 pub mod client;
+pub mod input_source;
+pub mod replay;
 pub mod server;
+
+// types the configs and builders take
+pub use self::{
+    config::{
+        ClipboardConfig, LogConfig, LogFormat, MetricsConfig, NoiseConfig, RotationConfig,
+        RotationPeriod, SecureChannel, WireFormat,
+    },
+    transport::{codec::Codec, protocol::LockState, tls::TlsMaterial, Certificate, PrivateKey},
+};
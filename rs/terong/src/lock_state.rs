@@ -0,0 +1,191 @@
+//! Keyboard lock state of the host machine.
+//!
+//! The server sends its lock state to the client when control switches to the
+//! client, and the client reports its own whenever it changes. The client's
+//! input sink toggles lock keys that don't match the server's.
+
+use crate::transport::protocol::LockState;
+use anyhow::Error;
+use cfg_if::cfg_if;
+use std::time::Duration;
+use tokio::{
+    sync::watch,
+    task,
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, warn};
+
+/// Interval the client's lock state is checked for changes at.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        use anyhow::anyhow;
+        use evdev_rs::{enums::{EventCode, EV_LED}, Device, DeviceWrapper};
+        use std::{
+            fs::{self, File},
+            path::{Path, PathBuf},
+        };
+
+        /// Reads lock state from the LEDs of an input device.
+        pub fn read(device: &Path) -> Result<LockState, Error> {
+            let device = Device::new_from_file(File::open(device)?)?;
+            let led = |x| device.event_value(&EventCode::EV_LED(x)) == Some(1);
+            Ok(LockState {
+                caps_lock: led(EV_LED::LED_CAPSL),
+                num_lock: led(EV_LED::LED_NUML),
+                scroll_lock: led(EV_LED::LED_SCROLLL),
+            })
+        }
+
+        /// Finds a physical keyboard, a device with a caps lock LED. Virtual
+        /// devices have no physical path and are skipped.
+        pub fn find_keyboard() -> Result<PathBuf, Error> {
+            for entry in fs::read_dir("/dev/input")? {
+                let path = entry?.path();
+                let is_event = path
+                    .file_name()
+                    .and_then(|x| x.to_str())
+                    .is_some_and(|x| x.starts_with("event"));
+                if !is_event {
+                    continue;
+                }
+                let device = match File::open(&path).map(Device::new_from_file) {
+                    Ok(Ok(x)) => x,
+                    _ => continue,
+                };
+                if device.phys().is_some()
+                    && device.has_event_code(&EventCode::EV_LED(EV_LED::LED_CAPSL)) {
+                    return Ok(path);
+                }
+            }
+            Err(anyhow!("no keyboard with LEDs found"))
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(target_os = "windows")] {
+        use windows::Win32::{
+            System::Threading::{AttachThreadInput, GetCurrentThreadId},
+            UI::{
+                Input::KeyboardAndMouse::{
+                    GetKeyState, VIRTUAL_KEY, VK_CAPITAL, VK_NUMLOCK, VK_SCROLL,
+                },
+                WindowsAndMessaging::{GetForegroundWindow, GetWindowThreadProcessId},
+            },
+        };
+
+        /// Reads lock state of the keyboard.
+        pub fn read() -> Result<LockState, Error> {
+            // key state is kept per input queue and this thread's isn't fed any
+            // input, the foreground window's follows the keyboard
+            let current = unsafe { GetCurrentThreadId() };
+            let foreground = unsafe { GetWindowThreadProcessId(GetForegroundWindow(), None) };
+            let attached = foreground != 0
+                && foreground != current
+                && unsafe { AttachThreadInput(current, foreground, true) }.as_bool();
+            // low-order bit is set if the key is toggled on
+            let toggled = |x: VIRTUAL_KEY| unsafe { GetKeyState(x.0 as _) } & 1 == 1;
+            let state = LockState {
+                caps_lock: toggled(VK_CAPITAL),
+                num_lock: toggled(VK_NUMLOCK),
+                scroll_lock: toggled(VK_SCROLL),
+            };
+            if attached {
+                unsafe { AttachThreadInput(current, foreground, false) };
+            }
+            Ok(state)
+        }
+    }
+}
+
+/// Reads lock state with `read` every time control switches to the client.
+pub fn read_on_switch<F>(
+    mut relay_rx: watch::Receiver<bool>,
+    read: F,
+) -> watch::Receiver<Option<LockState>>
+where
+    F: Fn() -> Result<LockState, Error> + Clone + Send + 'static,
+{
+    let (lock_tx, lock_rx) = watch::channel(None);
+    task::spawn(async move {
+        while relay_rx.changed().await.is_ok() {
+            if !*relay_rx.borrow_and_update() {
+                continue;
+            }
+            match task::spawn_blocking(read.clone()).await {
+                Ok(Ok(state)) => {
+                    debug!(?state, "control switched, lock state read");
+                    lock_tx.send_replace(Some(state));
+                }
+                Ok(Err(err)) => warn!(?err, "failed to read lock state"),
+                Err(err) => warn!(?err, "lock state read task failed"),
+            }
+        }
+    });
+    lock_rx
+}
+
+/// Reads lock state with `read` periodically, receivers are notified when it
+/// changes.
+pub fn poll<F>(lock_tx: watch::Sender<Option<LockState>>, read: F)
+where
+    F: Fn() -> Result<LockState, Error> + Clone + Send + 'static,
+{
+    task::spawn(async move {
+        let mut ticker = interval(POLL_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        while !lock_tx.is_closed() {
+            ticker.tick().await;
+            match task::spawn_blocking(read.clone()).await {
+                Ok(Ok(state)) => {
+                    lock_tx.send_if_modified(|x| {
+                        let changed = *x != Some(state);
+                        *x = Some(state);
+                        changed
+                    });
+                }
+                Ok(Err(err)) => debug!(?err, "failed to read lock state"),
+                Err(err) => warn!(?err, "lock state read task failed"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_read_on_switch() {
+        let (relay_tx, relay_rx) = watch::channel(false);
+        let state = LockState {
+            caps_lock: true,
+            ..Default::default()
+        };
+        let mut lock_rx = read_on_switch(relay_rx, move || Ok(state));
+
+        relay_tx.send_replace(true);
+        lock_rx.changed().await.unwrap();
+        assert_eq!(*lock_rx.borrow_and_update(), Some(state));
+    }
+
+    #[tokio::test]
+    async fn test_poll_notifies_on_change() {
+        let state = Arc::new(Mutex::new(LockState::default()));
+        let (lock_tx, mut lock_rx) = watch::channel(None);
+        poll(lock_tx, {
+            let state = state.clone();
+            move || Ok(*state.lock().unwrap())
+        });
+
+        lock_rx.changed().await.unwrap();
+        assert_eq!(*lock_rx.borrow_and_update(), Some(LockState::default()));
+
+        state.lock().unwrap().num_lock = true;
+        lock_rx.changed().await.unwrap();
+        assert!(lock_rx.borrow_and_update().unwrap().num_lock);
+    }
+}
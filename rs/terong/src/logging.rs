@@ -1,16 +1,193 @@
+use crate::config::{LogConfig, LogFormat, RotationConfig, RotationPeriod};
+use anyhow::{Context, Error};
+use std::{
+    env,
+    fmt::{self, Debug},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tracing::metadata::LevelFilter;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{
+    fmt::{self as subscriber_fmt, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
 
+/// Writes info logs as text to stdout, or as `RUST_LOG` says.
 pub fn init_tracing() {
-    tracing_subscriber::fmt()
-        .with_env_filter(env_filter())
-        .init();
+    // stdout is always there
+    init_tracing_with(&LogConfig::default()).unwrap();
 }
 
-fn env_filter() -> EnvFilter {
+/// Sets up the global tracing subscriber as configured.
+pub fn init_tracing_with(cfg: &LogConfig) -> Result<(), Error> {
+    let writer = match &cfg.file {
+        Some(path) => {
+            let file = RollingFile::open(path.clone(), cfg.rotation.clone())
+                .with_context(|| format!("failed to open log file at {:?}", path))?;
+            BoxMakeWriter::new(Mutex::new(file))
+        }
+        None => BoxMakeWriter::new(io::stdout),
+    };
+
+    tracing_subscriber::registry()
+        .with(new_layer(cfg.format, writer))
+        .with(env_filter(&cfg.level)?)
+        .try_init()?;
+
+    Ok(())
+}
+
+/// Formats logs as `format` says into `writer`.
+pub fn new_layer<S>(format: LogFormat, writer: BoxMakeWriter) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    let layer = subscriber_fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+fn env_filter(level: &str) -> Result<EnvFilter, Error> {
+    if env::var_os(EnvFilter::DEFAULT_ENV).is_some() {
+        return Ok(EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .from_env_lossy());
+    }
     EnvFilter::builder()
-        .with_default_directive(LevelFilter::INFO.into())
-        .from_env_lossy()
+        .parse(level)
+        .with_context(|| format!("invalid log level {:?}", level))
+}
+
+/// Log file that's rotated by size or time.
+///
+/// Rotated files are renamed to `<path>.1`, shifting older ones up to
+/// `<path>.<max_files>`.
+#[derive(Debug)]
+struct RollingFile {
+    path: PathBuf,
+    rotation: RotationConfig,
+    file: File,
+    size: u64,
+    /// Period the file was written in, `None` if it's never rotated by time.
+    period: Option<u64>,
+}
+
+impl RollingFile {
+    fn open(path: PathBuf, rotation: RotationConfig) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // a file left from an earlier period is rotated on the first write
+        let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        Ok(Self {
+            period: rotation.period.index(modified),
+            path,
+            rotation,
+            file,
+            size: metadata.len(),
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let max_files = self.rotation.max_files;
+        if max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // the oldest is dropped, missing ones are skipped
+            fs::remove_file(self.rotated_path(max_files)).ok();
+            for n in (1..max_files).rev() {
+                fs::rename(self.rotated_path(n), self.rotated_path(n + 1)).ok();
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = self.rotation.period.index(SystemTime::now());
+        let oversized = self
+            .rotation
+            .max_size
+            .is_some_and(|x| self.size > 0 && self.size + buf.len() as u64 > x);
+        if period != self.period || oversized {
+            // logs keep going to the current file, rotation is retried after
+            // another max size or period
+            if let Err(err) = self.rotate() {
+                eprintln!("failed to rotate log file at {:?}, {}", self.path, err);
+                self.size = 0;
+            }
+            self.period = period;
+        }
+
+        let size = self.file.write(buf)?;
+        self.size += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl RotationPeriod {
+    /// Returns the number of the period since the Unix epoch the time is in.
+    fn index(self, time: SystemTime) -> Option<u64> {
+        let secs = match self {
+            RotationPeriod::Never => return None,
+            RotationPeriod::Hourly => 60 * 60,
+            RotationPeriod::Daily => 24 * 60 * 60,
+        };
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Some(since_epoch.as_secs() / secs)
+    }
+}
+
+/// Values that may carry keystrokes.
+pub trait Redact: Debug {
+    /// Formats the value without keys it carries.
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+/// Logs the value with keys redacted, unless the second field, whether
+/// keystrokes are logged, is true.
+pub struct Redacted<'a, T: ?Sized>(pub &'a T, pub bool);
+
+impl<T: Redact + ?Sized> Debug for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.1 {
+            Debug::fmt(self.0, f)
+        } else {
+            self.0.fmt_redacted(f)
+        }
+    }
 }
 
 #[macro_export]
@@ -26,3 +203,90 @@ macro_rules! log_error {
         }
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::protocol::{InputEvent, KeyCode, Pong, ServerMessage};
+
+    #[test]
+    fn test_keys_are_redacted() {
+        let key = ServerMessage::Event(InputEvent::KeyDown { key: KeyCode::P });
+        assert_eq!(
+            format!("{:?}", Redacted(&key, false)),
+            "Event(KeyDown { .. })"
+        );
+        assert_eq!(format!("{:?}", Redacted(&key, true)), format!("{:?}", key));
+
+        let mouse_move = ServerMessage::Event(InputEvent::MouseMove { dx: 1, dy: -1 });
+        assert_eq!(
+            format!("{:?}", Redacted(&mouse_move, false)),
+            format!("{:?}", mouse_move)
+        );
+        let pong = ServerMessage::Pong(Pong { counter: 3 });
+        assert_eq!(
+            format!("{:?}", Redacted(&pong, false)),
+            format!("{:?}", pong)
+        );
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs/terong.log");
+        let rotation = RotationConfig {
+            max_size: Some(10),
+            period: RotationPeriod::Never,
+            max_files: 2,
+        };
+        let mut file = RollingFile::open(path.clone(), rotation).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        let read = |n| fs::read_to_string(file.rotated_path(n)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(read(1), "third\n");
+        assert_eq!(read(2), "second\n");
+        assert!(!file.rotated_path(3).exists());
+    }
+
+    #[test]
+    fn test_rotate_by_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("terong.log");
+        let rotation = RotationConfig {
+            period: RotationPeriod::Daily,
+            ..Default::default()
+        };
+        let mut file = RollingFile::open(path.clone(), rotation).unwrap();
+        file.write_all(b"today\n").unwrap();
+        assert!(!file.rotated_path(1).exists());
+
+        // pretend the file was written on the first day of the epoch
+        file.period = Some(0);
+        file.write_all(b"tomorrow\n").unwrap();
+        assert_eq!(fs::read_to_string(file.rotated_path(1)).unwrap(), "today\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "tomorrow\n");
+    }
+
+    #[test]
+    fn test_rotate_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("terong.log");
+        let rotation = RotationConfig {
+            max_size: Some(10),
+            period: RotationPeriod::Never,
+            max_files: 1,
+        };
+        let mut file = RollingFile::open(path.clone(), rotation).unwrap();
+        // the file can't be renamed over a non-empty directory
+        fs::create_dir(file.rotated_path(1)).unwrap();
+        fs::write(file.rotated_path(1).join("other"), "").unwrap();
+
+        for line in ["first\n", "second\n", "third\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\nsecond\nthird\n");
+    }
+}
@@ -0,0 +1,410 @@
+//! Metrics of the applications, served over HTTP in Prometheus text format.
+//!
+//! Each server or client counts its own metrics.
+
+use crate::{log_error, transport::protocol::InputEvent};
+use anyhow::{bail, Context, Error};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::{self, JoinHandle},
+    time::timeout,
+};
+use tracing::{debug, info};
+
+/// Upper bounds, in seconds, of the heartbeat round trip time buckets.
+const RTT_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Time a scraper has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests longer than this are rejected.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// Reads the number of messages waiting in a queue, `None` once the queue is
+/// gone.
+type QueueProbe = Box<dyn Fn() -> Option<usize> + Send>;
+
+/// Metrics of a server or client.
+#[derive(Default)]
+pub struct Metrics {
+    /// Relayed events by their type.
+    events_relayed: Mutex<BTreeMap<&'static str, u64>>,
+    bytes_sent: AtomicU64,
+    sessions: AtomicI64,
+    sessions_total: AtomicU64,
+    reconnect_attempts: AtomicU64,
+    heartbeat_rtt: Mutex<Histogram>,
+    relay_time: Mutex<RelayTime>,
+    queues: Mutex<Vec<(&'static str, QueueProbe)>>,
+}
+
+#[derive(Debug)]
+struct Histogram {
+    /// Observations per bucket, the last one is for those above every bound.
+    counts: [u64; RTT_BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: [0; RTT_BUCKETS.len() + 1],
+            sum: 0.0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = RTT_BUCKETS
+            .iter()
+            .position(|x| value <= *x)
+            .unwrap_or(RTT_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+}
+
+/// Time input has been relayed for.
+#[derive(Default, Debug)]
+struct RelayTime {
+    total: Duration,
+    /// When relaying started, `None` if input isn't relayed.
+    since: Option<Instant>,
+}
+
+impl RelayTime {
+    fn elapsed(&self) -> Duration {
+        self.total + self.since.map(|x| x.elapsed()).unwrap_or_default()
+    }
+}
+
+impl Metrics {
+    /// Counts an event handed on, to the client by the server or to the input
+    /// sink by the client.
+    pub fn event_relayed(&self, event: &InputEvent) {
+        let kind: &'static str = event.into();
+        *lock(&self.events_relayed).entry(kind).or_default() += 1;
+    }
+
+    pub fn bytes_sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn reconnect_attempted(&self) {
+        self.reconnect_attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn heartbeat_rtt(&self, rtt: Duration) {
+        lock(&self.heartbeat_rtt).observe(rtt.as_secs_f64());
+    }
+
+    /// Starts or stops counting time spent relaying.
+    pub fn relay_switched(&self, relay: bool) {
+        let mut time = lock(&self.relay_time);
+        match (relay, time.since) {
+            (true, None) => time.since = Some(Instant::now()),
+            (false, Some(since)) => {
+                time.total += since.elapsed();
+                time.since = None;
+            }
+            _ => (),
+        }
+    }
+
+    /// Counts a session until the returned guard is dropped.
+    pub fn session_started(self: &Arc<Self>) -> SessionGuard {
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        self.sessions_total.fetch_add(1, Ordering::Relaxed);
+        SessionGuard(self.clone())
+    }
+
+    /// Reports how many messages wait in the queue `tx` sends to, for as long
+    /// as the queue exists.
+    pub fn watch_queue<T: Send + 'static>(&self, name: &'static str, tx: &mpsc::Sender<T>) {
+        let tx = tx.downgrade();
+        let probe = move || {
+            let tx = tx.upgrade()?;
+            Some(tx.max_capacity() - tx.capacity())
+        };
+        lock(&self.queues).push((name, Box::new(probe)));
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+/// Counts an established session while it's alive.
+#[derive(Debug)]
+pub struct SessionGuard(Arc<Metrics>);
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.0.sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    // the metrics are plain numbers, they're still usable after a panic
+    mutex.lock().unwrap_or_else(|x| x.into_inner())
+}
+
+impl Metrics {
+    /// Renders the metrics in Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP terong_events_relayed_total Input events relayed, by type.\n");
+        out.push_str("# TYPE terong_events_relayed_total counter\n");
+        for (kind, count) in lock(&self.events_relayed).iter() {
+            writeln!(
+                out,
+                "terong_events_relayed_total{{type=\"{}\"}} {}",
+                kind, count
+            )
+            .ok();
+        }
+
+        let counters = [
+            (
+                "terong_bytes_sent_total",
+                "Bytes sent to the peer.",
+                self.bytes_sent.load(Ordering::Relaxed),
+            ),
+            (
+                "terong_sessions_total",
+                "Sessions established with a peer.",
+                self.sessions_total.load(Ordering::Relaxed),
+            ),
+            (
+                "terong_reconnect_attempts_total",
+                "Attempts to reconnect to the server after a failed connection.",
+                self.reconnect_attempts.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, help, value) in counters {
+            writeln!(out, "# HELP {} {}", name, help).ok();
+            writeln!(out, "# TYPE {} counter", name).ok();
+            writeln!(out, "{} {}", name, value).ok();
+        }
+
+        out.push_str("# HELP terong_sessions Sessions currently established.\n");
+        out.push_str("# TYPE terong_sessions gauge\n");
+        writeln!(
+            out,
+            "terong_sessions {}",
+            self.sessions.load(Ordering::Relaxed)
+        )
+        .ok();
+
+        out.push_str(
+            "# HELP terong_relay_seconds_total Time input has been relayed to the client.\n",
+        );
+        out.push_str("# TYPE terong_relay_seconds_total counter\n");
+        writeln!(
+            out,
+            "terong_relay_seconds_total {}",
+            lock(&self.relay_time).elapsed().as_secs_f64()
+        )
+        .ok();
+
+        out.push_str(
+            "# HELP terong_heartbeat_rtt_seconds Round trip time of heartbeats to the server.\n",
+        );
+        out.push_str("# TYPE terong_heartbeat_rtt_seconds histogram\n");
+        {
+            let rtt = lock(&self.heartbeat_rtt);
+            let mut cumulative = 0;
+            for (bound, count) in RTT_BUCKETS.iter().zip(rtt.counts) {
+                cumulative += count;
+                writeln!(
+                    out,
+                    "terong_heartbeat_rtt_seconds_bucket{{le=\"{}\"}} {}",
+                    bound, cumulative
+                )
+                .ok();
+            }
+            cumulative += rtt.counts[RTT_BUCKETS.len()];
+            writeln!(
+                out,
+                "terong_heartbeat_rtt_seconds_bucket{{le=\"+Inf\"}} {}",
+                cumulative
+            )
+            .ok();
+            writeln!(out, "terong_heartbeat_rtt_seconds_sum {}", rtt.sum).ok();
+            writeln!(out, "terong_heartbeat_rtt_seconds_count {}", cumulative).ok();
+        }
+
+        out.push_str("# HELP terong_queue_depth Messages waiting in a queue.\n");
+        out.push_str("# TYPE terong_queue_depth gauge\n");
+        {
+            let mut queues = lock(&self.queues);
+            let mut depths = BTreeMap::<_, usize>::new();
+            queues.retain(|(name, probe)| match probe() {
+                Some(depth) => {
+                    *depths.entry(*name).or_default() += depth;
+                    true
+                }
+                None => false,
+            });
+            for (name, depth) in depths {
+                writeln!(out, "terong_queue_depth{{queue=\"{}\"}} {}", name, depth).ok();
+            }
+        }
+
+        out
+    }
+}
+
+/// Serves the metrics at `/metrics` over HTTP.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<JoinHandle<()>, Error> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind metrics endpoint at {}", addr))?;
+    info!("serving metrics at http://{}/metrics", addr);
+
+    Ok(task::spawn(async move {
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(x) => x,
+                Err(err) => {
+                    debug!(?err, "failed to accept metrics request");
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            task::spawn(async move {
+                if let Err(err) = respond(stream, &metrics).await {
+                    debug!(?peer_addr, "failed to serve metrics");
+                    log_error!(err);
+                }
+            });
+        }
+    }))
+}
+
+/// Answers a single request, the connection is closed afterwards.
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<(), Error> {
+    let head = timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .context("metrics request timed out")??;
+
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_owned()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Reads the request line and headers.
+async fn read_head(stream: &mut TcpStream) -> Result<String, Error> {
+    let mut buf = Vec::new();
+    loop {
+        if buf.windows(4).any(|x| x == b"\r\n\r\n") {
+            break;
+        }
+        if buf.len() > MAX_REQUEST_LEN {
+            bail!("metrics request is too long");
+        }
+        let mut chunk = [0; 1024];
+        let size = stream.read(&mut chunk).await?;
+        if size == 0 {
+            bail!("metrics request ended early");
+        }
+        buf.extend_from_slice(&chunk[..size]);
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener as StdTcpListener;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.001);
+        histogram.observe(0.03);
+        histogram.observe(2.0);
+        assert_eq!(histogram.counts[0], 1);
+        assert_eq!(histogram.counts[5], 1);
+        assert_eq!(histogram.counts[RTT_BUCKETS.len()], 1);
+        assert!((histogram.sum - 2.031).abs() < 1e-9);
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let addr = StdTcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let server = serve(addr, metrics.clone()).await.unwrap();
+
+        let (tx, _rx) = mpsc::channel(4);
+        tx.send(()).await.unwrap();
+        metrics.watch_queue("test", &tx);
+        metrics.event_relayed(&InputEvent::MouseMove { dx: 1, dy: 1 });
+        let session = metrics.session_started();
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("terong_events_relayed_total{type=\"MouseMove\"}"));
+        assert!(response.contains("terong_queue_depth{queue=\"test\"} 1\n"));
+        assert!(response.contains("terong_heartbeat_rtt_seconds_bucket{le=\"+Inf\"}"));
+        assert!(response.contains("terong_sessions 1\n"));
+        drop(session);
+        assert!(metrics.render().contains("terong_sessions 0\n"));
+
+        // other instances count their own
+        assert!(!Metrics::default().render().contains("MouseMove"));
+
+        // the queue is gone with its channel
+        drop(tx);
+        let response = get(addr, "/metrics").await;
+        assert!(!response.contains("queue=\"test\""));
+
+        let response = get(addr, "/other").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        server.abort();
+    }
+}
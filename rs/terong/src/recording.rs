@@ -0,0 +1,231 @@
+//! Recordings of input event streams.
+//!
+//! A recording is a JSON lines file. The first line is a header with the
+//! format version, each following line is an event with milliseconds since the
+//! recording started. Local events are recorded as the input source sees them,
+//! relayed events as they're sent to the client, so a recording of both can be
+//! replayed through the input controller and checked against its output.
+
+use crate::{input_source::event::LocalInputEvent, transport::protocol::InputEvent};
+use anyhow::{bail, ensure, Error};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::File,
+    io::{BufRead, LineWriter, Write},
+    path::Path,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+use tracing::warn;
+
+/// Version of the recording format.
+pub const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Header {
+    version: u32,
+}
+
+/// Recorded event.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Record {
+    /// Time since the recording started, in milliseconds.
+    pub time_ms: u64,
+    #[serde(flatten)]
+    pub event: RecordedEvent,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordedEvent {
+    /// Event from the input source, before the input controller.
+    Local(LocalInputEvent),
+    /// Event the input controller relays.
+    Relayed(InputEvent),
+}
+
+/// Writes events to a recording.
+///
+/// Events are written by a background thread so writing doesn't hold input
+/// back. Dropping the recorder waits for the events to be written.
+pub struct Recorder {
+    record_tx: Option<mpsc::Sender<Record>>,
+    writer: Option<JoinHandle<()>>,
+    started_at: Instant,
+}
+
+impl Recorder {
+    /// Creates a recording file, replacing an existing one.
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        // written line by line so a recording of a crash is complete
+        let file = LineWriter::new(File::create(path)?);
+        Self::new(file)
+    }
+
+    pub fn new(mut writer: impl Write + Send + 'static) -> Result<Self, Error> {
+        write_header(&mut writer)?;
+        let (record_tx, record_rx) = mpsc::channel();
+        let writer = thread::spawn(move || {
+            for record in record_rx {
+                if let Err(err) = write_record(&mut writer, &record) {
+                    warn!(?err, "failed to write recording");
+                    return;
+                }
+            }
+            writer.flush().ok();
+        });
+        Ok(Self {
+            record_tx: Some(record_tx),
+            writer: Some(writer),
+            started_at: Instant::now(),
+        })
+    }
+
+    /// Fails if an earlier event couldn't be written.
+    pub fn record(&mut self, event: RecordedEvent) -> Result<(), Error> {
+        let time_ms = self.started_at.elapsed().as_millis() as u64;
+        let sent = match &self.record_tx {
+            Some(x) => x.send(Record { time_ms, event }).is_ok(),
+            None => false,
+        };
+        ensure!(sent, "recording writer stopped");
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // the writer stops once the channel is closed
+        self.record_tx.take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("started_at", &self.started_at)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Writes the header recordings start with.
+pub fn write_header(writer: &mut impl Write) -> Result<(), Error> {
+    write_line(writer, &Header { version: VERSION })
+}
+
+pub fn write_record(writer: &mut impl Write, record: &Record) -> Result<(), Error> {
+    write_line(writer, record)
+}
+
+fn write_line(writer: &mut impl Write, value: &impl Serialize) -> Result<(), Error> {
+    serde_json::to_writer(&mut *writer, value)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Reads records of a recording.
+pub fn read(reader: impl BufRead) -> Result<Vec<Record>, Error> {
+    let mut lines = reader.lines().filter(|x| match x {
+        Ok(x) => !x.trim().is_empty(),
+        Err(_) => true,
+    });
+
+    let header: Header = match lines.next() {
+        Some(x) => serde_json::from_str(&x?)?,
+        None => bail!("recording is empty"),
+    };
+    if header.version != VERSION {
+        bail!("unsupported recording version {}", header.version);
+    }
+
+    lines.map(|x| Ok(serde_json::from_str(&x?)?)).collect()
+}
+
+/// Reads a recording from `tests/fixtures`.
+#[cfg(test)]
+pub fn fixture(name: &str) -> Vec<Record> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    read(std::io::BufReader::new(File::open(path).unwrap())).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::protocol::KeyCode;
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    /// Writer tests can look into after it's moved into the recorder.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let buf = SharedBuf::default();
+        let mut recorder = Recorder::new(buf.clone()).unwrap();
+        let events = [
+            RecordedEvent::Local(LocalInputEvent::KeyDown { key: KeyCode::A }),
+            RecordedEvent::Relayed(InputEvent::KeyDown { key: KeyCode::A }),
+            RecordedEvent::Local(LocalInputEvent::MouseMove((1, -1).into())),
+        ];
+        for event in events {
+            recorder.record(event).unwrap();
+        }
+        drop(recorder);
+
+        let buf = buf.0.lock().unwrap().clone();
+        let text = String::from_utf8(buf.clone()).unwrap();
+        assert!(text.starts_with("{\"version\":1}\n"));
+
+        let records = read(Cursor::new(buf)).unwrap();
+        assert_eq!(
+            records.iter().map(|x| x.event).collect::<Vec<_>>(),
+            events.to_vec()
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let recording = r#"
+            {"version":1}
+            {"time_ms":5,"local":{"KeyDown":{"key":"RightCtrl"}}}
+            {"time_ms":9,"relayed":{"MouseMove":{"dx":1,"dy":2}}}
+        "#;
+        assert_eq!(
+            read(Cursor::new(recording.trim())).unwrap(),
+            vec![
+                Record {
+                    time_ms: 5,
+                    event: RecordedEvent::Local(LocalInputEvent::KeyDown {
+                        key: KeyCode::RightCtrl
+                    }),
+                },
+                Record {
+                    time_ms: 9,
+                    event: RecordedEvent::Relayed(InputEvent::MouseMove { dx: 1, dy: 2 }),
+                },
+            ]
+        );
+
+        assert!(read(Cursor::new(r#"{"version":2}"#)).is_err());
+    }
+}
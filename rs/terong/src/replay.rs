@@ -0,0 +1,228 @@
+//! Replays recordings of input event streams, see [crate::recording].
+
+use crate::{
+    client::input_sink::{self, SinkCommand, SystemInputSink},
+    config::Config,
+    input_source::{controller::InputController, script::ScriptHook},
+    logging::init_tracing,
+    recording::{self, Record, RecordedEvent},
+    server::config::{BlockConfig, ScriptConfig},
+};
+use anyhow::{anyhow, Error};
+use std::{
+    env,
+    fs::File,
+    io::{self, BufReader},
+    path::PathBuf,
+    process, thread,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, watch},
+    task,
+};
+
+const USAGE: &str = "\
+usage:
+  terong-replay <recording path> [--config <path>] [--speed <factor>] [--sink]
+
+Local events of the recording are replayed through the input controller with
+the scripts and block rules of the server config at path, or with default
+server settings. Events it relays are printed as a recording, or with --sink
+emulated on this machine at the recording's pace sped up by factor.";
+
+/// Run the replay application.
+pub async fn run() {
+    init_tracing();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = match parse_args(&args) {
+        Some(x) => x,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    replay_file(args).await.unwrap();
+}
+
+#[derive(Debug)]
+struct Args {
+    path: PathBuf,
+    config: Option<PathBuf>,
+    speed: f64,
+    sink: bool,
+}
+
+fn parse_args(args: &[String]) -> Option<Args> {
+    let mut path = None;
+    let mut config = None;
+    let mut speed = 1.0;
+    let mut sink = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config = Some(args.next()?.into()),
+            "--speed" => {
+                speed = args.next()?.parse().ok().filter(|x: &f64| *x > 0.0)?;
+            }
+            "--sink" => sink = true,
+            x if path.is_none() && !x.starts_with("--") => path = Some(x.into()),
+            _ => return None,
+        }
+    }
+
+    Some(Args {
+        path: path?,
+        config,
+        speed,
+        sink,
+    })
+}
+
+async fn replay_file(args: Args) -> Result<(), Error> {
+    let records = recording::read(BufReader::new(File::open(&args.path)?))?;
+    let (scripts, block) = match &args.config {
+        Some(path) => {
+            let cfg = Config::read_server(path).await?;
+            (cfg.scripts, cfg.block)
+        }
+        None => Default::default(),
+    };
+
+    if !args.sink {
+        return task::spawn_blocking(move || {
+            let mut out = io::stdout().lock();
+            recording::write_header(&mut out)?;
+            replay(&records, None, scripts, block, |x| {
+                recording::write_record(&mut out, &x)
+            })
+        })
+        .await?;
+    }
+
+    let (cmd_tx, cmd_rx) = mpsc::channel(1);
+    let (lock_tx, _) = watch::channel(None);
+    let sink = input_sink::start(
+        SystemInputSink,
+        cmd_rx,
+        lock_tx,
+        Default::default(),
+        Default::default(),
+    );
+
+    task::spawn_blocking(move || {
+        replay(&records, Some(args.speed), scripts, block, |x| {
+            match x.event {
+                RecordedEvent::Relayed(event) => cmd_tx
+                    .blocking_send(SinkCommand::Event(event))
+                    .map_err(|_| anyhow!("input sink stopped")),
+                RecordedEvent::Local(_) => Ok(()),
+            }
+        })
+    })
+    .await??;
+
+    sink.await?
+}
+
+/// Replays local events of the records through an input controller with the
+/// scripts and block rules, `relayed` is called with records of the events it
+/// relays.
+///
+/// The controller sees events at their recorded times. Replay waits for them
+/// at the recording's pace sped up by `speed`, or doesn't wait if it's None.
+pub fn replay(
+    records: &[Record],
+    speed: Option<f64>,
+    scripts: ScriptConfig,
+    block: BlockConfig,
+    mut relayed: impl FnMut(Record) -> Result<(), Error>,
+) -> Result<(), Error> {
+    // large enough for events relayed for a single local event
+    let (event_tx, mut event_rx) = mpsc::channel(64);
+    let (relay_tx, _) = watch::channel(false);
+    let scripts = ScriptHook::new(scripts);
+    let mut controller = InputController::new(event_tx, relay_tx, scripts, block);
+
+    let started_at = Instant::now();
+    for record in records {
+        let event = match record.event {
+            RecordedEvent::Local(x) => x,
+            // the output of the recorded replay, not its input
+            RecordedEvent::Relayed(_) => continue,
+        };
+
+        let time = Duration::from_millis(record.time_ms);
+        if let Some(speed) = speed {
+            let due = started_at + time.div_f64(speed);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+
+        controller.on_input_event_at(event, started_at + time)?;
+
+        while let Ok(event) = event_rx.try_recv() {
+            relayed(Record {
+                time_ms: record.time_ms,
+                event: RecordedEvent::Relayed(event),
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replays the fixture and checks the relayed events against the recorded
+    /// ones.
+    fn check_fixture(name: &str) {
+        let records = recording::fixture(name);
+        let expected: Vec<_> = records
+            .iter()
+            .filter(|x| matches!(x.event, RecordedEvent::Relayed(_)))
+            .copied()
+            .collect();
+        let mut actual = Vec::new();
+        replay(
+            &records,
+            None,
+            Default::default(),
+            Default::default(),
+            |x| {
+                actual.push(x);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_fixtures() {
+        check_fixture("hotkey_double_tap.jsonl");
+        check_fixture("hotkey_slow_taps.jsonl");
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = |xs: &[&str]| parse_args(&xs.iter().map(|x| x.to_string()).collect::<Vec<_>>());
+        let parsed = args(&["a.jsonl", "--speed", "4", "--sink"]).unwrap();
+        assert_eq!(parsed.path, PathBuf::from("a.jsonl"));
+        assert_eq!(parsed.config, None);
+        assert_eq!(parsed.speed, 4.0);
+        assert!(parsed.sink);
+        let parsed = args(&["--config", "terong.toml", "a.jsonl"]).unwrap();
+        assert_eq!(parsed.config, Some(PathBuf::from("terong.toml")));
+        assert!(args(&[]).is_none());
+        assert!(args(&["a.jsonl", "--config"]).is_none());
+        assert!(args(&["a.jsonl", "--speed", "0"]).is_none());
+        assert!(args(&["a.jsonl", "b.jsonl"]).is_none());
+    }
+}
@@ -1,3 +1,7 @@
+use crate::{
+    config::{ClipboardConfig, LogConfig, MetricsConfig, NoiseConfig, SecureChannel, WireFormat},
+    transport::protocol::KeyCode,
+};
 use cfg_if::cfg_if;
 use serde::Deserialize;
 use std::path::PathBuf;
@@ -6,22 +10,130 @@ use std::path::PathBuf;
 pub struct ServerConfig {
     pub port: u16,
 
-    pub tls_cert_path: PathBuf,
-    pub tls_key_path: PathBuf,
+    #[serde(default)]
+    pub channel: SecureChannel,
 
-    pub client_tls_cert_path: PathBuf,
+    /// Required if channel is TLS.
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+
+    pub client_tls_cert_path: Option<PathBuf>,
+
+    /// Required if channel is Noise.
+    pub noise: Option<NoiseConfig>,
+
+    #[serde(default)]
+    pub wire_format: WireFormat,
+
+    #[serde(default)]
+    pub clipboard: ClipboardConfig,
+
+    /// Where files sent by the peer are saved, file transfer from the peer is
+    /// rejected if not set.
+    pub download_dir: Option<PathBuf>,
+
+    #[serde(default)]
+    pub scripts: ScriptConfig,
+
+    #[serde(default)]
+    pub block: BlockConfig,
+
+    /// Log keys of input events, they're redacted otherwise.
+    #[serde(default)]
+    pub log_keystrokes: bool,
+
+    /// Records local and relayed input events to this file, keys included.
+    /// Replay it with `terong-replay`.
+    pub record: Option<PathBuf>,
+
+    /// Serves metrics if set.
+    pub metrics: Option<MetricsConfig>,
+
+    #[serde(default)]
+    pub log: LogConfig,
 
     #[cfg(target_os = "linux")]
     pub linux: LinuxConfig,
 }
 
+/// Scripts that see input events before they're relayed.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct ScriptConfig {
+    /// Scripts run in order, changes to them are picked up while running.
+    pub paths: Vec<PathBuf>,
+    /// Time in milliseconds each script may take for an event, scripts running
+    /// out of it a few times in a row are disabled until they change.
+    pub budget_ms: u64,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        Self {
+            paths: Vec::new(),
+            budget_ms: 5,
+        }
+    }
+}
+
+/// Keys that are never relayed.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct BlockConfig {
+    /// Don't relay key presses of the hotkeys that switch relays.
+    pub swallow_hotkeys: bool,
+    pub rules: Vec<BlockRule>,
+}
+
+impl Default for BlockConfig {
+    fn default() -> Self {
+        Self {
+            swallow_hotkeys: true,
+            rules: Vec::new(),
+        }
+    }
+}
+
+/// Blocks a key, or the last key of a chord while the others are held.
+#[derive(Clone, Deserialize, Debug)]
+pub struct BlockRule {
+    pub keys: Vec<KeyCode>,
+    #[serde(default = "BlockTarget::all")]
+    pub targets: Vec<BlockTarget>,
+}
+
+/// Where blocked keys don't get to. Rules apply to whichever client is
+/// connected, not to particular client machines.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockTarget {
+    /// User scripts, which see events before they're relayed.
+    Scripts,
+    /// The client, this includes events emitted by scripts.
+    Client,
+}
+
+impl BlockTarget {
+    fn all() -> Vec<Self> {
+        vec![Self::Scripts, Self::Client]
+    }
+}
+
 cfg_if! {
     if #[cfg(target_os = "linux")] {
         #[derive(Clone, Deserialize, Debug)]
         pub struct LinuxConfig {
             pub keyboard_device: Option<PathBuf>,
             pub mouse_device: Option<PathBuf>,
+            /// Touchpad, its swipe and pinch gestures are relayed. It isn't
+            /// grabbed, its pointer motion stays on the server.
             pub touchpad_device: Option<PathBuf>,
+            /// Drawing tablet or touchscreen, its position is relayed as an
+            /// absolute pointer.
+            pub tablet_device: Option<PathBuf>,
+            /// Gamepad or joystick, relayed independently of keyboard and
+            /// mouse by double tapping the scroll lock key.
+            pub gamepad_device: Option<PathBuf>,
         }
     }
 }
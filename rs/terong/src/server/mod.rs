@@ -3,71 +3,657 @@ mod transport_server;
 pub mod config;
 
 use crate::{
-    config::{read_certs, read_private_key, Config},
-    logging::init_tracing,
+    clipboard::{self, SystemClipboard},
+    config::{read_credentials, Config, SecureChannel},
+    file_transfer,
+    input_source::{
+        controller::{flush_hotkeys_on_timer, InputController},
+        script::ScriptHook,
+        InputSource, SystemInputSource,
+    },
+    lock_state,
+    logging::init_tracing_with,
+    metrics::{self, Metrics},
+    recording::Recorder,
     server::{config::ServerConfig, transport_server::TransportServer},
+    transport::{
+        tls::{TlsFiles, TlsMaterial},
+        Credentials,
+    },
 };
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use cfg_if::cfg_if;
-use tokio::{sync::mpsc, try_join};
-use tracing::info;
+use std::{
+    mem,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+};
+use tokio::{
+    select,
+    sync::{broadcast, mpsc, watch},
+    task::{self, JoinHandle},
+    try_join,
+};
+use tracing::{error, info};
 
-async fn start_app(cfg: ServerConfig) -> Result<(), Error> {
-    info!(?cfg, "starting server app");
+/// Starts the server app with input from `source` and paths of files to send
+/// to the client from `path_rx`, returns once it stops.
+pub async fn start_app(
+    cfg: ServerConfig,
+    source: impl InputSource,
+    path_rx: mpsc::Receiver<PathBuf>,
+) -> Result<(), Error> {
+    let server = ServerBuilder::new(cfg, source)
+        .file_paths(path_rx)
+        .start()
+        .await?;
+    server.wait().await
+}
 
-    let ServerConfig {
-        port,
-        tls_cert_path,
-        tls_key_path,
-        client_tls_cert_path,
-        ..
-    } = cfg;
+/// Builds a server for applications embedding it.
+///
+/// Nothing global like the tracing subscriber is set up, that's left to the
+/// application.
+pub struct ServerBuilder<S> {
+    cfg: ServerConfig,
+    source: S,
+    tls: Option<TlsMaterial>,
+    path_rx: Option<mpsc::Receiver<PathBuf>>,
+}
 
-    let (event_tx, event_rx) = mpsc::channel(1);
+impl<S: InputSource> ServerBuilder<S> {
+    pub fn new(cfg: ServerConfig, source: S) -> Self {
+        Self {
+            cfg,
+            source,
+            tls: None,
+            path_rx: None,
+        }
+    }
 
-    let input_source = {
-        cfg_if! {
-            if #[cfg(target_os = "linux")] {
-                crate::input_source::start(
-                    cfg.linux.keyboard_device,
-                    cfg.linux.mouse_device,
-                    cfg.linux.touchpad_device,
-                    event_tx
+    /// Secures connections with TLS using these certificates and key instead
+    /// of the ones in the config.
+    pub fn tls(mut self, material: TlsMaterial) -> Self {
+        self.tls = Some(material);
+        self
+    }
+
+    /// Sends files whose paths are received from `path_rx` to the client.
+    pub fn file_paths(mut self, path_rx: mpsc::Receiver<PathBuf>) -> Self {
+        self.path_rx = Some(path_rx);
+        self
+    }
+
+    /// Starts the server, it's listening for clients once this returns.
+    pub async fn start(self) -> Result<ServerHandle, Error> {
+        let Self {
+            cfg,
+            source,
+            tls,
+            path_rx,
+        } = self;
+
+        info!(?cfg, "starting server app");
+
+        let ServerConfig {
+            port,
+            channel,
+            tls_cert_path,
+            tls_key_path,
+            client_tls_cert_path,
+            noise,
+            wire_format,
+            clipboard,
+            download_dir,
+            scripts,
+            block,
+            log_keystrokes,
+            record,
+            metrics: metrics_cfg,
+            ..
+        } = cfg;
+
+        let credentials = match tls {
+            Some(material) => {
+                wire_format.check(SecureChannel::Tls)?;
+                Credentials::Tls(TlsFiles::from_material(material)?)
+            }
+            None => {
+                wire_format.check(channel)?;
+                read_credentials(
+                    channel,
+                    tls_cert_path,
+                    tls_key_path,
+                    client_tls_cert_path,
+                    noise.as_ref(),
                 )
-            } else {
-                crate::input_source::start(event_tx)
+                .await?
             }
+        };
+
+        let metrics = Arc::new(Metrics::default());
+        let (event_tx, event_rx) = mpsc::channel(1);
+        metrics.watch_queue("events", &event_tx);
+        let (relay_tx, relay_rx) = watch::channel(false);
+
+        let mut controller =
+            InputController::new(event_tx, relay_tx, ScriptHook::new(scripts), block);
+        controller.set_log_keystrokes(log_keystrokes);
+        controller.set_metrics(metrics.clone());
+        if let Some(path) = record {
+            controller.set_recorder(Recorder::create(&path)?);
         }
-    };
 
-    let server = {
-        let tls_certs = read_certs(&tls_cert_path).await?;
+        // lock state to send to the client when control switches to it
+        let lock_rx = {
+            let reader = source.lock_state_reader();
+            lock_state::read_on_switch(relay_rx.clone(), move || reader())
+        };
 
-        let tls_key = read_private_key(&tls_key_path).await?;
+        // helper tasks, they don't stop by themselves
+        let mut tasks = Vec::new();
 
-        let client_tls_certs = read_certs(&client_tls_cert_path).await?;
+        if let Some(cfg) = metrics_cfg {
+            tasks.push(metrics::serve(cfg.addr, metrics.clone()).await?);
+        }
 
-        let args = TransportServer {
-            port,
-            tls_certs,
-            tls_key,
-            client_tls_certs,
+        let clipboard = if clipboard.enabled {
+            match SystemClipboard::new() {
+                Ok(backend) => {
+                    let (channel, task) =
+                        clipboard::start(backend, clipboard.max_size, Some(relay_rx.clone()));
+                    tasks.push(task);
+                    Some(channel)
+                }
+                Err(err) => {
+                    error!(
+                        ?err,
+                        "failed to access clipboard, clipboard sharing is disabled"
+                    );
+                    None
+                }
+            }
+        } else {
+            None
         };
-        transport_server::start(args, event_rx)
-    };
 
-    try_join!(input_source, server).unwrap();
+        let (peer_tx, peer_rx) = watch::channel(None);
+
+        let transport = {
+            let args = TransportServer {
+                port,
+                credentials,
+                wire_format,
+                download_dir,
+                log_keystrokes,
+                metrics: metrics.clone(),
+            };
+            // no files are sent without paths, a closed channel stands in for them
+            let path_rx = path_rx.unwrap_or_else(|| mpsc::channel(1).1);
+            transport_server::start(args, event_rx, clipboard, path_rx, lock_rx, peer_tx).await
+        };
+        let transport = match transport {
+            Ok(x) => x,
+            Err(err) => {
+                tasks.iter().for_each(JoinHandle::abort);
+                return Err(err);
+            }
+        };
+
+        // the source owns the controller, the handle only borrows it so the
+        // server stops once the source ends
+        let controller = Arc::new(Mutex::new(controller));
+        let handle_controller = Arc::downgrade(&controller);
+        flush_hotkeys_on_timer(&controller);
+        let input_source = source.start(controller);
 
-    info!("server app stopped");
+        let (events_tx, _) = broadcast::channel(16);
+        publish_events(relay_rx.clone(), peer_rx.clone(), events_tx.clone());
 
-    Ok(())
+        Ok(ServerHandle {
+            controller: handle_controller,
+            relay_rx,
+            peer_rx,
+            events_tx,
+            input_source,
+            transport,
+            tasks,
+            metrics,
+        })
+    }
+}
+
+/// What a running server is doing.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ServerStatus {
+    /// Address of the connected client.
+    pub client: Option<SocketAddr>,
+    /// If input is relayed to the client.
+    pub relay: bool,
+}
+
+/// Changes of a server's status.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ServerEvent {
+    ClientConnected { addr: SocketAddr },
+    ClientDisconnected { addr: SocketAddr },
+    RelayToggled { relay: bool },
+}
+
+/// Controls a server started by [ServerBuilder].
+#[derive(Debug)]
+pub struct ServerHandle {
+    controller: Weak<Mutex<InputController>>,
+    relay_rx: watch::Receiver<bool>,
+    peer_rx: watch::Receiver<Option<SocketAddr>>,
+    events_tx: broadcast::Sender<ServerEvent>,
+    input_source: JoinHandle<Result<(), Error>>,
+    transport: JoinHandle<()>,
+    /// Helper tasks, aborted when the app stops.
+    tasks: Vec<JoinHandle<()>>,
+    metrics: Arc<Metrics>,
+}
+
+impl ServerHandle {
+    pub fn status(&self) -> ServerStatus {
+        ServerStatus {
+            client: *self.peer_rx.borrow(),
+            relay: *self.relay_rx.borrow(),
+        }
+    }
+
+    /// Subscribes to status changes from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events_tx.subscribe()
+    }
+
+    /// Renders the server's metrics in Prometheus text format.
+    pub fn metrics(&self) -> String {
+        self.metrics.render()
+    }
+
+    /// Switches control to the client or back to this host, like the hotkey
+    /// does. Input is captured or released from the next local event.
+    pub async fn switch(&self, relay: bool) -> Result<(), Error> {
+        self.with_controller(move |x| x.set_relay(relay)).await?
+    }
+
+    /// Waits for the server to stop by itself, which it does when its input
+    /// source ends. Fails if the input source failed.
+    pub async fn wait(mut self) -> Result<(), Error> {
+        let result = try_join!(&mut self.input_source, &mut self.transport);
+        self.stop_tasks();
+        let (source, ()) = result?;
+        source?;
+
+        info!("server app stopped");
+
+        Ok(())
+    }
+
+    /// Stops the server, input is left to this host and the client is
+    /// disconnected.
+    ///
+    /// Devices read in blocking threads are released on their next event.
+    pub async fn shutdown(self) -> Result<(), Error> {
+        // the source may be gone already
+        self.with_controller(|x| x.close()).await.ok();
+
+        self.input_source.abort();
+        self.transport.abort();
+        self.stop_tasks();
+        match self.input_source.await {
+            Ok(result) => result?,
+            Err(err) if !err.is_cancelled() => return Err(err.into()),
+            Err(_) => (),
+        }
+        match self.transport.await {
+            Err(err) if !err.is_cancelled() => return Err(err.into()),
+            _ => (),
+        }
+
+        info!("server app stopped");
+
+        Ok(())
+    }
+
+    /// Runs `f` with the controller in a blocking task, the controller blocks
+    /// on relaying events.
+    async fn with_controller<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut InputController) -> T + Send + 'static,
+    {
+        let controller = self
+            .controller
+            .upgrade()
+            .ok_or_else(|| anyhow!("server has stopped"))?;
+        task::spawn_blocking(move || {
+            let mut controller = controller
+                .lock()
+                .map_err(|_| anyhow!("input controller panicked"))?;
+            Ok(f(&mut controller))
+        })
+        .await?
+    }
+
+    fn stop_tasks(&self) {
+        self.tasks.iter().for_each(JoinHandle::abort);
+    }
+}
+
+/// Publishes changes of the relay flag and the connected client as events,
+/// until both stop changing.
+fn publish_events(
+    mut relay_rx: watch::Receiver<bool>,
+    mut peer_rx: watch::Receiver<Option<SocketAddr>>,
+    events_tx: broadcast::Sender<ServerEvent>,
+) -> JoinHandle<()> {
+    task::spawn(async move {
+        let mut client = None;
+        loop {
+            let event = select! {
+                Ok(()) = relay_rx.changed() => {
+                    let relay = *relay_rx.borrow_and_update();
+                    ServerEvent::RelayToggled { relay }
+                }
+                Ok(()) = peer_rx.changed() => {
+                    let peer = *peer_rx.borrow_and_update();
+                    match (mem::replace(&mut client, peer), peer) {
+                        (_, Some(addr)) => ServerEvent::ClientConnected { addr },
+                        (Some(addr), None) => ServerEvent::ClientDisconnected { addr },
+                        (None, None) => continue,
+                    }
+                }
+                else => break,
+            };
+            // no one may be subscribed
+            events_tx.send(event).ok();
+        }
+    })
 }
 
 /// Run the server application.
 pub async fn run() {
-    init_tracing();
-
     let cfg = Config::get().await.server();
 
-    start_app(cfg).await.unwrap();
+    init_tracing_with(&cfg.log).expect("failed to set up logging");
+
+    let source = {
+        cfg_if! {
+            if #[cfg(target_os = "linux")] {
+                SystemInputSource::new(cfg.linux.clone())
+            } else {
+                SystemInputSource
+            }
+        }
+    };
+
+    // paths of files to send to the client
+    let (path_tx, path_rx) = mpsc::channel(1);
+    file_transfer::read_commands(path_tx);
+
+    start_app(cfg, source, path_rx).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::{
+            self,
+            config::ClientConfig,
+            input_sink::{ChannelInputSink, SinkCommand},
+        },
+        config::LogFormat,
+        input_source::{event::LocalInputEvent, ChannelInputSource, LockStateReader},
+        logging::new_layer,
+        transport::{
+            noise::generate_psk,
+            protocol::{InputEvent, KeyCode},
+            tls::generate_self_signed,
+        },
+    };
+    use std::{fs, future::Future, net::TcpListener, time::Duration};
+    use tokio::{task, time};
+    use tracing_subscriber::{fmt::writer::BoxMakeWriter, layer::SubscriberExt};
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn new_configs() -> (ServerConfig, ClientConfig) {
+        let port = free_port();
+        let psk = generate_psk();
+        let server_cfg = toml::from_str(&format!(
+            r#"
+            port = {port}
+            channel = "noise"
+            noise.psk = "{psk}"
+            clipboard.enabled = false
+            [linux]
+            "#
+        ))
+        .unwrap();
+        let client_cfg = toml::from_str(&format!(
+            r#"
+            server_addr = "127.0.0.1:{port}"
+            channel = "noise"
+            noise.psk = "{psk}"
+            clipboard.enabled = false
+            "#
+        ))
+        .unwrap();
+        (server_cfg, client_cfg)
+    }
+
+    /// Returns TLS material of a server and of a client named "desk".
+    fn new_tls_materials() -> (TlsMaterial, TlsMaterial) {
+        let (server_cert, server_key) =
+            generate_self_signed(vec!["localhost".to_owned()], None).unwrap();
+        let (client_cert, client_key) =
+            generate_self_signed(vec!["desk".to_owned()], None).unwrap();
+        let server = TlsMaterial {
+            certs: vec![server_cert.clone()],
+            key: server_key,
+            peer_certs: vec![client_cert.clone()],
+        };
+        let client = TlsMaterial {
+            certs: vec![client_cert],
+            key: client_key,
+            peer_certs: vec![server_cert],
+        };
+        (server, client)
+    }
+
+    /// Input source whose device is gone as soon as it starts.
+    struct UnpluggedSource;
+
+    impl InputSource for UnpluggedSource {
+        fn start(self, _: Arc<Mutex<InputController>>) -> JoinHandle<Result<(), Error>> {
+            task::spawn(async { Err(anyhow!("device unplugged")) })
+        }
+
+        fn lock_state_reader(&self) -> LockStateReader {
+            Arc::new(|| Ok(Default::default()))
+        }
+    }
+
+    async fn within<F: Future>(future: F) -> F::Output {
+        time::timeout(Duration::from_secs(30), future)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_handles() {
+        let (server_cfg, client_cfg) = new_configs();
+        let (source, source_handle) = ChannelInputSource::new();
+        let (sink, mut sink_handle) = ChannelInputSink::new();
+
+        let server = ServerBuilder::new(server_cfg.clone(), source)
+            .start()
+            .await
+            .unwrap();
+        let mut server_events = server.subscribe();
+
+        // the port is taken, starting fails rather than panics
+        let (source, _) = ChannelInputSource::new();
+        assert!(ServerBuilder::new(server_cfg, source)
+            .start()
+            .await
+            .is_err());
+
+        let client = client::ClientBuilder::new(client_cfg, sink)
+            .start()
+            .await
+            .unwrap();
+        let mut client_events = client.subscribe();
+
+        assert_eq!(
+            within(client_events.recv()).await.unwrap(),
+            client::ClientEvent::Connected
+        );
+        assert!(client.status().connected);
+        assert!(matches!(
+            within(server_events.recv()).await.unwrap(),
+            ServerEvent::ClientConnected { .. }
+        ));
+        assert!(server.status().client.is_some());
+
+        server.switch(true).await.unwrap();
+        assert_eq!(
+            server_events.recv().await.unwrap(),
+            ServerEvent::RelayToggled { relay: true }
+        );
+        assert!(server.status().relay);
+
+        let key = KeyCode::A;
+        source_handle
+            .event_tx
+            .send(LocalInputEvent::KeyDown { key })
+            .await
+            .unwrap();
+        let received = within(async {
+            loop {
+                if let Some(SinkCommand::Event(event)) = sink_handle.cmd_rx.recv().await {
+                    return event;
+                }
+            }
+        })
+        .await;
+        assert_eq!(received, InputEvent::KeyDown { key });
+        // counted once sent, which may be after the client got it
+        within(async {
+            while !server
+                .metrics()
+                .contains("terong_events_relayed_total{type=\"KeyDown\"}")
+            {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+
+        // the client sees the server going away
+        server.shutdown().await.unwrap();
+        assert_eq!(
+            within(client_events.recv()).await.unwrap(),
+            client::ClientEvent::Disconnected
+        );
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_silent_connection_is_dropped() {
+        let (server_cfg, client_cfg) = new_configs();
+        let port = server_cfg.port;
+        let (source, _source_handle) = ChannelInputSource::new();
+        let (sink, _sink_handle) = ChannelInputSink::new();
+        let server = ServerBuilder::new(server_cfg, source)
+            .start()
+            .await
+            .unwrap();
+
+        // connects but never starts the handshake
+        let _silent = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+
+        let client = client::ClientBuilder::new(client_cfg, sink)
+            .start()
+            .await
+            .unwrap();
+        let mut client_events = client.subscribe();
+        assert_eq!(
+            within(client_events.recv()).await.unwrap(),
+            client::ClientEvent::Connected
+        );
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_source_error_ends_wait() {
+        let (server_cfg, _) = new_configs();
+        let server = ServerBuilder::new(server_cfg, UnpluggedSource)
+            .start()
+            .await
+            .unwrap();
+        let err = within(server.wait()).await.unwrap_err();
+        assert_eq!(err.to_string(), "device unplugged");
+    }
+
+    #[tokio::test]
+    async fn test_session_span_is_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("terong.log");
+        let file = fs::File::create(&path).unwrap();
+        let subscriber = tracing_subscriber::registry().with(new_layer(
+            LogFormat::Json,
+            BoxMakeWriter::new(Mutex::new(file)),
+        ));
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        let (server_cfg, client_cfg) = new_configs();
+        let (server_tls, client_tls) = new_tls_materials();
+        let (source, _source_handle) = ChannelInputSource::new();
+        let (sink, _sink_handle) = ChannelInputSink::new();
+        let server = ServerBuilder::new(server_cfg, source)
+            .tls(server_tls)
+            .start()
+            .await
+            .unwrap();
+        let mut server_events = server.subscribe();
+        let client = client::ClientBuilder::new(client_cfg, sink)
+            .tls(client_tls)
+            .start()
+            .await
+            .unwrap();
+
+        let peer_addr = match within(server_events.recv()).await.unwrap() {
+            ServerEvent::ClientConnected { addr } => addr,
+            x => panic!("unexpected event {:?}", x),
+        };
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+        drop(guard);
+
+        let log = fs::read_to_string(&path).unwrap();
+        let line = log
+            .lines()
+            .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap())
+            .find(|x| x["fields"]["message"] == "tls handshake completed")
+            .unwrap();
+        assert_eq!(line["span"]["name"], "session");
+        assert_eq!(line["span"]["peer_addr"], peer_addr.to_string());
+        // the client wasn't asked for its certificate
+        assert!(line["span"].get("client_name").is_none());
+        assert_eq!(line["span"]["expected_client"], "desk");
+    }
 }
@@ -1,59 +1,274 @@
 use crate::{
+    clipboard::{self, ClipboardChannel},
+    config::WireFormat,
+    file_transfer::FileTransfers,
     log_error,
+    metrics::Metrics,
     transport::{
-        protocol::{ClientMessage, InputEvent, Ping, Pong, ServerMessage},
-        Certificate, PrivateKey, Transport,
+        frame::TransportError,
+        go_compat::{self, GoMessage, GoTransport},
+        noise::{self, NoiseAuth},
+        protocol::{
+            AxisRange, ClientMessage, ClipboardContent, GamepadAxis, GamepadEvent, InputEvent,
+            LockState, Ping, Pong, ServerMessage,
+        },
+        tls::{check_peer_certificate, ExpiryWatcher, TlsFiles, TlsMaterial},
+        AsyncStream, Credentials, Role, Transport,
     },
 };
-use anyhow::{Context, Error};
+use anyhow::{anyhow, Context, Error};
 use futures::{future, FutureExt};
 use std::{
     fmt::Debug,
     net::{SocketAddr, SocketAddrV4},
+    path::PathBuf,
     sync::{Arc, Mutex},
     time::Duration,
 };
 use tokio::{
     net::{TcpListener, TcpStream},
     select,
-    sync::mpsc::{self, error::SendError},
+    sync::{
+        mpsc::{
+            self,
+            error::{SendError, TrySendError},
+        },
+        watch,
+    },
     task::{self, JoinError, JoinHandle},
-    time::{interval_at, Instant, MissedTickBehavior},
+    time::{interval_at, sleep, timeout, Instant, MissedTickBehavior},
 };
 use tokio_native_tls::native_tls;
-use tracing::{debug, error, info};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
 type ServerTransport = Transport<ClientMessage, ServerMessage>;
 
+/// Time a connecting client has to finish the handshake and hello, events
+/// aren't relayed meanwhile.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone)]
 pub struct TransportServer {
     pub port: u16,
-    pub tls_certs: Vec<Certificate>,
-    pub tls_key: PrivateKey,
-    pub client_tls_certs: Vec<Certificate>,
+    pub credentials: Credentials,
+    pub wire_format: WireFormat,
+    pub download_dir: Option<PathBuf>,
+    /// Log keys of input events, they're redacted otherwise.
+    pub log_keystrokes: bool,
+    pub metrics: Arc<Metrics>,
+}
+
+/// Starts listening for clients, the address of the connected one is sent to
+/// `peer_tx`.
+pub async fn start(
+    args: TransportServer,
+    event_rx: mpsc::Receiver<InputEvent>,
+    clipboard: Option<ClipboardChannel>,
+    path_rx: mpsc::Receiver<PathBuf>,
+    lock_rx: watch::Receiver<Option<LockState>>,
+    peer_tx: watch::Sender<Option<SocketAddr>>,
+) -> Result<JoinHandle<()>, Error> {
+    let TransportServer {
+        port,
+        credentials,
+        wire_format,
+        download_dir,
+        log_keystrokes,
+        metrics,
+    } = args;
+
+    let acceptor = Acceptor::new(credentials)?;
+
+    let server_addr = SocketAddrV4::new([0, 0, 0, 0].into(), port);
+    let listener = TcpListener::bind(server_addr)
+        .await
+        .with_context(|| format!("failed to bind server at {}", server_addr))?;
+    info!("listening at {}", server_addr);
+
+    let listener = Listener {
+        listener,
+        acceptor,
+        wire_format,
+        download_dir,
+        log_keystrokes,
+        metrics,
+    };
+    Ok(task::spawn(run_transport(
+        listener, event_rx, clipboard, path_rx, lock_rx, peer_tx,
+    )))
 }
 
-pub fn start(args: TransportServer, event_rx: mpsc::Receiver<InputEvent>) -> JoinHandle<()> {
-    task::spawn(run_transport(args, event_rx))
+/// Bound listener and what incoming connections are accepted with.
+struct Listener {
+    listener: TcpListener,
+    acceptor: Acceptor,
+    wire_format: WireFormat,
+    download_dir: Option<PathBuf>,
+    log_keystrokes: bool,
+    metrics: Arc<Metrics>,
 }
 
-async fn run_transport(args: TransportServer, mut event_rx: mpsc::Receiver<InputEvent>) {
-    let identity = native_tls::Identity::from_pkcs8(&args.tls_certs[0].0, &args.tls_key.0).unwrap();
+fn create_tls_acceptor(tls: &TlsMaterial) -> Result<tokio_native_tls::TlsAcceptor, Error> {
+    let identity = native_tls::Identity::from_pkcs8(&tls.certs[0].0, &tls.key.0)?;
     // schannel doesn't provide client cert verification https://learn.microsoft.com/en-us/windows/win32/secauthn/performing-authentication-using-schannel
     // and native-tls doesn't allow injecting peer verification
-    let tls_acceptor = native_tls::TlsAcceptor::builder(identity)
-        .build()
-        .unwrap()
-        .into();
+    let tls_acceptor = native_tls::TlsAcceptor::builder(identity).build()?.into();
+    Ok(tls_acceptor)
+}
 
-    let server_addr = SocketAddrV4::new([0, 0, 0, 0].into(), args.port);
+/// Secures incoming connections.
+enum Acceptor {
+    Tls {
+        tls: TlsFiles,
+        tls_acceptor: tokio_native_tls::TlsAcceptor,
+        _expiry: ExpiryWatcher,
+    },
+    Noise(NoiseAuth),
+}
 
-    info!("listening at {}", server_addr);
-    let listener = TcpListener::bind(server_addr)
+impl Acceptor {
+    fn new(credentials: Credentials) -> Result<Self, Error> {
+        let acceptor = match credentials {
+            Credentials::Tls(tls) => {
+                let tls_acceptor = create_tls_acceptor(tls.material())?;
+                let _expiry = ExpiryWatcher::start(tls.clone());
+                Acceptor::Tls {
+                    tls,
+                    tls_acceptor,
+                    _expiry,
+                }
+            }
+            Credentials::Noise(auth) => Acceptor::Noise(auth),
+        };
+        Ok(acceptor)
+    }
+
+    /// Performs handshake with the connecting client.
+    async fn accept(
+        &mut self,
+        stream: TcpStream,
+        wire_format: WireFormat,
+    ) -> Result<Connection, Error> {
+        match self {
+            Acceptor::Tls {
+                tls, tls_acceptor, ..
+            } => {
+                // pick up renewed certificates for the new connection
+                match tls.reload().await {
+                    Ok(true) => match create_tls_acceptor(tls.material()) {
+                        Ok(x) => {
+                            info!("tls files reloaded");
+                            *tls_acceptor = x;
+                        }
+                        Err(err) => log_error!(err),
+                    },
+                    Ok(false) => (),
+                    Err(err) => log_error!(err),
+                }
+
+                let stream = tls_acceptor.accept(stream).await.with_context(|| {
+                    format!(
+                        "tls handshake failed, check that certificates are valid and not expired, {}",
+                        tls.status()
+                    )
+                })?;
+                // native-tls doesn't ask clients for their certificate, the
+                // configured one only names the client that's expected
+                match check_peer_certificate(&stream) {
+                    Some(cert) => {
+                        Span::current().record("client_name", cert.name());
+                    }
+                    None => {
+                        if let Ok(cert) = tls.material().peer_certs[0].info() {
+                            Span::current().record("expected_client", cert.name());
+                        }
+                    }
+                }
+                info!(status = %tls.status(), "tls handshake completed");
+
+                establish(stream, wire_format).await
+            }
+
+            Acceptor::Noise(auth) => {
+                let stream = noise::respond(stream, auth)
+                    .await
+                    .context("noise handshake failed")?;
+                establish(stream, wire_format).await
+            }
+        }
+    }
+}
+
+/// Established connection with a client.
+#[derive(Debug)]
+enum Connection {
+    Native(ServerTransport),
+    Go(GoTransport),
+}
+
+impl Connection {
+    /// Sets if keys of input events are logged, they're redacted otherwise.
+    fn set_log_keystrokes(&mut self, value: bool) {
+        match self {
+            Connection::Native(x) => x.set_log_keystrokes(value),
+            Connection::Go(x) => x.set_log_keystrokes(value),
+        }
+    }
+
+    /// Sets the metrics to count sent bytes in.
+    fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        match self {
+            Connection::Native(x) => x.set_metrics(metrics),
+            Connection::Go(x) => x.set_metrics(metrics),
+        }
+    }
+}
+
+async fn establish(
+    mut stream: impl AsyncStream + Send + Unpin + 'static,
+    wire_format: WireFormat,
+) -> Result<Connection, Error> {
+    let (go, read_buf) = match wire_format {
+        WireFormat::Native => (false, Default::default()),
+        WireFormat::Go => (true, Default::default()),
+        WireFormat::Auto => go_compat::detect_go_client(&mut stream)
+            .await
+            .context("failed to detect client wire format")?,
+    };
+
+    if go {
+        info!("client speaks go wire format");
+        return Ok(Connection::Go(GoTransport::new(stream, read_buf)));
+    }
+
+    let mut transport = Transport::with_read_buf(stream, read_buf);
+    let hello = transport
+        .hello(Role::Server)
         .await
-        .expect("failed to bind server");
+        .context("hello exchange failed")?;
+    info!(codec = %hello.codec, "hello exchanged");
+    Ok(Connection::Native(transport))
+}
+
+async fn run_transport(
+    listener: Listener,
+    mut event_rx: mpsc::Receiver<InputEvent>,
+    mut clipboard: Option<ClipboardChannel>,
+    mut path_rx: mpsc::Receiver<PathBuf>,
+    lock_rx: watch::Receiver<Option<LockState>>,
+    peer_tx: watch::Sender<Option<SocketAddr>>,
+) {
+    let Listener {
+        listener,
+        mut acceptor,
+        wire_format,
+        download_dir,
+        log_keystrokes,
+        metrics,
+    } = listener;
 
     let mut session_handler: Option<SessionHandle> = None;
+    let mut gamepad = GamepadAnnouncement::default();
 
     loop {
         let finished = session_handler
@@ -65,10 +280,14 @@ async fn run_transport(args: TransportServer, mut event_rx: mpsc::Receiver<Input
             // check if session is finished if it exists
             Ok(()) = finished => {
                 session_handler.take();
+                peer_tx.send_replace(None);
             }
 
             // propagate to session if it exists
             event = event_rx.recv() => {
+                if let Some(event) = &event {
+                    gamepad.observe(event);
+                }
                 match (event, &mut session_handler) {
                     // propagate event to session
                     (Some(event), Some(session)) if session.is_connected() => { session.send_event(event).await.ok(); },
@@ -79,33 +298,130 @@ async fn run_transport(args: TransportServer, mut event_rx: mpsc::Receiver<Input
                 }
             }
 
+            // propagate to session if it exists
+            content = clipboard::recv_outgoing(&mut clipboard) => {
+                match &mut session_handler {
+                    Some(session) if session.is_connected() => session.send_clipboard(content),
+                    _ => (),
+                }
+            }
+
+            // propagate to session if it exists
+            Some(path) = path_rx.recv() => {
+                match &mut session_handler {
+                    Some(session) if session.is_connected() => session.send_file(path),
+                    _ => warn!(path = %path.display(), "not connected to a client, can't send file"),
+                }
+            }
+
             Ok((stream, peer_addr)) = listener.accept() => {
+                let had_session = session_handler.is_some();
                 handle_incoming_connection(
                     &mut session_handler,
                     stream,
                     peer_addr,
-                    &tls_acceptor,
-                ).await
+                    &mut acceptor,
+                    wire_format,
+                    SessionContext {
+                        clipboard_tx: clipboard.as_ref().map(|x| x.incoming_tx.clone()),
+                        download_dir: download_dir.clone(),
+                        lock_rx: lock_rx.clone(),
+                        log_keystrokes,
+                        metrics: metrics.clone(),
+                    },
+                    &peer_tx,
+                ).await;
+                // the client creates the relayed gamepad once it's announced
+                if let (false, Some(session)) = (had_session, &mut session_handler) {
+                    for event in gamepad.events() {
+                        session.send_event(event).await.ok();
+                    }
+                }
             },
         }
     }
 }
 
+/// Announcement of the relayed gamepad, repeated to clients connecting while
+/// it's relayed.
+#[derive(Default, Debug)]
+struct GamepadAnnouncement {
+    axes: Vec<(GamepadAxis, AxisRange)>,
+    connected: bool,
+}
+
+impl GamepadAnnouncement {
+    fn observe(&mut self, event: &InputEvent) {
+        let event = match *event {
+            InputEvent::Gamepad { event } => event,
+            _ => return,
+        };
+        match event {
+            GamepadEvent::AxisRange { axis, range } => {
+                if self.connected {
+                    *self = Default::default();
+                }
+                self.axes.push((axis, range));
+            }
+            GamepadEvent::Connected => self.connected = true,
+            GamepadEvent::Disconnected => *self = Default::default(),
+            _ => (),
+        }
+    }
+
+    /// Events announcing the gamepad, none if it isn't relayed.
+    fn events(&self) -> Vec<InputEvent> {
+        if !self.connected {
+            return Vec::new();
+        }
+        self.axes
+            .iter()
+            .map(|&(axis, range)| GamepadEvent::AxisRange { axis, range })
+            .chain([GamepadEvent::Connected])
+            .map(|event| InputEvent::Gamepad { event })
+            .collect()
+    }
+}
+
 // Handle incoming connection, create a new session if it's not exist, otherwise
 // drop the connection.
 async fn handle_incoming_connection(
     session_handler: &mut Option<SessionHandle>,
     stream: TcpStream,
     peer_addr: SocketAddr,
-    tls_acceptor: &tokio_native_tls::TlsAcceptor,
+    acceptor: &mut Acceptor,
+    wire_format: WireFormat,
+    ctx: SessionContext,
+    peer_tx: &watch::Sender<Option<SocketAddr>>,
 ) {
     info!(?peer_addr, "received incoming connection");
     if session_handler.is_none() {
-        let stream = tls_acceptor.accept(stream).await.unwrap();
-        let transport = Transport::new(stream);
+        // the client's name is known once it's authenticated
+        let span = info_span!(
+            "session",
+            %peer_addr,
+            client_name = field::Empty,
+            expected_client = field::Empty
+        );
+        let accept = timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream, wire_format));
+        let connection = match accept.instrument(span.clone()).await {
+            Ok(x) => x,
+            Err(_) => Err(anyhow!(
+                "client didn't complete the handshake in {} secs",
+                HANDSHAKE_TIMEOUT.as_secs()
+            )),
+        };
+        let connection = match connection {
+            Ok(x) => x,
+            Err(err) => {
+                span.in_scope(|| log_error!(err));
+                return;
+            }
+        };
 
-        let handler = spawn_session(peer_addr, transport);
+        let handler = spawn_session(peer_addr, connection, ctx, span);
         *session_handler = Some(handler);
+        peer_tx.send_replace(Some(peer_addr));
     } else {
         info!(?peer_addr, "dropping incoming connection")
     }
@@ -115,6 +431,8 @@ async fn handle_incoming_connection(
 #[derive(Debug)]
 struct SessionHandle {
     event_tx: mpsc::Sender<InputEvent>,
+    clipboard_tx: mpsc::Sender<ClipboardContent>,
+    path_tx: mpsc::Sender<PathBuf>,
     task: JoinHandle<()>,
     state: Arc<Mutex<SessionState>>,
 }
@@ -126,6 +444,23 @@ impl SessionHandle {
         Ok(())
     }
 
+    /// Send local clipboard content to this session, dropped if the session
+    /// is busy.
+    fn send_clipboard(&mut self, content: ClipboardContent) {
+        if self.clipboard_tx.try_send(content).is_err() {
+            debug!("session is busy, dropping clipboard content");
+        }
+    }
+
+    /// Send a file to the client of this session.
+    fn send_file(&mut self, path: PathBuf) {
+        match self.path_tx.try_send(path) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => warn!("session is busy, try sending the file again"),
+            Err(TrySendError::Closed(_)) => warn!("client doesn't support file transfer"),
+        }
+    }
+
     /// This method is cancel safe.
     async fn finished(&mut self) -> Result<(), JoinError> {
         (&mut self.task).await
@@ -145,6 +480,29 @@ struct Session {
     transport: ServerTransport,
     event_rx: mpsc::Receiver<InputEvent>,
     state: Arc<Mutex<SessionState>>,
+    /// Local clipboard content to send to the client.
+    clipboard_rx: mpsc::Receiver<ClipboardContent>,
+    /// Receives clipboard content from the client, `None` if clipboard
+    /// sharing is disabled.
+    clipboard_tx: Option<mpsc::Sender<ClipboardContent>>,
+    /// Paths of files to send to the client.
+    path_rx: mpsc::Receiver<PathBuf>,
+    files: FileTransfers,
+    /// Host's lock state, changes when control switches to the client.
+    lock_rx: watch::Receiver<Option<LockState>>,
+    metrics: Arc<Metrics>,
+}
+
+/// What sessions are created with.
+#[derive(Clone, Debug)]
+struct SessionContext {
+    /// Receives clipboard content from the client, `None` if clipboard
+    /// sharing is disabled.
+    clipboard_tx: Option<mpsc::Sender<ClipboardContent>>,
+    download_dir: Option<PathBuf>,
+    lock_rx: watch::Receiver<Option<LockState>>,
+    log_keystrokes: bool,
+    metrics: Arc<Metrics>,
 }
 
 #[derive(Clone, Copy, Default, Debug)]
@@ -156,31 +514,70 @@ enum SessionState {
     },
 }
 
-/// Creates a new session.
-fn spawn_session(peer_addr: SocketAddr, transport: ServerTransport) -> SessionHandle {
+/// Creates a new session, it logs in the span.
+fn spawn_session(
+    peer_addr: SocketAddr,
+    mut connection: Connection,
+    ctx: SessionContext,
+    span: Span,
+) -> SessionHandle {
+    let SessionContext {
+        clipboard_tx: incoming_clipboard_tx,
+        download_dir,
+        mut lock_rx,
+        log_keystrokes,
+        metrics,
+    } = ctx;
+    connection.set_log_keystrokes(log_keystrokes);
+    connection.set_metrics(metrics.clone());
+    // only lock state read after the session is created is sent
+    lock_rx.mark_unchanged();
+
     let (event_tx, event_rx) = mpsc::channel(1);
+    let (clipboard_tx, clipboard_rx) = mpsc::channel(1);
+    let (path_tx, path_rx) = mpsc::channel(1);
 
     let state: Arc<Mutex<SessionState>> = Default::default();
 
-    let session = Session {
-        transport,
-        event_rx,
-        state: state.clone(),
-    };
+    let session = state.clone();
+    let task = task::spawn(
+        async move {
+            let _session = metrics.session_started();
+
+            let result = match connection {
+                Connection::Native(transport) => {
+                    let session = Session {
+                        transport,
+                        event_rx,
+                        state: session,
+                        clipboard_rx,
+                        clipboard_tx: incoming_clipboard_tx,
+                        path_rx,
+                        files: FileTransfers::new(download_dir),
+                        lock_rx,
+                        metrics,
+                    };
+                    run_session(session).await
+                }
+                Connection::Go(transport) => run_go_session(transport, event_rx, &metrics).await,
+            };
 
-    let task = task::spawn(async move {
-        // handle session error if any
-        if let Err(err) = run_session(session).await {
-            log_error!(err);
-        };
+            // handle session error if any
+            if let Err(err) = result {
+                log_error!(err);
+            };
 
-        info!("session terminated");
+            info!("session terminated");
 
-        info!(?peer_addr, "disconnected from client");
-    });
+            info!(?peer_addr, "disconnected from client");
+        }
+        .instrument(span),
+    );
 
     SessionHandle {
         event_tx,
+        clipboard_tx,
+        path_tx,
         task,
         state,
     }
@@ -192,8 +589,17 @@ async fn run_session(session: Session) -> Result<(), Error> {
         mut transport,
         mut event_rx,
         state: state_ref,
+        mut clipboard_rx,
+        clipboard_tx,
+        mut path_rx,
+        mut files,
+        mut lock_rx,
+        metrics,
     } = session;
 
+    // last lock state reported by the client
+    let mut client_lock_state = None;
+
     let ping_ticker_interval = Duration::from_secs(20);
     let mut ping_ticker = {
         let mut ticker = interval_at(Instant::now() + ping_ticker_interval, ping_ticker_interval);
@@ -226,7 +632,20 @@ async fn run_session(session: Session) -> Result<(), Error> {
                         SessionState::Idle
                     }
 
-                    Ok(msg) = transport.recv_msg() => {
+                    msg = transport.recv_msg() => {
+                        let msg = match msg {
+                            Ok(x) => x,
+                            Err(TransportError::Eof) => {
+                                info!("terminating session, client closed the connection");
+                                break;
+                            }
+                            Err(err @ TransportError::Decode(_)) => {
+                                // frames are length delimited, the next one can still be read
+                                warn!(?err, "ignoring undecodable message");
+                                continue;
+                            }
+                            Err(err) => return Err(err).context("failed to receive message"),
+                        };
                         match msg {
                             ClientMessage::Ping(Ping { counter }) => {
                                 if counter == local_ping_counter {
@@ -252,7 +671,51 @@ async fn run_session(session: Session) -> Result<(), Error> {
                                     break;
                                 }
                             },
+                            ClientMessage::Clipboard(content) => {
+                                clipboard::forward_incoming(clipboard_tx.as_ref(), content);
+                                SessionState::Idle
+                            }
+                            ClientMessage::LockState(state) => {
+                                debug!(?state, "client reported lock state");
+                                client_lock_state = Some(state);
+                                SessionState::Idle
+                            }
+                            ClientMessage::File(msg) => {
+                                if let Some(reply) = files.handle(msg).await {
+                                    transport
+                                        .send_msg(reply.into())
+                                        .await
+                                        .context("failed to send file transfer reply")?;
+                                }
+                                SessionState::Idle
+                            }
+                        }
+                    }
+
+                    Ok(()) = lock_rx.changed() => {
+                        let state = *lock_rx.borrow_and_update();
+                        match state {
+                            Some(state) if client_lock_state != Some(state) => {
+                                debug!(?state, ?client_lock_state, "sending lock state");
+                                transport
+                                    .send_msg(state.into())
+                                    .await
+                                    .context("failed to send lock state")?;
+                            }
+                            _ => (),
+                        }
+                        SessionState::Idle
+                    }
+
+                    Some(content) = clipboard_rx.recv() => {
+                        match transport.send_msg(ServerMessage::Clipboard(content)).await {
+                            Ok(()) => (),
+                            Err(err @ TransportError::Oversized { .. }) => {
+                                warn!(?err, "clipboard content is too large to send");
+                            }
+                            Err(err) => return Err(err).context("failed to send clipboard"),
                         }
+                        SessionState::Idle
                     }
 
                     event = event_rx.recv() => {
@@ -264,6 +727,28 @@ async fn run_session(session: Session) -> Result<(), Error> {
                             },
                         }
                     }
+
+                    Some(path) = path_rx.recv() => {
+                        match files.offer(&path).await {
+                            Ok(msg) => transport
+                                .send_msg(msg.into())
+                                .await
+                                .context("failed to send file offer")?,
+                            Err(err) => log_error!(err),
+                        }
+                        SessionState::Idle
+                    }
+
+                    // lowest priority so chunks don't delay input events
+                    _ = future::ready(()), if files.is_sending() => {
+                        if let Some(msg) = files.next_chunk().await {
+                            transport
+                                .send_msg(msg.into())
+                                .await
+                                .context("failed to send file chunk")?;
+                        }
+                        SessionState::Idle
+                    }
                 }
             }
 
@@ -272,6 +757,7 @@ async fn run_session(session: Session) -> Result<(), Error> {
                     .send_msg(event.into())
                     .await
                     .context("failed to send message")?;
+                metrics.event_relayed(&event);
                 SessionState::Idle
             }
         };
@@ -285,3 +771,114 @@ async fn run_session(session: Session) -> Result<(), Error> {
 
     Ok(())
 }
+
+/// The session loop with a Go client.
+async fn run_go_session(
+    mut transport: GoTransport,
+    mut event_rx: mpsc::Receiver<InputEvent>,
+    metrics: &Metrics,
+) -> Result<(), Error> {
+    let mut ping_ticker = {
+        let mut ticker = interval_at(
+            Instant::now() + go_compat::PING_INTERVAL,
+            go_compat::PING_INTERVAL,
+        );
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker
+    };
+    let ping_deadline = sleep(go_compat::PING_TIMEOUT);
+    tokio::pin!(ping_deadline);
+
+    loop {
+        select! { biased;
+
+            _ = ping_ticker.tick() => {
+                transport
+                    .send_msg(GoMessage::Ping)
+                    .await
+                    .context("failed to send ping")?;
+            }
+
+            _ = &mut ping_deadline => {
+                info!("haven't heard ping from client for {} secs, terminating session", go_compat::PING_TIMEOUT.as_secs());
+                break;
+            }
+
+            msg = transport.recv_msg() => {
+                match msg {
+                    Ok(GoMessage::Ping) => {
+                        ping_deadline.as_mut().reset(Instant::now() + go_compat::PING_TIMEOUT);
+                    }
+                    Ok(msg) => debug!(msg = ?transport.redacted(&msg), "ignoring unexpected message"),
+                    Err(TransportError::Eof) => {
+                        info!("terminating session, client closed the connection");
+                        break;
+                    }
+                    Err(err @ TransportError::Decode(_)) => {
+                        warn!(?err, "ignoring undecodable message");
+                    }
+                    Err(err) => return Err(err).context("failed to receive message"),
+                }
+            }
+
+            event = event_rx.recv() => {
+                match event {
+                    Some(event) => {
+                        let sent = transport
+                            .send_msg(GoMessage::Event(event))
+                            .await
+                            .context("failed to send message")?;
+                        if sent {
+                            metrics.event_relayed(&event);
+                        }
+                    }
+                    None => {
+                        info!("terminating session, event channel was closed");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gamepad_announcement() {
+        let gamepad = |event| InputEvent::Gamepad { event };
+        let range = AxisRange {
+            minimum: -1,
+            maximum: 1,
+            fuzz: 0,
+            flat: 0,
+        };
+        let x = gamepad(GamepadEvent::AxisRange {
+            axis: GamepadAxis::X,
+            range,
+        });
+        let y = gamepad(GamepadEvent::AxisRange {
+            axis: GamepadAxis::Y,
+            range,
+        });
+        let connected = gamepad(GamepadEvent::Connected);
+
+        let mut announcement = GamepadAnnouncement::default();
+        announcement.observe(&x);
+        assert_eq!(announcement.events(), []);
+        announcement.observe(&connected);
+        announcement.observe(&gamepad(GamepadEvent::Sync));
+        assert_eq!(announcement.events(), [x, connected]);
+
+        announcement.observe(&gamepad(GamepadEvent::Disconnected));
+        assert_eq!(announcement.events(), []);
+
+        announcement.observe(&y);
+        announcement.observe(&connected);
+        assert_eq!(announcement.events(), [y, connected]);
+    }
+}
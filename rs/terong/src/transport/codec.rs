@@ -0,0 +1,148 @@
+//! Message codecs.
+//!
+//! The codec is negotiated per session in hello exchange, peers that predate
+//! codec negotiation use bincode.
+
+use super::frame::TransportError;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+
+/// Encoding of messages in frame payloads.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Compact but not self-describing, peers must agree on the exact
+    /// message types.
+    #[default]
+    Bincode,
+    /// Self-describing, unknown fields are ignored and messages with unknown
+    /// variants can be skipped without ending the session.
+    Cbor,
+}
+
+impl Codec {
+    /// Identifier of this codec in hello.
+    pub fn id(&self) -> u8 {
+        match self {
+            Codec::Bincode => 0,
+            Codec::Cbor => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Codec::Bincode),
+            1 => Some(Codec::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T>(&self, msg: &T) -> Result<Vec<u8>, TransportError>
+    where
+        T: Serialize,
+    {
+        match self {
+            Codec::Bincode => bincode::serialize(msg).map_err(|err| TransportError::Encode(err)),
+            Codec::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(msg, &mut buf)
+                    .map_err(|err| TransportError::Encode(err.into()))?;
+                Ok(buf)
+            }
+        }
+    }
+
+    pub fn decode<T>(&self, bytes: &[u8]) -> Result<T, TransportError>
+    where
+        T: DeserializeOwned,
+    {
+        match self {
+            Codec::Bincode => {
+                bincode::deserialize(bytes).map_err(|err| TransportError::Decode(err))
+            }
+            Codec::Cbor => {
+                ciborium::from_reader(bytes).map_err(|err| TransportError::Decode(err.into()))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Bincode => f.write_str("bincode"),
+            Codec::Cbor => f.write_str("cbor"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::protocol::{InputEvent, KeyCode, Pong, ServerMessage};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct V1 {
+        a: u16,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct V2 {
+        a: u16,
+        b: String,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum OldMessage {
+        A(V1),
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum NewMessage {
+        A(V2),
+        B(V1),
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let event = InputEvent::KeyDown { key: KeyCode::A };
+        for codec in [Codec::Bincode, Codec::Cbor] {
+            let bytes = codec.encode(&ServerMessage::Event(event)).unwrap();
+            let msg: ServerMessage = codec.decode(&bytes).unwrap();
+            assert!(matches!(msg, ServerMessage::Event(x) if x == event));
+
+            let bytes = codec
+                .encode(&ServerMessage::Pong(Pong { counter: 7 }))
+                .unwrap();
+            let msg: ServerMessage = codec.decode(&bytes).unwrap();
+            assert!(matches!(msg, ServerMessage::Pong(Pong { counter: 7 })));
+        }
+    }
+
+    #[test]
+    fn test_id() {
+        for codec in [Codec::Bincode, Codec::Cbor] {
+            assert_eq!(Codec::from_id(codec.id()), Some(codec));
+        }
+        assert_eq!(Codec::from_id(u8::MAX), None);
+    }
+
+    #[test]
+    fn test_cbor_unknown_field() {
+        let bytes = Codec::Cbor
+            .encode(&NewMessage::A(V2 {
+                a: 1,
+                b: "new".to_owned(),
+            }))
+            .unwrap();
+        let msg: OldMessage = Codec::Cbor.decode(&bytes).unwrap();
+        assert_eq!(msg, OldMessage::A(V1 { a: 1 }));
+    }
+
+    #[test]
+    fn test_cbor_unknown_variant() {
+        let bytes = Codec::Cbor.encode(&NewMessage::B(V1 { a: 1 })).unwrap();
+        let result: Result<OldMessage, _> = Codec::Cbor.decode(&bytes);
+        assert!(matches!(result, Err(TransportError::Decode(_))));
+    }
+}
@@ -0,0 +1,428 @@
+//! Message framing.
+//!
+//! A connection starts with hello exchange where the client sends its hello
+//! and the server replies with the negotiated parameters. Hello is made of
+//! magic bytes, version, 16 bit big endian body length, and the body. Peers
+//! ignore body fields they don't know so newer versions can add fields.
+//!
+//! Each message after that is a frame made of a 32 bit big endian payload
+//! length followed by the payload.
+
+use super::codec::Codec;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{error::Error, fmt, io};
+
+/// Magic bytes at the start of hello.
+pub const MAGIC: [u8; 4] = *b"TRNG";
+
+/// Framing protocol version.
+///
+/// Version 2 adds codec to hello.
+pub const VERSION: u8 = 2;
+
+pub const HEADER_LEN: usize = (u32::BITS / 8) as _; // 32 bit = 4 byte
+
+/// Maximum frame payload length we accept unless the peer asks for less.
+pub const DEFAULT_MAX_FRAME_LEN: u32 = 1024 * 1024; // 1 MiB
+
+/// Negotiated maximum frame payload length can't be smaller than this.
+pub const MIN_MAX_FRAME_LEN: u32 = 1024;
+
+const HELLO_HEADER_LEN: usize = MAGIC.len() + 1 /* version */ + 2 /* body length */;
+
+/// Transport error.
+#[derive(Debug)]
+pub enum TransportError {
+    /// Connection was closed by the peer.
+    Eof,
+    /// Frame payload is larger than the negotiated maximum.
+    Oversized {
+        len: usize,
+        max: usize,
+    },
+    /// Message can't be encoded into frame payload.
+    Encode(Box<dyn Error + Send + Sync>),
+    /// Frame payload can't be decoded into a message.
+    Decode(Box<dyn Error + Send + Sync>),
+    /// Peer doesn't speak a compatible protocol.
+    Incompatible {
+        msg: String,
+    },
+    Io(io::Error),
+}
+
+impl From<io::Error> for TransportError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => TransportError::Eof,
+            _ => TransportError::Io(err),
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Eof => f.write_str("connection closed by peer"),
+            TransportError::Oversized { len, max } => {
+                write!(f, "frame length {} exceeds maximum of {}", len, max)
+            }
+            TransportError::Encode(_) => f.write_str("failed to encode message"),
+            TransportError::Decode(_) => f.write_str("failed to decode message"),
+            TransportError::Incompatible { msg } => {
+                write!(f, "incompatible peer, {}", msg)
+            }
+            TransportError::Io(_) => f.write_str("i/o error"),
+        }
+    }
+}
+
+impl Error for TransportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TransportError::Encode(err) => Some(err.as_ref()),
+            TransportError::Decode(err) => Some(err.as_ref()),
+            TransportError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Parameters exchanged at the start of a connection.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Hello {
+    pub version: u8,
+    pub max_frame_len: u32,
+    pub codec: Codec,
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            codec: Codec::default(),
+        }
+    }
+}
+
+impl Hello {
+    pub fn encode(&self, buf: &mut BytesMut) {
+        let body_len = 4 /* max frame len */ + 1 /* codec */;
+        buf.reserve(HELLO_HEADER_LEN + body_len);
+        buf.put_slice(&MAGIC);
+        buf.put_u8(self.version);
+        buf.put_u16(body_len as _);
+        buf.put_u32(self.max_frame_len);
+        buf.put_u8(self.codec.id());
+    }
+
+    /// Takes hello from the buffer.
+    ///
+    /// Returns `None` if the buffer doesn't hold a complete hello yet, in
+    /// which case the buffer is left untouched.
+    pub fn decode(buf: &mut BytesMut) -> Result<Option<Self>, TransportError> {
+        let magic_len = buf.len().min(MAGIC.len());
+        if buf[..magic_len] != MAGIC[..magic_len] {
+            return Err(TransportError::Incompatible {
+                msg: "peer is not a terong peer".to_owned(),
+            });
+        }
+
+        if buf.len() < HELLO_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let version = buf[MAGIC.len()];
+        if version == 0 {
+            return Err(TransportError::Incompatible {
+                msg: format!("unsupported version {}", version),
+            });
+        }
+
+        let body_len = u16::from_be_bytes([buf[MAGIC.len() + 1], buf[MAGIC.len() + 2]]) as usize;
+        if buf.len() < HELLO_HEADER_LEN + body_len {
+            return Ok(None);
+        }
+
+        buf.advance(HELLO_HEADER_LEN);
+        let mut body = buf.split_to(body_len);
+
+        if body.remaining() < 4 {
+            return Err(TransportError::Incompatible {
+                msg: "hello is too short".to_owned(),
+            });
+        }
+        let max_frame_len = body.get_u32();
+
+        // version 1 peers only know bincode, unknown codecs fall back to it too
+        let codec = if body.has_remaining() {
+            Codec::from_id(body.get_u8()).unwrap_or_default()
+        } else {
+            Codec::Bincode
+        };
+
+        Ok(Some(Self {
+            version,
+            max_frame_len,
+            codec,
+        }))
+    }
+
+    /// Returns parameters both this and the other hello agree on.
+    ///
+    /// Codec is taken from the other hello, i.e. server uses the codec client
+    /// asks for, and client uses the codec in server's reply.
+    pub fn negotiate(&self, other: &Self) -> Self {
+        let version = self.version.min(other.version);
+        Self {
+            version,
+            max_frame_len: self
+                .max_frame_len
+                .min(other.max_frame_len)
+                .max(MIN_MAX_FRAME_LEN),
+            codec: if version < 2 {
+                Codec::Bincode
+            } else {
+                other.codec
+            },
+        }
+    }
+}
+
+/// Writes frame header and payload into the buffer.
+pub fn encode_frame(buf: &mut BytesMut, payload: &[u8], max: usize) -> Result<(), TransportError> {
+    if payload.len() > max {
+        return Err(TransportError::Oversized {
+            len: payload.len(),
+            max,
+        });
+    }
+    buf.reserve(HEADER_LEN + payload.len());
+    buf.put_u32(payload.len() as _);
+    buf.put_slice(payload);
+    Ok(())
+}
+
+/// Takes a complete frame payload from the buffer.
+///
+/// Returns `None` if the buffer doesn't hold a complete frame yet, in which
+/// case the buffer is left untouched.
+pub fn decode_frame(buf: &mut BytesMut, max: usize) -> Result<Option<Bytes>, TransportError> {
+    if buf.len() < HEADER_LEN {
+        return Ok(None);
+    }
+
+    let len = u32::from_be_bytes(buf[..HEADER_LEN].try_into().unwrap()) as usize;
+    if len > max {
+        return Err(TransportError::Oversized { len, max });
+    }
+
+    if buf.len() < HEADER_LEN + len {
+        // make room for the rest of the frame
+        buf.reserve(HEADER_LEN + len - buf.len());
+        return Ok(None);
+    }
+
+    buf.advance(HEADER_LEN);
+    Ok(Some(buf.split_to(len).freeze()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const MAX: usize = 4096;
+
+    fn encode_all(payloads: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        for x in payloads {
+            encode_frame(&mut buf, x, MAX).unwrap();
+        }
+        buf.to_vec()
+    }
+
+    fn encode_hello(hello: &Hello) -> BytesMut {
+        let mut buf = BytesMut::new();
+        hello.encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_hello_roundtrip() {
+        let hello = Hello::default();
+        let mut buf = encode_hello(&hello);
+        assert_eq!(Hello::decode(&mut buf).unwrap(), Some(hello));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_hello_partial() {
+        let hello = encode_hello(&Hello::default());
+        for cut in 0..hello.len() {
+            let mut buf = BytesMut::from(&hello[..cut]);
+            assert_eq!(Hello::decode(&mut buf).unwrap(), None);
+            assert_eq!(buf.len(), cut);
+        }
+    }
+
+    #[test]
+    fn test_hello_from_newer_version() {
+        // newer version with an extra body field
+        let mut buf = BytesMut::new();
+        buf.put_slice(&MAGIC);
+        buf.put_u8(VERSION + 1);
+        buf.put_u16(6);
+        buf.put_u32(4096);
+        buf.put_u8(Codec::Cbor.id());
+        buf.put_u8(0xFF);
+        buf.put_u32(0); // next frame header
+
+        let hello = Hello::decode(&mut buf).unwrap().unwrap();
+        assert_eq!(hello.version, VERSION + 1);
+        assert_eq!(hello.max_frame_len, 4096);
+        assert_eq!(hello.codec, Codec::Cbor);
+        assert_eq!(Hello::default().negotiate(&hello).version, VERSION);
+        assert_eq!(&buf[..], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_hello_from_version_1() {
+        let mut buf = BytesMut::new();
+        buf.put_slice(&MAGIC);
+        buf.put_u8(1);
+        buf.put_u16(4);
+        buf.put_u32(4096);
+
+        let hello = Hello::decode(&mut buf).unwrap().unwrap();
+        assert_eq!(hello.codec, Codec::Bincode);
+
+        let local = Hello {
+            codec: Codec::Cbor,
+            ..Default::default()
+        };
+        let negotiated = local.negotiate(&hello);
+        assert_eq!(negotiated.version, 1);
+        assert_eq!(negotiated.codec, Codec::Bincode);
+    }
+
+    #[test]
+    fn test_hello_negotiate() {
+        let a = Hello::default();
+        let b = Hello {
+            max_frame_len: 4096,
+            ..Default::default()
+        };
+        assert_eq!(a.negotiate(&b).max_frame_len, 4096);
+
+        let c = Hello {
+            max_frame_len: 0,
+            ..Default::default()
+        };
+        assert_eq!(a.negotiate(&c).max_frame_len, MIN_MAX_FRAME_LEN);
+
+        let d = Hello {
+            codec: Codec::Cbor,
+            ..Default::default()
+        };
+        assert_eq!(a.negotiate(&d).codec, Codec::Cbor);
+    }
+
+    #[test]
+    fn test_hello_incompatible() {
+        // detected as soon as the first byte doesn't match
+        assert!(matches!(
+            Hello::decode(&mut BytesMut::from(&b"G"[..])),
+            Err(TransportError::Incompatible { .. })
+        ));
+        assert!(matches!(
+            Hello::decode(&mut BytesMut::from(&b"GET / HTTP/1.1"[..])),
+            Err(TransportError::Incompatible { .. })
+        ));
+
+        let mut hello = encode_hello(&Hello::default());
+        hello[MAGIC.len()] = 0;
+        assert!(matches!(
+            Hello::decode(&mut hello),
+            Err(TransportError::Incompatible { .. })
+        ));
+    }
+
+    #[test]
+    fn test_encode_oversized() {
+        let mut buf = BytesMut::new();
+        let result = encode_frame(&mut buf, &[0; MAX + 1], MAX);
+        assert!(matches!(result, Err(TransportError::Oversized { .. })));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_zero_length_frame() {
+        let mut buf = BytesMut::from(&[0, 0, 0, 0][..]);
+        assert_eq!(decode_frame(&mut buf, MAX).unwrap(), Some(Bytes::new()));
+        assert!(buf.is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn test_roundtrip_in_chunks(
+            payloads in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..MAX), 0..8),
+            chunk_len in 1..MAX,
+        ) {
+            let bytes = encode_all(&payloads);
+
+            let mut buf = BytesMut::new();
+            let mut decoded = Vec::new();
+            for chunk in bytes.chunks(chunk_len) {
+                buf.extend_from_slice(chunk);
+                while let Some(x) = decode_frame(&mut buf, MAX).unwrap() {
+                    decoded.push(x.to_vec());
+                }
+            }
+
+            prop_assert_eq!(decoded, payloads);
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn test_truncated(payload in prop::collection::vec(any::<u8>(), 0..MAX), cut in 0usize..MAX) {
+            let bytes = encode_all(&[payload]);
+            let cut = cut % bytes.len();
+
+            let mut buf = BytesMut::from(&bytes[..cut]);
+            prop_assert!(decode_frame(&mut buf, MAX).unwrap().is_none());
+            prop_assert_eq!(&buf[..], &bytes[..cut]);
+        }
+
+        #[test]
+        fn test_malicious(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            let mut buf = BytesMut::from(&bytes[..]);
+            loop {
+                match decode_frame(&mut buf, MAX) {
+                    Ok(Some(x)) => prop_assert!(x.len() <= MAX),
+                    Ok(None) => break,
+                    Err(TransportError::Oversized { len, max }) => {
+                        prop_assert!(len > max);
+                        break;
+                    }
+                    Err(err) => prop_assert!(false, "unexpected error {:?}", err),
+                }
+            }
+        }
+
+        #[test]
+        fn test_claimed_length_over_max(len in (MAX as u32 + 1)..) {
+            let mut buf = BytesMut::new();
+            buf.put_u32(len);
+            let result = decode_frame(&mut buf, MAX);
+            prop_assert!(
+                matches!(result, Err(TransportError::Oversized { .. })),
+                "unexpected result {:?}",
+                result
+            );
+            // must not reserve memory for the claimed length
+            prop_assert!(buf.capacity() < MAX);
+        }
+    }
+}
@@ -0,0 +1,663 @@
+//! Wire format of the Go implementation of terong.
+//!
+//! Each frame is a 16 bit big endian tag, a 16 bit big endian value length,
+//! and the value. Event values are CBOR maps keyed by field names, ping has an
+//! empty value. There's no hello, and both peers send ping periodically and
+//! end the session if they don't receive one in time.
+
+use super::{frame::TransportError, AsyncStream};
+use crate::{
+    logging::{Redact, Redacted},
+    metrics::Metrics,
+    transport::protocol::{InputEvent, KeyCode, MouseButton, MouseScrollDirection, ScrollAxis},
+};
+use bytes::{Buf, BufMut, BytesMut};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fmt::{self, Debug},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+use tracing::debug;
+
+const HEADER_LEN: usize = 2 /* tag */ + 2 /* length */;
+
+/// Maximum value length Go peers accept.
+pub const MAX_VALUE_LEN: usize = 1024 - HEADER_LEN;
+
+/// Go peers end the session if they don't receive ping within this duration.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval we send ping at, comfortably within [PING_TIMEOUT].
+pub const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time the server waits for the client to speak first before assuming it's a
+/// Go client, which only speaks when it pings.
+const DETECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+const TAG_MOUSE_MOVE: u16 = 1;
+const TAG_MOUSE_CLICK: u16 = 2;
+const TAG_MOUSE_SCROLL: u16 = 3;
+const TAG_KEY_PRESS: u16 = 4;
+const TAG_PING: u16 = 5;
+
+const MOUSE_BUTTON_ACTION_DOWN: u8 = 1;
+const MOUSE_BUTTON_ACTION_UP: u8 = 2;
+
+const MOUSE_SCROLL_UP: u8 = 1;
+const MOUSE_SCROLL_DOWN: u8 = 2;
+
+/// High resolution scroll delta of a wheel click.
+const HI_RES_CLICK: i32 = 120;
+
+const KEY_ACTION_DOWN: u8 = 1;
+const KEY_ACTION_REPEAT: u8 = 2;
+const KEY_ACTION_UP: u8 = 3;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+struct GoMouseMove {
+    dx: i16,
+    dy: i16,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+struct GoMouseClick {
+    button: u8,
+    action: u8,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+struct GoMouseScroll {
+    direction: u8,
+    count: u8,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+struct GoKeyPress {
+    key: u16,
+    action: u8,
+}
+
+/// Message exchanged with Go peers.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GoMessage {
+    Event(InputEvent),
+    Ping,
+}
+
+impl Redact for GoMessage {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GoMessage::Event(x) => f.debug_tuple("Event").field(&Redacted(x, false)).finish(),
+            x => fmt::Debug::fmt(x, f),
+        }
+    }
+}
+
+/// Go mouse buttons start at 1 and end at `Mouse5`, in the same order as ours.
+fn go_mouse_button(button: MouseButton) -> Option<u8> {
+    if button as u8 > MouseButton::Mouse5 as u8 {
+        return None;
+    }
+    Some(button as u8 + 1)
+}
+
+fn mouse_button_from_go(button: u8) -> Option<MouseButton> {
+    let button = MouseButton::from_repr(button.checked_sub(1)?)?;
+    go_mouse_button(button).map(|_| button)
+}
+
+/// Go key codes start at 1 and end at `Right`, in the same order as ours.
+fn go_key_code(key: KeyCode) -> Option<u16> {
+    if key as u16 > KeyCode::Right as u16 {
+        return None;
+    }
+    Some(key as u16 + 1)
+}
+
+fn key_code_from_go(key: u16) -> Option<KeyCode> {
+    let key = KeyCode::from_repr(key.checked_sub(1)?)?;
+    go_key_code(key).map(|_| key)
+}
+
+fn cbor<T: Serialize>(value: &T) -> Result<Vec<u8>, TransportError> {
+    let mut buf = Vec::new();
+    ciborium::into_writer(value, &mut buf).map_err(|err| TransportError::Encode(err.into()))?;
+    Ok(buf)
+}
+
+fn from_cbor<T: DeserializeOwned>(value: &[u8]) -> Result<T, TransportError> {
+    ciborium::from_reader(value).map_err(|err| TransportError::Decode(err.into()))
+}
+
+fn unsupported(what: impl Debug) -> TransportError {
+    TransportError::Decode(format!("unsupported value {:?}", what).into())
+}
+
+/// Accumulates high resolution scroll into wheel clicks, Go peers only know
+/// the latter.
+#[derive(Default, Debug)]
+struct ScrollAccumulator {
+    vertical: i32,
+    horizontal: i32,
+}
+
+impl ScrollAccumulator {
+    /// Converts high resolution scroll into scroll by whole clicks, keeping the
+    /// remainder for the next one. Other events are returned as is.
+    ///
+    /// Returns `None` if the scroll hasn't added up to a click yet.
+    fn convert(&mut self, event: InputEvent) -> Option<InputEvent> {
+        let (axis, delta) = match event {
+            InputEvent::MouseScrollHiRes { axis, delta } => (axis, delta as i32),
+            x => return Some(x),
+        };
+        let acc = match axis {
+            ScrollAxis::Vertical => &mut self.vertical,
+            ScrollAxis::Horizontal => &mut self.horizontal,
+        };
+        // start over when the scroll changes direction
+        if acc.signum() * delta.signum() < 0 {
+            *acc = 0;
+        }
+        *acc += delta;
+        let clicks = *acc / HI_RES_CLICK;
+        if clicks == 0 {
+            return None;
+        }
+        *acc -= clicks * HI_RES_CLICK;
+        let count = clicks.unsigned_abs().min(u8::MAX as _) as u8;
+        let direction = match (axis, clicks > 0) {
+            (ScrollAxis::Vertical, true) => MouseScrollDirection::Up { clicks: count },
+            (ScrollAxis::Vertical, false) => MouseScrollDirection::Down { clicks: count },
+            (ScrollAxis::Horizontal, true) => MouseScrollDirection::Right { clicks: count },
+            (ScrollAxis::Horizontal, false) => MouseScrollDirection::Left { clicks: count },
+        };
+        Some(InputEvent::MouseScroll { direction })
+    }
+}
+
+/// Converts an event into Go tag and value.
+///
+/// Returns `None` if Go peers have no equivalent of the event.
+fn encode_event(event: &InputEvent) -> Result<Option<(u16, Vec<u8>)>, TransportError> {
+    let x = match *event {
+        InputEvent::MouseMove { dx, dy } => (TAG_MOUSE_MOVE, cbor(&GoMouseMove { dx, dy })?),
+        InputEvent::MouseButtonDown { button } | InputEvent::MouseButtonUp { button } => {
+            let button = match go_mouse_button(button) {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+            let action = match event {
+                InputEvent::MouseButtonDown { .. } => MOUSE_BUTTON_ACTION_DOWN,
+                _ => MOUSE_BUTTON_ACTION_UP,
+            };
+            (TAG_MOUSE_CLICK, cbor(&GoMouseClick { button, action })?)
+        }
+        InputEvent::MouseScroll { direction } => {
+            let (direction, count) = match direction {
+                MouseScrollDirection::Up { clicks } => (MOUSE_SCROLL_UP, clicks),
+                MouseScrollDirection::Down { clicks } => (MOUSE_SCROLL_DOWN, clicks),
+                MouseScrollDirection::Left { .. } | MouseScrollDirection::Right { .. } => {
+                    return Ok(None)
+                }
+            };
+            (TAG_MOUSE_SCROLL, cbor(&GoMouseScroll { direction, count })?)
+        }
+        InputEvent::KeyDown { key } | InputEvent::KeyRepeat { key } | InputEvent::KeyUp { key } => {
+            let key = match go_key_code(key) {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+            let action = match event {
+                InputEvent::KeyDown { .. } => KEY_ACTION_DOWN,
+                InputEvent::KeyRepeat { .. } => KEY_ACTION_REPEAT,
+                _ => KEY_ACTION_UP,
+            };
+            (TAG_KEY_PRESS, cbor(&GoKeyPress { key, action })?)
+        }
+        InputEvent::RawKeyDown { .. }
+        | InputEvent::RawKeyUp { .. }
+        | InputEvent::MouseScrollHiRes { .. }
+        | InputEvent::AbsolutePointer { .. }
+        | InputEvent::Gamepad { .. }
+        | InputEvent::Gesture { .. } => return Ok(None),
+    };
+    Ok(Some(x))
+}
+
+fn decode_event(tag: u16, value: &[u8]) -> Result<InputEvent, TransportError> {
+    let event = match tag {
+        TAG_MOUSE_MOVE => {
+            let GoMouseMove { dx, dy } = from_cbor(value)?;
+            InputEvent::MouseMove { dx, dy }
+        }
+        TAG_MOUSE_CLICK => {
+            let x: GoMouseClick = from_cbor(value)?;
+            let button = mouse_button_from_go(x.button).ok_or_else(|| unsupported(x))?;
+            match x.action {
+                MOUSE_BUTTON_ACTION_DOWN => InputEvent::MouseButtonDown { button },
+                MOUSE_BUTTON_ACTION_UP => InputEvent::MouseButtonUp { button },
+                _ => return Err(unsupported(x)),
+            }
+        }
+        TAG_MOUSE_SCROLL => {
+            let x: GoMouseScroll = from_cbor(value)?;
+            let direction = match x.direction {
+                MOUSE_SCROLL_UP => MouseScrollDirection::Up { clicks: x.count },
+                MOUSE_SCROLL_DOWN => MouseScrollDirection::Down { clicks: x.count },
+                _ => return Err(unsupported(x)),
+            };
+            InputEvent::MouseScroll { direction }
+        }
+        TAG_KEY_PRESS => {
+            let x: GoKeyPress = from_cbor(value)?;
+            let key = key_code_from_go(x.key).ok_or_else(|| unsupported(x))?;
+            match x.action {
+                KEY_ACTION_DOWN => InputEvent::KeyDown { key },
+                KEY_ACTION_REPEAT => InputEvent::KeyRepeat { key },
+                KEY_ACTION_UP => InputEvent::KeyUp { key },
+                _ => return Err(unsupported(x)),
+            }
+        }
+        _ => return Err(unsupported(format!("tag {}", tag))),
+    };
+    Ok(event)
+}
+
+/// Writes message frame into the buffer.
+///
+/// Returns `false` if Go peers have no equivalent of the message, in which
+/// case nothing is written.
+pub fn encode_frame(buf: &mut BytesMut, msg: &GoMessage) -> Result<bool, TransportError> {
+    let (tag, value) = match msg {
+        GoMessage::Event(event) => match encode_event(event)? {
+            Some(x) => x,
+            None => return Ok(false),
+        },
+        GoMessage::Ping => (TAG_PING, Vec::new()),
+    };
+    if value.len() > MAX_VALUE_LEN {
+        return Err(TransportError::Oversized {
+            len: value.len(),
+            max: MAX_VALUE_LEN,
+        });
+    }
+    buf.reserve(HEADER_LEN + value.len());
+    buf.put_u16(tag);
+    buf.put_u16(value.len() as _);
+    buf.put_slice(&value);
+    Ok(true)
+}
+
+/// Takes a message from the buffer.
+///
+/// Returns `None` if the buffer doesn't hold a complete frame yet, in which
+/// case the buffer is left untouched. Frames that can't be decoded are taken
+/// from the buffer so the next frame can still be read.
+pub fn decode_frame(buf: &mut BytesMut) -> Result<Option<GoMessage>, TransportError> {
+    if buf.len() < HEADER_LEN {
+        return Ok(None);
+    }
+
+    let tag = u16::from_be_bytes([buf[0], buf[1]]);
+    let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    if len > MAX_VALUE_LEN {
+        return Err(TransportError::Oversized {
+            len,
+            max: MAX_VALUE_LEN,
+        });
+    }
+
+    if buf.len() < HEADER_LEN + len {
+        buf.reserve(HEADER_LEN + len - buf.len());
+        return Ok(None);
+    }
+
+    buf.advance(HEADER_LEN);
+    let value = buf.split_to(len);
+
+    let msg = match tag {
+        TAG_PING => GoMessage::Ping,
+        tag => GoMessage::Event(decode_event(tag, &value)?),
+    };
+    Ok(Some(msg))
+}
+
+/// Waits for the client to speak first to tell which wire format it speaks.
+///
+/// Our clients start with hello while Go clients stay quiet until they ping.
+/// Returns whether the client speaks Go wire format, and the bytes read.
+pub async fn detect_go_client(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<(bool, BytesMut), TransportError> {
+    let mut buf = BytesMut::new();
+    match timeout(DETECT_TIMEOUT, stream.read_buf(&mut buf)).await {
+        Ok(Ok(0)) => Err(TransportError::Eof),
+        Ok(Ok(_)) => Ok((buf[0] != super::frame::MAGIC[0], buf)),
+        Ok(Err(err)) => Err(err.into()),
+        Err(_) => Ok((true, buf)),
+    }
+}
+
+/// Transport speaking Go wire format.
+#[derive(Debug)]
+pub struct GoTransport {
+    stream: Pin<Box<dyn AsyncStream + Send>>,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    scroll: ScrollAccumulator,
+    log_keystrokes: bool,
+    /// Counts sent bytes if set.
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl GoTransport {
+    /// Creates a new transport, `read_buf` holds bytes already read from the
+    /// stream.
+    pub fn new(stream: impl AsyncStream + Send + 'static, read_buf: BytesMut) -> Self {
+        Self {
+            stream: Box::pin(stream),
+            read_buf,
+            write_buf: Default::default(),
+            scroll: Default::default(),
+            log_keystrokes: false,
+            metrics: None,
+        }
+    }
+
+    /// Sets if keys of input events are logged, they're redacted otherwise.
+    pub fn set_log_keystrokes(&mut self, value: bool) {
+        self.log_keystrokes = value;
+    }
+
+    /// Sets the metrics to count sent bytes in.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Formats the value for logs, as configured by [Self::set_log_keystrokes].
+    pub fn redacted<'a, T: Redact + ?Sized>(&self, value: &'a T) -> Redacted<'a, T> {
+        Redacted(value, self.log_keystrokes)
+    }
+
+    /// Sends a message, messages Go peers don't understand are dropped and high
+    /// resolution scroll is sent once it adds up to a wheel click. Returns if
+    /// the message was sent.
+    ///
+    /// This method is not cancel safe.
+    pub async fn send_msg(&mut self, msg: GoMessage) -> Result<bool, TransportError> {
+        let msg = match msg {
+            GoMessage::Event(event) => match self.scroll.convert(event) {
+                Some(x) => GoMessage::Event(x),
+                None => return Ok(false),
+            },
+            x => x,
+        };
+        self.write_buf.clear();
+        if !encode_frame(&mut self.write_buf, &msg)? {
+            debug!(msg = ?self.redacted(&msg), "go peer doesn't support message, dropping");
+            return Ok(false);
+        }
+        let size = self.write_buf.len();
+        debug!(msg = ?self.redacted(&msg), size, "sending message");
+        self.stream.write_all(&self.write_buf).await?;
+        self.stream.flush().await?;
+        if let Some(metrics) = &self.metrics {
+            metrics.bytes_sent(size);
+        }
+        Ok(true)
+    }
+
+    /// Waits for a message.
+    ///
+    /// This method is cancel safe.
+    pub async fn recv_msg(&mut self) -> Result<GoMessage, TransportError> {
+        loop {
+            if let Some(msg) = decode_frame(&mut self.read_buf)? {
+                debug!(msg = ?self.redacted(&msg), "received message");
+                break Ok(msg);
+            }
+            let size = self.stream.read_buf(&mut self.read_buf).await?;
+            if size == 0 {
+                break Err(TransportError::Eof);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strum::IntoEnumIterator;
+
+    fn encode(msg: GoMessage) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        assert!(encode_frame(&mut buf, &msg).unwrap());
+        buf.to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> GoMessage {
+        let mut buf = BytesMut::from(bytes);
+        let msg = decode_frame(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        msg
+    }
+
+    fn assert_golden(event: InputEvent, golden: &[u8]) {
+        assert_eq!(encode(GoMessage::Event(event)), golden, "{:?}", event);
+        assert_eq!(decode(golden), GoMessage::Event(event));
+    }
+
+    // golden bytes are produced by the Go implementation, i.e.
+    // `transport.WriteFrame` with value from `cbor.Marshal(&input)`
+
+    #[test]
+    fn test_golden_ping() {
+        let golden = [0x00, 0x05, 0x00, 0x00];
+        assert_eq!(encode(GoMessage::Ping), golden);
+        assert_eq!(decode(&golden), GoMessage::Ping);
+    }
+
+    #[test]
+    fn test_golden_mouse_move() {
+        // {"dx": 1, "dy": -300}
+        assert_golden(
+            InputEvent::MouseMove { dx: 1, dy: -300 },
+            &[
+                0x00, 0x01, 0x00, 0x0B, //
+                0xA2, 0x62, b'd', b'x', 0x01, 0x62, b'd', b'y', 0x39, 0x01, 0x2B,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_golden_mouse_click() {
+        // {"button": 3, "action": 1}
+        assert_golden(
+            InputEvent::MouseButtonDown {
+                button: MouseButton::Middle,
+            },
+            &[
+                0x00, 0x02, 0x00, 0x11, //
+                0xA2, 0x66, b'b', b'u', b't', b't', b'o', b'n', 0x03, //
+                0x66, b'a', b'c', b't', b'i', b'o', b'n', 0x01,
+            ],
+        );
+        // {"button": 1, "action": 2}
+        assert_golden(
+            InputEvent::MouseButtonUp {
+                button: MouseButton::Left,
+            },
+            &[
+                0x00, 0x02, 0x00, 0x11, //
+                0xA2, 0x66, b'b', b'u', b't', b't', b'o', b'n', 0x01, //
+                0x66, b'a', b'c', b't', b'i', b'o', b'n', 0x02,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_golden_mouse_scroll() {
+        // {"direction": 2, "count": 3}
+        assert_golden(
+            InputEvent::MouseScroll {
+                direction: MouseScrollDirection::Down { clicks: 3 },
+            },
+            &[
+                0x00, 0x03, 0x00, 0x13, //
+                0xA2, 0x69, b'd', b'i', b'r', b'e', b'c', b't', b'i', b'o', b'n', 0x02, //
+                0x65, b'c', b'o', b'u', b'n', b't', 0x03,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_golden_key_press() {
+        // {"key": 1, "action": 1}
+        assert_golden(
+            InputEvent::KeyDown {
+                key: KeyCode::Escape,
+            },
+            &[
+                0x00, 0x04, 0x00, 0x0E, //
+                0xA2, 0x63, b'k', b'e', b'y', 0x01, //
+                0x66, b'a', b'c', b't', b'i', b'o', b'n', 0x01,
+            ],
+        );
+        // {"key": 30, "action": 2}
+        assert_golden(
+            InputEvent::KeyRepeat { key: KeyCode::A },
+            &[
+                0x00, 0x04, 0x00, 0x0F, //
+                0xA2, 0x63, b'k', b'e', b'y', 0x18, 0x1E, //
+                0x66, b'a', b'c', b't', b'i', b'o', b'n', 0x02,
+            ],
+        );
+        // {"key": 86, "action": 3}
+        assert_golden(
+            InputEvent::KeyUp {
+                key: KeyCode::Right,
+            },
+            &[
+                0x00, 0x04, 0x00, 0x0F, //
+                0xA2, 0x63, b'k', b'e', b'y', 0x18, 0x56, //
+                0x66, b'a', b'c', b't', b'i', b'o', b'n', 0x03,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_all_keys_and_buttons() {
+        for key in KeyCode::iter().take_while(|x| *x as u16 <= KeyCode::Right as u16) {
+            let event = InputEvent::KeyDown { key };
+            assert_eq!(
+                decode(&encode(GoMessage::Event(event))),
+                GoMessage::Event(event)
+            );
+        }
+        for button in MouseButton::iter().take_while(|x| *x as u8 <= MouseButton::Mouse5 as u8) {
+            let event = InputEvent::MouseButtonDown { button };
+            assert_eq!(
+                decode(&encode(GoMessage::Event(event))),
+                GoMessage::Event(event)
+            );
+        }
+    }
+
+    #[test]
+    fn test_keys_unknown_to_go_are_not_written() {
+        let mut buf = BytesMut::new();
+        let event = InputEvent::KeyDown {
+            key: KeyCode::NumLock,
+        };
+        assert!(!encode_frame(&mut buf, &GoMessage::Event(event)).unwrap());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_tag_is_skipped() {
+        let mut buf = BytesMut::from(&[0x00, 0x63, 0x00, 0x01, 0xFF, 0x00, 0x05, 0x00, 0x00][..]);
+        assert!(matches!(
+            decode_frame(&mut buf),
+            Err(TransportError::Decode(_))
+        ));
+        assert_eq!(decode_frame(&mut buf).unwrap(), Some(GoMessage::Ping));
+    }
+
+    #[test]
+    fn test_oversized() {
+        let mut buf = BytesMut::from(&[0x00, 0x01, 0x03, 0xFD][..]);
+        assert!(matches!(
+            decode_frame(&mut buf),
+            Err(TransportError::Oversized { len: 1021, .. })
+        ));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let bytes = encode(GoMessage::Event(InputEvent::MouseMove { dx: 1, dy: 1 }));
+        for cut in 0..bytes.len() {
+            let mut buf = BytesMut::from(&bytes[..cut]);
+            assert_eq!(decode_frame(&mut buf).unwrap(), None);
+            assert_eq!(buf.len(), cut);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hi_res_scroll_is_sent_as_clicks() {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut tx = GoTransport::new(a, Default::default());
+        let mut rx = GoTransport::new(b, Default::default());
+
+        let scroll = |axis, delta| GoMessage::Event(InputEvent::MouseScrollHiRes { axis, delta });
+        let mut sent = Vec::new();
+        for delta in [30, 30, 30, 30, 300] {
+            sent.push(
+                tx.send_msg(scroll(ScrollAxis::Vertical, delta))
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(sent, [false, false, false, true, true]);
+        // remainder is dropped when the scroll changes direction
+        tx.send_msg(scroll(ScrollAxis::Vertical, -120))
+            .await
+            .unwrap();
+        tx.send_msg(GoMessage::Ping).await.unwrap();
+
+        let clicks = |direction| GoMessage::Event(InputEvent::MouseScroll { direction });
+        assert_eq!(
+            rx.recv_msg().await.unwrap(),
+            clicks(MouseScrollDirection::Up { clicks: 1 })
+        );
+        assert_eq!(
+            rx.recv_msg().await.unwrap(),
+            clicks(MouseScrollDirection::Up { clicks: 2 })
+        );
+        assert_eq!(
+            rx.recv_msg().await.unwrap(),
+            clicks(MouseScrollDirection::Down { clicks: 1 })
+        );
+        assert_eq!(rx.recv_msg().await.unwrap(), GoMessage::Ping);
+    }
+
+    #[tokio::test]
+    async fn test_detect_go_client() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+
+        b.write_all(&[0x00, 0x05, 0x00, 0x00]).await.unwrap();
+        let (go, buf) = detect_go_client(&mut a).await.unwrap();
+        assert!(go);
+        assert_eq!(&buf[..], &[0x00, 0x05, 0x00, 0x00]);
+
+        b.write_all(&super::super::frame::MAGIC).await.unwrap();
+        let (go, _) = detect_go_client(&mut a).await.unwrap();
+        assert!(!go);
+    }
+}
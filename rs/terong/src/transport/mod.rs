@@ -1,47 +1,66 @@
+pub mod codec;
+pub mod frame;
+pub mod go_compat;
+pub mod noise;
 pub mod protocol;
-
-use self::protocol::{ClientMessage, ServerMessage};
-use anyhow::Error;
-use bytes::{Buf, BufMut, BytesMut};
+pub mod tls;
+
+use self::{
+    codec::Codec,
+    frame::{decode_frame, encode_frame, Hello, TransportError, DEFAULT_MAX_FRAME_LEN},
+    noise::NoiseAuth,
+    protocol::{ClientMessage, ServerMessage},
+    tls::TlsFiles,
+};
+use crate::{
+    logging::{Redact, Redacted},
+    metrics::Metrics,
+};
+use bytes::BytesMut;
 use macross::newtype;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    convert::TryInto,
     fmt::{self, Debug},
     marker::PhantomData,
     pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
 };
-use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use tracing::debug;
 
 /// Protocol message marker trait.
-pub trait Message: Serialize + DeserializeOwned {}
+pub trait Message: Serialize + DeserializeOwned + Redact {}
 
 impl Message for ServerMessage {}
 
 impl Message for ClientMessage {}
 
-const HEADER_LEN: usize = (u16::BITS / 8) as _; // 16 bit = 2 byte
+/// Time allowed for the peer to complete hello exchange.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Send protocol message.
 ///
 /// This function is not cancel safe.
 async fn send_msg(
     sink: &mut (impl AsyncWrite + Unpin),
+    buf: &mut BytesMut,
+    max_frame_len: usize,
+    codec: Codec,
+    log_keystrokes: bool,
     msg: impl Message + Debug,
-) -> Result<(), Error> {
-    debug!(?msg, "sending message");
+) -> Result<(), TransportError> {
+    let payload = codec.encode(&msg)?;
+    debug!(msg = ?Redacted(&msg, log_keystrokes), size = payload.len(), "sending message");
 
-    let msg_len: u16 = bincode::serialized_size(&msg)?.try_into()?;
-    let len = HEADER_LEN + msg_len as usize;
+    buf.clear();
+    encode_frame(buf, &payload, max_frame_len)?;
 
-    let mut buf = vec![0; len];
-    buf[0..HEADER_LEN].copy_from_slice(&msg_len.to_be_bytes());
-
-    bincode::serialize_into(&mut buf[HEADER_LEN..], &msg)?;
-
-    sink.write_all(&buf).await?;
+    sink.write_all(buf).await?;
 
     sink.flush().await?;
 
@@ -49,62 +68,72 @@ async fn send_msg(
 }
 
 #[derive(Debug)]
-struct MessageReader<'a, B> {
+struct MessageReader<'a> {
     src: &'a mut Pin<Box<dyn AsyncStream + Send>>,
-    buf: &'a mut B,
+    buf: &'a mut BytesMut,
+    max_frame_len: usize,
+    codec: Codec,
+    log_keystrokes: bool,
 }
 
-impl<'a, B> MessageReader<'a, B> {
-    fn new(src: &'a mut Pin<Box<dyn AsyncStream + Send>>, buf: &'a mut B) -> Self {
-        Self { src, buf }
+impl<'a> MessageReader<'a> {
+    fn new(
+        src: &'a mut Pin<Box<dyn AsyncStream + Send>>,
+        buf: &'a mut BytesMut,
+        max_frame_len: usize,
+        codec: Codec,
+        log_keystrokes: bool,
+    ) -> Self {
+        Self {
+            src,
+            buf,
+            max_frame_len,
+            codec,
+            log_keystrokes,
+        }
     }
-}
 
-impl<'a, B> MessageReader<'a, B>
-where
-    B: Buf + BufMut,
-{
-    /// Fill buffer until the specified size is reached.
+    /// Reads more bytes into the buffer.
     ///
     /// This function is cancel safe.
-    async fn fill_buf(&mut self, size: usize) -> Result<(), Error> {
-        while self.buf.remaining() < size {
-            let size = self.src.read_buf(&mut self.buf).await?;
-            if size == 0 {
-                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
-            }
+    async fn fill_buf(&mut self) -> Result<(), TransportError> {
+        let size = self.src.read_buf(self.buf).await?;
+        if size == 0 {
+            return Err(TransportError::Eof);
         }
         Ok(())
     }
 
+    /// Receive hello.
+    ///
+    /// This function is cancel safe.
+    async fn recv_hello(&mut self) -> Result<Hello, TransportError> {
+        loop {
+            if let Some(hello) = Hello::decode(self.buf)? {
+                break Ok(hello);
+            }
+            self.fill_buf().await?;
+        }
+    }
+
     /// Receive protocol message.
     ///
     /// This function is cancel safe.
-    async fn recv_msg<M>(&mut self) -> Result<M, Error>
+    async fn recv_msg<M>(&mut self) -> Result<M, TransportError>
     where
         M: Message + Debug,
     {
-        loop {
-            self.fill_buf(HEADER_LEN).await?;
-
-            // get message length
-            let length = self.buf.get_u16();
-
-            // ignore 0 bytes message
-            if length == 0 {
-                continue;
+        let payload = loop {
+            if let Some(payload) = decode_frame(self.buf, self.max_frame_len)? {
+                break payload;
             }
+            self.fill_buf().await?;
+        };
 
-            self.fill_buf(length as _).await?;
-
-            // take message length bytes
-            let bytes = self.buf.copy_to_bytes(length as _);
+        let msg: M = self.codec.decode(&payload)?;
+        debug!(msg = ?Redacted(&msg, self.log_keystrokes), size = payload.len(), "received message");
 
-            let msg: M = bincode::deserialize(&bytes)?;
-            debug!(?msg, "received message");
-
-            break Ok(msg);
-        }
+        Ok(msg)
     }
 }
 
@@ -112,11 +141,29 @@ pub trait AsyncStream: AsyncRead + AsyncWrite + Debug {}
 
 impl<T: AsyncRead + AsyncWrite + Debug> AsyncStream for T {}
 
+/// Side of a connection.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    /// Client asks for the codec it wants to use.
+    Client {
+        codec: Codec,
+    },
+    Server,
+}
+
 #[derive(Debug)]
 pub struct Transport<IN, OUT> {
     /// The IO stream.
     stream: Pin<Box<dyn AsyncStream + Send>>,
     read_buf: BytesMut,
+    write_buf: BytesMut,
+    /// Maximum frame payload length, in bytes.
+    max_frame_len: usize,
+    codec: Codec,
+    /// Log keys of input events, they're redacted otherwise.
+    log_keystrokes: bool,
+    /// Counts sent bytes if set.
+    metrics: Option<Arc<Metrics>>,
     /// Incoming message data type.
     _in: PhantomData<IN>,
     /// Outgoing message data type.
@@ -126,13 +173,94 @@ pub struct Transport<IN, OUT> {
 impl<IN, OUT> Transport<IN, OUT> {
     /// Creates a new transport.
     pub fn new(stream: impl AsyncStream + Send + 'static) -> Self {
+        Self::with_read_buf(stream, Default::default())
+    }
+
+    /// Creates a new transport, `read_buf` holds bytes already read from the
+    /// stream.
+    pub fn with_read_buf(stream: impl AsyncStream + Send + 'static, read_buf: BytesMut) -> Self {
         Self {
             stream: Box::pin(stream),
-            read_buf: Default::default(),
+            read_buf,
+            write_buf: Default::default(),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN as _,
+            codec: Codec::default(),
+            log_keystrokes: false,
+            metrics: None,
             _in: PhantomData,
             _out: PhantomData,
         }
     }
+
+    /// Sets if keys of input events are logged, they're redacted otherwise.
+    pub fn set_log_keystrokes(&mut self, value: bool) {
+        self.log_keystrokes = value;
+    }
+
+    /// Sets the metrics to count sent bytes in.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Exchanges hello with the peer and applies the negotiated parameters.
+    ///
+    /// Client sends its hello first, then server replies with the negotiated
+    /// parameters.
+    pub async fn hello(&mut self, role: Role) -> Result<Hello, TransportError> {
+        let local = Hello {
+            max_frame_len: self.max_frame_len as _,
+            codec: match role {
+                Role::Client { codec } => codec,
+                Role::Server => self.codec,
+            },
+            ..Default::default()
+        };
+
+        let exchange = async {
+            match role {
+                Role::Client { .. } => {
+                    self.send_hello(&local).await?;
+                    let reply = self.as_msg_reader().recv_hello().await?;
+                    Ok::<_, TransportError>(local.negotiate(&reply))
+                }
+                Role::Server => {
+                    let hello = self.as_msg_reader().recv_hello().await?;
+                    let negotiated = local.negotiate(&hello);
+                    self.send_hello(&negotiated).await?;
+                    Ok(negotiated)
+                }
+            }
+        };
+        let negotiated = timeout(HELLO_TIMEOUT, exchange).await.map_err(|_| {
+            TransportError::Incompatible {
+                msg: "timed out waiting for hello".to_owned(),
+            }
+        })??;
+        debug!(?negotiated, "hello exchanged");
+
+        self.max_frame_len = negotiated.max_frame_len as _;
+        self.codec = negotiated.codec;
+
+        Ok(negotiated)
+    }
+
+    async fn send_hello(&mut self, hello: &Hello) -> Result<(), TransportError> {
+        self.write_buf.clear();
+        hello.encode(&mut self.write_buf);
+        self.stream.write_all(&self.write_buf).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    fn as_msg_reader(&mut self) -> MessageReader<'_> {
+        MessageReader::new(
+            &mut self.stream,
+            &mut self.read_buf,
+            self.max_frame_len,
+            self.codec,
+            self.log_keystrokes,
+        )
+    }
 }
 
 impl<IN, OUT> Transport<IN, OUT>
@@ -142,8 +270,20 @@ where
     /// Sends a protocol message.
     ///
     /// This method is not cancel safe.
-    pub async fn send_msg<'a>(&mut self, msg: OUT) -> Result<(), Error> {
-        send_msg(&mut self.stream, msg).await
+    pub async fn send_msg(&mut self, msg: OUT) -> Result<(), TransportError> {
+        send_msg(
+            &mut self.stream,
+            &mut self.write_buf,
+            self.max_frame_len,
+            self.codec,
+            self.log_keystrokes,
+            msg,
+        )
+        .await?;
+        if let Some(metrics) = &self.metrics {
+            metrics.bytes_sent(self.write_buf.len());
+        }
+        Ok(())
     }
 }
 
@@ -151,19 +291,22 @@ impl<IN, OUT> Transport<IN, OUT>
 where
     IN: Message + Debug,
 {
-    fn as_msg_reader(&mut self) -> MessageReader<BytesMut> {
-        MessageReader::new(&mut self.stream, &mut self.read_buf)
-    }
-
     /// Waits for a protocol message.
     ///
     /// This method is cancel safe.
-    pub async fn recv_msg(&mut self) -> Result<IN, Error> {
+    pub async fn recv_msg(&mut self) -> Result<IN, TransportError> {
         let mut reader = self.as_msg_reader();
         reader.recv_msg().await
     }
 }
 
+/// Credentials to establish a secure channel with.
+#[derive(Clone, Debug)]
+pub enum Credentials {
+    Tls(TlsFiles),
+    Noise(NoiseAuth),
+}
+
 newtype! {
     /// TLS certificate.
     #[derive(Clone, Serialize, Deserialize)]
@@ -183,3 +326,54 @@ newtype! {
     #[derive(Clone, Debug)]
     pub PrivateKey = Vec<u8>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::protocol::Ping;
+    use tokio::io::duplex;
+
+    fn transport_pair() -> (
+        Transport<ServerMessage, ClientMessage>,
+        Transport<ClientMessage, ServerMessage>,
+    ) {
+        let (a, b) = duplex(64);
+        (Transport::new(a), Transport::new(b))
+    }
+
+    #[tokio::test]
+    async fn test_hello_and_roundtrip() {
+        let (mut client, mut server) = transport_pair();
+        server.max_frame_len = 2048;
+
+        let (client_hello, server_hello) = tokio::join!(
+            client.hello(Role::Client { codec: Codec::Cbor }),
+            server.hello(Role::Server)
+        );
+        assert_eq!(client_hello.unwrap(), server_hello.unwrap());
+        assert_eq!(client.max_frame_len, 2048);
+        assert_eq!(server.codec, Codec::Cbor);
+
+        client.send_msg(Ping { counter: 1 }.into()).await.unwrap();
+        let msg = server.recv_msg().await.unwrap();
+        assert!(matches!(msg, ClientMessage::Ping(Ping { counter: 1 })));
+    }
+
+    #[tokio::test]
+    async fn test_eof() {
+        let (client, mut server) = transport_pair();
+        drop(client);
+        assert!(matches!(server.recv_msg().await, Err(TransportError::Eof)));
+    }
+
+    #[tokio::test]
+    async fn test_hello_from_non_terong_peer() {
+        let (a, mut b) = duplex(64);
+        let mut server = Transport::<ClientMessage, ServerMessage>::new(a);
+        b.write_all(&[0, 5, 0, 0]).await.unwrap();
+        assert!(matches!(
+            server.hello(Role::Server).await,
+            Err(TransportError::Incompatible { .. })
+        ));
+    }
+}
@@ -0,0 +1,366 @@
+//! Secure channel based on the [Noise protocol](https://noiseprotocol.org/noise.html).
+//!
+//! An alternative to TLS that doesn't need X.509 certificates. Peers
+//! authenticate each other with either static key pairs or a pre-shared key.
+
+use crate::config::NoiseConfig;
+use anyhow::{anyhow, bail, ensure, Context, Error};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rand::RngCore;
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::{fmt, sync::Arc};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    task,
+};
+use tracing::debug;
+
+/// Mutually authenticated handshake, both peers know each other's static
+/// public key.
+const STATIC_KEYS_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Handshake authenticated by a pre-shared key.
+const PSK_PATTERN: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+
+/// Maximum size of a Noise message as defined in the specification.
+const MAX_MSG_LEN: usize = 65535;
+
+/// Size of the authentication tag appended to every encrypted message.
+const TAG_LEN: usize = 16;
+
+const MAX_PAYLOAD_LEN: usize = MAX_MSG_LEN - TAG_LEN;
+
+/// Size of keys and pre-shared keys, snow pads shorter ones and panics on
+/// longer ones.
+const KEY_LEN: usize = 32;
+
+/// Noise credentials.
+#[derive(Clone)]
+pub enum NoiseAuth {
+    StaticKeys {
+        private_key: Vec<u8>,
+        peer_public_key: Vec<u8>,
+    },
+    Psk(Vec<u8>),
+}
+
+impl fmt::Debug for NoiseAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoiseAuth::StaticKeys {
+                peer_public_key, ..
+            } => f
+                .debug_struct("StaticKeys")
+                .field("peer_public_key", &BASE64.encode(peer_public_key))
+                .finish_non_exhaustive(),
+            NoiseAuth::Psk(_) => f.debug_tuple("Psk").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl NoiseAuth {
+    /// Decodes credentials in config.
+    pub fn from_config(cfg: &NoiseConfig) -> Result<Self, Error> {
+        let decode = |name: &str, x: &str| {
+            let key = BASE64
+                .decode(x.trim())
+                .with_context(|| format!("{} is not valid base64", name))?;
+            ensure!(
+                key.len() == KEY_LEN,
+                "{} must be {} bytes, it's {} bytes",
+                name,
+                KEY_LEN,
+                key.len()
+            );
+            Ok(key)
+        };
+
+        let auth = match cfg {
+            NoiseConfig {
+                private_key: Some(private_key),
+                peer_public_key: Some(peer_public_key),
+                psk: None,
+            } => NoiseAuth::StaticKeys {
+                private_key: decode("private_key", private_key)?,
+                peer_public_key: decode("peer_public_key", peer_public_key)?,
+            },
+            NoiseConfig {
+                private_key: None,
+                peer_public_key: None,
+                psk: Some(psk),
+            } => NoiseAuth::Psk(decode("psk", psk)?),
+            _ => bail!("noise config requires either private_key and peer_public_key, or psk"),
+        };
+
+        Ok(auth)
+    }
+
+    fn builder(&self) -> Result<Builder<'_>, Error> {
+        let builder = match self {
+            NoiseAuth::StaticKeys { private_key, .. } => {
+                Builder::new(STATIC_KEYS_PATTERN.parse()?).local_private_key(private_key)
+            }
+            NoiseAuth::Psk(psk) => Builder::new(PSK_PATTERN.parse()?).psk(0, psk),
+        };
+        Ok(builder)
+    }
+
+    /// Verifies the peer's static key after handshake.
+    fn verify_peer(&self, handshake: &HandshakeState) -> Result<(), Error> {
+        if let NoiseAuth::StaticKeys {
+            peer_public_key, ..
+        } = self
+        {
+            let remote = handshake
+                .get_remote_static()
+                .ok_or_else(|| anyhow!("peer didn't send its static key"))?;
+            if remote != peer_public_key.as_slice() {
+                bail!("peer static key {} is not trusted", BASE64.encode(remote));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Generates a static key pair, returned as base64 encoded private and public
+/// keys.
+pub fn generate_keypair() -> Result<(String, String), Error> {
+    let keypair = Builder::new(STATIC_KEYS_PATTERN.parse()?).generate_keypair()?;
+    Ok((BASE64.encode(keypair.private), BASE64.encode(keypair.public)))
+}
+
+/// Generates a base64 encoded pre-shared key.
+pub fn generate_psk() -> String {
+    let mut psk = [0; 32];
+    rand::thread_rng().fill_bytes(&mut psk);
+    BASE64.encode(psk)
+}
+
+/// Performs handshake as the connecting peer.
+pub async fn initiate<S>(stream: S, auth: &NoiseAuth) -> Result<DuplexStream, Error>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let handshake = auth.builder()?.build_initiator()?;
+    handshake_and_spawn(stream, auth, handshake).await
+}
+
+/// Performs handshake as the accepting peer.
+pub async fn respond<S>(stream: S, auth: &NoiseAuth) -> Result<DuplexStream, Error>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let handshake = auth.builder()?.build_responder()?;
+    handshake_and_spawn(stream, auth, handshake).await
+}
+
+/// Completes the handshake, then spawns tasks that encrypt and decrypt data
+/// flowing between the returned stream and the underlying stream.
+async fn handshake_and_spawn<S>(
+    stream: S,
+    auth: &NoiseAuth,
+    mut handshake: HandshakeState,
+) -> Result<DuplexStream, Error>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = io::split(stream);
+
+    let mut msg = vec![0; MAX_MSG_LEN];
+    let mut payload = vec![0; MAX_MSG_LEN];
+
+    while !handshake.is_handshake_finished() {
+        if handshake.is_my_turn() {
+            let len = handshake.write_message(&[], &mut msg)?;
+            write_frame(&mut writer, &msg[..len]).await?;
+        } else {
+            let len = read_frame(&mut reader, &mut msg).await?;
+            handshake.read_message(&msg[..len], &mut payload)?;
+        }
+    }
+
+    auth.verify_peer(&handshake)?;
+    debug!("noise handshake completed");
+
+    let state = Arc::new(handshake.into_stateless_transport_mode()?);
+
+    let (local, remote) = io::duplex(MAX_MSG_LEN);
+    let (remote_reader, remote_writer) = io::split(remote);
+
+    task::spawn({
+        let state = state.clone();
+        async move {
+            if let Err(err) = encrypt_outgoing(state, remote_reader, writer).await {
+                debug!(?err, "noise outgoing stream closed");
+            }
+        }
+    });
+    task::spawn(async move {
+        if let Err(err) = decrypt_incoming(state, reader, remote_writer).await {
+            debug!(?err, "noise incoming stream closed");
+        }
+    });
+
+    Ok(local)
+}
+
+async fn encrypt_outgoing(
+    state: Arc<StatelessTransportState>,
+    mut plain: impl AsyncRead + Unpin,
+    mut sink: impl AsyncWrite + Unpin,
+) -> Result<(), Error> {
+    let mut buf = vec![0; MAX_PAYLOAD_LEN];
+    let mut msg = vec![0; MAX_MSG_LEN];
+    let mut nonce = 0;
+    loop {
+        let len = plain.read(&mut buf).await?;
+        if len == 0 {
+            sink.shutdown().await?;
+            break Ok(());
+        }
+        let len = state.write_message(nonce, &buf[..len], &mut msg)?;
+        nonce += 1;
+        write_frame(&mut sink, &msg[..len]).await?;
+    }
+}
+
+async fn decrypt_incoming(
+    state: Arc<StatelessTransportState>,
+    mut src: impl AsyncRead + Unpin,
+    mut plain: impl AsyncWrite + Unpin,
+) -> Result<(), Error> {
+    let mut msg = vec![0; MAX_MSG_LEN];
+    let mut buf = vec![0; MAX_MSG_LEN];
+    let mut nonce = 0;
+    loop {
+        let len = match read_frame(&mut src, &mut msg).await {
+            Ok(x) => x,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                plain.shutdown().await?;
+                break Ok(());
+            }
+            Err(err) => break Err(err.into()),
+        };
+        let len = state.read_message(nonce, &msg[..len], &mut buf)?;
+        nonce += 1;
+        plain.write_all(&buf[..len]).await?;
+    }
+}
+
+/// Writes a Noise message prefixed with its 16 bit length.
+async fn write_frame(sink: &mut (impl AsyncWrite + Unpin), msg: &[u8]) -> io::Result<()> {
+    sink.write_all(&(msg.len() as u16).to_be_bytes()).await?;
+    sink.write_all(msg).await?;
+    sink.flush().await
+}
+
+/// Reads a Noise message prefixed with its 16 bit length into the buffer.
+async fn read_frame(src: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> io::Result<usize> {
+    let len = src.read_u16().await? as usize;
+    src.read_exact(&mut buf[..len]).await?;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn connect_loopback(
+        server_auth: NoiseAuth,
+        client_auth: NoiseAuth,
+    ) -> (Result<DuplexStream, Error>, Result<DuplexStream, Error>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            respond(stream, &server_auth).await
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let client = initiate(stream, &client_auth).await;
+
+        (server.await.unwrap(), client)
+    }
+
+    fn static_keys() -> (NoiseAuth, NoiseAuth) {
+        let (server_private, server_public) = generate_keypair().unwrap();
+        let (client_private, client_public) = generate_keypair().unwrap();
+        let server = NoiseAuth::StaticKeys {
+            private_key: BASE64.decode(server_private).unwrap(),
+            peer_public_key: BASE64.decode(client_public).unwrap(),
+        };
+        let client = NoiseAuth::StaticKeys {
+            private_key: BASE64.decode(client_private).unwrap(),
+            peer_public_key: BASE64.decode(server_public).unwrap(),
+        };
+        (server, client)
+    }
+
+    async fn assert_roundtrip(mut a: DuplexStream, mut b: DuplexStream) {
+        a.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // larger than a single noise message
+        let big = vec![7; MAX_MSG_LEN * 2];
+        let big_ = big.clone();
+        let writer = task::spawn(async move {
+            b.write_all(&big_).await.unwrap();
+            b
+        });
+        let mut buf = vec![0; big.len()];
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, big);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_static_keys() {
+        let (server, client) = static_keys();
+        let (server, client) = connect_loopback(server, client).await;
+        assert_roundtrip(client.unwrap(), server.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_static_key() {
+        let (server, _) = static_keys();
+        let (_, client) = static_keys();
+        let (server, _) = connect_loopback(server, client).await;
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_psk() {
+        let psk = BASE64.decode(generate_psk()).unwrap();
+        let (server, client) =
+            connect_loopback(NoiseAuth::Psk(psk.clone()), NoiseAuth::Psk(psk)).await;
+        assert_roundtrip(client.unwrap(), server.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_psk_mismatch() {
+        let a = BASE64.decode(generate_psk()).unwrap();
+        let b = BASE64.decode(generate_psk()).unwrap();
+        let (server, client) = connect_loopback(NoiseAuth::Psk(a), NoiseAuth::Psk(b)).await;
+        assert!(server.is_err() || client.is_err());
+    }
+
+    #[test]
+    fn test_key_length() {
+        let (private_key, public_key) = generate_keypair().unwrap();
+        let config = |private_key: &str| NoiseConfig {
+            private_key: Some(private_key.to_owned()),
+            peer_public_key: Some(public_key.clone()),
+            psk: None,
+        };
+        assert!(NoiseAuth::from_config(&config(&private_key)).is_ok());
+
+        let long = BASE64.encode([1; KEY_LEN + 1]);
+        assert!(NoiseAuth::from_config(&config(&long)).is_err());
+        let short = BASE64.encode([1; KEY_LEN - 1]);
+        assert!(NoiseAuth::from_config(&config(&short)).is_err());
+    }
+}
@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Debug};
+
+/// Clipboard content.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub enum ClipboardContent {
+    Text(String),
+    /// PNG encoded image.
+    Image(Vec<u8>),
+}
+
+impl ClipboardContent {
+    /// Returns size of the content in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            ClipboardContent::Text(x) => x.len(),
+            ClipboardContent::Image(x) => x.len(),
+        }
+    }
+}
+
+impl Debug for ClipboardContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // clipboard may hold secrets, keep them out of logs
+        let kind = match self {
+            ClipboardContent::Text(_) => "Text",
+            ClipboardContent::Image(_) => "Image",
+        };
+        f.debug_struct(kind).field("size", &self.size()).finish()
+    }
+}
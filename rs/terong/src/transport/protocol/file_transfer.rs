@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Debug};
+
+/// File transfer message, sent in either direction.
+///
+/// Transfer ids are assigned by the sender. `Offer`, `Chunk`, `Complete` and
+/// `Cancel` are sent by the sender, `Accept` and `Reject` by the receiver.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub enum FileTransfer {
+    Offer {
+        id: u32,
+        /// File name without directories.
+        name: String,
+        size: u64,
+    },
+    Accept {
+        id: u32,
+    },
+    /// Receiver declines the offer or gives up on the transfer.
+    Reject {
+        id: u32,
+        reason: String,
+    },
+    Chunk(FileChunk),
+    /// Sender has sent all chunks.
+    Complete {
+        id: u32,
+        sha256: [u8; 32],
+    },
+    /// Sender gives up on the transfer.
+    Cancel {
+        id: u32,
+        reason: String,
+    },
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileChunk {
+    pub id: u32,
+    pub data: Vec<u8>,
+}
+
+impl Debug for FileChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileChunk")
+            .field("id", &self.id)
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
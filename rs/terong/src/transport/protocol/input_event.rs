@@ -1 +1,29 @@
 pub use input_event::*;
+
+use crate::logging::Redact;
+use std::fmt;
+
+impl Redact for InputEvent {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputEvent::KeyDown { .. } => f.debug_struct("KeyDown").finish_non_exhaustive(),
+            InputEvent::KeyRepeat { .. } => f.debug_struct("KeyRepeat").finish_non_exhaustive(),
+            InputEvent::KeyUp { .. } => f.debug_struct("KeyUp").finish_non_exhaustive(),
+            InputEvent::RawKeyDown { .. } => f.debug_struct("RawKeyDown").finish_non_exhaustive(),
+            InputEvent::RawKeyUp { .. } => f.debug_struct("RawKeyUp").finish_non_exhaustive(),
+            x => fmt::Debug::fmt(x, f),
+        }
+    }
+}
+
+/// Press of the key, for tests.
+#[cfg(test)]
+pub fn down(key: KeyCode) -> InputEvent {
+    InputEvent::KeyDown { key }
+}
+
+/// Release of the key, for tests.
+#[cfg(test)]
+pub fn up(key: KeyCode) -> InputEvent {
+    InputEvent::KeyUp { key }
+}
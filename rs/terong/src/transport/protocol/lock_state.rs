@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+/// State of the keyboard lock keys.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, Debug)]
+pub struct LockState {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LockKey {
+    Caps,
+    Num,
+    Scroll,
+}
+
+impl LockState {
+    /// Returns lock keys to toggle to get from this state to `target`.
+    pub fn toggles_to(&self, target: &LockState) -> Vec<LockKey> {
+        [
+            (self.caps_lock != target.caps_lock, LockKey::Caps),
+            (self.num_lock != target.num_lock, LockKey::Num),
+            (self.scroll_lock != target.scroll_lock, LockKey::Scroll),
+        ]
+        .into_iter()
+        .filter_map(|(differs, key)| differs.then_some(key))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toggles_to() {
+        let caps = LockState {
+            caps_lock: true,
+            ..Default::default()
+        };
+        let num = LockState {
+            num_lock: true,
+            ..Default::default()
+        };
+        assert_eq!(caps.toggles_to(&caps), vec![]);
+        assert_eq!(caps.toggles_to(&num), vec![LockKey::Caps, LockKey::Num]);
+    }
+}
@@ -1,32 +1,65 @@
+mod clipboard;
+mod file_transfer;
 mod heartbeat;
 mod input_event;
+mod lock_state;
 
+use crate::logging::{Redact, Redacted};
 use macross::impl_from;
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 
+pub use self::clipboard::*;
+pub use self::file_transfer::*;
 pub use self::heartbeat::*;
 pub use self::input_event::*;
+pub use self::lock_state::*;
 
 /// Client to server message.
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum ClientMessage {
     Ping(Ping),
+    Clipboard(ClipboardContent),
+    File(FileTransfer),
+    /// Lock state of the client host machine, sent when it changes.
+    LockState(LockState),
 }
 
 impl_from!(ClientMessage, {
     Self::Ping => Ping,
+    Self::Clipboard => ClipboardContent,
+    Self::File => FileTransfer,
+    Self::LockState => LockState,
 });
 
+impl Redact for ClientMessage {}
+
 /// Server to client message.
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum ServerMessage {
     /// Propagated event from the server host machine.
     Event(InputEvent),
     Pong(Pong),
+    Clipboard(ClipboardContent),
+    File(FileTransfer),
+    /// Lock state of the server host machine, sent when control switches to
+    /// the client.
+    LockState(LockState),
 }
 
 impl_from!(ServerMessage, {
      Self::Event => InputEvent,
      Self::Pong => Pong,
+     Self::Clipboard => ClipboardContent,
+     Self::File => FileTransfer,
+     Self::LockState => LockState,
 });
+
+impl Redact for ServerMessage {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerMessage::Event(x) => f.debug_tuple("Event").field(&Redacted(x, false)).finish(),
+            x => Debug::fmt(x, f),
+        }
+    }
+}
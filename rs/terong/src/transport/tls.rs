@@ -0,0 +1,497 @@
+//! TLS certificates lifecycle.
+
+use super::{Certificate, PrivateKey};
+use crate::config::{read_certs, read_private_key};
+use anyhow::{anyhow, ensure, Context, Error};
+use rcgen::{CertificateParams, DnType, KeyPair};
+use std::{
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    slice,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use time::OffsetDateTime;
+use tokio::{
+    fs,
+    task::{self, JoinHandle},
+    time::{interval_at, Instant},
+};
+use tracing::{error, info, warn};
+use x509_parser::extensions::GeneralName;
+
+/// Certificates expiring within this many days are warned about.
+pub const EXPIRY_WARNING_DAYS: i64 = 30;
+
+/// Validity period of generated self-signed certificates.
+pub const SELF_SIGNED_VALIDITY_DAYS: i64 = 365;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Interval [ExpiryWatcher] checks the certificates at.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(SECS_PER_DAY as _);
+
+/// Parsed metadata of a certificate.
+#[derive(Clone, Debug)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub common_name: Option<String>,
+    /// DNS names in the subject alternative name extension.
+    pub dns_names: Vec<String>,
+    /// IP addresses in the subject alternative name extension.
+    pub ip_addresses: Vec<IpAddr>,
+    /// Unix timestamp, in seconds, after which the certificate is invalid.
+    pub not_after: i64,
+}
+
+impl CertificateInfo {
+    /// Returns whole days until the certificate expires, negative if it has
+    /// expired.
+    pub fn days_to_expiry(&self) -> i64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() as i64)
+            .unwrap_or_default();
+        (self.not_after - now).div_euclid(SECS_PER_DAY)
+    }
+
+    /// Returns the first DNS name, or the subject if there's none.
+    pub fn name(&self) -> &str {
+        self.dns_names.first().unwrap_or(&self.subject)
+    }
+}
+
+impl Certificate {
+    /// Parses this certificate, which can be either PEM or DER encoded.
+    pub fn info(&self) -> Result<CertificateInfo, Error> {
+        let pem;
+        let der = if self.0.trim_ascii_start().starts_with(b"-----BEGIN") {
+            let (_, x) = x509_parser::pem::parse_x509_pem(&self.0)
+                .map_err(|err| anyhow!("failed to parse pem, {}", err))?;
+            pem = x;
+            &pem.contents
+        } else {
+            &self.0
+        };
+
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .map_err(|err| anyhow!("failed to parse certificate, {}", err))?;
+
+        let general_names = match cert.subject_alternative_name() {
+            Ok(Some(ext)) => ext.value.general_names.as_slice(),
+            _ => &[],
+        };
+        let dns_names = general_names
+            .iter()
+            .filter_map(|x| match x {
+                GeneralName::DNSName(x) => Some(x.to_string()),
+                _ => None,
+            })
+            .collect();
+        let ip_addresses = general_names
+            .iter()
+            .filter_map(|x| match x {
+                GeneralName::IPAddress(&[a, b, c, d]) => Some(IpAddr::from([a, b, c, d])),
+                GeneralName::IPAddress(x) => <[u8; 16]>::try_from(*x).ok().map(IpAddr::from),
+                _ => None,
+            })
+            .collect();
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|x| x.as_str().ok())
+            .map(str::to_owned);
+
+        Ok(CertificateInfo {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            common_name,
+            dns_names,
+            ip_addresses,
+            not_after: cert.validity().not_after.timestamp(),
+        })
+    }
+}
+
+/// Logs the expiry status of the certificates and returns days until the
+/// earliest of them expires.
+///
+/// `label` names the certificates in the log, e.g. `"server certificate"`.
+pub fn check_expiry(label: &str, certs: &[Certificate]) -> Option<i64> {
+    let mut earliest = None;
+    for cert in certs {
+        let info = match cert.info() {
+            Ok(x) => x,
+            Err(err) => {
+                warn!(?err, "failed to parse {}", label);
+                continue;
+            }
+        };
+
+        let days = info.days_to_expiry();
+        let subject = &info.subject;
+        if days < 0 {
+            error!(?subject, days_to_expiry = days, "{} has expired", label);
+        } else if days < EXPIRY_WARNING_DAYS {
+            warn!(?subject, days_to_expiry = days, "{} is expiring soon", label);
+        } else {
+            info!(?subject, days_to_expiry = days, "{} is valid", label);
+        }
+
+        earliest = Some(earliest.map_or(days, |x: i64| x.min(days)));
+    }
+    earliest
+}
+
+/// Days until the loaded certificates expire.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TlsStatus {
+    pub cert_days_to_expiry: Option<i64>,
+    pub peer_cert_days_to_expiry: Option<i64>,
+}
+
+impl TlsStatus {
+    /// Logs the expiry status of the material's certificates.
+    fn check(material: &TlsMaterial) -> Self {
+        Self {
+            cert_days_to_expiry: check_expiry("certificate", &material.certs),
+            peer_cert_days_to_expiry: check_expiry("peer certificate", &material.peer_certs),
+        }
+    }
+}
+
+impl fmt::Display for TlsStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn days(x: Option<i64>) -> String {
+            x.map(|x| format!("{} days", x))
+                .unwrap_or_else(|| "unknown days".to_owned())
+        }
+        write!(
+            f,
+            "certificate expires in {}, peer certificate expires in {}",
+            days(self.cert_days_to_expiry),
+            days(self.peer_cert_days_to_expiry)
+        )
+    }
+}
+
+/// Certificates and key of [TlsFiles].
+#[derive(Clone, Debug)]
+pub struct TlsMaterial {
+    pub certs: Vec<Certificate>,
+    pub key: PrivateKey,
+    pub peer_certs: Vec<Certificate>,
+}
+
+/// Host's TLS key pair and its peer's certificate, read from files or given in
+/// memory.
+///
+/// Files are re-read when their modification time changes so new connections
+/// can use renewed certificates without restarting the application.
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    /// Certificate, key and peer certificate paths, `None` if the material was
+    /// given in memory.
+    paths: Option<[PathBuf; 3]>,
+    modified: [Option<SystemTime>; 3],
+    material: TlsMaterial,
+    status: TlsStatus,
+}
+
+impl TlsFiles {
+    /// Reads the files.
+    pub async fn load(
+        cert_path: PathBuf,
+        key_path: PathBuf,
+        peer_cert_path: PathBuf,
+    ) -> Result<Self, Error> {
+        let modified = modified_times([&cert_path, &key_path, &peer_cert_path]).await;
+        let (material, status) = read_material(&cert_path, &key_path, &peer_cert_path).await?;
+        Ok(Self {
+            paths: Some([cert_path, key_path, peer_cert_path]),
+            modified,
+            material,
+            status,
+        })
+    }
+
+    /// Uses the material as is, it's never reloaded.
+    ///
+    /// Fails if either certificate list is empty.
+    pub fn from_material(material: TlsMaterial) -> Result<Self, Error> {
+        ensure!(!material.certs.is_empty(), "missing tls certificate");
+        ensure!(
+            !material.peer_certs.is_empty(),
+            "missing tls peer certificate"
+        );
+        let status = TlsStatus::check(&material);
+        Ok(Self {
+            paths: None,
+            modified: Default::default(),
+            material,
+            status,
+        })
+    }
+
+    /// Re-reads the files if any of them has been modified since they were
+    /// last read. Returns `true` if they were re-read.
+    ///
+    /// Previously read material is kept if reading fails.
+    pub async fn reload(&mut self) -> Result<bool, Error> {
+        let [cert_path, key_path, peer_cert_path] = match &self.paths {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+
+        let modified = modified_times([cert_path, key_path, peer_cert_path]).await;
+        if modified == self.modified {
+            return Ok(false);
+        }
+
+        let (material, status) = read_material(cert_path, key_path, peer_cert_path).await?;
+        self.material = material;
+        self.status = status;
+        self.modified = modified;
+
+        Ok(true)
+    }
+
+    pub fn material(&self) -> &TlsMaterial {
+        &self.material
+    }
+
+    pub fn status(&self) -> TlsStatus {
+        self.status
+    }
+
+    /// Logs the expiry status of the certificates again.
+    pub fn check_expiry(&mut self) {
+        self.status = TlsStatus::check(&self.material);
+    }
+}
+
+/// Checks expiry of the certificates daily until it's dropped, the application
+/// can run for longer than they're valid.
+#[derive(Debug)]
+pub struct ExpiryWatcher(JoinHandle<()>);
+
+impl ExpiryWatcher {
+    pub fn start(mut tls: TlsFiles) -> Self {
+        let task = task::spawn(async move {
+            let mut ticker = interval_at(
+                Instant::now() + EXPIRY_CHECK_INTERVAL,
+                EXPIRY_CHECK_INTERVAL,
+            );
+            loop {
+                ticker.tick().await;
+                // renewed certificates are checked as they're read
+                match tls.reload().await {
+                    Ok(true) => (),
+                    Ok(false) => tls.check_expiry(),
+                    Err(err) => {
+                        warn!(?err, "failed to reload tls files");
+                        tls.check_expiry();
+                    }
+                }
+            }
+        });
+        Self(task)
+    }
+}
+
+impl Drop for ExpiryWatcher {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn modified_times(paths: [&Path; 3]) -> [Option<SystemTime>; 3] {
+    let mut times = [None; 3];
+    for (time, path) in times.iter_mut().zip(paths) {
+        *time = fs::metadata(path).await.and_then(|x| x.modified()).ok();
+    }
+    times
+}
+
+async fn read_material(
+    cert_path: &Path,
+    key_path: &Path,
+    peer_cert_path: &Path,
+) -> Result<(TlsMaterial, TlsStatus), Error> {
+    let certs = read_certs(cert_path)
+        .await
+        .with_context(|| format!("failed to read certificate at {:?}", cert_path))?;
+    let key = read_private_key(key_path)
+        .await
+        .with_context(|| format!("failed to read private key at {:?}", key_path))?;
+    let peer_certs = read_certs(peer_cert_path)
+        .await
+        .with_context(|| format!("failed to read peer certificate at {:?}", peer_cert_path))?;
+
+    let material = TlsMaterial {
+        certs,
+        key,
+        peer_certs,
+    };
+    let status = TlsStatus::check(&material);
+
+    Ok((material, status))
+}
+
+/// Logs the expiry status of the certificate a peer presented during TLS
+/// handshake and returns its metadata.
+pub fn check_peer_certificate<S>(stream: &tokio_native_tls::TlsStream<S>) -> Option<CertificateInfo>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let cert = stream.get_ref().peer_certificate().ok().flatten()?;
+    let cert: Certificate = cert.to_der().ok()?.into();
+    check_expiry("presented peer certificate", slice::from_ref(&cert));
+    cert.info().ok()
+}
+
+/// Generates a self-signed certificate for the subject names.
+///
+/// The existing key is reused if one is given, otherwise a new key is
+/// generated.
+pub fn generate_self_signed(
+    names: Vec<String>,
+    key: Option<&PrivateKey>,
+) -> Result<(Certificate, PrivateKey), Error> {
+    let common_name = names.first().cloned();
+    self_signed(common_name, names, key)
+}
+
+/// Generates a self-signed certificate for the common name and subject
+/// alternative names, either DNS names or IP addresses.
+fn self_signed(
+    common_name: Option<String>,
+    names: Vec<String>,
+    key: Option<&PrivateKey>,
+) -> Result<(Certificate, PrivateKey), Error> {
+    let key_pair = match key {
+        Some(key) => {
+            let pem = std::str::from_utf8(&key.0).context("private key is not pem encoded")?;
+            KeyPair::from_pem(pem)?
+        }
+        None => KeyPair::generate()?,
+    };
+
+    let mut params = CertificateParams::new(names)?;
+    if let Some(name) = common_name {
+        params.distinguished_name.push(DnType::CommonName, name);
+    }
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now;
+    params.not_after = now + time::Duration::days(SELF_SIGNED_VALIDITY_DAYS);
+
+    let cert = params.self_signed(&key_pair)?;
+
+    Ok((
+        cert.pem().into_bytes().into(),
+        key_pair.serialize_pem().into_bytes().into(),
+    ))
+}
+
+/// Replaces a self-signed certificate with a new one for the same common
+/// name, subject alternative names and key.
+pub async fn renew_self_signed(cert_path: &Path, key_path: &Path) -> Result<(), Error> {
+    let certs = read_certs(cert_path).await?;
+    let key = read_private_key(key_path).await?;
+
+    let info = certs
+        .first()
+        .ok_or_else(|| anyhow!("missing certificate"))?
+        .info()?;
+    ensure!(
+        info.issuer == info.subject,
+        "certificate isn't self-signed, it's issued by {}",
+        info.issuer
+    );
+    info!(subject = ?info.subject, days_to_expiry = info.days_to_expiry(), "renewing certificate");
+
+    let names = info
+        .dns_names
+        .into_iter()
+        .chain(info.ip_addresses.iter().map(ToString::to_string))
+        .collect();
+    let (cert, _) = self_signed(info.common_name, names, Some(&key))?;
+    fs::write(cert_path, &cert.0).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_self_signed() {
+        let (cert, key) = generate_self_signed(vec!["terong".to_owned()], None).unwrap();
+        let info = cert.info().unwrap();
+        assert_eq!(info.dns_names, vec!["terong".to_owned()]);
+        assert!((SELF_SIGNED_VALIDITY_DAYS - 1..=SELF_SIGNED_VALIDITY_DAYS)
+            .contains(&info.days_to_expiry()));
+
+        let (renewed, _) = generate_self_signed(info.dns_names, Some(&key)).unwrap();
+        assert_eq!(renewed.info().unwrap().subject, info.subject);
+    }
+
+    #[tokio::test]
+    async fn test_renew_self_signed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+
+        let names = vec!["terong".to_owned(), "10.0.0.2".to_owned()];
+        let (cert, key) = self_signed(Some("desk".to_owned()), names, None).unwrap();
+        fs::write(&cert_path, &cert.0).await.unwrap();
+        fs::write(&key_path, &key.0).await.unwrap();
+        renew_self_signed(&cert_path, &key_path).await.unwrap();
+
+        let info = cert.info().unwrap();
+        let renewed = read_certs(&cert_path).await.unwrap()[0].info().unwrap();
+        assert_eq!(renewed.common_name.as_deref(), Some("desk"));
+        assert_eq!(renewed.subject, info.subject);
+        assert_eq!(renewed.dns_names, vec!["terong".to_owned()]);
+        assert_eq!(renewed.ip_addresses, vec![IpAddr::from([10, 0, 0, 2])]);
+
+        // certificates issued by a CA aren't replaced
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.distinguished_name.push(DnType::CommonName, "ca");
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+        let key_pair = KeyPair::from_pem(std::str::from_utf8(&key.0).unwrap()).unwrap();
+        let issued = CertificateParams::new(vec!["terong".to_owned()])
+            .unwrap()
+            .signed_by(&key_pair, &ca_cert, &ca_key)
+            .unwrap();
+        fs::write(&cert_path, issued.pem()).await.unwrap();
+        assert!(renew_self_signed(&cert_path, &key_path).await.is_err());
+        assert_eq!(fs::read_to_string(&cert_path).await.unwrap(), issued.pem());
+    }
+
+    #[test]
+    fn test_material_without_certificates() {
+        let (cert, key) = generate_self_signed(vec!["terong".to_owned()], None).unwrap();
+        let material = TlsMaterial {
+            certs: vec![cert.clone()],
+            key,
+            peer_certs: vec![cert],
+        };
+        assert!(TlsFiles::from_material(material.clone()).is_ok());
+
+        let no_certs = TlsMaterial {
+            certs: Vec::new(),
+            ..material.clone()
+        };
+        assert!(TlsFiles::from_material(no_certs).is_err());
+        let no_peer_certs = TlsMaterial {
+            peer_certs: Vec::new(),
+            ..material
+        };
+        assert!(TlsFiles::from_material(no_peer_certs).is_err());
+    }
+}
@@ -0,0 +1,416 @@
+//! End-to-end tests of a server and clients connected over loopback.
+//!
+//! Input comes from and goes to channels instead of devices, so these run
+//! without access to evdev or uinput. Time is virtual, tests advance it to get
+//! through heartbeats and reconnect delays.
+
+use anyhow::Error;
+use input_event::{InputEvent, KeyCode, MouseButton};
+use std::{future::Future, net::TcpListener, sync::mpsc as std_mpsc, thread, time::Duration};
+use tempfile::TempDir;
+use terong::{
+    cert,
+    client::{
+        self,
+        config::ClientConfig,
+        input_sink::{ChannelInputSink, ChannelInputSinkHandle, SinkCommand},
+    },
+    input_source::{event::LocalInputEvent, ChannelInputSource, ChannelInputSourceHandle},
+    server::{self, config::ServerConfig},
+};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot},
+    task::{self, JoinHandle},
+    time::{self, Instant},
+};
+
+/// Virtual time that passes between retries of a probe.
+const STEP: Duration = Duration::from_secs(1);
+
+/// Real time given to socket IO to settle before virtual time moves on.
+const SETTLE: Duration = Duration::from_millis(50);
+
+/// Real time an expected event may take to arrive.
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Real time the controller looks back for hotkey double taps.
+const HOTKEY_WINDOW: Duration = Duration::from_millis(300);
+
+/// Server's heartbeat interval, a client that hasn't pinged for this long is
+/// disconnected.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Throwaway certificates, a port and a held clock for a test.
+struct Harness {
+    certs: TempDir,
+    port: u16,
+    _clock: ClockHold,
+}
+
+impl Harness {
+    async fn new() -> Self {
+        let certs = tempfile::tempdir().unwrap();
+        for name in ["server", "client"] {
+            cert::new_cert(
+                &certs.path().join(format!("{}.crt", name)),
+                &certs.path().join(format!("{}.key", name)),
+                vec!["localhost".to_owned()],
+            )
+            .await
+            .unwrap();
+        }
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        Self {
+            certs,
+            port,
+            _clock: ClockHold::new(),
+        }
+    }
+
+    fn cert_path(&self, name: &str) -> String {
+        self.certs.path().join(name).display().to_string()
+    }
+
+    async fn start_server(&self) -> Server {
+        let cfg: ServerConfig = toml::from_str(&format!(
+            r#"
+            port = {}
+            tls_cert_path = '{}'
+            tls_key_path = '{}'
+            client_tls_cert_path = '{}'
+            clipboard.enabled = false
+            [linux]
+            "#,
+            self.port,
+            self.cert_path("server.crt"),
+            self.cert_path("server.key"),
+            self.cert_path("client.crt"),
+        ))
+        .unwrap();
+
+        let (source, input) = ChannelInputSource::new();
+        let (_, path_rx) = mpsc::channel(1);
+        let task = task::spawn(server::start_app(cfg, source, path_rx));
+
+        // let it listen before clients connect
+        settle().await;
+
+        Server { input, task }
+    }
+
+    fn start_client(&self) -> Client {
+        let cfg: ClientConfig = toml::from_str(&format!(
+            r#"
+            server_addr = "127.0.0.1:{}"
+            tls_cert_path = '{}'
+            tls_key_path = '{}'
+            server_tls_cert_path = '{}'
+            clipboard.enabled = false
+            "#,
+            self.port,
+            self.cert_path("client.crt"),
+            self.cert_path("client.key"),
+            self.cert_path("server.crt"),
+        ))
+        .unwrap();
+
+        let (sink, handle) = ChannelInputSink::new();
+        let (_, path_rx) = mpsc::channel(1);
+        let task = task::spawn(client::start_app(cfg, sink, path_rx));
+
+        Client {
+            sink: handle,
+            _task: task,
+        }
+    }
+}
+
+/// Keeps paused time from auto-advancing.
+///
+/// Auto-advance jumps to the next timer whenever the runtime is idle, even if
+/// a socket read is only about to be polled, so timers would fire ahead of
+/// IO. Tokio doesn't auto-advance while a blocking task runs, this holds one
+/// until dropped so time only moves with [time::advance].
+struct ClockHold {
+    _release: std_mpsc::Sender<()>,
+}
+
+impl ClockHold {
+    fn new() -> Self {
+        let (tx, rx) = std_mpsc::channel::<()>();
+        task::spawn_blocking(move || rx.recv().ok());
+        Self { _release: tx }
+    }
+}
+
+/// Waits in real time, virtual time stays put.
+async fn real_sleep(duration: Duration) {
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        thread::sleep(duration);
+        tx.send(()).ok();
+    });
+    rx.await.ok();
+}
+
+async fn settle() {
+    real_sleep(SETTLE).await;
+}
+
+/// Returns None if the future doesn't complete within the real time limit.
+async fn real_timeout<F: Future>(limit: Duration, future: F) -> Option<F::Output> {
+    select! {
+        x = future => Some(x),
+        _ = real_sleep(limit) => None,
+    }
+}
+
+/// Advances virtual time step by step, letting IO settle after each.
+async fn pass_time(duration: Duration) {
+    let until = Instant::now() + duration;
+    while Instant::now() < until {
+        time::advance(STEP).await;
+        settle().await;
+    }
+}
+
+struct Server {
+    input: ChannelInputSourceHandle,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl Server {
+    async fn send(&self, event: LocalInputEvent) {
+        self.input.event_tx.send(event).await.unwrap();
+    }
+
+    async fn tap(&self, key: KeyCode) {
+        self.send(LocalInputEvent::KeyDown { key }).await;
+        self.send(LocalInputEvent::KeyUp { key }).await;
+    }
+
+    /// Double taps the hotkey and waits for the relay to toggle.
+    async fn toggle_relay(&mut self) {
+        // the double tap is only seen without other presses in its window,
+        // which is measured in real time
+        real_sleep(HOTKEY_WINDOW).await;
+
+        let relay = *self.input.capture_rx.borrow();
+        self.tap(KeyCode::RightCtrl).await;
+        self.tap(KeyCode::RightCtrl).await;
+        let toggled = self.input.capture_rx.wait_for(|x| *x != relay);
+        real_timeout(EVENT_TIMEOUT, toggled)
+            .await
+            .expect("relay didn't toggle")
+            .unwrap();
+    }
+
+    /// Closes its input, which stops the server.
+    async fn stop(self) {
+        drop(self.input);
+        self.task.await.unwrap().unwrap();
+    }
+}
+
+struct Client {
+    sink: ChannelInputSinkHandle,
+    _task: JoinHandle<Result<(), Error>>,
+}
+
+impl Client {
+    /// Receives the next input event, skipping other commands.
+    async fn next_event(&mut self) -> InputEvent {
+        let recv = async {
+            loop {
+                if let SinkCommand::Event(event) = self.sink.cmd_rx.recv().await.unwrap() {
+                    return event;
+                }
+            }
+        };
+        real_timeout(EVENT_TIMEOUT, recv)
+            .await
+            .expect("client didn't receive an event")
+    }
+
+    /// Returns true if no input event is pending.
+    fn is_idle(&mut self) -> bool {
+        while let Ok(cmd) = self.sink.cmd_rx.try_recv() {
+            if let SinkCommand::Event(_) = cmd {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Taps the key until the client receives it, returns the virtual time it
+/// took.
+///
+/// Events are dropped while the client isn't connected, so this is also how
+/// tests wait for a session. Virtual time advances between taps.
+async fn probe(server: &Server, client: &mut Client, key: KeyCode, within: Duration) -> Duration {
+    let started_at = Instant::now();
+    loop {
+        server.tap(key).await;
+
+        let received = async {
+            loop {
+                let cmd = client.sink.cmd_rx.recv().await.unwrap();
+                if let SinkCommand::Event(InputEvent::KeyDown { key: x }) = cmd {
+                    if x == key {
+                        return;
+                    }
+                }
+            }
+        };
+        if real_timeout(SETTLE, received).await.is_some() {
+            break;
+        }
+
+        assert!(
+            started_at.elapsed() < within,
+            "client didn't receive the probe"
+        );
+        time::advance(STEP).await;
+    }
+    assert_eq!(client.next_event().await, InputEvent::KeyUp { key });
+    started_at.elapsed()
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_event_delivery() {
+    let harness = Harness::new().await;
+    let mut server = harness.start_server().await;
+    let mut client = harness.start_client();
+
+    server.toggle_relay().await;
+    probe(&server, &mut client, KeyCode::A, Duration::from_secs(30)).await;
+
+    let events = [
+        (
+            LocalInputEvent::KeyDown {
+                key: KeyCode::LeftShift,
+            },
+            InputEvent::KeyDown {
+                key: KeyCode::LeftShift,
+            },
+        ),
+        (
+            LocalInputEvent::KeyDown { key: KeyCode::B },
+            InputEvent::KeyDown { key: KeyCode::B },
+        ),
+        (
+            LocalInputEvent::KeyUp { key: KeyCode::B },
+            InputEvent::KeyUp { key: KeyCode::B },
+        ),
+        (
+            LocalInputEvent::KeyUp {
+                key: KeyCode::LeftShift,
+            },
+            InputEvent::KeyUp {
+                key: KeyCode::LeftShift,
+            },
+        ),
+        (
+            LocalInputEvent::MouseMove((3, -4).into()),
+            InputEvent::MouseMove { dx: 3, dy: -4 },
+        ),
+        (
+            LocalInputEvent::MouseButtonDown {
+                button: MouseButton::Left,
+            },
+            InputEvent::MouseButtonDown {
+                button: MouseButton::Left,
+            },
+        ),
+        (
+            LocalInputEvent::MouseButtonUp {
+                button: MouseButton::Left,
+            },
+            InputEvent::MouseButtonUp {
+                button: MouseButton::Left,
+            },
+        ),
+    ];
+    for (local, _) in events {
+        server.send(local).await;
+    }
+    for (_, relayed) in events {
+        assert_eq!(client.next_event().await, relayed);
+    }
+
+    // the hotkey takes control back, nothing is relayed afterwards
+    server.toggle_relay().await;
+    server.tap(KeyCode::A).await;
+    settle().await;
+    assert!(client.is_idle());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_reconnect() {
+    let harness = Harness::new().await;
+    let mut server = harness.start_server().await;
+    let mut client = harness.start_client();
+
+    server.toggle_relay().await;
+    probe(&server, &mut client, KeyCode::A, Duration::from_secs(30)).await;
+
+    server.stop().await;
+
+    // the client keeps retrying until a server is back on the port
+    let mut server = harness.start_server().await;
+    server.toggle_relay().await;
+    probe(&server, &mut client, KeyCode::A, Duration::from_secs(60)).await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_live_session_is_kept() {
+    let harness = Harness::new().await;
+    let mut server = harness.start_server().await;
+    let mut first = harness.start_client();
+
+    server.toggle_relay().await;
+    probe(&server, &mut first, KeyCode::A, Duration::from_secs(30)).await;
+
+    // heartbeats keep the first client's session, the second is turned away
+    let mut second = harness.start_client();
+    pass_time(HEARTBEAT_INTERVAL * 3).await;
+
+    // quicker than the client could reconnect
+    probe(&server, &mut first, KeyCode::A, Duration::from_secs(5)).await;
+    assert!(second.is_idle());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_heartbeat_timeout() {
+    let harness = Harness::new().await;
+    let mut server = harness.start_server().await;
+    let mut first = harness.start_client();
+
+    server.toggle_relay().await;
+    let started_at = Instant::now();
+    probe(&server, &mut first, KeyCode::A, Duration::from_secs(30)).await;
+
+    // The first client stops taking events. They fill its sink and its session
+    // stalls, so it stops sending heartbeats.
+    for _ in 0..32 {
+        server.tap(KeyCode::A).await;
+    }
+
+    // the server disconnects it and the second client's session replaces it
+    let mut second = harness.start_client();
+    probe(&server, &mut second, KeyCode::A, Duration::from_secs(120)).await;
+    let elapsed = started_at.elapsed();
+    assert!(
+        elapsed >= HEARTBEAT_INTERVAL,
+        "replaced after {:?}",
+        elapsed
+    );
+}